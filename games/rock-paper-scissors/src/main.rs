@@ -4,9 +4,11 @@ use std::{
 };
 
 use anyhow::bail;
+use ijson::{ijson, IValue};
 use playferrous_types::{
-    process::GameProcess, CommandResponse, ConsoleUi, Game, GameResult, GameSetup, GameState,
-    GameTick, InProgressGameState, PlayerResult,
+    process::GameProcess, CommandResponse, ConsoleUi, Game, GameCapabilities, GameDescription,
+    GameResult, GameSetup, GameState, GameTick, InProgressGameState, InputMode, PlayerResult,
+    PlayerView,
 };
 use serde::{Deserialize, Serialize};
 
@@ -105,6 +107,39 @@ impl Game for RockPaperScissors {
 }
 
 impl GameProcess for RockPaperScissors {
+    fn describe() -> GameDescription {
+        GameDescription {
+            display_name: Some("Rock, Paper, Scissors".into()),
+            description: Some(
+                "Each round, both players secretly choose rock, paper or scissors. \
+                 Rock beats scissors, scissors beats paper, and paper beats rock."
+                    .into(),
+            ),
+            min_players: 2,
+            max_players: 2,
+            snapshot_version: 0,
+            capabilities: GameCapabilities {
+                console_ui: true,
+                masked_input: false,
+                spectators: true,
+                binary_snapshots: false,
+                undo: false,
+                render_diff: true,
+            },
+        }
+    }
+
+    fn rules_schema() -> IValue {
+        ijson!({
+            "type": "object",
+            "properties": {
+                "num_rounds": { "type": "integer", "minimum": 1 },
+                "turn_timeout": { "type": "integer", "minimum": 1 },
+            },
+            "required": ["num_rounds", "turn_timeout"],
+        })
+    }
+
     fn new(setup: GameSetup<Self>) -> anyhow::Result<Self> {
         Ok(Self {
             rules: setup.rules,
@@ -121,6 +156,19 @@ impl GameProcess for RockPaperScissors {
         Ok(self.state.clone())
     }
 
+    fn render_player_state(&mut self, player: PlayerView) -> anyhow::Result<IValue> {
+        let mut state = self.state.clone();
+        // Nobody else may know what player 0 has chosen until player 1 goes
+        if player != PlayerView::Player(0) {
+            state.player0_action = None;
+            state.player0_prompt.clear();
+        }
+        if player != PlayerView::Player(1) {
+            state.player1_prompt.clear();
+        }
+        Ok(ijson::to_value(state)?)
+    }
+
     fn advance(&mut self, tick: GameTick, action: Self::Action) -> anyhow::Result<()> {
         if let Some(player0_action) = self.state.player0_action.take() {
             let player0_outcome = if let Some(player1_action) = action {
@@ -154,16 +202,17 @@ impl GameProcess for RockPaperScissors {
             self.state.player0_score += player0_outcome.score();
             self.state.player1_score += (-player0_outcome).score();
             self.state.rounds_played += 1;
+        } else if let Some(player0_action) = action {
+            // Wait for player 1 before deciding the round
+            self.state.player0_action = Some(player0_action);
         } else {
-            if action.is_none() {
-                self.state.player0_prompt =
-                    "You lost this round because you took too long to go.".into();
-                self.state.player1_prompt =
-                    "You won this round because the other player took too long to go.".into();
-                self.state.player1_score += 3;
-                self.state.rounds_played += 1;
-            }
-            self.state.player0_action = action;
+            // Player 0 forfeits, so player 1 never gets a turn this round
+            self.state.player0_prompt =
+                "You lost this round because you took too long to go.".into();
+            self.state.player1_prompt =
+                "You won this round because the other player took too long to go.".into();
+            self.state.player1_score += Outcome::Won.score();
+            self.state.rounds_played += 1;
         }
         self.state.last_action = tick;
         Ok(())
@@ -198,6 +247,7 @@ impl GameProcess for RockPaperScissors {
             CommandResponse {
                 update_ui: Some(ConsoleUi {
                     prompt: "It's not your turn yet!".into(),
+                    input: InputMode::Line,
                 }),
                 ..Default::default()
             }
@@ -210,32 +260,65 @@ impl GameProcess for RockPaperScissors {
                     return Ok(Some(CommandResponse {
                         update_ui: Some(ConsoleUi {
                             prompt: format!("Invalid command: {other}"),
+                            input: action_choices(),
                         }),
                         ..Default::default()
                     }))
                 }
             };
+            // Let player 0 know their opponent has gone, before the result
+            let update_others = if player == 1 {
+                vec![(
+                    0,
+                    ConsoleUi {
+                        prompt: "The other player has made their choice.".into(),
+                        input: InputMode::Line,
+                    },
+                )]
+            } else {
+                Vec::new()
+            };
             CommandResponse {
+                update_others,
                 advance: Some(Some(action)),
                 ..Default::default()
             }
         }))
     }
 
-    fn render_console_ui(&mut self, player: i32) -> anyhow::Result<Option<ConsoleUi>> {
-        let mut prompt = String::new();
-        if self.state.player0_action.is_none() {
-            if self.state.rounds_played > 0 {
-                write!(
+    fn render_console_ui(&mut self, player: PlayerView) -> anyhow::Result<Option<ConsoleUi>> {
+        let player = match player {
+            PlayerView::Player(player) => player,
+            // Spectators only see the scores, since the prompts would give
+            // away what player 0 has chosen.
+            PlayerView::Spectator => {
+                let mut prompt = String::new();
+                writeln!(
                     prompt,
-                    "Round {} of {} - ",
-                    self.state.rounds_played, self.rules.num_rounds
+                    "After {} of {} rounds: player 0 has {}, player 1 has {}.",
+                    self.state.rounds_played,
+                    self.rules.num_rounds,
+                    self.state.player0_score,
+                    self.state.player1_score
                 )?;
-                match player {
-                    0 => writeln!(prompt, "{}", self.state.player0_prompt)?,
-                    1 => writeln!(prompt, "{}", self.state.player1_prompt)?,
-                    _ => bail!("Invalid player number"),
-                }
+                return Ok(Some(ConsoleUi {
+                    prompt,
+                    input: InputMode::Line,
+                }));
+            }
+        };
+        let mut prompt = String::new();
+        let mut input = InputMode::Line;
+        if self.state.player0_action.is_none() && self.state.rounds_played > 0 {
+            write!(
+                prompt,
+                "Round {} of {} - ",
+                self.state.rounds_played, self.rules.num_rounds
+            )?;
+            match player {
+                0 => writeln!(prompt, "{}", self.state.player0_prompt)?,
+                1 => writeln!(prompt, "{}", self.state.player1_prompt)?,
+                _ => bail!("Invalid player number"),
             }
         }
         if self.state.rounds_played < self.rules.num_rounds {
@@ -247,15 +330,124 @@ impl GameProcess for RockPaperScissors {
             )?;
             if self.player_turn() == player {
                 writeln!(prompt, "It's your go! Enter [r]ock, [p]aper or [s]cissors:")?;
+                input = action_choices();
             } else {
                 writeln!(prompt, "Waiting for the other player...")?;
             }
         }
 
-        Ok(None)
+        Ok(Some(ConsoleUi { prompt, input }))
     }
 }
 
+fn action_choices() -> InputMode {
+    InputMode::Choice(vec!["rock".into(), "paper".into(), "scissors".into()])
+}
+
 fn main() -> anyhow::Result<()> {
     RockPaperScissors::main()
 }
+
+#[cfg(test)]
+mod tests {
+    use playferrous_launcher::{mock::MockGameInstance, TypedGameInstance};
+
+    use super::*;
+
+    fn setup() -> GameSetup<RockPaperScissors> {
+        GameSetup {
+            game_type: "rock-paper-scissors".into(),
+            num_players: 2,
+            seed: 0,
+            rules: Rules {
+                num_rounds: 3,
+                turn_timeout: GameTick(1000),
+            },
+            snapshot: None,
+        }
+    }
+
+    fn new_game() -> RockPaperScissors {
+        RockPaperScissors::new(setup()).unwrap()
+    }
+
+    #[test]
+    fn player_on_their_turn_is_prompted() {
+        let mut game = new_game();
+        let ui = game
+            .render_console_ui(PlayerView::Player(0))
+            .unwrap()
+            .unwrap();
+        assert!(ui.prompt.contains("It's your go!"));
+        assert_eq!(ui.input, action_choices());
+    }
+    #[test]
+    fn player0_timing_out_gives_player1_the_round() {
+        let mut game = new_game();
+        game.advance(GameTick(1000), None).unwrap();
+        assert_eq!(game.state.rounds_played, 1);
+        assert_eq!(game.state.player0_score, 0);
+        assert_eq!(game.state.player1_score, Outcome::Won.score());
+        // The next round starts with player 0, rather than waiting on player 1
+        assert!(game.state.player0_action.is_none());
+        let GameState::InProgress(state) = game.state().unwrap() else {
+            panic!("expected the game to continue");
+        };
+        assert_eq!(state.player_turn, 0);
+        assert_eq!(state.deadline, GameTick(2000));
+    }
+    /// The scores and rounds played, followed by whose turn it is and when
+    /// their turn ends.
+    fn progress(game: &mut RockPaperScissors) -> ((i64, i64, i64), (i32, GameTick)) {
+        let GameState::InProgress(state) = game.state().unwrap() else {
+            panic!("expected the game to continue");
+        };
+        (
+            (
+                game.state.player0_score,
+                game.state.player1_score,
+                game.state.rounds_played,
+            ),
+            (state.player_turn, state.deadline),
+        )
+    }
+    #[test]
+    fn player1_timing_out_gives_player0_the_round() {
+        let mut game = new_game();
+        game.advance(GameTick(10), Some(Action::Rock)).unwrap();
+        assert_eq!(progress(&mut game), ((0, 0, 0), (1, GameTick(1010))));
+        game.advance(GameTick(1010), None).unwrap();
+        let won = Outcome::Won.score();
+        assert_eq!(progress(&mut game), ((won, 0, 1), (0, GameTick(2010))));
+    }
+    #[test]
+    fn both_players_timing_out_in_consecutive_rounds() {
+        let mut game = new_game();
+        let won = Outcome::Won.score();
+        // Player 0 runs out of time in the first round
+        game.advance(GameTick(1000), None).unwrap();
+        assert_eq!(progress(&mut game), ((0, won, 1), (0, GameTick(2000))));
+        // and player 1 in the second
+        game.advance(GameTick(1500), Some(Action::Paper)).unwrap();
+        assert_eq!(progress(&mut game), ((0, won, 1), (1, GameTick(2500))));
+        game.advance(GameTick(2500), None).unwrap();
+        assert_eq!(progress(&mut game), ((won, won, 2), (0, GameTick(3500))));
+    }
+    #[tokio::test]
+    async fn typed_instance_round_trips_actions() {
+        let mut game = TypedGameInstance::<RockPaperScissors>::new(Box::new(
+            MockGameInstance::new(setup()).unwrap(),
+        ));
+        game.advance(GameTick(1), Some(Action::Rock)).await.unwrap();
+        let snapshot = game.save_snapshot().await.unwrap();
+        assert!(matches!(snapshot.player0_action, Some(Action::Rock)));
+        let response = game.interpret_console_command(1, "paper").await.unwrap();
+        assert!(matches!(response.advance, Some(Some(Action::Paper))));
+
+        let action = response.advance.unwrap();
+        game.advance(GameTick(2), action).await.unwrap();
+        let snapshot = game.save_snapshot().await.unwrap();
+        assert_eq!(snapshot.rounds_played, 1);
+        assert_eq!(snapshot.player1_score, Outcome::Won.score());
+    }
+}
@@ -2,14 +2,25 @@ use std::{fmt::Debug, sync::Arc};
 
 use async_trait::async_trait;
 use ijson::IValue;
-use playferrous_types::{CommandResponse, ConsoleUi, GameSetup, GameState, GameTick};
+use playferrous_types::{
+    CommandResponse, ConsoleUi, GameDescription, GameSetup, GameState, GameTick, PlayerView,
+    SnapshotEnvelope,
+};
 use serde::de::DeserializeOwned;
 use thiserror::Error;
 
+#[cfg(any(test, feature = "test-util"))]
+pub mod mock;
+mod typed;
+
+pub use typed::TypedGameInstance;
+
 #[derive(Debug, Error)]
 pub enum LauncherError {
     #[error("Unknown game type")]
     UnknownGameType,
+    #[error("Invalid rules: {0}")]
+    InvalidRules(String),
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
@@ -18,13 +29,24 @@ pub enum LauncherError {
 pub enum GameError {
     #[error("Unsupported presentation mode")]
     UnsupportedPresentationMode,
+    /// The game understood the request, but wouldn't carry it out.
+    #[error("{0}")]
+    Refused(String),
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
 
 #[async_trait]
 pub trait Launcher: Send + Sync + Debug {
+    async fn describe(&self, game_type: &str) -> Result<GameDescription, LauncherError>;
+    async fn validate_rules(&self, game_type: &str, rules: IValue) -> Result<(), LauncherError>;
+    async fn rules_schema(&self, game_type: &str) -> Result<IValue, LauncherError>;
     async fn launch(&self, game_setup: GameSetup) -> Result<Box<dyn GameInstance>, LauncherError>;
+    /// Check that the launcher is still able to launch games.
+    async fn health_check(&self) -> anyhow::Result<()>;
+    /// The game types this launcher can launch, so that operators can check
+    /// that it's configured correctly.
+    async fn supported_game_types(&self) -> anyhow::Result<Vec<String>>;
 }
 
 #[async_trait]
@@ -34,13 +56,33 @@ pub trait LauncherConfig: DeserializeOwned {
 
 #[async_trait]
 pub trait GameInstance: Send + Sync {
-    async fn load_snapshot(&mut self, snapshot: IValue) -> anyhow::Result<()>;
-    async fn save_snapshot(&mut self) -> anyhow::Result<IValue>;
+    async fn load_snapshot(&mut self, snapshot: SnapshotEnvelope) -> anyhow::Result<()>;
+    async fn save_snapshot(&mut self) -> anyhow::Result<SnapshotEnvelope>;
+    /// Only supported by games with the `binary_snapshots` capability.
+    async fn load_snapshot_binary(&mut self, version: u32, data: Vec<u8>) -> anyhow::Result<()>;
+    async fn save_snapshot_binary(&mut self) -> anyhow::Result<Vec<u8>>;
     async fn advance(&mut self, tick: GameTick, action: IValue) -> anyhow::Result<()>;
     async fn state(&mut self) -> anyhow::Result<GameState>;
+    /// Only supported by games with the `undo` capability, which may still
+    /// refuse if their rules don't allow it.
+    async fn undo(&mut self, to_tick: GameTick) -> Result<GameState, GameError>;
+    /// The state once the player has resigned, which may still be in progress.
+    async fn resign(&mut self, player: i32) -> anyhow::Result<GameState>;
+    /// What the given player may see of the game, safe to send to clients.
+    async fn render_player_state(&mut self, player: PlayerView) -> anyhow::Result<IValue>;
+    /// What has changed since the player was shown the game at `since_tick`,
+    /// as a JSON merge patch. Only supported by games with the `render_diff`
+    /// capability, and `None` if the game doesn't remember that render.
+    async fn render_diff(
+        &mut self,
+        player: PlayerView,
+        since_tick: GameTick,
+    ) -> anyhow::Result<Option<IValue>>;
+    /// Check that the instance is still responsive.
+    async fn ping(&mut self) -> anyhow::Result<()>;
 
     // Presentation-specific functionality
-    async fn render_console_ui(&mut self, _player: i32) -> Result<ConsoleUi, GameError> {
+    async fn render_console_ui(&mut self, _player: PlayerView) -> Result<ConsoleUi, GameError> {
         Err(GameError::UnsupportedPresentationMode)
     }
     async fn interpret_console_command(
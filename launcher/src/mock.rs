@@ -0,0 +1,315 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use ijson::IValue;
+use playferrous_types::{
+    diff::RenderCache, process::GameProcess, CommandResponse, ConsoleUi, Game, GameRequest,
+    GameResponse, GameResult, GameSetup, GameState, GameTick, InProgressGameState, InputMode,
+    PlayerResult, PlayerView, SnapshotEnvelope,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{GameError, GameInstance};
+
+/// Runs a game in-process, exchanging the same JSON a game process would, so
+/// that whatever drives game instances can be tested without launching one.
+/// Anything the game doesn't support fails, just as it would in a process.
+pub struct MockGameInstance<G = TakeTurns> {
+    game: Option<G>,
+    renders: RenderCache,
+    render_delay: Arc<Mutex<Duration>>,
+}
+
+impl<G: GameProcess> MockGameInstance<G> {
+    pub fn new(setup: GameSetup<G>) -> anyhow::Result<Self> {
+        let mut this = Self {
+            game: None,
+            renders: RenderCache::new(),
+            render_delay: Default::default(),
+        };
+        G::handle_request(
+            &mut this.game,
+            &mut this.renders,
+            GameRequest::Initialize(setup),
+        )?;
+        Ok(this)
+    }
+    pub fn game(&self) -> &G {
+        self.game.as_ref().expect("initialized by `new`")
+    }
+    /// Each console render waits for however long this is set to, which can
+    /// be changed after the instance has been handed over.
+    pub fn render_delay(&self) -> Arc<Mutex<Duration>> {
+        self.render_delay.clone()
+    }
+    fn request(&mut self, req: &GameRequest) -> anyhow::Result<GameResponse> {
+        let req = ijson::from_value(&ijson::to_value(req)?)?;
+        let resp = G::handle_request(&mut self.game, &mut self.renders, req)?;
+        Ok(ijson::from_value(&ijson::to_value(resp)?)?)
+    }
+    fn response_type_error(req: &GameRequest, resp: &GameResponse) -> anyhow::Error {
+        anyhow!("Invalid response {resp:?} for {req:?}")
+    }
+}
+
+#[async_trait]
+impl<G: GameProcess + Send + Sync + 'static> GameInstance for MockGameInstance<G> {
+    async fn load_snapshot(&mut self, snapshot: SnapshotEnvelope) -> anyhow::Result<()> {
+        let req = GameRequest::LoadSnapshot(snapshot);
+        let resp = self.request(&req)?;
+        if let GameResponse::LoadSnapshot = resp {
+            Ok(())
+        } else {
+            Err(Self::response_type_error(&req, &resp))
+        }
+    }
+    async fn save_snapshot(&mut self) -> anyhow::Result<SnapshotEnvelope> {
+        let req = GameRequest::SaveSnapshot;
+        let resp = self.request(&req)?;
+        if let GameResponse::SaveSnapshot(snapshot) = resp {
+            Ok(snapshot)
+        } else {
+            Err(Self::response_type_error(&req, &resp))
+        }
+    }
+    async fn load_snapshot_binary(&mut self, version: u32, data: Vec<u8>) -> anyhow::Result<()> {
+        let req = GameRequest::LoadSnapshotBinary { version, data };
+        let resp = self.request(&req)?;
+        if let GameResponse::LoadSnapshotBinary = resp {
+            Ok(())
+        } else {
+            Err(Self::response_type_error(&req, &resp))
+        }
+    }
+    async fn save_snapshot_binary(&mut self) -> anyhow::Result<Vec<u8>> {
+        let req = GameRequest::SaveSnapshotBinary;
+        let resp = self.request(&req)?;
+        if let GameResponse::SaveSnapshotBinary(data) = resp {
+            Ok(data)
+        } else {
+            Err(Self::response_type_error(&req, &resp))
+        }
+    }
+    async fn advance(&mut self, tick: GameTick, action: IValue) -> anyhow::Result<()> {
+        let req = GameRequest::Advance { tick, action };
+        let resp = self.request(&req)?;
+        if let GameResponse::Advance = resp {
+            Ok(())
+        } else {
+            Err(Self::response_type_error(&req, &resp))
+        }
+    }
+    async fn state(&mut self) -> anyhow::Result<GameState> {
+        let req = GameRequest::State;
+        let resp = self.request(&req)?;
+        if let GameResponse::State(state) = resp {
+            Ok(state)
+        } else {
+            Err(Self::response_type_error(&req, &resp))
+        }
+    }
+    async fn undo(&mut self, to_tick: GameTick) -> Result<GameState, GameError> {
+        let req = GameRequest::Undo { to_tick };
+        let resp = self.request(&req)?;
+        if let GameResponse::Undo(res) = resp {
+            res.map_err(GameError::Refused)
+        } else {
+            Err(Self::response_type_error(&req, &resp).into())
+        }
+    }
+    async fn resign(&mut self, player: i32) -> anyhow::Result<GameState> {
+        let req = GameRequest::Resign { player };
+        let resp = self.request(&req)?;
+        if let GameResponse::Resign(state) = resp {
+            Ok(state)
+        } else {
+            Err(Self::response_type_error(&req, &resp))
+        }
+    }
+    async fn render_player_state(&mut self, player: PlayerView) -> anyhow::Result<IValue> {
+        let req = GameRequest::RenderPlayerState { player };
+        let resp = self.request(&req)?;
+        if let GameResponse::RenderPlayerState(state) = resp {
+            Ok(state)
+        } else {
+            Err(Self::response_type_error(&req, &resp))
+        }
+    }
+    async fn render_diff(
+        &mut self,
+        player: PlayerView,
+        since_tick: GameTick,
+    ) -> anyhow::Result<Option<IValue>> {
+        let req = GameRequest::RenderDiff { player, since_tick };
+        let resp = self.request(&req)?;
+        if let GameResponse::RenderDiff(patch) = resp {
+            Ok(patch)
+        } else {
+            Err(Self::response_type_error(&req, &resp))
+        }
+    }
+    async fn ping(&mut self) -> anyhow::Result<()> {
+        let req = GameRequest::Ping;
+        let resp = self.request(&req)?;
+        if let GameResponse::Pong = resp {
+            Ok(())
+        } else {
+            Err(Self::response_type_error(&req, &resp))
+        }
+    }
+
+    // Presentation-specific functionality
+    async fn render_console_ui(&mut self, player: PlayerView) -> Result<ConsoleUi, GameError> {
+        let delay = *self.render_delay.lock().unwrap();
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        let req = GameRequest::RenderConsoleUi { player };
+        let resp = self.request(&req)?;
+        if let GameResponse::RenderConsoleUi(ui) = resp {
+            ui.ok_or(GameError::UnsupportedPresentationMode)
+        } else {
+            Err(Self::response_type_error(&req, &resp).into())
+        }
+    }
+    async fn interpret_console_command(
+        &mut self,
+        player: i32,
+        command: &str,
+    ) -> Result<CommandResponse<ConsoleUi>, GameError> {
+        let req = GameRequest::InterpretConsoleCommand {
+            player,
+            command: command.into(),
+        };
+        let resp = self.request(&req)?;
+        if let GameResponse::InterpretConsoleCommand(r) = resp {
+            r.ok_or(GameError::UnsupportedPresentationMode)
+        } else {
+            Err(Self::response_type_error(&req, &resp).into())
+        }
+    }
+}
+
+/// The simplest game: the players take turns in order, with any action, until
+/// one of them resigns and the rest win.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TakeTurns {
+    pub num_players: i32,
+    /// The tick of each action taken so far.
+    pub ticks: Vec<GameTick>,
+    pub resigned: Option<i32>,
+}
+
+impl TakeTurns {
+    /// How long each player has to take their turn.
+    pub const TURN_TICKS: i64 = 1000;
+
+    pub fn setup(num_players: i32) -> GameSetup<Self> {
+        GameSetup {
+            game_type: "take-turns".into(),
+            num_players,
+            seed: 0,
+            rules: (),
+            snapshot: None,
+        }
+    }
+    fn player_turn(&self) -> i32 {
+        self.ticks.len() as i32 % self.num_players
+    }
+}
+
+impl Game for TakeTurns {
+    type Snapshot = Self;
+    type Action = IValue;
+    type Rules = ();
+}
+
+impl GameProcess for TakeTurns {
+    fn new(setup: GameSetup<Self>) -> anyhow::Result<Self> {
+        Ok(Self {
+            num_players: setup.num_players,
+            ticks: Vec::new(),
+            resigned: None,
+        })
+    }
+    fn load_snapshot(&mut self, snapshot: Self) -> anyhow::Result<()> {
+        *self = snapshot;
+        Ok(())
+    }
+    fn save_snapshot(&mut self) -> anyhow::Result<Self> {
+        Ok(self.clone())
+    }
+    fn advance(&mut self, tick: GameTick, _action: IValue) -> anyhow::Result<()> {
+        self.ticks.push(tick);
+        Ok(())
+    }
+    fn state(&mut self) -> anyhow::Result<GameState> {
+        Ok(match self.resigned {
+            Some(resigned) => GameState::Complete(GameResult {
+                player_results: (0..self.num_players)
+                    .map(|player_index| PlayerResult {
+                        score: (player_index != resigned).into(),
+                    })
+                    .collect(),
+            }),
+            None => GameState::InProgress(InProgressGameState {
+                player_turn: self.player_turn(),
+                deadline: GameTick(self.ticks.last().map_or(0, |tick| tick.0) + Self::TURN_TICKS),
+            }),
+        })
+    }
+    fn resign(&mut self, player: i32) -> anyhow::Result<GameState> {
+        self.resigned = Some(player);
+        self.state()
+    }
+    fn render_console_ui(&mut self, player: PlayerView) -> anyhow::Result<Option<ConsoleUi>> {
+        let prompt = match player {
+            PlayerView::Player(player) if player == self.player_turn() => "Your move".into(),
+            _ => format!("Waiting for player {}", self.player_turn()),
+        };
+        Ok(Some(ConsoleUi {
+            prompt,
+            input: InputMode::Line,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn takes_turns_until_someone_resigns() {
+        let mut instance = MockGameInstance::new(TakeTurns::setup(3)).unwrap();
+        instance.advance(GameTick(5), IValue::NULL).await.unwrap();
+        let GameState::InProgress(state) = instance.state().await.unwrap() else {
+            panic!("expected the game to continue");
+        };
+        assert_eq!(state.player_turn, 1);
+        assert_eq!(state.deadline, GameTick(1005));
+
+        let GameState::Complete(result) = instance.resign(1).await.unwrap() else {
+            panic!("expected the game to be over");
+        };
+        let scores: Vec<_> = result.player_results.iter().map(|r| r.score).collect();
+        assert_eq!(scores, [1, 0, 1]);
+        assert_eq!(instance.game().ticks, [GameTick(5)]);
+    }
+    #[tokio::test]
+    async fn unsupported_requests_are_errors() {
+        let mut instance = MockGameInstance::new(TakeTurns::setup(2)).unwrap();
+        assert!(instance.save_snapshot_binary().await.is_err());
+        assert!(matches!(
+            instance.undo(GameTick(0)).await,
+            Err(GameError::Refused(_))
+        ));
+        assert!(matches!(
+            instance.interpret_console_command(0, "go").await,
+            Err(GameError::UnsupportedPresentationMode)
+        ));
+    }
+}
@@ -0,0 +1,78 @@
+use std::marker::PhantomData;
+
+use anyhow::Context;
+use playferrous_types::{
+    CommandResponse, ConsoleUi, Game, GameState, GameTick, PlayerView, SnapshotEnvelope,
+};
+
+use crate::{GameError, GameInstance};
+
+/// Wraps a game instance to use the types of a particular game, rather than
+/// untyped JSON, so that mismatched actions and snapshots are caught by the
+/// compiler instead of failing inside the game.
+pub struct TypedGameInstance<G: Game> {
+    inner: Box<dyn GameInstance>,
+    _game: PhantomData<fn() -> G>,
+}
+
+impl<G: Game> TypedGameInstance<G> {
+    pub fn new(inner: Box<dyn GameInstance>) -> Self {
+        Self {
+            inner,
+            _game: PhantomData,
+        }
+    }
+    pub fn into_inner(self) -> Box<dyn GameInstance> {
+        self.inner
+    }
+    /// The version is that of the game which saved the snapshot.
+    pub async fn load_snapshot(
+        &mut self,
+        version: u32,
+        snapshot: G::Snapshot,
+    ) -> anyhow::Result<()> {
+        let data = ijson::to_value(snapshot).context("Failed to serialize snapshot")?;
+        self.inner
+            .load_snapshot(SnapshotEnvelope { version, data })
+            .await
+    }
+    pub async fn save_snapshot(&mut self) -> anyhow::Result<G::Snapshot> {
+        let envelope = self.inner.save_snapshot().await?;
+        ijson::from_value(&envelope.data).context("Failed to deserialize snapshot")
+    }
+    pub async fn advance(&mut self, tick: GameTick, action: G::Action) -> anyhow::Result<()> {
+        let action = ijson::to_value(action).context("Failed to serialize action")?;
+        self.inner.advance(tick, action).await
+    }
+    pub async fn state(&mut self) -> anyhow::Result<GameState> {
+        self.inner.state().await
+    }
+    pub async fn undo(&mut self, to_tick: GameTick) -> Result<GameState, GameError> {
+        self.inner.undo(to_tick).await
+    }
+    pub async fn resign(&mut self, player: i32) -> anyhow::Result<GameState> {
+        self.inner.resign(player).await
+    }
+    pub async fn render_console_ui(&mut self, player: PlayerView) -> Result<ConsoleUi, GameError> {
+        self.inner.render_console_ui(player).await
+    }
+    pub async fn interpret_console_command(
+        &mut self,
+        player: i32,
+        command: &str,
+    ) -> Result<CommandResponse<ConsoleUi, G>, GameError> {
+        let response = self
+            .inner
+            .interpret_console_command(player, command)
+            .await?;
+        Ok(CommandResponse {
+            update_ui: response.update_ui,
+            update_others: response.update_others,
+            advance: response
+                .advance
+                .map(|action| ijson::from_value(&action))
+                .transpose()
+                .context("Failed to deserialize action")?,
+        })
+    }
+}
@@ -6,7 +6,10 @@ use playferrous_presentation::{
     terminal::{PresentationToTerminalMsg, TerminalToPresentationMsg},
 };
 use thiserror::Error;
-use tokio::io::AsyncWriteExt;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::watch,
+};
 
 use tracing::instrument;
 
@@ -28,11 +31,43 @@ impl From<NolineError> for ClientError {
     }
 }
 
+/// Read a line of input without echoing it, as used for password prompts.
+async fn read_secret(
+    prompt: &str,
+    data_reader: &mut DataReader,
+    data_writer: &mut DataWriter,
+) -> Result<String, ClientError> {
+    data_writer
+        .write_all(format!("\x1b[G\x1b[K{prompt}").as_bytes())
+        .await?;
+    data_writer.flush().await?;
+    let mut secret = Vec::new();
+    loop {
+        match data_reader.read_u8().await? {
+            // Return
+            b'\r' | b'\n' => break,
+            // Backspace
+            8 | 127 => {
+                while let Some(c) = secret.pop() {
+                    if c < 0x80 || c >= 0xC0 {
+                        break;
+                    }
+                }
+            }
+            c => secret.push(c),
+        }
+    }
+    data_writer.write_all(b"\r\n").await?;
+    data_writer.flush().await?;
+    Ok(String::from_utf8_lossy(&secret).into_owned())
+}
+
 #[instrument(level = "debug", skip_all)]
 pub async fn run(
     mut presentation_connection: Bichannel<TerminalToPresentationMsg, PresentationToTerminalMsg>,
     mut data_reader: DataReader,
     mut data_writer: DataWriter,
+    mut shutdown: watch::Receiver<bool>,
 ) -> Result<(), ClientError> {
     let mut editor = EditorBuilder::new_unbounded()
         .with_unbounded_history()
@@ -41,12 +76,22 @@ pub async fn run(
     while let Some(server_cmd) = loop {
         tokio::select! {
             line = editor.readline("> ", &mut data_reader, &mut data_writer) => {
+                let line = line?;
+                if let Some(max_line_length) = data_reader.take_too_long() {
+                    let message = format!(
+                        "\x1b[G\x1b[KLine too long: at most {max_line_length} characters are allowed.\r\n"
+                    );
+                    data_writer.write_all(message.as_bytes()).await?;
+                    data_writer.flush().await?;
+                    continue;
+                }
                 let _ = presentation_connection
                 .s
-                .send(TerminalToPresentationMsg::ReadLine(line?.into()))
+                .send(TerminalToPresentationMsg::ReadLine(line.into()))
                 .await;
             },
             server_cmd = presentation_connection.r.recv() => break server_cmd,
+            _ = shutdown.changed() => break None,
         }
     } {
         tracing::info!("Command: {:?}", server_cmd);
@@ -54,9 +99,25 @@ pub async fn run(
             PresentationToTerminalMsg::PrintLine(line)
             | PresentationToTerminalMsg::ErrorLine(line) => {
                 let data = format!("\x1b[G\x1b[K{}\r\n", line.replace("\n", "\r\n"));
-                data_writer.write(data.as_bytes()).await?;
+                data_writer.write_all(data.as_bytes()).await?;
                 data_writer.flush().await?;
             }
+            PresentationToTerminalMsg::ReadSecret(prompt) => {
+                data_reader.set_completion_enabled(false);
+                let secret = read_secret(&prompt, &mut data_reader, &mut data_writer).await;
+                data_reader.set_completion_enabled(true);
+                // Only lines from the editor are rejected for being too long
+                data_reader.take_too_long();
+                let secret = secret?;
+                let _ = presentation_connection
+                    .s
+                    .send(TerminalToPresentationMsg::ReadLine(secret))
+                    .await;
+            }
+            PresentationToTerminalMsg::SessionChanged(session) => {
+                data_reader.set_session(session);
+            }
+            PresentationToTerminalMsg::Close => break,
         }
     }
     data_writer.shutdown().await?;
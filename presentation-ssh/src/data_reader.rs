@@ -1,20 +1,85 @@
 use std::{
-    io,
+    io, mem,
     pin::Pin,
     task::{ready, Context, Poll},
 };
 
+use playferrous_presentation::SessionKind;
 use tokio::{
     io::{AsyncRead, ReadBuf},
     sync::mpsc,
 };
 use tracing::debug;
 
+type Completer = fn(&str, Option<&SessionKind>) -> Vec<String>;
+
+/// A character may be split across reads, so append `input` to whatever was
+/// held back from the previous read, and return everything except an
+/// incomplete sequence at the end, which is held back until the rest of it
+/// arrives.
+pub fn take_complete_utf8(partial: &mut Vec<u8>, input: &[u8]) -> Vec<u8> {
+    let mut buffered = mem::take(partial);
+    buffered.extend_from_slice(input);
+    *partial = buffered.split_off(complete_utf8_len(&buffered));
+    buffered
+}
+
+/// The length of the longest prefix of `data` which doesn't end part way
+/// through a UTF-8 sequence. Invalid input is left for the consumer to deal
+/// with, so only a truncated sequence at the very end is excluded.
+fn complete_utf8_len(data: &[u8]) -> usize {
+    // Sequences are at most four bytes long, so the lead byte of any
+    // incomplete sequence must be within the last three bytes.
+    for (idx, &b) in data.iter().enumerate().rev().take(3) {
+        let seq_len = match b {
+            0x00..=0x7F => return data.len(),
+            // Continuation byte
+            0x80..=0xBF => continue,
+            0xC0..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            _ => 4,
+        };
+        return if data.len() - idx < seq_len {
+            idx
+        } else {
+            data.len()
+        };
+    }
+    data.len()
+}
+
+/// Where we are in an escape sequence. These don't count towards the length of
+/// the line, since they move the cursor rather than insert anything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    Started,
+    /// A control sequence, which ends with a byte in the range `0x40..=0x7E`.
+    Control,
+}
+
 #[derive(Debug)]
 pub struct DataReader {
     receiver: mpsc::Receiver<Vec<u8>>,
     buffer: Vec<u8>,
     offset: usize,
+    completer: Option<Completer>,
+    completion_enabled: bool,
+    /// The session the user is in, which the completer is told about.
+    session: Option<SessionKind>,
+    /// Our best guess at the contents of the line currently being edited.
+    /// This is `None` once the user moves the cursor or recalls history,
+    /// since we can no longer follow the editor's state.
+    line: Option<String>,
+    max_line_length: Option<usize>,
+    /// The number of characters on the current line.
+    line_length: usize,
+    escape: Escape,
+    /// Whether the last character was dropped, so its continuation bytes
+    /// should be too.
+    dropping: bool,
+    /// Whether characters have been dropped since this was last checked.
+    too_long: bool,
 }
 
 impl DataReader {
@@ -23,7 +88,137 @@ impl DataReader {
             receiver,
             buffer: Vec::new(),
             offset: 0,
+            completer: None,
+            completion_enabled: true,
+            session: None,
+            line: Some(String::new()),
+            max_line_length: None,
+            line_length: 0,
+            escape: Escape::None,
+            dropping: false,
+            too_long: false,
+        }
+    }
+    /// Drop any characters typed beyond this many on one line.
+    pub fn with_max_line_length(mut self, max_line_length: usize) -> Self {
+        self.max_line_length = Some(max_line_length);
+        self
+    }
+    /// If characters were dropped for making a line too long, returns the
+    /// limit, so that the line can be rejected.
+    pub fn take_too_long(&mut self) -> Option<usize> {
+        mem::take(&mut self.too_long)
+            .then_some(self.max_line_length)
+            .flatten()
+    }
+    /// Complete the current line when the user presses tab, by feeding the
+    /// completed text through as though it had been typed.
+    pub fn with_completer(mut self, completer: Completer) -> Self {
+        self.completer = Some(completer);
+        self
+    }
+    pub fn set_completion_enabled(&mut self, enabled: bool) {
+        self.completion_enabled = enabled;
+    }
+    pub fn set_session(&mut self, session: Option<SessionKind>) {
+        self.session = session;
+    }
+    fn complete(&self, line: &str) -> Option<String> {
+        let completer = self.completer.filter(|_| self.completion_enabled)?;
+        let candidates = completer(line, self.session.as_ref());
+        let partial_len = line.len()
+            - line
+                .rfind(|c: char| c.is_ascii_whitespace())
+                .map_or(0, |idx| idx + 1);
+        let (first, rest) = candidates.split_first()?;
+        let common_len = rest.iter().fold(first.len(), |len, candidate| {
+            first
+                .bytes()
+                .zip(candidate.bytes())
+                .take(len)
+                .take_while(|(a, b)| a.eq_ignore_ascii_case(b))
+                .count()
+        });
+        let mut completion = first.get(partial_len..common_len)?.to_owned();
+        if rest.is_empty() {
+            completion.push(' ');
+        }
+        Some(completion)
+    }
+    fn limit_line_length(&mut self, input: Vec<u8>) -> Vec<u8> {
+        let Some(max_line_length) = self.max_line_length else {
+            return input;
+        };
+        let mut output = Vec::with_capacity(input.len());
+        for c in input {
+            match (self.escape, c) {
+                (Escape::Started, b'[' | b'O') => self.escape = Escape::Control,
+                (Escape::Started, _) => self.escape = Escape::None,
+                (Escape::Control, 0x40..=0x7E) => self.escape = Escape::None,
+                (Escape::Control, _) => {}
+                (Escape::None, 0x1B) => self.escape = Escape::Started,
+                (Escape::None, b'\r' | b'\n') => self.line_length = 0,
+                (Escape::None, 8 | 127) => self.line_length = self.line_length.saturating_sub(1),
+                (Escape::None, 0x00..=0x1F) => {}
+                // Continuation byte
+                (Escape::None, 0x80..=0xBF) => {
+                    if self.dropping {
+                        continue;
+                    }
+                }
+                (Escape::None, _) => {
+                    self.dropping = self.line_length >= max_line_length;
+                    if self.dropping {
+                        self.too_long = true;
+                        self.line = None;
+                        continue;
+                    }
+                    self.line_length += 1;
+                }
+            }
+            output.push(c);
+        }
+        output
+    }
+    fn process_input(&mut self, input: Vec<u8>) -> Vec<u8> {
+        let input = if self.completer.is_none() {
+            input
+        } else {
+            self.complete_input(input)
+        };
+        self.limit_line_length(input)
+    }
+    fn complete_input(&mut self, input: Vec<u8>) -> Vec<u8> {
+        let mut output = Vec::with_capacity(input.len());
+        for c in input {
+            match c {
+                b'\r' | b'\n' => self.line = Some(String::new()),
+                b'\t' => {
+                    if let Some(completion) = self.line.as_deref().and_then(|l| self.complete(l)) {
+                        output.extend_from_slice(completion.as_bytes());
+                        if let Some(line) = &mut self.line {
+                            line.push_str(&completion);
+                        }
+                    }
+                    continue;
+                }
+                8 | 127 => {
+                    if let Some(line) = &mut self.line {
+                        line.pop();
+                    }
+                }
+                0x20..=0x7E => {
+                    if let Some(line) = &mut self.line {
+                        line.push(c as char);
+                    }
+                }
+                // Escape sequences, other control characters and non-ASCII
+                // input could all leave the editor in a state we can't track.
+                _ => self.line = None,
+            }
+            output.push(c);
         }
+        output
     }
 }
 
@@ -33,14 +228,16 @@ impl AsyncRead for DataReader {
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
-        if self.offset == self.buffer.len() {
+        while self.offset == self.buffer.len() {
             if let Some(buffer) = ready!(self.receiver.poll_recv(cx)) {
                 debug!(
                     "DataReader::poll_read received {:?}",
                     std::str::from_utf8(&buffer).ok()
                 );
-                self.buffer = buffer;
+                self.buffer = self.process_input(buffer);
                 self.offset = 0;
+            } else {
+                break;
             }
         }
         let amt = buf.remaining().min(self.buffer.len() - self.offset);
@@ -49,3 +246,59 @@ impl AsyncRead for DataReader {
         Poll::Ready(Ok(()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    /// Feed each of `chunks` to `take_complete_utf8`, as consecutive calls to
+    /// the SSH handler's `data` do, returning what's passed on from each.
+    fn split_reads(chunks: &[&[u8]]) -> Vec<String> {
+        let mut partial = Vec::new();
+        chunks
+            .iter()
+            .map(|chunk| String::from_utf8(take_complete_utf8(&mut partial, chunk)).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn holds_back_characters_split_across_reads() {
+        let input = "a£b😀c".as_bytes();
+        for i in 0..=input.len() {
+            for j in i..=input.len() {
+                let reads = split_reads(&[&input[..i], &input[i..j], &input[j..]]);
+                assert_eq!(reads.concat(), "a£b😀c", "split at {i} and {j}");
+            }
+        }
+        // Each byte of the emoji arrives separately
+        let emoji = "😀".as_bytes();
+        let chunks: Vec<&[u8]> = emoji.chunks(1).collect();
+        assert_eq!(split_reads(&chunks), ["", "", "", "😀"]);
+        // Two reads, with a character split between them
+        let mut partial = Vec::new();
+        assert_eq!(take_complete_utf8(&mut partial, b"a\xC2"), b"a");
+        assert_eq!(partial, b"\xC2");
+        assert_eq!(take_complete_utf8(&mut partial, b"\xA3b"), "£b".as_bytes());
+        assert!(partial.is_empty());
+        assert_eq!(complete_utf8_len(&"£".as_bytes()[..1]), 0);
+        assert_eq!(complete_utf8_len("£".as_bytes()), 2);
+    }
+    #[tokio::test]
+    async fn drops_characters_beyond_the_line_length() {
+        let (sender, receiver) = mpsc::channel(4);
+        let mut reader = DataReader::new(receiver).with_max_line_length(3);
+        // Escape sequences and multi-byte characters count as one character
+        sender
+            .send("ab£\x1b[Dé😀x\rxyz".as_bytes().to_vec())
+            .await
+            .unwrap();
+        drop(sender);
+        let mut output = String::new();
+        reader.read_to_string(&mut output).await.unwrap();
+        assert_eq!(output, "ab£\x1b[D\rxyz");
+        assert_eq!(reader.take_too_long(), Some(3));
+        assert_eq!(reader.take_too_long(), None);
+    }
+}
@@ -1,17 +1,23 @@
 use std::{
-    fmt,
-    future::Future,
-    io, mem,
+    fmt, io, mem,
     pin::Pin,
     task::{ready, Context, Poll},
 };
 
-use futures::FutureExt;
+use futures::{future::BoxFuture, FutureExt};
 use russh::{ChannelId, CryptoVec};
 use tokio::io::AsyncWrite;
 use tracing::instrument;
 
-struct ChannelHandle {
+/// Where a `DataWriter` sends its data. Each operation takes ownership of the
+/// channel until it completes.
+pub trait Channel: fmt::Debug + Send + Unpin + Sized + 'static {
+    fn data(self, bytes: CryptoVec) -> BoxFuture<'static, (Self, Result<(), ()>)>;
+    fn shutdown(self) -> BoxFuture<'static, (Self, Result<(), ()>)>;
+    fn is_closed(&self) -> bool;
+}
+
+pub struct ChannelHandle {
     handle: russh::server::Handle,
     channel: ChannelId,
     closed: bool,
@@ -27,26 +33,38 @@ impl fmt::Debug for ChannelHandle {
 }
 
 impl ChannelHandle {
-    #[instrument(level = "debug", fields(bytes=std::str::from_utf8(&*bytes).ok()))]
-    async fn data(self, bytes: CryptoVec) -> (Self, Result<(), ()>) {
+    #[instrument(level = "debug", fields(bytes=std::str::from_utf8(&bytes).ok()))]
+    async fn send(self, bytes: CryptoVec) -> (Self, Result<(), ()>) {
         let res = self.handle.data(self.channel, bytes).await.map_err(|_| ());
         (self, res)
     }
     #[instrument(level = "debug")]
-    async fn shutdown(mut self) -> (Self, Result<(), ()>) {
+    async fn close(mut self) -> (Self, Result<(), ()>) {
         let res = self.handle.close(self.channel).await;
         self.closed = true;
         (self, res)
     }
 }
 
-enum DataWriterInner {
-    Idle(ChannelHandle),
-    Active(Pin<Box<dyn Future<Output = (ChannelHandle, Result<(), ()>)> + Send>>),
+impl Channel for ChannelHandle {
+    fn data(self, bytes: CryptoVec) -> BoxFuture<'static, (Self, Result<(), ()>)> {
+        self.send(bytes).boxed()
+    }
+    fn shutdown(self) -> BoxFuture<'static, (Self, Result<(), ()>)> {
+        self.close().boxed()
+    }
+    fn is_closed(&self) -> bool {
+        self.closed
+    }
+}
+
+enum DataWriterInner<C> {
+    Idle(C),
+    Active(BoxFuture<'static, (C, Result<(), ()>)>),
     Invalid,
 }
 
-impl fmt::Debug for DataWriterInner {
+impl<C: Channel> fmt::Debug for DataWriterInner<C> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Idle(arg0) => f.debug_tuple("Idle").field(arg0).finish(),
@@ -56,16 +74,8 @@ impl fmt::Debug for DataWriterInner {
     }
 }
 
-impl DataWriterInner {
-    pub fn new(handle: russh::server::Handle, channel: ChannelId) -> Self {
-        Self::Idle(ChannelHandle {
-            handle,
-            channel,
-            closed: false,
-        })
-    }
-
-    fn poll_idle(&mut self, cx: &mut Context<'_>) -> Poll<(ChannelHandle, Result<(), io::Error>)> {
+impl<C: Channel> DataWriterInner<C> {
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> Poll<(C, Result<(), io::Error>)> {
         match mem::replace(self, Self::Invalid) {
             Self::Idle(ch) => Poll::Ready((ch, Ok(()))),
             Self::Active(mut f) => match f.poll_unpin(cx) {
@@ -91,7 +101,7 @@ impl DataWriterInner {
         let (ch, res) = ready!(self.poll_idle(cx));
         Poll::Ready(match res {
             Ok(()) => {
-                *self = Self::Active(ch.data(mem::replace(buf, CryptoVec::new())).boxed());
+                *self = Self::Active(ch.data(mem::replace(buf, CryptoVec::new())));
                 Ok(())
             }
             Err(e) => {
@@ -112,11 +122,11 @@ impl DataWriterInner {
             let (ch, res) = ready!(self.poll_idle(cx));
             break Poll::Ready(match res {
                 Ok(()) => {
-                    if ch.closed {
+                    if ch.is_closed() {
                         *self = Self::Idle(ch);
                         Ok(())
                     } else {
-                        *self = Self::Active(ch.shutdown().boxed());
+                        *self = Self::Active(ch.shutdown());
                         continue;
                     }
                 }
@@ -129,33 +139,48 @@ impl DataWriterInner {
     }
 }
 
+/// Maximum amount of data buffered before it is sent to the channel. At most
+/// one chunk of this size is in flight while another is being buffered.
+const MAX_CHUNK_SIZE: usize = 1024;
+
 #[derive(Debug)]
-pub struct DataWriter {
-    inner: DataWriterInner,
+pub struct DataWriter<C: Channel = ChannelHandle> {
+    inner: DataWriterInner<C>,
     buffer: CryptoVec,
 }
 
 impl DataWriter {
     pub fn new(handle: russh::server::Handle, channel: ChannelId) -> Self {
+        Self::with_channel(ChannelHandle {
+            handle,
+            channel,
+            closed: false,
+        })
+    }
+}
+
+impl<C: Channel> DataWriter<C> {
+    fn with_channel(channel: C) -> Self {
         Self {
-            inner: DataWriterInner::new(handle, channel),
+            inner: DataWriterInner::Idle(channel),
             buffer: CryptoVec::new(),
         }
     }
 }
 
-impl AsyncWrite for DataWriter {
+impl<C: Channel> AsyncWrite for DataWriter<C> {
     fn poll_write(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize, io::Error>> {
         let this = &mut *self;
-        if this.buffer.len() >= 1024 {
+        if this.buffer.len() >= MAX_CHUNK_SIZE {
             ready!(this.inner.poll_write(cx, &mut this.buffer))?;
         }
-        self.buffer.extend(buf);
-        Poll::Ready(Ok(buf.len()))
+        let amt = buf.len().min(MAX_CHUNK_SIZE - this.buffer.len());
+        this.buffer.extend(&buf[..amt]);
+        Poll::Ready(Ok(amt))
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
@@ -177,3 +202,55 @@ impl AsyncWrite for DataWriter {
         this.inner.poll_shutdown(cx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    /// Records the size of each chunk sent, yielding once per chunk so that
+    /// writes overlap with sending as they would over SSH.
+    #[derive(Debug, Default, Clone)]
+    struct RecordingChannel {
+        chunks: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl Channel for RecordingChannel {
+        fn data(self, bytes: CryptoVec) -> BoxFuture<'static, (Self, Result<(), ()>)> {
+            async move {
+                tokio::task::yield_now().await;
+                self.chunks.lock().unwrap().push(bytes.len());
+                (self, Ok(()))
+            }
+            .boxed()
+        }
+        fn shutdown(self) -> BoxFuture<'static, (Self, Result<(), ()>)> {
+            async move { (self, Ok(())) }.boxed()
+        }
+        fn is_closed(&self) -> bool {
+            false
+        }
+    }
+
+    #[tokio::test]
+    async fn large_writes_are_sent_in_bounded_chunks() {
+        const TOTAL: usize = 1024 * 1024;
+        let channel = RecordingChannel::default();
+        let mut writer = DataWriter::with_channel(channel.clone());
+        let data = vec![b'x'; TOTAL];
+
+        let mut written = 0;
+        while written < TOTAL {
+            written += writer.write(&data[written..]).await.unwrap();
+            assert!(writer.buffer.len() <= MAX_CHUNK_SIZE);
+        }
+        writer.flush().await.unwrap();
+
+        let chunks = channel.chunks.lock().unwrap();
+        assert!(chunks.iter().all(|&len| len <= MAX_CHUNK_SIZE));
+        assert_eq!(chunks.iter().sum::<usize>(), TOTAL);
+    }
+}
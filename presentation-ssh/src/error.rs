@@ -11,6 +11,12 @@ pub enum Error {
     SshKeys(#[from] russh_keys::Error),
     #[error(transparent)]
     UserManagement(#[from] UserManagementError),
+    #[error("Environment variable `{0}` for the SSH host key is not set")]
+    MissingHostKeyVar(String),
+    #[error("Environment variable `{0}` does not contain a base64 encoded SSH host key")]
+    InvalidHostKeyVar(String),
+    #[error("At least one SSH authentication method must be enabled")]
+    NoAuthMethods,
     #[error("Failed to start SSH server")]
     FailedToStart(#[source] io::Error),
     #[error(transparent)]
@@ -1,15 +1,22 @@
-use std::{fmt, sync::Arc};
+use std::{fmt, net::IpAddr, sync::Arc};
 
 use async_trait::async_trait;
-use russh::{ChannelId, MethodSet};
+use russh::{ChannelId, CryptoVec, MethodSet};
 
 use playferrous_presentation::{
-    terminal::TerminalPresentation, UserId, UserManagement, UserManagementError,
+    auth_limiter::AuthLimiter,
+    terminal::{self, TerminalPresentation, TerminalToPresentationMsg},
+    UserId, UserManagement, UserManagementError,
 };
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 use tracing::{error, instrument};
 
-use crate::{client, data_reader::DataReader, data_writer::DataWriter, error::Error};
+use crate::{
+    client,
+    data_reader::{take_complete_utf8, DataReader},
+    data_writer::DataWriter,
+    error::Error,
+};
 
 #[derive(Debug)]
 pub(crate) enum AuthState {
@@ -21,6 +28,7 @@ pub(crate) enum AuthState {
     },
     Authenticated {
         user_id: UserId,
+        username: String,
     },
 }
 
@@ -28,8 +36,20 @@ pub(crate) struct Handler {
     auth_state: AuthState,
     auth_key_fingerprint: Option<String>,
     user_management: Arc<dyn UserManagement>,
+    auth_limiter: Arc<AuthLimiter>,
+    banner: Option<Arc<str>>,
+    /// The authentication methods which are enabled.
+    methods: MethodSet,
+    /// In characters, for the client, or in bytes when re-entering a password.
+    max_line_length: usize,
+    shutdown: watch::Receiver<bool>,
+    peer_ip: Option<IpAddr>,
     session: Option<(russh::server::Handle, ChannelId)>,
     data_stream: Option<mpsc::Sender<Vec<u8>>>,
+    /// The start of a UTF-8 sequence whose remaining bytes haven't arrived yet.
+    partial_utf8: Vec<u8>,
+    col_width: Option<u32>,
+    terminal_input: Option<mpsc::Sender<TerminalToPresentationMsg>>,
 }
 
 impl fmt::Debug for Handler {
@@ -37,57 +57,169 @@ impl fmt::Debug for Handler {
         f.debug_struct("Handler")
             .field("auth_state", &self.auth_state)
             .field("auth_key_fingerprint", &self.auth_key_fingerprint)
+            .field("peer_ip", &self.peer_ip)
             .field("data_stream", &self.data_stream)
+            .field("col_width", &self.col_width)
             .finish_non_exhaustive()
     }
 }
 
 impl Handler {
-    pub fn new(user_management: Arc<dyn UserManagement>) -> Self {
+    pub fn new(
+        user_management: Arc<dyn UserManagement>,
+        auth_limiter: Arc<AuthLimiter>,
+        banner: Option<Arc<str>>,
+        methods: MethodSet,
+        max_line_length: usize,
+        shutdown: watch::Receiver<bool>,
+        peer_ip: Option<IpAddr>,
+    ) -> Self {
         Self {
             auth_state: AuthState::Unauthenticated,
             auth_key_fingerprint: None,
             user_management,
+            auth_limiter,
+            banner,
+            methods,
+            max_line_length,
+            shutdown,
+            peer_ip,
             session: None,
             data_stream: None,
+            partial_utf8: Vec::new(),
+            col_width: None,
+            terminal_input: None,
         }
     }
+    /// If this peer has failed to authenticate too many times, wait out the
+    /// lockout delay and return `true` to indicate the attempt must be rejected.
+    async fn check_locked_out(&self) -> bool {
+        let Some(delay) = self
+            .peer_ip
+            .and_then(|ip| self.auth_limiter.lockout_delay(ip))
+        else {
+            return false;
+        };
+        tracing::warn!(
+            "Rejecting authentication from locked out peer {:?}",
+            self.peer_ip
+        );
+        tokio::time::sleep(delay).await;
+        true
+    }
+    fn auth_failure(&self) {
+        if let Some(ip) = self.peer_ip {
+            self.auth_limiter.record_failure(ip);
+        }
+    }
+    /// Render the configured banner for this user, with terminal line endings.
+    fn render_banner(&self, username: &str) -> Option<String> {
+        let banner = self.banner.as_deref()?;
+        Some(
+            banner
+                .replace("{username}", username)
+                .replace("\r\n", "\n")
+                .replace("\n", "\r\n"),
+        )
+    }
     #[instrument(skip(self))]
-    async fn auth_success(&mut self, user_id: UserId) -> Result<(), Error> {
-        self.auth_state = AuthState::Authenticated { user_id };
-        if let Some(fingerprint) = &self.auth_key_fingerprint {
-            self.user_management
-                .add_user_public_key(user_id, fingerprint)
-                .await?;
+    async fn auth_success(&mut self, user_id: UserId, username: String) -> Result<(), Error> {
+        if let Some(ip) = self.peer_ip {
+            self.auth_limiter.record_success(ip);
         }
+        self.auth_state = AuthState::Authenticated { user_id, username };
         Ok(())
     }
+    /// Connect the user, or tell them why that wasn't possible and close the
+    /// channel.
+    async fn connect_or_close(
+        &mut self,
+        user_id: UserId,
+        username: String,
+        channel: ChannelId,
+        session: &mut russh::server::Session,
+    ) {
+        if let Err(e) = self.connect(user_id, username).await {
+            error!("Failed to connect user {}: {:#}", user_id, e);
+            let message = format!("{}\r\n", connect_error_message(&e));
+            session.data(channel, message.into());
+            session.close(channel);
+        }
+    }
+    /// Nothing is kept unless the connection succeeds, so a failure leaves the
+    /// handler as it was.
     #[instrument(skip(self))]
-    async fn connect(&mut self, user_id: UserId) -> Result<(), Error> {
+    async fn connect(&mut self, user_id: UserId, username: String) -> anyhow::Result<()> {
         let (session, channel) = self
             .session
             .clone()
             .expect("Should not try to connect without channel");
-        let presentation_connection =
-            TerminalPresentation::connect(&*self.user_management, user_id).await?;
+        let presentation_connection = TerminalPresentation::connect(
+            self.user_management.clone(),
+            user_id,
+            username,
+            self.auth_key_fingerprint.clone(),
+        )
+        .await?;
+        if let Some(columns) = self.col_width {
+            let _ = presentation_connection
+                .s
+                .send(TerminalToPresentationMsg::Resize { columns })
+                .await;
+        }
+        self.terminal_input = Some(presentation_connection.s.clone());
         let (tx, rx) = mpsc::channel(4);
         self.data_stream = Some(tx);
 
+        let shutdown = self.shutdown.clone();
+        let max_line_length = self.max_line_length;
         tokio::spawn(async move {
             let res = client::run(
                 presentation_connection,
-                DataReader::new(rx),
+                DataReader::new(rx)
+                    .with_completer(terminal::complete_command)
+                    .with_max_line_length(max_line_length),
                 DataWriter::new(session.clone(), channel),
+                shutdown,
             )
             .await;
             if let Err(e) = res {
-                error!("Client task failed: {}", e)
+                error!("Client task failed: {}", e);
+                // The client may already be gone, in which case there's no
+                // one left to tell
+                let message = "\r\nThe connection failed unexpectedly.\r\n";
+                let _ = session
+                    .data(channel, CryptoVec::from_slice(message.as_bytes()))
+                    .await;
+                let _ = session.close(channel).await;
             }
         });
         Ok(())
     }
 }
 
+/// What to tell the user when they can't be connected.
+fn connect_error_message(e: &anyhow::Error) -> String {
+    match e.downcast_ref::<UserManagementError>() {
+        Some(e) => user_management_error_message(e),
+        None => "Unable to connect at the moment. Please try again later.".into(),
+    }
+}
+
+fn user_management_error_message(e: &UserManagementError) -> String {
+    match e {
+        UserManagementError::TooManyConnections => {
+            "You have too many connections open. Close one and try again.".into()
+        }
+        UserManagementError::UserDoesNotExist => "This account no longer exists.".into(),
+        UserManagementError::UserAlreadyExists => {
+            "Someone else has just taken this username. Please choose another.".into()
+        }
+        UserManagementError::WeakPassword(reason) => reason.clone(),
+        _ => "Unable to connect at the moment. Please try again later.".into(),
+    }
+}
+
 #[async_trait]
 impl russh::server::Handler for Handler {
     type Error = Error;
@@ -98,6 +230,14 @@ impl russh::server::Handler for Handler {
         username: &str,
         public_key: &russh_keys::key::PublicKey,
     ) -> Result<(Self, russh::server::Auth), Error> {
+        if self.check_locked_out().await {
+            return Ok((
+                self,
+                russh::server::Auth::Reject {
+                    proceed_with_methods: None,
+                },
+            ));
+        }
         let fingerprint = public_key.fingerprint();
         match self
             .user_management
@@ -105,7 +245,7 @@ impl russh::server::Handler for Handler {
             .await
         {
             Ok(user_id) => {
-                self.auth_success(user_id).await?;
+                self.auth_success(user_id, username.into()).await?;
                 Ok((self, russh::server::Auth::Accept))
             }
             Err(UserManagementError::UserDoesNotExist | UserManagementError::InvalidAuth) => {
@@ -113,7 +253,7 @@ impl russh::server::Handler for Handler {
                 Ok((
                     self,
                     russh::server::Auth::Reject {
-                        proceed_with_methods: Some(MethodSet::PASSWORD | MethodSet::PUBLICKEY),
+                        proceed_with_methods: Some(self.methods),
                     },
                 ))
             }
@@ -127,21 +267,42 @@ impl russh::server::Handler for Handler {
         username: &str,
         password: &str,
     ) -> Result<(Self, russh::server::Auth), Error> {
+        // Clients shouldn't offer a method which wasn't advertised, and without
+        // passwords there's no way to sign up either
+        if !self.methods.contains(MethodSet::PASSWORD) {
+            return Ok((
+                self,
+                russh::server::Auth::Reject {
+                    proceed_with_methods: Some(self.methods),
+                },
+            ));
+        }
+        if self.check_locked_out().await {
+            return Ok((
+                self,
+                russh::server::Auth::Reject {
+                    proceed_with_methods: None,
+                },
+            ));
+        }
         match self
             .user_management
             .login_user_with_password(username, password)
             .await
         {
             Ok(user_id) => {
-                self.auth_success(user_id).await?;
+                self.auth_success(user_id, username.into()).await?;
                 Ok((self, russh::server::Auth::Accept))
             }
-            Err(UserManagementError::InvalidAuth) => Ok((
-                self,
-                russh::server::Auth::Reject {
-                    proceed_with_methods: Some(MethodSet::PASSWORD | MethodSet::PUBLICKEY),
-                },
-            )),
+            Err(UserManagementError::InvalidAuth) => {
+                self.auth_failure();
+                Ok((
+                    self,
+                    russh::server::Auth::Reject {
+                        proceed_with_methods: Some(self.methods),
+                    },
+                ))
+            }
             Err(UserManagementError::UserDoesNotExist) => {
                 self.auth_state = AuthState::Attempted {
                     username: username.into(),
@@ -172,18 +333,22 @@ impl russh::server::Handler for Handler {
         Ok((self, allow, session))
     }
 
-    #[instrument(level = "debug", skip(self, session), fields(data=std::str::from_utf8(data).ok()))]
+    #[instrument(level = "debug", skip(self, session), fields(data=std::str::from_utf8(input).ok()))]
     async fn data(
         mut self,
         channel: ChannelId,
-        mut data: &[u8],
+        input: &[u8],
         mut session: russh::server::Session,
     ) -> Result<(Self, russh::server::Session), Error> {
         // Disconnect on Ctrl+C or Ctrl+D
-        if data.contains(&3) || data.contains(&4) {
+        if input.contains(&3) || input.contains(&4) {
             session.close(channel);
             return Ok((self, session));
         }
+        // A character may be split across reads, so hold back any incomplete
+        // sequence until the rest of it arrives
+        let buffered = take_complete_utf8(&mut self.partial_utf8, input);
+        let mut data = &buffered[..];
         if let AuthState::Attempted {
             username,
             password,
@@ -204,9 +369,21 @@ impl russh::server::Handler for Handler {
                         };
                         if success {
                             let user_id =
-                                self.user_management.create_user(username, password).await?;
-                            self.auth_success(user_id).await?;
-                            self.connect(user_id).await?;
+                                match self.user_management.create_user(username, password).await {
+                                    Ok(user_id) => user_id,
+                                    Err(e) => {
+                                        error!("Failed to create user {}: {}", username, e);
+                                        let message =
+                                            format!("{}\r\n", user_management_error_message(&e));
+                                        session.data(channel, message.into());
+                                        session.close(channel);
+                                        break;
+                                    }
+                                };
+                            let username = username.clone();
+                            self.auth_success(user_id, username.clone()).await?;
+                            self.connect_or_close(user_id, username, channel, &mut session)
+                                .await;
                             break;
                         } else {
                             let message = format!("Passwords did not match.\r\n");
@@ -222,6 +399,7 @@ impl russh::server::Handler for Handler {
                             }
                         }
                     }
+                    _ if reenter_password.len() >= self.max_line_length => {}
                     _ => reenter_password.push(hd),
                 }
             }
@@ -242,34 +420,60 @@ impl russh::server::Handler for Handler {
         mut self,
         channel: ChannelId,
         _term: &str,
-        _col_width: u32,
+        col_width: u32,
         _row_height: u32,
         _pix_width: u32,
         _pix_height: u32,
         _modes: &[(russh::Pty, u32)],
         mut session: russh::server::Session,
     ) -> Result<(Self, russh::server::Session), Error> {
+        self.col_width = Some(col_width);
         match &self.auth_state {
             AuthState::Attempted {
                 username, password, ..
             } => {
-                if password.len() < 8 {
-                    let message = format!("Password must be at least 8 characters long.\r\n");
+                if let Err(e) = self.user_management.validate_password(password).await {
+                    let message = format!("{e}\r\n");
                     session.data(channel, message.into());
                     session.close(channel);
                 } else {
-                    let message = format!(
+                    let mut message = self.render_banner(username).unwrap_or_default();
+                    message += &format!(
                         "User `{username}` does not exist. Re-enter password to create user:\r\n"
                     );
                     session.data(channel, message.into());
                 }
             }
-            AuthState::Authenticated { user_id } => {
-                self.connect(*user_id).await?;
+            AuthState::Authenticated { user_id, username } => {
+                let banner = self.render_banner(username);
+                if let Some(banner) = banner {
+                    session.data(channel, banner.into());
+                }
+                let (user_id, username) = (*user_id, username.clone());
+                self.connect_or_close(user_id, username, channel, &mut session)
+                    .await;
             }
             AuthState::Unauthenticated => {}
         }
 
         Ok((self, session))
     }
+    #[instrument(skip(self, session))]
+    async fn window_change_request(
+        mut self,
+        _channel: ChannelId,
+        col_width: u32,
+        _row_height: u32,
+        _pix_width: u32,
+        _pix_height: u32,
+        session: russh::server::Session,
+    ) -> Result<(Self, russh::server::Session), Error> {
+        self.col_width = Some(col_width);
+        if let Some(tx) = &self.terminal_input {
+            let _ = tx
+                .send(TerminalToPresentationMsg::Resize { columns: col_width })
+                .await;
+        }
+        Ok((self, session))
+    }
 }
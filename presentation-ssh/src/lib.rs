@@ -1,9 +1,11 @@
-use std::{io, sync::Arc};
+use std::{io, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
-use playferrous_presentation::{Presentation, UserManagement};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use playferrous_presentation::{auth_limiter::AuthLimiter, Presentation, UserManagement};
 use russh::MethodSet;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use tokio::{net::TcpListener, sync::watch, task::JoinHandle};
 
 mod data_writer;
 mod error;
@@ -17,42 +19,153 @@ const fn default_port() -> u16 {
     9000
 }
 
-fn default_key_path() -> String {
-    "server_key.p8".into()
+fn default_key_sources() -> Vec<KeySource> {
+    vec![KeySource::File("server_key.p8".into())]
+}
+
+/// Configs from before host keys could come from elsewhere name a single file
+/// with `key_path`, which is still accepted.
+fn deserialize_key_sources<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<KeySource>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum KeySources {
+        Path(String),
+        Sources(Vec<KeySource>),
+    }
+    Ok(match KeySources::deserialize(deserializer)? {
+        KeySources::Path(key_path) => vec![KeySource::File(key_path)],
+        KeySources::Sources(key_sources) => key_sources,
+    })
+}
+
+const fn default_max_auth_failures() -> u32 {
+    5
+}
+
+const fn default_auth_failure_window_secs() -> u64 {
+    300
+}
+
+const fn default_max_line_length() -> usize {
+    1024
+}
+
+fn default_auth_methods() -> Vec<AuthMethod> {
+    vec![AuthMethod::PublicKey, AuthMethod::Password]
+}
+
+/// Where to find one of the server's host keys.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeySource {
+    /// A PKCS8 file, which is generated if it doesn't exist.
+    File(String),
+    /// An environment variable containing a base64 encoded PKCS8 key. This is
+    /// never generated, so the variable must be set.
+    Env(String),
+    /// A new key each time the server starts, which clients will see change.
+    Generate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMethod {
+    PublicKey,
+    /// Also how new users sign up, by logging in with an unknown username.
+    Password,
+}
+
+impl From<AuthMethod> for MethodSet {
+    fn from(value: AuthMethod) -> Self {
+        match value {
+            AuthMethod::PublicKey => MethodSet::PUBLICKEY,
+            AuthMethod::Password => MethodSet::PASSWORD,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     #[serde(default = "default_port")]
     port: u16,
-    #[serde(default = "default_key_path")]
-    key_path: String,
+    #[serde(
+        default = "default_key_sources",
+        alias = "key_path",
+        deserialize_with = "deserialize_key_sources"
+    )]
+    key_sources: Vec<KeySource>,
+    #[serde(default = "default_max_auth_failures")]
+    max_auth_failures: u32,
+    #[serde(default = "default_auth_failure_window_secs")]
+    auth_failure_window_secs: u64,
+    #[serde(default)]
+    banner_path: Option<String>,
+    #[serde(default = "default_auth_methods")]
+    auth_methods: Vec<AuthMethod>,
+    /// Input beyond this many characters on one line is dropped.
+    #[serde(default = "default_max_line_length")]
+    max_line_length: usize,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             port: default_port(),
-            key_path: default_key_path(),
+            key_sources: default_key_sources(),
+            max_auth_failures: default_max_auth_failures(),
+            auth_failure_window_secs: default_auth_failure_window_secs(),
+            banner_path: None,
+            auth_methods: default_auth_methods(),
+            max_line_length: default_max_line_length(),
         }
     }
 }
 
-pub struct PresentationSsh {}
+pub struct PresentationSsh {
+    server_task: JoinHandle<()>,
+    shutdown: watch::Sender<bool>,
+}
 
 impl PresentationSsh {
-    async fn load_or_generate_key(config: &Config) -> Result<russh_keys::key::KeyPair, Error> {
-        match tokio::fs::read(&config.key_path).await {
-            Ok(key_data) => Ok(russh_keys::pkcs8::decode_pkcs8(&key_data, None)?),
-            Err(e) if e.kind() == io::ErrorKind::NotFound => {
-                let key = russh_keys::key::KeyPair::generate_ed25519().unwrap();
-                let key_data = russh_keys::pkcs8::encode_pkcs8(&key);
-                tokio::fs::write(&config.key_path, key_data).await?;
-                Ok(key)
+    async fn load_or_generate_key(
+        key_source: &KeySource,
+    ) -> Result<russh_keys::key::KeyPair, Error> {
+        match key_source {
+            KeySource::File(key_path) => match tokio::fs::read(key_path).await {
+                Ok(key_data) => Ok(russh_keys::pkcs8::decode_pkcs8(&key_data, None)?),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    let key = russh_keys::key::KeyPair::generate_ed25519().unwrap();
+                    let key_data = russh_keys::pkcs8::encode_pkcs8(&key);
+                    tokio::fs::write(key_path, key_data).await?;
+                    Ok(key)
+                }
+                Err(e) => Err(e.into()),
+            },
+            KeySource::Env(var) => {
+                let encoded =
+                    std::env::var(var).map_err(|_| Error::MissingHostKeyVar(var.clone()))?;
+                let key_data = STANDARD
+                    .decode(encoded.trim())
+                    .map_err(|_| Error::InvalidHostKeyVar(var.clone()))?;
+                Ok(russh_keys::pkcs8::decode_pkcs8(&key_data, None)?)
+            }
+            KeySource::Generate => {
+                tracing::warn!("Using a generated host key, which will change on restart");
+                Ok(russh_keys::key::KeyPair::generate_ed25519().unwrap())
             }
-            Err(e) => Err(e.into()),
         }
     }
+    async fn load_or_generate_keys(
+        config: &Config,
+    ) -> Result<Vec<russh_keys::key::KeyPair>, Error> {
+        let mut keys = Vec::with_capacity(config.key_sources.len());
+        for key_source in &config.key_sources {
+            keys.push(Self::load_or_generate_key(key_source).await?);
+        }
+        Ok(keys)
+    }
 }
 
 #[async_trait]
@@ -61,39 +174,178 @@ impl Presentation for PresentationSsh {
     type Error = Error;
 
     async fn new(config: &Config, user_management: Arc<dyn UserManagement>) -> Result<Self, Error> {
-        let key = Self::load_or_generate_key(&config).await?;
+        let methods = config
+            .auth_methods
+            .iter()
+            .fold(MethodSet::empty(), |methods, &method| {
+                methods | method.into()
+            });
+        if methods.is_empty() {
+            return Err(Error::NoAuthMethods);
+        }
+        let keys = Self::load_or_generate_keys(config).await?;
+        let banner = if let Some(banner_path) = &config.banner_path {
+            Some(tokio::fs::read_to_string(banner_path).await?.into())
+        } else {
+            None
+        };
         let mut ssh_config = russh::server::Config {
             auth_rejection_time: std::time::Duration::from_millis(200),
             auth_rejection_time_initial: Some(std::time::Duration::from_secs(0)),
-            keys: vec![key],
+            keys,
             // This is actually an inactivity timeout
             connection_timeout: None,
             ..Default::default()
         };
-        ssh_config.methods = MethodSet::PUBLICKEY | MethodSet::PASSWORD;
-        let server = Server::new(user_management);
+        ssh_config.methods = methods;
+
+        let auth_limiter = AuthLimiter::new(
+            config.max_auth_failures,
+            Duration::from_secs(config.auth_failure_window_secs),
+        );
 
-        russh::server::run(Arc::new(ssh_config), ("0.0.0.0", config.port), server)
+        let (shutdown, shutdown_r) = watch::channel(false);
+        let server = Server::new(
+            user_management,
+            auth_limiter,
+            banner,
+            methods,
+            config.max_line_length,
+            shutdown_r,
+        );
+
+        // Bind before returning, so that the server fails to start rather
+        // than running without SSH
+        let listener = TcpListener::bind(("0.0.0.0", config.port))
             .await
             .map_err(Error::FailedToStart)?;
-        Ok(Self {})
+        let ssh_config = Arc::new(ssh_config);
+        let server_task = tokio::spawn(async move {
+            let mut server = server;
+            loop {
+                let (socket, peer_addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        tracing::error!("SSH server stopped: {}", e);
+                        break;
+                    }
+                };
+                let handler = russh::server::Server::new_client(&mut server, Some(peer_addr));
+                let ssh_config = ssh_config.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = russh::server::run_stream(ssh_config, socket, handler).await {
+                        tracing::warn!("SSH session from {} failed: {}", peer_addr, e);
+                    }
+                });
+            }
+        });
+        Ok(Self {
+            server_task,
+            shutdown,
+        })
+    }
+
+    async fn shutdown(&self) {
+        // Stop listening for new connections
+        self.server_task.abort();
+        // Close any connections which are still active
+        let _ = self.shutdown.send(true);
     }
 }
 
 struct Server {
     user_management: Arc<dyn UserManagement>,
+    auth_limiter: Arc<AuthLimiter>,
+    banner: Option<Arc<str>>,
+    methods: MethodSet,
+    max_line_length: usize,
+    shutdown: watch::Receiver<bool>,
 }
 
 impl Server {
-    fn new(user_management: Arc<dyn UserManagement>) -> Self {
-        Self { user_management }
+    fn new(
+        user_management: Arc<dyn UserManagement>,
+        auth_limiter: Arc<AuthLimiter>,
+        banner: Option<Arc<str>>,
+        methods: MethodSet,
+        max_line_length: usize,
+        shutdown: watch::Receiver<bool>,
+    ) -> Self {
+        Self {
+            user_management,
+            auth_limiter,
+            banner,
+            methods,
+            max_line_length,
+            shutdown,
+        }
     }
 }
 
 impl russh::server::Server for Server {
     type Handler = handler::Handler;
 
-    fn new_client(&mut self, _peer_addr: Option<std::net::SocketAddr>) -> Self::Handler {
-        handler::Handler::new(self.user_management.clone())
+    fn new_client(&mut self, peer_addr: Option<std::net::SocketAddr>) -> Self::Handler {
+        handler::Handler::new(
+            self.user_management.clone(),
+            self.auth_limiter.clone(),
+            self.banner.clone(),
+            self.methods,
+            self.max_line_length,
+            self.shutdown.clone(),
+            peer_addr.map(|addr| addr.ip()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn loads_a_key_from_each_file() {
+        let dir = std::env::temp_dir().join(format!("playferrous-ssh-keys-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = Config {
+            key_sources: ["ed25519.p8", "rotated.p8"]
+                .into_iter()
+                .map(|name| KeySource::File(dir.join(name).to_string_lossy().into_owned()))
+                .collect(),
+            ..Default::default()
+        };
+
+        let generated = PresentationSsh::load_or_generate_keys(&config)
+            .await
+            .unwrap();
+        assert_eq!(generated.len(), 2);
+
+        // The second time, the keys are read back from the files
+        let loaded = PresentationSsh::load_or_generate_keys(&config)
+            .await
+            .unwrap();
+        let fingerprints = |keys: &[russh_keys::key::KeyPair]| {
+            keys.iter()
+                .map(|key| key.clone_public_key().unwrap().fingerprint())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(fingerprints(&generated), fingerprints(&loaded));
+        assert_ne!(fingerprints(&loaded)[0], fingerprints(&loaded)[1]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn key_path_is_still_accepted() {
+        let config: Config = serde_json::from_str(r#"{ "key_path": "old_key.p8" }"#).unwrap();
+        assert!(matches!(
+            &config.key_sources[..],
+            [KeySource::File(key_path)] if key_path == "old_key.p8"
+        ));
+        let config: Config =
+            serde_json::from_str(r#"{ "key_sources": [{ "env": "HOST_KEY" }, "generate"] }"#)
+                .unwrap();
+        assert!(matches!(
+            &config.key_sources[..],
+            [KeySource::Env(_), KeySource::Generate]
+        ));
     }
 }
@@ -0,0 +1,127 @@
+use std::{net::IpAddr, sync::Arc};
+
+use futures::{SinkExt, StreamExt};
+use playferrous_presentation::{
+    auth_limiter::AuthLimiter, ConnectionToPresentationMsg, PresentationKind,
+    PresentationToConnectionMsg, UserId, UserManagement,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{net::TcpStream, sync::watch};
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use tracing::instrument;
+
+use crate::error::Error;
+
+/// The first message a client must send after the WebSocket is established.
+#[derive(Debug, Deserialize)]
+struct AuthRequest {
+    username: String,
+    password: String,
+}
+
+/// Sent in reply to an `AuthRequest`.
+#[derive(Debug, Serialize)]
+enum AuthResponse {
+    Accepted { user_id: UserId },
+    Rejected { reason: String },
+}
+
+async fn send_json<T: Serialize>(
+    ws: &mut WebSocketStream<TcpStream>,
+    value: &T,
+) -> Result<(), Error> {
+    ws.send(Message::Text(serde_json::to_string(value)?))
+        .await?;
+    Ok(())
+}
+
+/// Wait for the next text frame, ignoring pings and other control frames.
+async fn recv_text(ws: &mut WebSocketStream<TcpStream>) -> Result<Option<String>, Error> {
+    while let Some(msg) = ws.next().await {
+        match msg? {
+            Message::Text(text) => return Ok(Some(text)),
+            Message::Close(_) => break,
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+async fn authenticate(
+    ws: &mut WebSocketStream<TcpStream>,
+    peer_ip: IpAddr,
+    user_management: &dyn UserManagement,
+    auth_limiter: &AuthLimiter,
+) -> Result<UserId, Error> {
+    let text = recv_text(ws).await?.ok_or(Error::NotAuthenticated)?;
+    let request: AuthRequest = serde_json::from_str(&text)?;
+    // Throttled the same way as SSH, so that passwords can't be guessed any
+    // faster through this presentation
+    if let Some(delay) = auth_limiter.lockout_delay(peer_ip) {
+        tracing::warn!("Rejecting authentication from locked out peer {}", peer_ip);
+        tokio::time::sleep(delay).await;
+        send_json(
+            ws,
+            &AuthResponse::Rejected {
+                reason: Error::LockedOut.to_string(),
+            },
+        )
+        .await?;
+        return Err(Error::LockedOut);
+    }
+    match user_management
+        .login_user_with_password(&request.username, &request.password)
+        .await
+    {
+        Ok(user_id) => {
+            auth_limiter.record_success(peer_ip);
+            send_json(ws, &AuthResponse::Accepted { user_id }).await?;
+            Ok(user_id)
+        }
+        Err(e) => {
+            auth_limiter.record_failure(peer_ip);
+            send_json(
+                ws,
+                &AuthResponse::Rejected {
+                    reason: e.to_string(),
+                },
+            )
+            .await?;
+            Err(e.into())
+        }
+    }
+}
+
+#[instrument(level = "debug", skip_all)]
+pub async fn run(
+    stream: TcpStream,
+    peer_ip: IpAddr,
+    user_management: Arc<dyn UserManagement>,
+    auth_limiter: Arc<AuthLimiter>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<(), Error> {
+    let mut ws = tokio_tungstenite::accept_async(stream).await?;
+    let user_id = authenticate(&mut ws, peer_ip, &*user_management, &auth_limiter).await?;
+    let mut connection = user_management
+        .connect(user_id, PresentationKind::Graphical)
+        .await?;
+
+    loop {
+        tokio::select! {
+            text = recv_text(&mut ws) => {
+                let Some(text) = text? else { break };
+                let msg: PresentationToConnectionMsg = serde_json::from_str(&text)?;
+                if connection.s.send(msg).await.is_err() {
+                    break;
+                }
+            },
+            msg = connection.r.recv() => {
+                let Some(msg): Option<ConnectionToPresentationMsg> = msg else { break };
+                send_json(&mut ws, &msg).await?;
+            },
+            _ = shutdown.changed() => break,
+        }
+    }
+    ws.close(None).await?;
+    Ok(())
+}
@@ -0,0 +1,24 @@
+use std::io;
+
+use playferrous_presentation::UserManagementError;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(transparent)]
+    WebSocket(#[from] tokio_tungstenite::tungstenite::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    UserManagement(#[from] UserManagementError),
+    #[error("Failed to start WebSocket server")]
+    FailedToStart(#[source] io::Error),
+    #[error("Client did not authenticate")]
+    NotAuthenticated,
+    #[error("Too many failed attempts to log in, try again later")]
+    LockedOut,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Internal(#[from] anyhow::Error),
+}
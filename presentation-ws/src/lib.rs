@@ -0,0 +1,108 @@
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use playferrous_presentation::{auth_limiter::AuthLimiter, Presentation, UserManagement};
+use serde::{Deserialize, Serialize};
+use tokio::{net::TcpListener, sync::watch, task::JoinHandle};
+
+mod client;
+mod error;
+use error::Error;
+
+const fn default_port() -> u16 {
+    9001
+}
+
+const fn default_max_auth_failures() -> u32 {
+    5
+}
+
+const fn default_auth_failure_window_secs() -> u64 {
+    300
+}
+
+/// How long to wait after failing to accept a connection, since the cause,
+/// such as running out of file descriptors, is unlikely to clear immediately.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Config {
+    #[serde(default = "default_port")]
+    port: u16,
+    #[serde(default = "default_max_auth_failures")]
+    max_auth_failures: u32,
+    #[serde(default = "default_auth_failure_window_secs")]
+    auth_failure_window_secs: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            port: default_port(),
+            max_auth_failures: default_max_auth_failures(),
+            auth_failure_window_secs: default_auth_failure_window_secs(),
+        }
+    }
+}
+
+pub struct PresentationWs {
+    server_task: JoinHandle<()>,
+    shutdown: watch::Sender<bool>,
+}
+
+#[async_trait]
+impl Presentation for PresentationWs {
+    type Config = Config;
+    type Error = Error;
+
+    async fn new(config: &Config, user_management: Arc<dyn UserManagement>) -> Result<Self, Error> {
+        let listener = TcpListener::bind(("0.0.0.0", config.port))
+            .await
+            .map_err(Error::FailedToStart)?;
+        let (shutdown, shutdown_r) = watch::channel(false);
+        let auth_limiter = AuthLimiter::new(
+            config.max_auth_failures,
+            Duration::from_secs(config.auth_failure_window_secs),
+        );
+
+        let server_task = tokio::spawn(async move {
+            loop {
+                let (stream, peer_addr) = match listener.accept().await {
+                    Ok(res) => res,
+                    Err(e) => {
+                        tracing::error!("Failed to accept WebSocket connection: {}", e);
+                        tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                        continue;
+                    }
+                };
+                let user_management = user_management.clone();
+                let auth_limiter = auth_limiter.clone();
+                let shutdown_r = shutdown_r.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = client::run(
+                        stream,
+                        peer_addr.ip(),
+                        user_management,
+                        auth_limiter,
+                        shutdown_r,
+                    )
+                    .await
+                    {
+                        tracing::error!("WebSocket client {} failed: {}", peer_addr, e);
+                    }
+                });
+            }
+        });
+        Ok(Self {
+            server_task,
+            shutdown,
+        })
+    }
+
+    async fn shutdown(&self) {
+        // Stop listening for new connections
+        self.server_task.abort();
+        // Close any connections which are still active
+        let _ = self.shutdown.send(true);
+    }
+}
@@ -1,21 +1,132 @@
 use std::any::type_name;
 
 use async_trait::async_trait;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, Span};
+
+/// Whether an actor should be replaced with a fresh instance if it panics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartPolicy {
+    Never,
+    UpTo(u32),
+}
 
 #[async_trait]
 pub trait Actor: Sized + Send + 'static {
-    async fn run(self) -> anyhow::Result<()>;
+    /// Run the actor until it finishes, or until `shutdown` is cancelled.
+    async fn run(self, shutdown: CancellationToken) -> anyhow::Result<()>;
+    /// Only actors which can safely be recreated from scratch should opt in to
+    /// being restarted.
+    fn restart_policy() -> RestartPolicy {
+        RestartPolicy::Never
+    }
+    /// Everything the actor logs is recorded within this span, so it should
+    /// carry the IDs of whatever the actor is responsible for.
+    fn span(&self) -> Span {
+        Span::none()
+    }
     fn spawn(self) {
-        tokio::spawn(async move {
-            if let Err(e) = self.run().await {
-                tracing::error!("{}: {}\n{}", type_name::<Self>(), e, e.backtrace());
+        // The token is never cancelled, so the actor runs until its channels close.
+        let _ = self.spawn_with_shutdown();
+    }
+    /// Spawn the actor, returning a handle to the task and a token which can be
+    /// cancelled to stop it.
+    fn spawn_with_shutdown(self) -> (JoinHandle<()>, CancellationToken) {
+        self.spawn_supervised(|| None)
+    }
+    /// Like `spawn_with_shutdown`, but the actor is also stopped when `parent`
+    /// is cancelled.
+    fn spawn_child(self, parent: &CancellationToken) -> (JoinHandle<()>, CancellationToken) {
+        self.spawn_supervised_child(parent, || None)
+    }
+    /// Like `spawn_with_shutdown`, but if the actor panics and its restart
+    /// policy allows, `restart` is called to obtain a replacement.
+    fn spawn_supervised(
+        self,
+        restart: impl FnMut() -> Option<Self> + Send + 'static,
+    ) -> (JoinHandle<()>, CancellationToken) {
+        self.spawn_supervised_child(&CancellationToken::new(), restart)
+    }
+    /// Like `spawn_supervised`, but the actor is also stopped when `parent` is
+    /// cancelled.
+    fn spawn_supervised_child(
+        self,
+        parent: &CancellationToken,
+        mut restart: impl FnMut() -> Option<Self> + Send + 'static,
+    ) -> (JoinHandle<()>, CancellationToken) {
+        let shutdown = parent.child_token();
+        let handle = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move {
+                let mut actor = self;
+                let mut restarts = 0;
+                loop {
+                    // Run the actor in its own task so that a panic surfaces
+                    // as a `JoinError` rather than taking down the supervisor.
+                    let span = actor.span();
+                    match tokio::spawn(actor.run(shutdown.clone()).instrument(span.clone())).await {
+                        Ok(Ok(())) => break,
+                        Ok(Err(e)) => {
+                            span.in_scope(|| {
+                                tracing::error!("{}: {}\n{}", type_name::<Self>(), e, e.backtrace())
+                            });
+                            break;
+                        }
+                        Err(e) if e.is_panic() => {
+                            let payload = e.into_panic();
+                            let msg = payload
+                                .downcast_ref::<&str>()
+                                .copied()
+                                .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                                .unwrap_or("Box<dyn Any>");
+                            span.in_scope(|| {
+                                tracing::error!("{} panicked: {}", type_name::<Self>(), msg)
+                            });
+                        }
+                        Err(_) => break,
+                    }
+                    let can_restart = match Self::restart_policy() {
+                        RestartPolicy::Never => false,
+                        RestartPolicy::UpTo(limit) => restarts < limit,
+                    };
+                    if !can_restart || shutdown.is_cancelled() {
+                        break;
+                    }
+                    let Some(replacement) = restart() else { break };
+                    restarts += 1;
+                    tracing::info!(
+                        "Restarting {} ({} of {:?})",
+                        type_name::<Self>(),
+                        restarts,
+                        Self::restart_policy()
+                    );
+                    actor = replacement;
+                }
             }
         });
+        (handle, shutdown)
     }
 }
 
 #[macro_export]
 macro_rules! select_recv_loop {
+    (shutdown = $shutdown:expr; $($v:pat = $a:expr => $b:expr,)*) => {
+        loop {
+            tokio::select! {
+                biased;
+                _ = $shutdown.cancelled() => break,
+                $(
+                    _msg = $a => if let Some(_msg) = _msg {
+                        let $v = _msg;
+                        $b
+                    } else {
+                        break;
+                    },
+                )*
+            }
+        }
+    };
     ($($v:pat = $a:expr => $b:expr,)*) => {
         loop {
             tokio::select! {
@@ -32,3 +143,65 @@ macro_rules! select_recv_loop {
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    use tracing_subscriber::fmt::MakeWriter;
+
+    use super::*;
+
+    /// Everything logged, shared between the test and the subscriber.
+    #[derive(Debug, Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Captured {
+        type Writer = Self;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    struct Greeter;
+
+    #[async_trait]
+    impl Actor for Greeter {
+        fn span(&self) -> Span {
+            tracing::info_span!("greeter", user_id = 42)
+        }
+        async fn run(self, _shutdown: CancellationToken) -> anyhow::Result<()> {
+            tracing::info!("Hello");
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn logs_within_the_actors_span() {
+        let captured = Captured::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(captured.clone())
+            .with_ansi(false)
+            .finish();
+        // The test runs on a single thread, so the actor's task logs here too
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let (handle, _) = Greeter.spawn_with_shutdown();
+        handle.await.unwrap();
+        let logs = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("greeter{user_id=42}: "), "{logs}");
+        assert!(logs.contains("Hello"), "{logs}");
+    }
+}
@@ -0,0 +1,81 @@
+use std::{
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+
+#[derive(Debug)]
+struct FailureRecord {
+    count: u32,
+    window_start: Instant,
+}
+
+/// Tracks failed authentication attempts per source address, so that
+/// repeated failures can be throttled. Shared by the presentations which
+/// accept passwords.
+#[derive(Debug)]
+pub struct AuthLimiter {
+    max_failures: u32,
+    window: Duration,
+    failures: DashMap<IpAddr, FailureRecord>,
+}
+
+impl AuthLimiter {
+    /// The window is also how often expired records are swept, which happens
+    /// in the background until the limiter is dropped. It can't be zero.
+    pub fn new(max_failures: u32, window: Duration) -> Arc<Self> {
+        let window = window.max(Duration::from_secs(1));
+        let this = Arc::new(Self {
+            max_failures,
+            window,
+            failures: DashMap::new(),
+        });
+        let weak = Arc::downgrade(&this);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(window);
+            loop {
+                interval.tick().await;
+                let Some(this) = weak.upgrade() else {
+                    break;
+                };
+                this.sweep();
+            }
+        });
+        this
+    }
+
+    /// Returns the delay to apply before rejecting an attempt from this
+    /// address, or `None` if the address is not currently locked out.
+    pub fn lockout_delay(&self, addr: IpAddr) -> Option<Duration> {
+        let record = self.failures.get(&addr)?;
+        if record.window_start.elapsed() >= self.window || record.count < self.max_failures {
+            return None;
+        }
+        let excess = (record.count - self.max_failures).min(6);
+        Some(Duration::from_millis(500) * 2u32.pow(excess))
+    }
+
+    pub fn record_failure(&self, addr: IpAddr) {
+        let mut record = self.failures.entry(addr).or_insert_with(|| FailureRecord {
+            count: 0,
+            window_start: Instant::now(),
+        });
+        if record.window_start.elapsed() >= self.window {
+            record.count = 0;
+            record.window_start = Instant::now();
+        }
+        record.count += 1;
+    }
+
+    pub fn record_success(&self, addr: IpAddr) {
+        self.failures.remove(&addr);
+    }
+
+    /// Discard records whose window has elapsed.
+    fn sweep(&self) {
+        self.failures
+            .retain(|_, record| record.window_start.elapsed() < self.window);
+    }
+}
@@ -1,4 +1,4 @@
-use tokio::sync::mpsc;
+use tokio::sync::mpsc::{self, error::TrySendError};
 
 #[derive(Debug)]
 pub struct Bichannel<S, R = S> {
@@ -6,6 +6,25 @@ pub struct Bichannel<S, R = S> {
     pub r: mpsc::Receiver<R>,
 }
 
+impl<S, R> Bichannel<S, R> {
+    /// Whether the other end has stopped receiving our messages.
+    pub fn is_closed(&self) -> bool {
+        self.s.is_closed()
+    }
+    /// Send a message without waiting for capacity, so that callers can decide
+    /// what to do about a slow receiver.
+    pub fn try_send(&self, msg: S) -> Result<(), TrySendError<S>> {
+        self.s.try_send(msg)
+    }
+    /// Number of messages which can be sent before the receiver falls behind.
+    pub fn capacity(&self) -> usize {
+        self.s.capacity()
+    }
+    pub fn split(self) -> (mpsc::Sender<S>, mpsc::Receiver<R>) {
+        (self.s, self.r)
+    }
+}
+
 pub fn bichannel<S, R>(buffer: usize) -> (Bichannel<S, R>, Bichannel<R, S>) {
     let (s1, r1) = mpsc::channel(buffer);
     let (s2, r2) = mpsc::channel(buffer);
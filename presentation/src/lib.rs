@@ -3,11 +3,16 @@ use std::{error::Error, fmt::Debug, num::ParseIntError, sync::Arc};
 use async_trait::async_trait;
 use bichannel::Bichannel;
 use chrono::{DateTime, Utc};
-use serde::{de::DeserializeOwned, Serialize};
+use chrono_tz::Tz;
+use ijson::IValue;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use thiserror::Error;
 
 pub mod actor;
+pub mod auth_limiter;
 pub mod bichannel;
+#[cfg(any(test, feature = "test-util"))]
+pub mod mock;
 pub mod terminal;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -24,6 +29,8 @@ pub trait Presentation: Sized {
         config: &Self::Config,
         user_management: Arc<dyn UserManagement>,
     ) -> Result<Self, Self::Error>;
+    /// Stop accepting new connections and close any active ones.
+    async fn shutdown(&self);
 }
 
 #[derive(Debug, Copy, Clone, Error)]
@@ -39,8 +46,12 @@ impl From<ParseIntError> for InvalidIdError {
 macro_rules! declare_ids {
     ($($name:ident => $prefix:literal,)*) => {
         $(
-            #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, sqlx::Type)]
+            #[derive(
+                Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, sqlx::Type, Serialize,
+                Deserialize,
+            )]
             #[sqlx(transparent)]
+            #[serde(transparent)]
             pub struct $name(pub i64);
 
             impl std::fmt::Display for $name {
@@ -84,6 +95,14 @@ pub enum UserManagementError {
     InvalidAuth,
     #[error("User already exists")]
     UserAlreadyExists,
+    #[error("Key does not exist")]
+    KeyDoesNotExist,
+    #[error("Cannot remove the last remaining authentication method")]
+    LastAuthMethod,
+    #[error("Too many connections for this user")]
+    TooManyConnections,
+    #[error("{0}")]
+    WeakPassword(String),
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
@@ -100,6 +119,10 @@ pub trait UserManagement: Send + Sync {
         username: &str,
         fingerprint: &str,
     ) -> Result<UserId, UserManagementError>;
+    /// Check a new password against the password policy. This is also done
+    /// when creating a user or changing a password, but checking first lets
+    /// the user be told before they are asked to confirm it.
+    async fn validate_password(&self, password: &str) -> Result<(), UserManagementError>;
     async fn create_user(
         &self,
         username: &str,
@@ -110,6 +133,21 @@ pub trait UserManagement: Send + Sync {
         user_id: UserId,
         fingerprint: &str,
     ) -> Result<(), UserManagementError>;
+    async fn list_user_public_keys(
+        &self,
+        user_id: UserId,
+    ) -> Result<Vec<UserKeyMin>, UserManagementError>;
+    async fn remove_user_public_key(
+        &self,
+        user_id: UserId,
+        fingerprint: &str,
+    ) -> Result<(), UserManagementError>;
+    async fn change_password(
+        &self,
+        user_id: UserId,
+        old_password: &str,
+        new_password: &str,
+    ) -> Result<(), UserManagementError>;
     async fn connect(
         &self,
         user_id: UserId,
@@ -117,57 +155,202 @@ pub trait UserManagement: Send + Sync {
     ) -> anyhow::Result<Bichannel<PresentationToConnectionMsg, ConnectionToPresentationMsg>>;
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateGameProposal {
     pub game_type: String,
+    /// Defaults to any number of players the game supports.
+    pub num_players: Option<i32>,
+    /// Public proposals are listed for everyone, whereas private ones are
+    /// only visible to those invited.
+    #[serde(default)]
+    pub is_public: bool,
+    /// Users to invite as soon as the proposal is created.
+    #[serde(default)]
+    pub invite: Vec<UserId>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum PresentationToConnectionMsg {
     ListGames,
+    ListOnlineUsers,
+    /// Only available to administrators.
+    ListLaunchers,
+    /// Every game type which can be proposed.
+    ListGameTypes,
     ListProposals,
     ListSessions,
     ListMessages,
+    SearchMessages(String),
     Propose(CreateGameProposal),
+    /// Propose a game with the settings saved in one of the user's templates.
+    ProposeFromTemplate {
+        name: String,
+        is_public: bool,
+        invite: Vec<UserId>,
+    },
+    ListProposalTemplates,
+    /// Save the game type, rules and player bounds of a proposal the user has
+    /// joined, replacing any template with the same name.
+    SaveProposalTemplate {
+        proposal_id: GameProposalId,
+        name: String,
+    },
+    DeleteProposalTemplate(String),
     Withdraw(GameProposalId),
+    Join(GameProposalId),
+    Leave(GameProposalId),
+    GetProposalRules(GameProposalId),
+    /// Rules are given as JSON, and are checked by the game before being
+    /// stored. Only the creator of the proposal may change them.
+    SetProposalRules {
+        proposal_id: GameProposalId,
+        rules: String,
+    },
+    /// Change a single rule, leaving the others as they are. Values which
+    /// aren't valid JSON are taken to be strings.
+    EditProposalRule {
+        proposal_id: GameProposalId,
+        key: String,
+        value: String,
+    },
+    StartProposal(GameProposalId),
+    /// Remove a member from a proposal. Only its creator may do this.
+    KickFromProposal {
+        proposal_id: GameProposalId,
+        user_id: UserId,
+    },
+    /// Send someone a request to join a proposal, which also makes it visible
+    /// to them. Any member may do this.
+    InviteToProposal {
+        proposal_id: GameProposalId,
+        user_id: UserId,
+    },
     Enter(SessionId),
+    Watch(GameId),
+    /// The final standings of a game the user played in.
+    GetGameResult(GameId),
+    /// The people the user has most recently played games with.
+    ListRecentOpponents,
+    /// The user's own ratings, or the highest rated players of a game type.
+    GetRatings(Option<String>),
+    /// Watch a game as each of its players sees it, without them being told.
+    /// Only available to administrators.
+    Observe(GameId),
+    /// Show a line to everyone who is online. Only available to
+    /// administrators.
+    Announce(String),
     Exit,
+    ListRequests,
+    AcceptRequest(RequestId),
+    RejectRequest(RequestId),
+    /// Withdraw a request the user sent.
+    CancelRequest(RequestId),
+    SetSetting {
+        key: String,
+        value: String,
+    },
     SessionCommand(SessionCommand),
 }
 
-#[derive(Debug, Clone)]
+impl PresentationToConnectionMsg {
+    /// Whether the connection should refuse this message unless the user is
+    /// an administrator.
+    pub fn requires_admin(&self) -> bool {
+        matches!(
+            self,
+            Self::ListLaunchers | Self::Observe(_) | Self::Announce(_)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum SessionCommand {
     Terminal(TerminalSessionCommand),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum TerminalSessionCommand {
     Line(String),
+    /// Ask for up to this many of the session's recent events to be sent
+    /// again, or a default number if `None`.
+    Scrollback(Option<usize>),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SessionInfo {
-    pub id: SessionId,
+    /// `None` when spectating, since spectators have no session of their own.
+    pub id: Option<SessionId>,
     pub kind: SessionKind,
+    /// `None` unless the session is for a game.
+    #[serde(default)]
+    pub capabilities: Option<GameCapabilities>,
 }
 
-#[derive(Debug, Clone)]
+/// What a game supports, so that presentations can adapt to it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GameCapabilities {
+    pub console_ui: bool,
+    /// The game may send `TerminalInputMode::Masked`.
+    pub masked_input: bool,
+    pub spectators: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum SessionKind {
     GameProposal(GameProposalId),
     Game(GameId),
+    GameSpectator(GameId),
+    GameObserver(GameId),
 }
 
-#[derive(Debug, Clone)]
+impl std::fmt::Display for SessionKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SessionKind::GameProposal(proposal_id) => write!(f, "proposal {proposal_id}"),
+            SessionKind::Game(game_id) => write!(f, "game {game_id}"),
+            SessionKind::GameSpectator(game_id) => write!(f, "watching game {game_id}"),
+            SessionKind::GameObserver(game_id) => write!(f, "observing game {game_id}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum SessionEvent {
     Terminal(TerminalSessionEvent),
+    Graphical(GraphicalSessionEvent),
 }
 
-#[derive(Debug, Clone)]
+/// What a graphical presentation is shown of a game. Each state is identified
+/// by the tick of the last action applied, if known, so that later states can
+/// be sent as changes to it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum GraphicalSessionEvent {
+    /// Everything the player may see, replacing whatever they were shown before.
+    State { tick: Option<i64>, state: IValue },
+    /// A JSON merge patch (RFC 7386) to apply to the state shown at `since_tick`.
+    Diff {
+        since_tick: i64,
+        tick: Option<i64>,
+        patch: IValue,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum TerminalSessionEvent {
     Line(String),
+    /// Change how the next line of input to the session is read.
+    Input(TerminalInputMode),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TerminalInputMode {
+    Line,
+    Masked,
+    /// Lines which are a number are replaced with the corresponding choice.
+    Choice(Vec<String>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MessageMin {
     pub id: MessageId,
     pub sent_at: DateTime<Utc>,
@@ -176,14 +359,104 @@ pub struct MessageMin {
     pub request_id: Option<RequestId>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GameProposalMin {
     pub id: GameProposalId,
     pub created_at: DateTime<Utc>,
     pub game_type: String,
+    /// What the game calls itself, for showing to users.
+    pub display_name: String,
+    /// The number of users who have joined so far.
+    pub joined: i32,
+    pub max_players: i32,
 }
 
-#[derive(Debug, Clone)]
+/// Settings saved by a user for proposing the same kind of game again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProposalTemplateMin {
+    pub name: String,
+    pub game_type: String,
+    pub min_players: i32,
+    pub max_players: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A request awaiting a response, which the user either sent or may respond to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestMin {
+    pub id: RequestId,
+    /// Whether the user sent the request, rather than may respond to it.
+    pub outgoing: bool,
+    /// What kind of request it is, eg. "friend request".
+    pub description: String,
+    /// The user or group on the other end of the request.
+    pub other: Option<String>,
+    pub sent_at: DateTime<Utc>,
+}
+
+/// Someone the user has played with, and the last game they played together.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpponentMin {
+    pub user_id: UserId,
+    pub username: String,
+    pub game_id: GameId,
+    pub game_type: String,
+    pub played_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RatingMin {
+    pub user_id: UserId,
+    pub username: String,
+    pub game_type: String,
+    pub rating: i32,
+    pub games_played: i32,
+}
+
+/// A game the user is playing in, which has not yet finished.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GameMin {
+    pub id: GameId,
+    pub game_type: String,
+    /// What the game calls itself, for showing to users.
+    pub display_name: String,
+    /// Whether the game is waiting on the user to act.
+    pub your_turn: bool,
+    /// When the current player must act by, if the game has been started.
+    pub deadline: Option<DateTime<Utc>>,
+}
+
+/// A launcher configured on the server, for administrators.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlayerResultMin {
+    pub player_index: i32,
+    /// `None` if the player's account has been deleted.
+    pub username: Option<String>,
+    pub score: Option<i64>,
+    /// Tied players share a position, starting from 1 for the winners.
+    pub position: Option<i32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LauncherInfo {
+    /// The kind of launcher and its configuration.
+    pub launcher: String,
+    pub game_types: Vec<String>,
+    /// Set if the launcher is unhealthy.
+    pub error: Option<String>,
+}
+
+/// A game type which can be proposed, as the game describes itself.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GameTypeInfo {
+    pub game_type: String,
+    pub display_name: String,
+    pub description: Option<String>,
+    pub min_players: i32,
+    pub max_players: i32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SessionMin {
     pub id: SessionId,
     pub created_at: DateTime<Utc>,
@@ -191,18 +464,201 @@ pub struct SessionMin {
 }
 
 #[derive(Debug, Clone)]
+pub struct UserKeyMin {
+    pub fingerprint: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UserMin {
     pub id: UserId,
     pub username: String,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OnlineUserMin {
+    pub id: UserId,
+    pub username: String,
+    pub last_seen_at: DateTime<Utc>,
+}
+
+/// What a list sent in chunks contains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ListKind {
+    Messages,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ConnectionToPresentationMsg {
+    GameList(Vec<GameMin>),
+    /// Sent when the connection opens, so that the user can be told which
+    /// games are waiting on them.
+    ActiveGames(Vec<GameMin>),
     MessageList(Vec<MessageMin>),
+    /// Long lists are sent in chunks, so that they don't hold up the
+    /// connection: this is followed by chunks of `total` items in order, and
+    /// then `ListEnd`.
+    ListBegin {
+        kind: ListKind,
+        total: usize,
+    },
+    MessageChunk(Vec<MessageMin>),
+    ListEnd,
     ProposalList(Vec<GameProposalMin>),
+    ProposalTemplateList(Vec<ProposalTemplateMin>),
+    RequestList(Vec<RequestMin>),
     SessionList(Vec<SessionMin>),
+    /// Pretty-printed JSON.
+    ProposalRules {
+        proposal_id: GameProposalId,
+        rules: String,
+    },
     EnteredSession(SessionInfo),
     ExitedSession,
     SessionEvent(SessionEvent),
-    Error(String),
+    /// Recent events in the session, oldest first. Sent on entering a session
+    /// which has any, and whenever they're asked for.
+    SessionHistory(Vec<SessionEvent>),
+    /// Sent to all of a player's connections, whichever session they're in.
+    YourTurn {
+        game_id: GameId,
+        /// Whether the user wants to be alerted with a bell.
+        bell: bool,
+    },
+    /// Someone sent the user a message.
+    NewMessage,
+    /// Sent by an administrator to everyone online.
+    Announcement(String),
+    LauncherList(Vec<LauncherInfo>),
+    GameTypeList(Vec<GameTypeInfo>),
+    /// Players in the order they placed.
+    GameResult {
+        game_id: GameId,
+        players: Vec<PlayerResultMin>,
+    },
+    /// Most recent first.
+    RecentOpponents(Vec<OpponentMin>),
+    /// In response to `GetRatings`, with the game type if one was given.
+    Ratings {
+        game_type: Option<String>,
+        ratings: Vec<RatingMin>,
+    },
+    /// Users who are online, and visible to this user.
+    OnlineUsers(Vec<OnlineUserMin>),
+    /// Sent when the connection opens, and whenever a setting changes.
+    Settings(UserSettings),
+    Error(PresentationError),
+}
+
+/// Per-user preferences, stored by the server and applied by presentations.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserSettings {
+    /// Ring the bell when it becomes the user's turn in a game.
+    pub turn_bell: bool,
+    /// An IANA time zone name, or `None` for UTC.
+    pub timezone: Option<String>,
+    /// Hide the user from everyone but their friends when listing who is online.
+    pub invisible: bool,
+}
+
+impl Default for UserSettings {
+    fn default() -> Self {
+        Self {
+            turn_bell: true,
+            timezone: None,
+            invisible: false,
+        }
+    }
+}
+
+impl UserSettings {
+    pub const KEYS: &'static [&'static str] = &["turn_bell", "timezone", "invisible"];
+
+    pub fn get(&self, key: &str) -> Result<String, PresentationError> {
+        Ok(match key {
+            "turn_bell" => self.turn_bell.to_string(),
+            "timezone" => self.timezone.clone().unwrap_or_else(|| "UTC".into()),
+            "invisible" => self.invisible.to_string(),
+            _ => return Err(PresentationError::UnknownSetting(key.into())),
+        })
+    }
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), PresentationError> {
+        let invalid = |reason: &str| PresentationError::InvalidSetting {
+            key: key.into(),
+            reason: reason.into(),
+        };
+        let parse_bool = |value| match value {
+            "true" | "on" => Ok(true),
+            "false" | "off" => Ok(false),
+            _ => Err(invalid("expected `on` or `off`")),
+        };
+        match key {
+            "turn_bell" => self.turn_bell = parse_bool(value)?,
+            "invisible" => self.invisible = parse_bool(value)?,
+            "timezone" => {
+                let tz: Tz = value
+                    .parse()
+                    .map_err(|_| invalid("expected a time zone name, eg. `Europe/London`"))?;
+                self.timezone = (tz != Tz::UTC).then(|| tz.name().into());
+            }
+            _ => return Err(PresentationError::UnknownSetting(key.into())),
+        }
+        Ok(())
+    }
+    /// Falls back to UTC if the stored time zone is no longer recognised.
+    pub fn timezone(&self) -> Tz {
+        self.timezone
+            .as_deref()
+            .and_then(|tz| tz.parse().ok())
+            .unwrap_or(Tz::UTC)
+    }
+}
+
+/// Why a request from the presentation could not be carried out.
+#[derive(Debug, Clone, Error, Serialize, Deserialize)]
+pub enum PresentationError {
+    #[error("Invalid {0} ID")]
+    InvalidId(String),
+    #[error("No active session")]
+    NotInSession,
+    #[error("Already in this session")]
+    AlreadyInSession,
+    #[error("Already joined this proposal")]
+    AlreadyJoined,
+    #[error("Not a member of this proposal")]
+    NotJoined,
+    #[error("This proposal is full")]
+    ProposalFull,
+    #[error("Only the creator of this proposal can do that")]
+    NotProposalCreator,
+    #[error("You can't kick yourself")]
+    CannotKickSelf,
+    #[error("You were recently kicked from this proposal")]
+    RecentlyKicked,
+    #[error("You don't have permission to do that")]
+    PermissionDenied,
+    #[error("Disconnected due to inactivity")]
+    IdleTimeout,
+    #[error("Announcements can be at most {0} characters")]
+    AnnouncementTooLong(usize),
+    #[error("Invalid rules: {0}")]
+    InvalidRules(String),
+    #[error("This game hasn't finished yet")]
+    GameNotComplete,
+    #[error("At least {0} players are needed to start")]
+    NotEnoughPlayers(i32),
+    #[error("Unknown setting `{0}`. Valid settings are: {keys}", keys = UserSettings::KEYS.join(", "))]
+    UnknownSetting(String),
+    #[error("Invalid value for `{key}`: {reason}")]
+    InvalidSetting { key: String, reason: String },
+    #[error("No template named `{0}`")]
+    UnknownTemplate(String),
+    #[error("Unknown game type `{0}`")]
+    UnknownGameType(String),
+    #[error("`{game_type}` supports {min_players} to {max_players} players")]
+    InvalidPlayerCount {
+        game_type: String,
+        min_players: i32,
+        max_players: i32,
+    },
 }
@@ -0,0 +1,278 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use tokio::sync::mpsc;
+
+use crate::{
+    bichannel::{bichannel, Bichannel},
+    ConnectionToPresentationMsg, PresentationKind, PresentationToConnectionMsg, UserId, UserKeyMin,
+    UserManagement, UserManagementError,
+};
+
+/// A connection opened through `MockUserManagement`, for the test to play the
+/// part of the server.
+#[derive(Debug)]
+pub struct MockConnection {
+    pub user_id: UserId,
+    pub kind: PresentationKind,
+    pub bichannel: Bichannel<ConnectionToPresentationMsg, PresentationToConnectionMsg>,
+}
+
+struct MockUser {
+    username: String,
+    password: String,
+    keys: Vec<UserKeyMin>,
+}
+
+/// Keeps users in memory, so that presentations can be tested without a
+/// database. Every connection opened is handed to the test.
+pub struct MockUserManagement {
+    users: Mutex<HashMap<UserId, MockUser>>,
+    connections: mpsc::UnboundedSender<MockConnection>,
+}
+
+impl MockUserManagement {
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<MockConnection>) {
+        let (connections, connections_r) = mpsc::unbounded_channel();
+        let this = Self {
+            users: Mutex::new(HashMap::new()),
+            connections,
+        };
+        (this, connections_r)
+    }
+    /// Users are numbered from one in the order they're added. Unlike
+    /// `create_user`, the password isn't validated.
+    pub fn add_user(&self, username: &str, password: &str) -> Result<UserId, UserManagementError> {
+        let mut users = self.users.lock().unwrap();
+        if users.values().any(|user| user.username == username) {
+            return Err(UserManagementError::UserAlreadyExists);
+        }
+        let user_id = UserId(users.len() as i64 + 1);
+        users.insert(
+            user_id,
+            MockUser {
+                username: username.into(),
+                password: password.into(),
+                keys: Vec::new(),
+            },
+        );
+        Ok(user_id)
+    }
+    fn find_user_id(&self, username: &str) -> Result<UserId, UserManagementError> {
+        self.users
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, user)| user.username == username)
+            .map(|(&user_id, _)| user_id)
+            .ok_or(UserManagementError::UserDoesNotExist)
+    }
+    fn with_user<T>(
+        &self,
+        user_id: UserId,
+        f: impl FnOnce(&mut MockUser) -> Result<T, UserManagementError>,
+    ) -> Result<T, UserManagementError> {
+        let mut users = self.users.lock().unwrap();
+        let user = users
+            .get_mut(&user_id)
+            .ok_or(UserManagementError::UserDoesNotExist)?;
+        f(user)
+    }
+}
+
+#[async_trait]
+impl UserManagement for MockUserManagement {
+    async fn login_user_with_password(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<UserId, UserManagementError> {
+        let user_id = self.find_user_id(username)?;
+        self.with_user(user_id, |user| {
+            if !user.password.is_empty() && user.password == password {
+                Ok(user_id)
+            } else {
+                Err(UserManagementError::InvalidAuth)
+            }
+        })
+    }
+    async fn login_user_with_public_key(
+        &self,
+        username: &str,
+        fingerprint: &str,
+    ) -> Result<UserId, UserManagementError> {
+        let user_id = self.find_user_id(username)?;
+        self.with_user(user_id, |user| {
+            if user.keys.iter().any(|key| key.fingerprint == fingerprint) {
+                Ok(user_id)
+            } else {
+                Err(UserManagementError::InvalidAuth)
+            }
+        })
+    }
+    /// Any password is accepted, except an empty one.
+    async fn validate_password(&self, password: &str) -> Result<(), UserManagementError> {
+        if password.is_empty() {
+            Err(UserManagementError::WeakPassword(
+                "Password must not be empty".into(),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+    async fn create_user(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<UserId, UserManagementError> {
+        self.validate_password(password).await?;
+        self.add_user(username, password)
+    }
+    async fn add_user_public_key(
+        &self,
+        user_id: UserId,
+        fingerprint: &str,
+    ) -> Result<(), UserManagementError> {
+        self.with_user(user_id, |user| {
+            if !user.keys.iter().any(|key| key.fingerprint == fingerprint) {
+                user.keys.push(UserKeyMin {
+                    fingerprint: fingerprint.into(),
+                    created_at: Utc::now(),
+                });
+            }
+            Ok(())
+        })
+    }
+    async fn list_user_public_keys(
+        &self,
+        user_id: UserId,
+    ) -> Result<Vec<UserKeyMin>, UserManagementError> {
+        self.with_user(user_id, |user| Ok(user.keys.clone()))
+    }
+    async fn remove_user_public_key(
+        &self,
+        user_id: UserId,
+        fingerprint: &str,
+    ) -> Result<(), UserManagementError> {
+        self.with_user(user_id, |user| {
+            let index = user
+                .keys
+                .iter()
+                .position(|key| key.fingerprint == fingerprint)
+                .ok_or(UserManagementError::KeyDoesNotExist)?;
+            if user.password.is_empty() && user.keys.len() == 1 {
+                return Err(UserManagementError::LastAuthMethod);
+            }
+            user.keys.remove(index);
+            Ok(())
+        })
+    }
+    async fn change_password(
+        &self,
+        user_id: UserId,
+        old_password: &str,
+        new_password: &str,
+    ) -> Result<(), UserManagementError> {
+        self.validate_password(new_password).await?;
+        self.with_user(user_id, |user| {
+            if user.password != old_password {
+                return Err(UserManagementError::InvalidAuth);
+            }
+            user.password = new_password.into();
+            Ok(())
+        })
+    }
+    async fn connect(
+        &self,
+        user_id: UserId,
+        kind: PresentationKind,
+    ) -> anyhow::Result<Bichannel<PresentationToConnectionMsg, ConnectionToPresentationMsg>> {
+        self.with_user(user_id, |_| Ok(()))?;
+        let (presentation_bichannel, connection_bichannel) = bichannel(4);
+        self.connections
+            .send(MockConnection {
+                user_id,
+                kind,
+                bichannel: presentation_bichannel,
+            })
+            .map_err(|_| anyhow::anyhow!("Test harness stopped accepting connections"))?;
+        Ok(connection_bichannel)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        terminal::{PresentationToTerminalMsg, TerminalPresentation, TerminalToPresentationMsg},
+        GameCapabilities, GameId, SessionId, SessionInfo, SessionKind,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn logs_in_and_enters_a_session() {
+        let (user_management, mut connections) = MockUserManagement::new();
+        user_management.add_user("alice", "hunter2").unwrap();
+        assert!(matches!(
+            user_management
+                .login_user_with_password("alice", "hunter3")
+                .await,
+            Err(UserManagementError::InvalidAuth)
+        ));
+        let user_id = user_management
+            .login_user_with_password("alice", "hunter2")
+            .await
+            .unwrap();
+        let mut terminal =
+            TerminalPresentation::connect(Arc::new(user_management), user_id, "alice".into(), None)
+                .await
+                .unwrap();
+        let mut connection = connections.recv().await.unwrap();
+        assert_eq!(connection.user_id, user_id);
+
+        terminal
+            .s
+            .send(TerminalToPresentationMsg::ReadLine("enter s1".into()))
+            .await
+            .unwrap();
+        assert!(matches!(
+            connection.bichannel.r.recv().await,
+            Some(PresentationToConnectionMsg::Enter(SessionId(1)))
+        ));
+        connection
+            .bichannel
+            .s
+            .send(ConnectionToPresentationMsg::EnteredSession(SessionInfo {
+                id: Some(SessionId(1)),
+                kind: SessionKind::Game(GameId(1)),
+                capabilities: Some(GameCapabilities {
+                    console_ui: true,
+                    ..Default::default()
+                }),
+            }))
+            .await
+            .unwrap();
+        assert!(matches!(
+            terminal.r.recv().await,
+            Some(PresentationToTerminalMsg::SessionChanged(Some(
+                SessionKind::Game(GameId(1))
+            )))
+        ));
+    }
+    #[tokio::test]
+    async fn usernames_are_unique() {
+        let (user_management, _connections) = MockUserManagement::new();
+        user_management.add_user("alice", "hunter2").unwrap();
+        assert!(matches!(
+            user_management.add_user("alice", "hunter3"),
+            Err(UserManagementError::UserAlreadyExists)
+        ));
+        assert!(matches!(
+            user_management.create_user("alice", "hunter3").await,
+            Err(UserManagementError::UserAlreadyExists)
+        ));
+    }
+}
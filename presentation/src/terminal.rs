@@ -1,35 +1,79 @@
+use std::sync::Arc;
+
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use thiserror::Error;
-use tracing::error;
+use tokio_util::sync::CancellationToken;
 
 use crate::{
     actor::Actor,
     bichannel::{bichannel, Bichannel},
-    select_recv_loop, ConnectionToPresentationMsg, CreateGameProposal, GameProposalMin,
-    InvalidIdError, MessageMin, PresentationKind, PresentationToConnectionMsg, SessionCommand,
-    SessionEvent, SessionInfo, SessionKind, SessionMin, TerminalSessionCommand,
-    TerminalSessionEvent, UserId, UserManagement,
+    select_recv_loop, ConnectionToPresentationMsg, CreateGameProposal, GameId, GameMin,
+    GameProposalId, GameProposalMin, GameTypeInfo, InvalidIdError, LauncherInfo, ListKind,
+    MessageMin, OnlineUserMin, OpponentMin, PlayerResultMin, PresentationKind,
+    PresentationToConnectionMsg, ProposalTemplateMin, RatingMin, RequestMin, SessionCommand,
+    SessionEvent, SessionInfo, SessionKind, SessionMin, TerminalInputMode, TerminalSessionCommand,
+    TerminalSessionEvent, UserId, UserManagement, UserManagementError, UserSettings,
 };
 
-use self::ui::{CommandInterpretation, Ui};
+use self::{
+    table::Table,
+    ui::{CommandInterpretation, Ui, UiContext},
+    wrap::wrap_text,
+};
 
+mod table;
 mod ui;
+mod wrap;
+
+/// Width to wrap output to when the terminal hasn't told us its size.
+const DEFAULT_COLUMNS: usize = 80;
 
 #[derive(Debug, Clone)]
 pub enum PresentationToTerminalMsg {
     PrintLine(String),
     ErrorLine(String),
+    /// Prompt for a line of input without echoing it back. The terminal
+    /// responds with a `ReadLine`.
+    ReadSecret(String),
+    /// The user entered or left a session, which changes what their input
+    /// means, and so how it should be completed.
+    SessionChanged(Option<SessionKind>),
+    /// Disconnect, once everything sent before has been shown.
+    Close,
 }
 
 #[derive(Debug, Clone)]
 pub enum TerminalToPresentationMsg {
     ReadLine(String),
+    Resize { columns: u32 },
+}
+
+enum PasswordChange {
+    Old,
+    New { old: String },
+    Confirm { old: String, new: String },
 }
 
 pub struct TerminalPresentation {
+    user_management: Arc<dyn UserManagement>,
+    user_id: UserId,
+    username: String,
+    /// Fingerprint of a public key the client offered but which is not
+    /// registered to this user.
+    offered_key_fingerprint: Option<String>,
     terminal_channel: Bichannel<PresentationToTerminalMsg, TerminalToPresentationMsg>,
     connection_channel: Bichannel<PresentationToConnectionMsg, ConnectionToPresentationMsg>,
     active_session: Option<SessionInfo>,
+    password_change: Option<PasswordChange>,
+    /// Commands the session has offered, which may be picked by number.
+    session_choices: Vec<String>,
+    settings: UserSettings,
+    /// A setting we've asked to change, to be shown once the change is saved.
+    pending_setting: Option<String>,
+    /// The list being received in chunks, and how many items it has.
+    list: Option<(ListKind, usize)>,
+    columns: usize,
 }
 
 #[derive(Debug, Error)]
@@ -48,10 +92,29 @@ impl From<InvalidIdError> for TerminalError {
     }
 }
 
+impl From<UserManagementError> for TerminalError {
+    fn from(value: UserManagementError) -> Self {
+        match value {
+            UserManagementError::Other(e) => Self::Internal(e),
+            other => Self::Print(other.to_string()),
+        }
+    }
+}
+
 #[async_trait]
 impl Actor for TerminalPresentation {
-    async fn run(mut self) -> anyhow::Result<()> {
+    fn span(&self) -> tracing::Span {
+        tracing::info_span!("terminal", user_id = %self.user_id)
+    }
+    async fn run(mut self, shutdown: CancellationToken) -> anyhow::Result<()> {
+        if self.offered_key_fingerprint.is_some() {
+            let res = self
+                .println("Your public key is not registered. Use `keys add` to register it.".into())
+                .await;
+            self.handle_error(res).await?;
+        }
         select_recv_loop! {
+            shutdown = shutdown;
             msg = self.connection_channel.r.recv() => {
                 let res = self.handle_connection_msg(msg).await;
                 self.handle_error(res).await?;
@@ -81,17 +144,29 @@ impl TerminalPresentation {
         }
     }
     pub async fn connect(
-        user_management: &dyn UserManagement,
+        user_management: Arc<dyn UserManagement>,
         user_id: UserId,
+        username: String,
+        offered_key_fingerprint: Option<String>,
     ) -> anyhow::Result<Bichannel<TerminalToPresentationMsg, PresentationToTerminalMsg>> {
         let connection_channel = user_management
             .connect(user_id, PresentationKind::Terminal)
             .await?;
         let (terminal_channel, presentation_channel) = bichannel(1);
         Self {
+            user_management,
+            user_id,
+            username,
+            offered_key_fingerprint,
             terminal_channel,
             connection_channel,
             active_session: None,
+            password_change: None,
+            session_choices: Vec::new(),
+            settings: UserSettings::default(),
+            pending_setting: None,
+            list: None,
+            columns: DEFAULT_COLUMNS,
         }
         .spawn();
         Ok(presentation_channel)
@@ -120,6 +195,9 @@ impl TerminalPresentation {
         self.send_to_terminal(PresentationToTerminalMsg::PrintLine(line))
             .await
     }
+    async fn println_wrapped(&mut self, line: String) -> Result<(), TerminalError> {
+        self.println(wrap_text(&line, self.columns)).await
+    }
     fn unpack_args<const N: usize>(args: Vec<String>) -> Result<[String; N], TerminalError> {
         args.try_into().map_err(|args: Vec<_>| {
             TerminalError::Print(format!(
@@ -129,12 +207,140 @@ impl TerminalPresentation {
             ))
         })
     }
-    async fn propose(&mut self, [game_type]: [String; 1]) -> Result<(), TerminalError> {
+    async fn propose(&mut self, mut args: Vec<String>) -> Result<(), TerminalError> {
+        let len = args.len();
+        args.retain(|arg| arg != "--public");
+        let is_public = args.len() != len;
+        let mut invite = Vec::new();
+        while let Some(idx) = args.iter().position(|arg| arg == "--invite") {
+            args.remove(idx);
+            if idx == args.len() {
+                return Err(TerminalError::Print(
+                    "Expected a user ID after --invite".into(),
+                ));
+            }
+            invite.push(args.remove(idx).parse()?);
+        }
+        if let Some(idx) = args.iter().position(|arg| arg == "--template") {
+            args.remove(idx);
+            let [name] = Self::unpack_args(args)?;
+            return self
+                .send_to_connection(PresentationToConnectionMsg::ProposeFromTemplate {
+                    name,
+                    is_public,
+                    invite,
+                })
+                .await;
+        }
+        let (game_type, num_players) = match <[String; 2]>::try_from(args) {
+            Ok([game_type, num_players]) => {
+                let num_players = num_players.parse().map_err(|_| {
+                    TerminalError::Print(format!("Invalid number of players: {num_players}"))
+                })?;
+                (game_type, Some(num_players))
+            }
+            Err(args) => {
+                let [game_type] = Self::unpack_args(args)?;
+                (game_type, None)
+            }
+        };
         self.send_to_connection(PresentationToConnectionMsg::Propose(CreateGameProposal {
             game_type,
+            num_players,
+            is_public,
+            invite,
         }))
         .await
     }
+    async fn withdraw(&mut self, [proposal_id]: [String; 1]) -> Result<(), TerminalError> {
+        self.send_to_connection(PresentationToConnectionMsg::Withdraw(proposal_id.parse()?))
+            .await
+    }
+    async fn join(&mut self, [proposal_id]: [String; 1]) -> Result<(), TerminalError> {
+        self.send_to_connection(PresentationToConnectionMsg::Join(proposal_id.parse()?))
+            .await
+    }
+    async fn leave(&mut self, [proposal_id]: [String; 1]) -> Result<(), TerminalError> {
+        self.send_to_connection(PresentationToConnectionMsg::Leave(proposal_id.parse()?))
+            .await
+    }
+    /// The proposal whose lobby we're in, for commands which act on it.
+    fn current_proposal(&self) -> Result<GameProposalId, TerminalError> {
+        match self.active_session.as_ref().map(|session| &session.kind) {
+            Some(&SessionKind::GameProposal(proposal_id)) => Ok(proposal_id),
+            _ => Err(TerminalError::Print("Not in a proposal.".into())),
+        }
+    }
+    async fn rules_show(&mut self, []: [String; 0]) -> Result<(), TerminalError> {
+        let proposal_id = self.current_proposal()?;
+        self.send_to_connection(PresentationToConnectionMsg::GetProposalRules(proposal_id))
+            .await
+    }
+    async fn rules_edit(&mut self, args: Vec<String>) -> Result<(), TerminalError> {
+        let proposal_id = self.current_proposal()?;
+        // The value may be JSON containing spaces
+        let [key, value] = match args.split_first() {
+            Some((key, value)) if !value.is_empty() => [key.clone(), value.join(" ")],
+            _ => Self::unpack_args(args)?,
+        };
+        self.send_to_connection(PresentationToConnectionMsg::EditProposalRule {
+            proposal_id,
+            key,
+            value,
+        })
+        .await
+    }
+    async fn rules_set(&mut self, args: Vec<String>) -> Result<(), TerminalError> {
+        let proposal_id = self.current_proposal()?;
+        if args.is_empty() {
+            return Err(TerminalError::Print("Expected rules as JSON!\n".into()));
+        }
+        self.send_to_connection(PresentationToConnectionMsg::SetProposalRules {
+            proposal_id,
+            rules: args.join(" "),
+        })
+        .await
+    }
+    async fn start(&mut self, []: [String; 0]) -> Result<(), TerminalError> {
+        let proposal_id = self.current_proposal()?;
+        self.send_to_connection(PresentationToConnectionMsg::StartProposal(proposal_id))
+            .await
+    }
+    async fn kick(&mut self, [user_id]: [String; 1]) -> Result<(), TerminalError> {
+        let proposal_id = self.current_proposal()?;
+        self.send_to_connection(PresentationToConnectionMsg::KickFromProposal {
+            proposal_id,
+            user_id: user_id.parse()?,
+        })
+        .await
+    }
+    async fn invite(&mut self, [proposal_id, user_id]: [String; 2]) -> Result<(), TerminalError> {
+        self.send_to_connection(PresentationToConnectionMsg::InviteToProposal {
+            proposal_id: proposal_id.parse()?,
+            user_id: user_id.parse()?,
+        })
+        .await
+    }
+    async fn template_list(&mut self, []: [String; 0]) -> Result<(), TerminalError> {
+        self.send_to_connection(PresentationToConnectionMsg::ListProposalTemplates)
+            .await
+    }
+    async fn template_save(&mut self, [name]: [String; 1]) -> Result<(), TerminalError> {
+        let proposal_id = self.current_proposal()?;
+        self.send_to_connection(PresentationToConnectionMsg::SaveProposalTemplate {
+            proposal_id,
+            name,
+        })
+        .await
+    }
+    async fn template_delete(&mut self, [name]: [String; 1]) -> Result<(), TerminalError> {
+        self.send_to_connection(PresentationToConnectionMsg::DeleteProposalTemplate(name))
+            .await
+    }
+    async fn games(&mut self, []: [String; 0]) -> Result<(), TerminalError> {
+        self.send_to_connection(PresentationToConnectionMsg::ListGames)
+            .await
+    }
     async fn sessions(&mut self, []: [String; 0]) -> Result<(), TerminalError> {
         self.send_to_connection(PresentationToConnectionMsg::ListSessions)
             .await
@@ -143,10 +349,54 @@ impl TerminalPresentation {
         self.send_to_connection(PresentationToConnectionMsg::Enter(session_id.parse()?))
             .await
     }
+    async fn watch(&mut self, [game_id]: [String; 1]) -> Result<(), TerminalError> {
+        self.send_to_connection(PresentationToConnectionMsg::Watch(game_id.parse()?))
+            .await
+    }
+    async fn result(&mut self, [game_id]: [String; 1]) -> Result<(), TerminalError> {
+        self.send_to_connection(PresentationToConnectionMsg::GetGameResult(game_id.parse()?))
+            .await
+    }
+    async fn recent(&mut self, []: [String; 0]) -> Result<(), TerminalError> {
+        self.send_to_connection(PresentationToConnectionMsg::ListRecentOpponents)
+            .await
+    }
+    async fn rating(&mut self, args: Vec<String>) -> Result<(), TerminalError> {
+        let game_type = match <[String; 1]>::try_from(args) {
+            Ok([game_type]) => Some(game_type),
+            Err(args) => {
+                let [] = Self::unpack_args(args)?;
+                None
+            }
+        };
+        self.send_to_connection(PresentationToConnectionMsg::GetRatings(game_type))
+            .await
+    }
+    async fn observe(&mut self, [game_id]: [String; 1]) -> Result<(), TerminalError> {
+        self.send_to_connection(PresentationToConnectionMsg::Observe(game_id.parse()?))
+            .await
+    }
+    async fn announce(&mut self, words: Vec<String>) -> Result<(), TerminalError> {
+        if words.is_empty() {
+            return Err(TerminalError::Print("Expected a message!\n".into()));
+        }
+        self.send_to_connection(PresentationToConnectionMsg::Announce(words.join(" ")))
+            .await
+    }
     async fn exit(&mut self, []: [String; 0]) -> Result<(), TerminalError> {
         self.send_to_connection(PresentationToConnectionMsg::Exit)
             .await
     }
+    async fn quit(&mut self, []: [String; 0]) -> Result<(), TerminalError> {
+        if self.active_session.is_some() {
+            self.send_to_connection(PresentationToConnectionMsg::Exit)
+                .await?;
+        }
+        self.println("Goodbye!".into()).await?;
+        // The terminal hangs up, which in turn closes the connection
+        self.send_to_terminal(PresentationToTerminalMsg::Close)
+            .await
+    }
     async fn proposals(&mut self, []: [String; 0]) -> Result<(), TerminalError> {
         self.send_to_connection(PresentationToConnectionMsg::ListProposals)
             .await
@@ -155,15 +405,193 @@ impl TerminalPresentation {
         self.send_to_connection(PresentationToConnectionMsg::ListMessages)
             .await
     }
-    async fn handle_message_list(
+    async fn messages_search(&mut self, terms: Vec<String>) -> Result<(), TerminalError> {
+        if terms.is_empty() {
+            return Err(TerminalError::Print("Expected search terms!\n".into()));
+        }
+        self.send_to_connection(PresentationToConnectionMsg::SearchMessages(terms.join(" ")))
+            .await
+    }
+    async fn accept(&mut self, [request_id]: [String; 1]) -> Result<(), TerminalError> {
+        self.send_to_connection(PresentationToConnectionMsg::AcceptRequest(
+            request_id.parse()?,
+        ))
+        .await
+    }
+    async fn decline(&mut self, [request_id]: [String; 1]) -> Result<(), TerminalError> {
+        self.send_to_connection(PresentationToConnectionMsg::RejectRequest(
+            request_id.parse()?,
+        ))
+        .await
+    }
+    async fn cancel(&mut self, [request_id]: [String; 1]) -> Result<(), TerminalError> {
+        self.send_to_connection(PresentationToConnectionMsg::CancelRequest(
+            request_id.parse()?,
+        ))
+        .await
+    }
+    async fn requests(&mut self, []: [String; 0]) -> Result<(), TerminalError> {
+        self.send_to_connection(PresentationToConnectionMsg::ListRequests)
+            .await
+    }
+    async fn whoami(&mut self, []: [String; 0]) -> Result<(), TerminalError> {
+        self.println(format!("{} {}", self.user_id, self.username))
+            .await
+    }
+    async fn scrollback(&mut self, args: Vec<String>) -> Result<(), TerminalError> {
+        if self.active_session.is_none() {
+            return self.println("Not in a session.".into()).await;
+        }
+        let count =
+            match <[String; 1]>::try_from(args) {
+                Ok([count]) => Some(count.parse().map_err(|_| {
+                    TerminalError::Print(format!("Invalid number of events: {count}"))
+                })?),
+                Err(args) => {
+                    let [] = Self::unpack_args(args)?;
+                    None
+                }
+            };
+        self.send_to_connection(PresentationToConnectionMsg::SessionCommand(
+            SessionCommand::Terminal(TerminalSessionCommand::Scrollback(count)),
+        ))
+        .await
+    }
+    async fn session(&mut self, []: [String; 0]) -> Result<(), TerminalError> {
+        let Some(session) = &self.active_session else {
+            return self.println("Not in a session.".into()).await;
+        };
+        let line = match session.id {
+            Some(id) => format!("{id} {}", session.kind),
+            None => session.kind.to_string(),
+        };
+        self.println(line).await
+    }
+    async fn keys_list(&mut self, []: [String; 0]) -> Result<(), TerminalError> {
+        let keys = self
+            .user_management
+            .list_user_public_keys(self.user_id)
+            .await?;
+        if keys.is_empty() {
+            return self.println("No public keys registered.".into()).await;
+        }
+        for key in keys {
+            self.println(format!("SHA256:{} {}", key.fingerprint, key.created_at))
+                .await?;
+        }
+        Ok(())
+    }
+    async fn keys_add(&mut self, args: Vec<String>) -> Result<(), TerminalError> {
+        let fingerprint = match <[String; 1]>::try_from(args) {
+            Ok([fingerprint]) => fingerprint,
+            Err(args) if args.is_empty() => {
+                self.offered_key_fingerprint.take().ok_or_else(|| {
+                    TerminalError::Print(
+                        "No unregistered key was offered. Specify a fingerprint to add.".into(),
+                    )
+                })?
+            }
+            Err(args) => {
+                return Err(TerminalError::Print(format!(
+                    "Expected at most 1 argument, received {}!\n",
+                    args.len()
+                )))
+            }
+        };
+        let fingerprint = normalize_fingerprint(&fingerprint);
+        self.user_management
+            .add_user_public_key(self.user_id, fingerprint)
+            .await?;
+        self.println(format!("Added key SHA256:{fingerprint}"))
+            .await
+    }
+    async fn keys_remove(&mut self, [fingerprint]: [String; 1]) -> Result<(), TerminalError> {
+        let fingerprint = normalize_fingerprint(&fingerprint);
+        self.user_management
+            .remove_user_public_key(self.user_id, fingerprint)
+            .await?;
+        self.println(format!("Removed key SHA256:{fingerprint}"))
+            .await
+    }
+    async fn get(&mut self, [key]: [String; 1]) -> Result<(), TerminalError> {
+        let value = self
+            .settings
+            .get(&key)
+            .map_err(|e| TerminalError::Print(e.to_string()))?;
+        self.println(format!("{key} = {value}")).await
+    }
+    async fn set(&mut self, [key, value]: [String; 2]) -> Result<(), TerminalError> {
+        self.pending_setting = Some(key.clone());
+        self.send_to_connection(PresentationToConnectionMsg::SetSetting { key, value })
+            .await
+    }
+    async fn launchers(&mut self, []: [String; 0]) -> Result<(), TerminalError> {
+        self.send_to_connection(PresentationToConnectionMsg::ListLaunchers)
+            .await
+    }
+    async fn catalog(&mut self, []: [String; 0]) -> Result<(), TerminalError> {
+        self.send_to_connection(PresentationToConnectionMsg::ListGameTypes)
+            .await
+    }
+    async fn who(&mut self, []: [String; 0]) -> Result<(), TerminalError> {
+        self.send_to_connection(PresentationToConnectionMsg::ListOnlineUsers)
+            .await
+    }
+    async fn passwd(&mut self, []: [String; 0]) -> Result<(), TerminalError> {
+        self.password_change = Some(PasswordChange::Old);
+        self.send_to_terminal(PresentationToTerminalMsg::ReadSecret(
+            "Current password: ".into(),
+        ))
+        .await
+    }
+    async fn continue_password_change(
         &mut self,
-        messages: Vec<MessageMin>,
+        state: PasswordChange,
+        line: String,
     ) -> Result<(), TerminalError> {
+        let (next_state, prompt) = match state {
+            PasswordChange::Old => (PasswordChange::New { old: line }, "New password: "),
+            PasswordChange::New { old } => {
+                self.user_management.validate_password(&line).await?;
+                (
+                    PasswordChange::Confirm { old, new: line },
+                    "Re-enter new password: ",
+                )
+            }
+            PasswordChange::Confirm { old, new } => {
+                if new != line {
+                    return Err(TerminalError::Print("Passwords did not match.".into()));
+                }
+                self.user_management
+                    .change_password(self.user_id, &old, &new)
+                    .await?;
+                return self.println("Password changed.".into()).await;
+            }
+        };
+        self.password_change = Some(next_state);
+        self.send_to_terminal(PresentationToTerminalMsg::ReadSecret(prompt.into()))
+            .await
+    }
+    /// Times are shown in the user's time zone.
+    fn format_time(&self, time: DateTime<Utc>) -> String {
+        time.with_timezone(&self.settings.timezone())
+            .format("%Y-%m-%d %H:%M")
+            .to_string()
+    }
+    /// Columns are wide enough for most messages, so that the chunks of a
+    /// long list line up.
+    fn message_table(&self, messages: Vec<MessageMin>) -> Table {
+        let mut table = Table::new(["ID", "Sent", "From", "Request", "Subject"])
+            .align_right(0)
+            .align_right(3)
+            .min_width(0, 6)
+            .min_width(1, 16)
+            .min_width(2, 12)
+            .min_width(3, 6);
         for message in messages {
-            self.println(format!(
-                "{:>6} {:12?} {:12?} {:>6} {}\n",
-                message.id,
-                message.sent_at,
+            table.row([
+                message.id.to_string(),
+                self.format_time(message.sent_at),
                 message
                     .from
                     .map(|u| u.username)
@@ -173,75 +601,480 @@ impl TerminalPresentation {
                     .as_ref()
                     .map(ToString::to_string)
                     .unwrap_or_default(),
-                message.subject
-            ))
-            .await?;
+                message.subject,
+            ]);
+        }
+        table
+    }
+    async fn handle_message_list(
+        &mut self,
+        messages: Vec<MessageMin>,
+    ) -> Result<(), TerminalError> {
+        if messages.is_empty() {
+            return self.println("No messages.".into()).await;
+        }
+        self.println(self.message_table(messages).render()).await
+    }
+    /// Print the headers now, and each chunk as it arrives.
+    async fn handle_list_begin(
+        &mut self,
+        kind: ListKind,
+        total: usize,
+    ) -> Result<(), TerminalError> {
+        self.list = Some((kind, total));
+        match kind {
+            ListKind::Messages if total == 0 => self.println("No messages.".into()).await,
+            ListKind::Messages => self.println(self.message_table(Vec::new()).render()).await,
+        }
+    }
+    async fn handle_message_chunk(
+        &mut self,
+        messages: Vec<MessageMin>,
+    ) -> Result<(), TerminalError> {
+        self.println(self.message_table(messages).render_rows())
+            .await
+    }
+    async fn handle_list_end(&mut self) -> Result<(), TerminalError> {
+        match self.list.take() {
+            Some((ListKind::Messages, total)) if total > 0 => {
+                self.println(format!("{total} unread messages.")).await
+            }
+            _ => Ok(()),
+        }
+    }
+    async fn handle_game_list(&mut self, games: Vec<GameMin>) -> Result<(), TerminalError> {
+        if games.is_empty() {
+            return self.println("No games in progress.".into()).await;
+        }
+        let mut table = Table::new(["ID", "Game", "Turn", "Deadline"]).align_right(0);
+        for game in games {
+            table.row([
+                game.id.to_string(),
+                game.display_name,
+                if game.your_turn { "yours" } else { "" }.into(),
+                game.deadline
+                    .map(|deadline| self.format_time(deadline))
+                    .unwrap_or_default(),
+            ]);
+        }
+        self.println(table.render()).await
+    }
+    async fn handle_active_games(&mut self, games: Vec<GameMin>) -> Result<(), TerminalError> {
+        let waiting = games.iter().filter(|game| game.your_turn).count();
+        if waiting == 0 {
+            return Ok(());
+        }
+        let plural = if waiting == 1 { "" } else { "s" };
+        self.println(format!(
+            "You have {waiting} game{plural} waiting on your move. Use `games` to list them."
+        ))
+        .await
+    }
+    async fn handle_launcher_list(
+        &mut self,
+        launchers: Vec<LauncherInfo>,
+    ) -> Result<(), TerminalError> {
+        for info in launchers {
+            let mut text = format!("{}\n  Games: ", info.launcher);
+            if info.game_types.is_empty() {
+                text.push_str("none");
+            } else {
+                text.push_str(&info.game_types.join(", "));
+            }
+            if let Some(error) = info.error {
+                text.push_str(&format!("\n  Unhealthy: {error}"));
+            }
+            self.println_wrapped(text).await?;
+        }
+        Ok(())
+    }
+    async fn handle_game_type_list(
+        &mut self,
+        game_types: Vec<GameTypeInfo>,
+    ) -> Result<(), TerminalError> {
+        if game_types.is_empty() {
+            return self.println("No games are available.".into()).await;
+        }
+        for info in game_types {
+            let players = if info.min_players == info.max_players {
+                format!("{} players", info.min_players)
+            } else {
+                format!("{}-{} players", info.min_players, info.max_players)
+            };
+            let mut text = format!("{} (`{}`), {players}", info.display_name, info.game_type);
+            if let Some(description) = info.description {
+                text.push_str(&format!("\n  {description}"));
+            }
+            self.println_wrapped(text).await?;
         }
         Ok(())
     }
+    async fn handle_game_result(
+        &mut self,
+        game_id: GameId,
+        players: Vec<PlayerResultMin>,
+    ) -> Result<(), TerminalError> {
+        let name = |player: &PlayerResultMin| {
+            player
+                .username
+                .clone()
+                .unwrap_or_else(|| format!("Player {}", player.player_index))
+        };
+        let mut table = Table::new(["Place", "Player", "Username", "Score"])
+            .align_right(0)
+            .align_right(1)
+            .align_right(3);
+        for player in &players {
+            table.row([
+                player
+                    .position
+                    .map_or("-".into(), |position| position.to_string()),
+                player.player_index.to_string(),
+                name(player),
+                player.score.map_or("-".into(), |score| score.to_string()),
+            ]);
+        }
+        self.println(format!("Final scores for {game_id}:")).await?;
+        self.println(table.render()).await?;
+        let winners: Vec<_> = players
+            .iter()
+            .filter(|player| player.position == Some(1))
+            .map(name)
+            .collect();
+        match winners.as_slice() {
+            [] => Ok(()),
+            [winner] => self.println(format!("{winner} won.")).await,
+            _ => {
+                self.println(format!("Tied for first: {}.", winners.join(", ")))
+                    .await
+            }
+        }
+    }
+    async fn handle_recent_opponents(
+        &mut self,
+        opponents: Vec<OpponentMin>,
+    ) -> Result<(), TerminalError> {
+        if opponents.is_empty() {
+            return self
+                .println("You haven't played with anyone yet.".into())
+                .await;
+        }
+        let mut table = Table::new(["ID", "Username", "Game", "Type", "Played"])
+            .align_right(0)
+            .align_right(2);
+        for opponent in opponents {
+            table.row([
+                opponent.user_id.to_string(),
+                opponent.username,
+                opponent.game_id.to_string(),
+                opponent.game_type,
+                self.format_time(opponent.played_at),
+            ]);
+        }
+        self.println(table.render()).await?;
+        self.println("Use `propose --invite <user-id> <game-type>` to play again.".into())
+            .await
+    }
+    async fn handle_ratings(
+        &mut self,
+        game_type: Option<String>,
+        ratings: Vec<RatingMin>,
+    ) -> Result<(), TerminalError> {
+        if ratings.is_empty() {
+            return self.println("No rated games yet.".into()).await;
+        }
+        let table = if let Some(game_type) = game_type {
+            self.println(format!("Top players of {game_type}:")).await?;
+            let mut table = Table::new(["Rank", "Username", "Rating", "Games"])
+                .align_right(0)
+                .align_right(2)
+                .align_right(3);
+            for (rank, rating) in ratings.into_iter().enumerate() {
+                table.row([
+                    (rank + 1).to_string(),
+                    rating.username,
+                    rating.rating.to_string(),
+                    rating.games_played.to_string(),
+                ]);
+            }
+            table
+        } else {
+            let mut table = Table::new(["Game", "Rating", "Games"])
+                .align_right(1)
+                .align_right(2);
+            for rating in ratings {
+                table.row([
+                    rating.game_type,
+                    rating.rating.to_string(),
+                    rating.games_played.to_string(),
+                ]);
+            }
+            table
+        };
+        self.println(table.render()).await
+    }
+    async fn handle_online_users(
+        &mut self,
+        users: Vec<OnlineUserMin>,
+    ) -> Result<(), TerminalError> {
+        let mut table = Table::new(["ID", "Username", "Active"]).align_right(0);
+        for user in users {
+            table.row([
+                user.id.to_string(),
+                user.username,
+                format_ago(user.last_seen_at),
+            ]);
+        }
+        self.println(table.render()).await
+    }
     async fn handle_proposal_list(
         &mut self,
         proposals: Vec<GameProposalMin>,
     ) -> Result<(), TerminalError> {
+        if proposals.is_empty() {
+            return self.println("No proposals.".into()).await;
+        }
+        let mut table = Table::new(["ID", "Game", "Players", "Created"])
+            .align_right(0)
+            .align_right(2);
         for proposal in proposals {
-            self.println(format!(
-                "{:>6} {:12?} {}\n",
-                proposal.id, proposal.game_type, proposal.created_at
-            ))
-            .await?;
+            table.row([
+                proposal.id.to_string(),
+                proposal.display_name,
+                format!("{}/{}", proposal.joined, proposal.max_players),
+                self.format_time(proposal.created_at),
+            ]);
         }
-        Ok(())
+        self.println(table.render()).await
+    }
+    async fn handle_proposal_template_list(
+        &mut self,
+        templates: Vec<ProposalTemplateMin>,
+    ) -> Result<(), TerminalError> {
+        if templates.is_empty() {
+            return self.println("No templates.".into()).await;
+        }
+        let mut table = Table::new(["Name", "Game", "Players", "Saved"]).align_right(2);
+        for template in templates {
+            let players = if template.min_players == template.max_players {
+                template.min_players.to_string()
+            } else {
+                format!("{}-{}", template.min_players, template.max_players)
+            };
+            table.row([
+                template.name,
+                template.game_type,
+                players,
+                self.format_time(template.created_at),
+            ]);
+        }
+        self.println(table.render()).await
+    }
+    async fn handle_request_list(
+        &mut self,
+        requests: Vec<RequestMin>,
+    ) -> Result<(), TerminalError> {
+        if requests.is_empty() {
+            return self.println("No pending requests.".into()).await;
+        }
+        let mut table = Table::new(["ID", "Request", "From/To", "Sent"]).align_right(0);
+        for request in requests {
+            let direction = if request.outgoing { "to" } else { "from" };
+            table.row([
+                request.id.to_string(),
+                request.description,
+                format!("{direction} {}", request.other.as_deref().unwrap_or("?")),
+                self.format_time(request.sent_at),
+            ]);
+        }
+        self.println(table.render()).await
     }
     async fn handle_session_list(
         &mut self,
         sessions: Vec<SessionMin>,
     ) -> Result<(), TerminalError> {
+        if sessions.is_empty() {
+            return self.println("No sessions.".into()).await;
+        }
+        let mut table = Table::new(["ID", "Session", "Created"]).align_right(0);
         for session in sessions {
-            self.println(format!(
-                "{:>6} {:12} {}\n",
-                session.id,
-                match session.kind {
-                    SessionKind::GameProposal(_) => "proposal",
-                    SessionKind::Game(_) => "game",
-                },
-                session.created_at
-            ))
-            .await?;
+            table.row([
+                session.id.to_string(),
+                session.kind.to_string(),
+                self.format_time(session.created_at),
+            ]);
         }
-        Ok(())
+        self.println(table.render()).await
     }
     async fn handle_command_line(&mut self, line: &str) -> Result<(), TerminalError> {
-        match Ui::instance().interpret_command(line)? {
+        let context = self
+            .active_session
+            .as_ref()
+            .map(|session| UiContext::from(&session.kind));
+        match Ui::instance().interpret_command(line, context)? {
             CommandInterpretation::Action { command, args } => match command.as_str() {
                 "propose" => {
-                    self.propose(Self::unpack_args(args)?).await?;
+                    self.propose(args).await?;
+                }
+                "games" => {
+                    self.games(Self::unpack_args(args)?).await?;
                 }
                 "proposals" => {
                     self.proposals(Self::unpack_args(args)?).await?;
                 }
-                "messages" => {
+                "catalog" => {
+                    self.catalog(Self::unpack_args(args)?).await?;
+                }
+                "withdraw" => {
+                    self.withdraw(Self::unpack_args(args)?).await?;
+                }
+                "join" => {
+                    self.join(Self::unpack_args(args)?).await?;
+                }
+                "leave" => {
+                    self.leave(Self::unpack_args(args)?).await?;
+                }
+                "rules" | "rules show" => {
+                    self.rules_show(Self::unpack_args(args)?).await?;
+                }
+                "rules edit" => {
+                    self.rules_edit(args).await?;
+                }
+                "rules set" => {
+                    self.rules_set(args).await?;
+                }
+                "start" => {
+                    self.start(Self::unpack_args(args)?).await?;
+                }
+                "kick" => {
+                    self.kick(Self::unpack_args(args)?).await?;
+                }
+                "invite" => {
+                    self.invite(Self::unpack_args(args)?).await?;
+                }
+                "template" | "template list" => {
+                    self.template_list(Self::unpack_args(args)?).await?;
+                }
+                "template save" => {
+                    self.template_save(Self::unpack_args(args)?).await?;
+                }
+                "template delete" => {
+                    self.template_delete(Self::unpack_args(args)?).await?;
+                }
+                "messages" | "messages list" => {
                     self.messages(Self::unpack_args(args)?).await?;
                 }
+                "messages search" => {
+                    self.messages_search(args).await?;
+                }
                 "sessions" => {
                     self.sessions(Self::unpack_args(args)?).await?;
                 }
                 "enter" => {
                     self.enter(Self::unpack_args(args)?).await?;
                 }
+                "watch" => {
+                    self.watch(Self::unpack_args(args)?).await?;
+                }
+                "result" => {
+                    self.result(Self::unpack_args(args)?).await?;
+                }
+                "recent" => {
+                    self.recent(Self::unpack_args(args)?).await?;
+                }
+                "rating" => {
+                    self.rating(args).await?;
+                }
                 "exit" => {
                     self.exit(Self::unpack_args(args)?).await?;
                 }
+                "quit" => {
+                    self.quit(Self::unpack_args(args)?).await?;
+                }
+                "accept" => {
+                    self.accept(Self::unpack_args(args)?).await?;
+                }
+                "decline" => {
+                    self.decline(Self::unpack_args(args)?).await?;
+                }
+                "cancel" => {
+                    self.cancel(Self::unpack_args(args)?).await?;
+                }
+                "requests" => {
+                    self.requests(Self::unpack_args(args)?).await?;
+                }
+                "keys" | "keys list" => {
+                    self.keys_list(Self::unpack_args(args)?).await?;
+                }
+                "keys add" => {
+                    self.keys_add(args).await?;
+                }
+                "keys remove" => {
+                    self.keys_remove(Self::unpack_args(args)?).await?;
+                }
+                "passwd" => {
+                    self.passwd(Self::unpack_args(args)?).await?;
+                }
+                "whoami" => {
+                    self.whoami(Self::unpack_args(args)?).await?;
+                }
+                "launchers" => {
+                    self.launchers(Self::unpack_args(args)?).await?;
+                }
+                "observe" => {
+                    self.observe(Self::unpack_args(args)?).await?;
+                }
+                "announce" => {
+                    self.announce(args).await?;
+                }
+                "who" => {
+                    self.who(Self::unpack_args(args)?).await?;
+                }
+                "get" => {
+                    self.get(Self::unpack_args(args)?).await?;
+                }
+                "set" => {
+                    self.set(Self::unpack_args(args)?).await?;
+                }
+                "session" => {
+                    self.session(Self::unpack_args(args)?).await?;
+                }
+                "scrollback" => {
+                    self.scrollback(args).await?;
+                }
                 _ => return Err(TerminalError::Print("Not implemented\n".into())),
             },
             CommandInterpretation::Response { prompt } => {
-                self.send_to_terminal(PresentationToTerminalMsg::PrintLine(prompt))
+                self.println_wrapped(prompt).await?;
+            }
+            CommandInterpretation::Session { line } => {
+                self.send_session_line(line).await?;
+            }
+            // Within a session, commands are only reached with a `/`, and a
+            // line which starts with one but names no command was probably
+            // meant for the session, so it's passed on rather than lost.
+            CommandInterpretation::Unrecognised { prompt } => {
+                if self.active_session.is_some() {
+                    self.println(format!(
+                        "`/{line}` is not a command, so it was sent to the session."
+                    ))
                     .await?;
+                    self.send_session_line(format!("/{line}")).await?;
+                } else {
+                    self.println_wrapped(prompt).await?;
+                }
             }
             CommandInterpretation::Noop => {}
         }
         Ok(())
     }
+    /// Outside of a session every line is a command. Within one, lines are
+    /// sent to the session unless they start with a `/`, in which case the
+    /// rest is a command, or is sent to the session after all if it isn't.
     async fn handle_read_line(&mut self, line: String) -> Result<(), TerminalError> {
+        if let Some(state) = self.password_change.take() {
+            return self.continue_password_change(state, line).await;
+        }
         enum Mode<'a> {
             Command(&'a str),
             SessionCommand(&'a str),
@@ -256,21 +1089,38 @@ impl TerminalPresentation {
         match mode {
             Mode::Command(line) => self.handle_command_line(line).await?,
             Mode::SessionCommand(line) => {
-                self.send_to_connection(PresentationToConnectionMsg::SessionCommand(
-                    SessionCommand::Terminal(TerminalSessionCommand::Line(line.into())),
-                ))
-                .await?;
+                let line = line
+                    .trim()
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|n| self.session_choices.get(n.checked_sub(1)?))
+                    .map_or(line, String::as_str)
+                    .to_owned();
+                self.send_session_line(line).await?;
             }
         }
 
         Ok(())
     }
+    async fn send_session_line(&mut self, line: String) -> Result<(), TerminalError> {
+        self.send_to_connection(PresentationToConnectionMsg::SessionCommand(
+            SessionCommand::Terminal(TerminalSessionCommand::Line(line)),
+        ))
+        .await
+    }
     async fn handle_terminal_msg(
         &mut self,
         msg: TerminalToPresentationMsg,
     ) -> Result<(), TerminalError> {
         match msg {
             TerminalToPresentationMsg::ReadLine(line) => self.handle_read_line(line).await,
+            TerminalToPresentationMsg::Resize { columns } => {
+                self.columns = usize::try_from(columns)
+                    .ok()
+                    .filter(|&columns| columns > 0)
+                    .unwrap_or(DEFAULT_COLUMNS);
+                Ok(())
+            }
         }
     }
     #[tracing::instrument(skip(self))]
@@ -280,22 +1130,116 @@ impl TerminalPresentation {
     ) -> Result<(), TerminalError> {
         match msg {
             ConnectionToPresentationMsg::EnteredSession(session) => {
+                let console_ui = session
+                    .capabilities
+                    .as_ref()
+                    .is_none_or(|capabilities| capabilities.console_ui);
+                self.send_to_terminal(PresentationToTerminalMsg::SessionChanged(Some(
+                    session.kind.clone(),
+                )))
+                .await?;
                 self.active_session = Some(session);
+                self.session_choices.clear();
+                if !console_ui {
+                    self.println("This game can't be played from a terminal.".into())
+                        .await?;
+                }
             }
             ConnectionToPresentationMsg::ExitedSession => {
+                self.send_to_terminal(PresentationToTerminalMsg::SessionChanged(None))
+                    .await?;
                 self.active_session = None;
+                self.session_choices.clear();
                 self.println("Exited session".into()).await?;
             }
             ConnectionToPresentationMsg::SessionEvent(SessionEvent::Terminal(ev)) => {
                 self.handle_session_event(ev).await?
             }
-            ConnectionToPresentationMsg::Error(e) => return Err(TerminalError::Print(e)),
+            // Terminals have no use for the game's state
+            ConnectionToPresentationMsg::SessionEvent(SessionEvent::Graphical(_)) => {}
+            ConnectionToPresentationMsg::SessionHistory(events) => {
+                self.handle_session_history(events).await?
+            }
+            ConnectionToPresentationMsg::ProposalRules { proposal_id, rules } => {
+                self.println(format!("Rules for {proposal_id}:\n{rules}"))
+                    .await?
+            }
+            ConnectionToPresentationMsg::Settings(settings) => {
+                self.settings = settings;
+                if let Some(key) = self.pending_setting.take() {
+                    let value = self.settings.get(&key).unwrap_or_default();
+                    self.println(format!("{key} = {value}")).await?;
+                }
+            }
+            ConnectionToPresentationMsg::YourTurn { game_id, bell } => {
+                // Players already in the game will see its own prompt
+                let in_game = matches!(
+                    self.active_session.as_ref().map(|session| &session.kind),
+                    Some(&SessionKind::Game(id)) if id == game_id
+                );
+                let mut line = String::new();
+                if bell {
+                    line.push('\x07');
+                }
+                if !in_game {
+                    line += &format!("It's your turn in game {game_id}");
+                }
+                if !line.is_empty() {
+                    self.println(line).await?;
+                }
+            }
+            ConnectionToPresentationMsg::NewMessage => {
+                self.println("You have a new message. Use `messages` to read it.".into())
+                    .await?;
+            }
+            ConnectionToPresentationMsg::Announcement(text) => {
+                self.println(format!("*** Announcement: {text} ***"))
+                    .await?;
+            }
+            ConnectionToPresentationMsg::Error(e) => {
+                return Err(TerminalError::Print(e.to_string()))
+            }
+            ConnectionToPresentationMsg::GameList(games) => self.handle_game_list(games).await?,
+            ConnectionToPresentationMsg::ActiveGames(games) => {
+                self.handle_active_games(games).await?
+            }
+            ConnectionToPresentationMsg::LauncherList(launchers) => {
+                self.handle_launcher_list(launchers).await?
+            }
+            ConnectionToPresentationMsg::GameTypeList(game_types) => {
+                self.handle_game_type_list(game_types).await?
+            }
+            ConnectionToPresentationMsg::GameResult { game_id, players } => {
+                self.handle_game_result(game_id, players).await?
+            }
+            ConnectionToPresentationMsg::RecentOpponents(opponents) => {
+                self.handle_recent_opponents(opponents).await?
+            }
+            ConnectionToPresentationMsg::Ratings { game_type, ratings } => {
+                self.handle_ratings(game_type, ratings).await?
+            }
+            ConnectionToPresentationMsg::OnlineUsers(users) => {
+                self.handle_online_users(users).await?
+            }
             ConnectionToPresentationMsg::MessageList(messages) => {
                 self.handle_message_list(messages).await?
             }
+            ConnectionToPresentationMsg::ListBegin { kind, total } => {
+                self.handle_list_begin(kind, total).await?
+            }
+            ConnectionToPresentationMsg::MessageChunk(messages) => {
+                self.handle_message_chunk(messages).await?
+            }
+            ConnectionToPresentationMsg::ListEnd => self.handle_list_end().await?,
             ConnectionToPresentationMsg::ProposalList(proposals) => {
                 self.handle_proposal_list(proposals).await?
             }
+            ConnectionToPresentationMsg::ProposalTemplateList(templates) => {
+                self.handle_proposal_template_list(templates).await?
+            }
+            ConnectionToPresentationMsg::RequestList(requests) => {
+                self.handle_request_list(requests).await?
+            }
             ConnectionToPresentationMsg::SessionList(sessions) => {
                 self.handle_session_list(sessions).await?
             }
@@ -303,12 +1247,221 @@ impl TerminalPresentation {
         Ok(())
     }
 
+    async fn handle_session_history(
+        &mut self,
+        events: Vec<SessionEvent>,
+    ) -> Result<(), TerminalError> {
+        if events.is_empty() {
+            return self.println("No earlier events.".into()).await;
+        }
+        self.println("--- Earlier in this session ---".into())
+            .await?;
+        for ev in events {
+            // Only lines are kept, since how input was read is long out of date
+            if let SessionEvent::Terminal(TerminalSessionEvent::Line(line)) = ev {
+                self.println(line).await?;
+            }
+        }
+        self.println("---".into()).await
+    }
     async fn handle_session_event(
         &mut self,
         ev: TerminalSessionEvent,
     ) -> Result<(), TerminalError> {
         match ev {
             TerminalSessionEvent::Line(line) => self.println(line).await,
+            TerminalSessionEvent::Input(mode) => {
+                self.session_choices.clear();
+                match mode {
+                    TerminalInputMode::Line => Ok(()),
+                    TerminalInputMode::Masked => {
+                        self.send_to_terminal(PresentationToTerminalMsg::ReadSecret("> ".into()))
+                            .await
+                    }
+                    TerminalInputMode::Choice(choices) => {
+                        for (i, choice) in choices.iter().enumerate() {
+                            self.println(format!("{}) {}", i + 1, choice)).await?;
+                        }
+                        self.session_choices = choices;
+                        Ok(())
+                    }
+                }
+            }
         }
     }
 }
+
+/// Candidate completions for the last token of a partially typed command line,
+/// typed in the given session if any.
+pub fn complete_command(line: &str, session: Option<&SessionKind>) -> Vec<String> {
+    Ui::instance().complete(line, session.map(UiContext::from))
+}
+
+fn normalize_fingerprint(fingerprint: &str) -> &str {
+    fingerprint.strip_prefix("SHA256:").unwrap_or(fingerprint)
+}
+
+/// Roughly how long ago `time` was, eg. "3m ago".
+fn format_ago(time: DateTime<Utc>) -> String {
+    let elapsed = Utc::now() - time;
+    if elapsed.num_minutes() < 1 {
+        "just now".into()
+    } else if elapsed.num_hours() < 1 {
+        format!("{}m ago", elapsed.num_minutes())
+    } else if elapsed.num_days() < 1 {
+        format!("{}h ago", elapsed.num_hours())
+    } else {
+        format!("{}d ago", elapsed.num_days())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        mock::{MockConnection, MockUserManagement},
+        SessionId,
+    };
+
+    use super::*;
+
+    /// Log a user in, returning the terminal's end of the presentation and
+    /// the server's end of its connection.
+    async fn connect() -> (
+        Bichannel<TerminalToPresentationMsg, PresentationToTerminalMsg>,
+        MockConnection,
+    ) {
+        let (user_management, mut connections) = MockUserManagement::new();
+        let user_id = user_management.add_user("alice", "hunter2").unwrap();
+        let terminal =
+            TerminalPresentation::connect(Arc::new(user_management), user_id, "alice".into(), None)
+                .await
+                .unwrap();
+        (terminal, connections.recv().await.unwrap())
+    }
+
+    async fn read_line(
+        terminal: &Bichannel<TerminalToPresentationMsg, PresentationToTerminalMsg>,
+        line: &str,
+    ) {
+        terminal
+            .s
+            .send(TerminalToPresentationMsg::ReadLine(line.into()))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn proposes_lists_enters_and_exits_sessions() {
+        let (mut terminal, mut connection) = connect().await;
+        let proposal_id = GameProposalId(1);
+
+        read_line(&terminal, "propose rock-paper-scissors 2").await;
+        let Some(PresentationToConnectionMsg::Propose(proposal)) =
+            connection.bichannel.r.recv().await
+        else {
+            panic!("expected a proposal");
+        };
+        assert_eq!(proposal.game_type, "rock-paper-scissors");
+        assert_eq!(proposal.num_players, Some(2));
+
+        read_line(&terminal, "sessions").await;
+        assert!(matches!(
+            connection.bichannel.r.recv().await,
+            Some(PresentationToConnectionMsg::ListSessions)
+        ));
+        connection
+            .bichannel
+            .s
+            .send(ConnectionToPresentationMsg::SessionList(vec![SessionMin {
+                id: SessionId(1),
+                created_at: Utc::now(),
+                kind: SessionKind::GameProposal(proposal_id),
+            }]))
+            .await
+            .unwrap();
+        let Some(PresentationToTerminalMsg::PrintLine(table)) = terminal.r.recv().await else {
+            panic!("expected the session list");
+        };
+        assert!(table.contains("s1"));
+
+        read_line(&terminal, "enter s1").await;
+        assert!(matches!(
+            connection.bichannel.r.recv().await,
+            Some(PresentationToConnectionMsg::Enter(SessionId(1)))
+        ));
+        connection
+            .bichannel
+            .s
+            .send(ConnectionToPresentationMsg::EnteredSession(SessionInfo {
+                id: Some(SessionId(1)),
+                kind: SessionKind::GameProposal(proposal_id),
+                capabilities: None,
+            }))
+            .await
+            .unwrap();
+        assert!(matches!(
+            terminal.r.recv().await,
+            Some(PresentationToTerminalMsg::SessionChanged(Some(
+                SessionKind::GameProposal(GameProposalId(1))
+            )))
+        ));
+
+        // Without the `/`, the line would be sent to the session
+        read_line(&terminal, "/exit").await;
+        assert!(matches!(
+            connection.bichannel.r.recv().await,
+            Some(PresentationToConnectionMsg::Exit)
+        ));
+        connection
+            .bichannel
+            .s
+            .send(ConnectionToPresentationMsg::ExitedSession)
+            .await
+            .unwrap();
+        assert!(matches!(
+            terminal.r.recv().await,
+            Some(PresentationToTerminalMsg::SessionChanged(None))
+        ));
+    }
+    #[tokio::test]
+    async fn unrecognised_commands_outside_a_session_are_reported() {
+        let (mut terminal, mut connection) = connect().await;
+        read_line(&terminal, "frobnicate").await;
+        let Some(PresentationToTerminalMsg::PrintLine(line)) = terminal.r.recv().await else {
+            panic!("expected an error");
+        };
+        assert!(line.contains("Unrecognised command."));
+        assert!(connection.bichannel.r.try_recv().is_err());
+    }
+    #[tokio::test]
+    async fn unrecognised_commands_in_a_session_are_sent_to_it() {
+        let (mut terminal, mut connection) = connect().await;
+        connection
+            .bichannel
+            .s
+            .send(ConnectionToPresentationMsg::EnteredSession(SessionInfo {
+                id: Some(SessionId(1)),
+                kind: SessionKind::GameProposal(GameProposalId(1)),
+                capabilities: None,
+            }))
+            .await
+            .unwrap();
+        assert!(matches!(
+            terminal.r.recv().await,
+            Some(PresentationToTerminalMsg::SessionChanged(Some(_)))
+        ));
+
+        read_line(&terminal, "/frobnicate").await;
+        let Some(PresentationToTerminalMsg::PrintLine(line)) = terminal.r.recv().await else {
+            panic!("expected a warning");
+        };
+        assert!(line.contains("is not a command"));
+        let Some(PresentationToConnectionMsg::SessionCommand(SessionCommand::Terminal(
+            TerminalSessionCommand::Line(line),
+        ))) = connection.bichannel.r.recv().await
+        else {
+            panic!("expected the line to be sent to the session");
+        };
+        assert_eq!(line, "/frobnicate");
+    }
+}
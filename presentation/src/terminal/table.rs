@@ -0,0 +1,75 @@
+/// Lays out rows of text in columns, each as wide as its widest cell.
+pub struct Table {
+    headers: Vec<String>,
+    right_aligned: Vec<bool>,
+    min_widths: Vec<usize>,
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    pub fn new<const N: usize>(headers: [&str; N]) -> Self {
+        Self {
+            headers: headers.iter().map(|&header| header.into()).collect(),
+            right_aligned: vec![false; N],
+            min_widths: vec![0; N],
+            rows: Vec::new(),
+        }
+    }
+    /// Right-align a column, which suits IDs and counts.
+    pub fn align_right(mut self, column: usize) -> Self {
+        self.right_aligned[column] = true;
+        self
+    }
+    /// Make a column at least this wide, so that tables rendered separately,
+    /// such as the chunks of a long list, line up.
+    pub fn min_width(mut self, column: usize, width: usize) -> Self {
+        self.min_widths[column] = width;
+        self
+    }
+    /// Missing cells are left blank, and extra ones are ignored.
+    pub fn row(&mut self, cells: impl IntoIterator<Item = String>) {
+        let mut row: Vec<_> = cells.into_iter().take(self.headers.len()).collect();
+        row.resize(self.headers.len(), String::new());
+        self.rows.push(row);
+    }
+    pub fn render(&self) -> String {
+        self.render_inner(true)
+    }
+    /// Render without the headers, to continue a table already started.
+    pub fn render_rows(&self) -> String {
+        self.render_inner(false)
+    }
+    fn render_inner(&self, with_headers: bool) -> String {
+        let mut widths: Vec<_> = self
+            .headers
+            .iter()
+            .zip(&self.min_widths)
+            .map(|(h, &min_width)| h.chars().count().max(min_width))
+            .collect();
+        for row in &self.rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        let headers = with_headers.then_some(&self.headers);
+        let mut lines = Vec::new();
+        for row in headers.into_iter().chain(&self.rows) {
+            let mut line = String::new();
+            for (i, cell) in row.iter().enumerate() {
+                if i > 0 {
+                    line.push(' ');
+                }
+                let padding = " ".repeat(widths[i] - cell.chars().count());
+                if self.right_aligned[i] {
+                    line.push_str(&padding);
+                    line.push_str(cell);
+                } else {
+                    line.push_str(cell);
+                    line.push_str(&padding);
+                }
+            }
+            lines.push(line.trim_end().to_owned());
+        }
+        lines.join("\n")
+    }
+}
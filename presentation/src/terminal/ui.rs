@@ -1,12 +1,50 @@
 use serde::Deserialize;
 use std::{fmt::Write, sync::OnceLock};
 
+use crate::SessionKind;
+
 const UI_CONFIG: &str = include_str!("ui.toml");
 
 #[derive(Debug, Deserialize)]
 pub struct Ui {
     help_text: String,
     group: Vec<UiGroup>,
+    #[serde(default)]
+    context: Vec<UiContextGroup>,
+}
+
+/// The kind of session the user is in, which decides what `help` shows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UiContext {
+    GameProposal,
+    Game,
+    GameSpectator,
+    GameObserver,
+}
+
+impl From<&SessionKind> for UiContext {
+    fn from(kind: &SessionKind) -> Self {
+        match kind {
+            SessionKind::GameProposal(_) => Self::GameProposal,
+            SessionKind::Game(_) => Self::Game,
+            SessionKind::GameSpectator(_) => Self::GameSpectator,
+            SessionKind::GameObserver(_) => Self::GameObserver,
+        }
+    }
+}
+
+/// Commands which only make sense within some kinds of session.
+#[derive(Debug, Deserialize)]
+struct UiContextGroup {
+    sessions: Vec<UiContext>,
+    help_text: String,
+    /// Typed straight into the session, but also accepted with a leading `/`.
+    #[serde(default)]
+    command: Vec<UiCommand>,
+    /// Names of top-level commands which act on the session.
+    #[serde(default)]
+    global_commands: Vec<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -22,6 +60,8 @@ struct UiCommand {
     help_text: String,
     name: String,
     #[serde(default)]
+    aliases: Vec<String>,
+    #[serde(default)]
     args: String,
     #[serde(default)]
     subgroup: Vec<UiGroup>,
@@ -29,8 +69,22 @@ struct UiCommand {
 
 #[derive(Debug)]
 pub enum CommandInterpretation {
-    Action { command: String, args: Vec<String> },
-    Response { prompt: String },
+    Action {
+        command: String,
+        args: Vec<String>,
+    },
+    Response {
+        prompt: String,
+    },
+    /// The line should be passed on to the session.
+    Session {
+        line: String,
+    },
+    /// The first word isn't a command at all, which may mean the line wasn't
+    /// meant as one.
+    Unrecognised {
+        prompt: String,
+    },
     Noop,
 }
 
@@ -42,31 +96,73 @@ impl Ui {
     fn load() -> Self {
         toml::from_str(UI_CONFIG).expect("UI config to be valid")
     }
-    pub fn interpret_command(&self, line: &str) -> anyhow::Result<CommandInterpretation> {
-        let res = self.interpret_command_inner(line)?;
-        if let CommandInterpretation::Action { command, .. } = &res {
+    /// Interpret a command line, typed within the given kind of session, if
+    /// any.
+    pub fn interpret_command(
+        &self,
+        line: &str,
+        context: Option<UiContext>,
+    ) -> anyhow::Result<CommandInterpretation> {
+        let res = self.interpret_command_inner(line, context)?;
+        if let CommandInterpretation::Action { command, args } = &res {
             let mut parts = command
                 .split_ascii_whitespace()
                 .filter(|part| !part.is_empty());
             if parts.next() == Some("help") {
                 return Ok(CommandInterpretation::Response {
-                    prompt: self.help(parts)?,
+                    prompt: self.help(args.iter().map(String::as_str), context)?,
                 });
             }
         }
         Ok(res)
     }
-    fn help<'a>(&self, mut parts: impl Iterator<Item = &'a str>) -> anyhow::Result<String> {
+    fn contexts(&self, context: Option<UiContext>) -> impl Iterator<Item = &UiContextGroup> {
+        self.context
+            .iter()
+            .filter(move |group| context.is_some_and(|context| group.sessions.contains(&context)))
+    }
+    fn help<'a>(
+        &self,
+        mut parts: impl Iterator<Item = &'a str>,
+        context: Option<UiContext>,
+    ) -> anyhow::Result<String> {
         Ok(if let Some(part) = parts.next() {
+            if part == "all" {
+                return self.help(parts, None);
+            }
             for group in &self.group {
                 for command in &group.command {
-                    if command.name.eq_ignore_ascii_case(part) {
+                    if command.matches(part) {
+                        return command.help(vec![command.name.clone()], parts);
+                    }
+                }
+            }
+            for group in self.contexts(context) {
+                for command in &group.command {
+                    if command.matches(part) {
                         return command.help(vec![command.name.clone()], parts);
                     }
                 }
             }
 
             format!("Unrecognised command {part}\n")
+        } else if context.is_some() && self.contexts(context).next().is_some() {
+            let mut response = String::new();
+            for group in self.contexts(context) {
+                writeln!(response, "{}", group.help_text)?;
+                for command in &group.command {
+                    writeln!(response, "    {} {}", command.name, command.args)?;
+                }
+                for name in &group.global_commands {
+                    if let Some(command) = self.find_command(name) {
+                        writeln!(response, "    /{} {}", command.name, command.args)?;
+                    }
+                }
+                writeln!(response)?;
+            }
+            writeln!(response, "Use `/help all` to list every command.")?;
+
+            response
         } else {
             let mut response = String::new();
             writeln!(response, "{}", self.help_text)?;
@@ -80,20 +176,84 @@ impl Ui {
             response
         })
     }
-    fn interpret_command_inner(&self, line: &str) -> anyhow::Result<CommandInterpretation> {
+    /// Find candidate completions for the last token of a partially typed
+    /// command line. Returns nothing once the line reaches argument position.
+    ///
+    /// Within a session, only lines starting with `/` are commands, so other
+    /// text is only completed if the session has commands of its own.
+    pub fn complete(&self, line: &str, context: Option<UiContext>) -> Vec<String> {
+        let global_commands = self.group.iter().flat_map(|group| &group.command);
+        let session_commands = self.contexts(context).flat_map(|group| &group.command);
+        let (line, mut commands): (_, Vec<_>) = match line.strip_prefix('/') {
+            Some(line) => (line, global_commands.chain(session_commands).collect()),
+            None if context.is_some() => (line, session_commands.collect()),
+            None => (line, global_commands.collect()),
+        };
+        let mut parts: Vec<&str> = line.split_ascii_whitespace().collect();
+        let partial = if line.ends_with(|c: char| c.is_ascii_whitespace()) {
+            ""
+        } else {
+            parts.pop().unwrap_or_default()
+        };
+        for part in parts {
+            let Some(command) = commands.into_iter().find(|command| command.matches(part)) else {
+                return Vec::new();
+            };
+            if command.subgroup.is_empty() {
+                return Vec::new();
+            }
+            commands = command
+                .subgroup
+                .iter()
+                .flat_map(|group| &group.command)
+                .collect();
+        }
+        commands
+            .into_iter()
+            .filter(|command| {
+                command
+                    .name
+                    .get(..partial.len())
+                    .is_some_and(|prefix| prefix.eq_ignore_ascii_case(partial))
+            })
+            .map(|command| command.name.clone())
+            .collect()
+    }
+    fn find_command(&self, name: &str) -> Option<&UiCommand> {
+        self.group
+            .iter()
+            .flat_map(|group| &group.command)
+            .find(|command| command.matches(name))
+    }
+    fn interpret_command_inner(
+        &self,
+        line: &str,
+        context: Option<UiContext>,
+    ) -> anyhow::Result<CommandInterpretation> {
         let mut parts = line
             .split_ascii_whitespace()
             .filter(|part| !part.is_empty());
         Ok(if let Some(part) = parts.next() {
             for group in &self.group {
                 for command in &group.command {
-                    if command.name.eq_ignore_ascii_case(part) {
+                    if command.matches(part) {
                         return command.interpret_subcommand(vec![command.name.clone()], parts);
                     }
                 }
             }
-            CommandInterpretation::Response {
-                prompt: "Unrecognised command. Use `help` for more information.\n".into(),
+            // Session commands typed with a leading `/` out of habit
+            if self
+                .contexts(context)
+                .flat_map(|group| &group.command)
+                .any(|command| command.matches(part))
+            {
+                return Ok(CommandInterpretation::Session { line: line.into() });
+            }
+            CommandInterpretation::Unrecognised {
+                prompt: format!(
+                    "Unrecognised command.{} Use `help` for more information.\n",
+                    did_you_mean(part, &self.group)
+                ),
             }
         } else {
             CommandInterpretation::Noop
@@ -102,6 +262,13 @@ impl Ui {
 }
 
 impl UiCommand {
+    fn matches(&self, part: &str) -> bool {
+        self.name.eq_ignore_ascii_case(part)
+            || self
+                .aliases
+                .iter()
+                .any(|alias| alias.eq_ignore_ascii_case(part))
+    }
     fn help<'a>(
         &self,
         mut prefix: Vec<String>,
@@ -111,7 +278,7 @@ impl UiCommand {
         Ok(if let Some(part) = parts.next() {
             for group in &self.subgroup {
                 for command in &group.command {
-                    if command.name.eq_ignore_ascii_case(part) {
+                    if command.matches(part) {
                         prefix.push(command.name.clone());
                         return command.help(prefix, parts);
                     }
@@ -121,7 +288,11 @@ impl UiCommand {
             format!("Unrecognised subcommand {part}\n")
         } else {
             let mut response = String::new();
-            writeln!(response, "{} {}\n\n{}", command, self.args, self.help_text)?;
+            writeln!(response, "{} {}", command, self.args)?;
+            if !self.aliases.is_empty() {
+                writeln!(response, "Aliases: {}", self.aliases.join(", "))?;
+            }
+            writeln!(response, "\n{}", self.help_text)?;
             for group in &self.subgroup {
                 writeln!(response, "\n{}", group.help_text)?;
                 for command in &group.command {
@@ -144,7 +315,7 @@ impl UiCommand {
         } else if let Some(part) = parts.next() {
             for group in &self.subgroup {
                 for command in &group.command {
-                    if command.name.eq_ignore_ascii_case(part) {
+                    if command.matches(part) {
                         prefix.push(command.name.clone());
                         return command.interpret_subcommand(prefix, parts);
                     }
@@ -152,7 +323,8 @@ impl UiCommand {
             }
             CommandInterpretation::Response {
                 prompt: format!(
-                    "Unrecognised subcommand. Use `help {command}` for more information.\n"
+                    "Unrecognised subcommand.{} Use `help {command}` for more information.\n",
+                    did_you_mean(part, &self.subgroup)
                 ),
             }
         } else {
@@ -163,3 +335,91 @@ impl UiCommand {
         })
     }
 }
+
+/// Suggest the command closest to an unrecognised one, if any is close enough
+/// to plausibly be a typo.
+fn did_you_mean(part: &str, groups: &[UiGroup]) -> String {
+    const MAX_DISTANCE: usize = 2;
+    let part = part.to_ascii_lowercase();
+    groups
+        .iter()
+        .flat_map(|group| &group.command)
+        .map(|command| (edit_distance(&part, &command.name), &command.name))
+        .filter(|&(distance, _)| distance <= MAX_DISTANCE)
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, name)| format!(" Did you mean `{name}`?"))
+        .unwrap_or_default()
+}
+
+/// Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev_diag = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let cost = prev_diag + usize::from(ca != cb);
+            prev_diag = row[j + 1];
+            row[j + 1] = cost.min(row[j] + 1).min(prev_diag + 1);
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn action(line: &str) -> (String, Vec<String>) {
+        match Ui::instance().interpret_command(line, None).unwrap() {
+            CommandInterpretation::Action { command, args } => (command, args),
+            other => panic!("expected an action for `{line}`, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn aliases_resolve_to_the_canonical_command() {
+        assert_eq!(action("p rps 2"), action("propose rps 2"));
+        assert_eq!(action("P rps"), action("propose rps"));
+        assert_eq!(action("ls"), action("sessions"));
+        assert_eq!(action("reject 3"), action("decline 3"));
+    }
+
+    #[test]
+    fn help_shows_the_canonical_name_and_aliases() {
+        let CommandInterpretation::Response { prompt } =
+            Ui::instance().interpret_command("help p", None).unwrap()
+        else {
+            panic!("expected help");
+        };
+        assert!(prompt.starts_with("propose "));
+        assert!(prompt.contains("Aliases: p\n"));
+    }
+
+    #[test]
+    fn completes_commands_and_subcommands() {
+        let ui = Ui::instance();
+        assert_eq!(ui.complete("propo", None), ["proposals", "propose"]);
+        assert_eq!(ui.complete("/propo", None), ["proposals", "propose"]);
+        assert_eq!(ui.complete("rules e", None), ["edit"]);
+        // Arguments aren't completed
+        assert!(ui.complete("join 1", None).is_empty());
+    }
+
+    #[test]
+    fn completes_only_commands_within_sessions() {
+        let ui = Ui::instance();
+        // Text in a lobby is chat, not a command
+        assert!(ui
+            .complete("propo", Some(UiContext::GameProposal))
+            .is_empty());
+        assert_eq!(
+            ui.complete("/propo", Some(UiContext::GameProposal)),
+            ["proposals", "propose"]
+        );
+        assert_eq!(ui.complete("resi", Some(UiContext::Game)), ["resign"]);
+        assert_eq!(ui.complete("/resi", Some(UiContext::Game)), ["resign"]);
+        assert!(ui.complete("/resi", None).is_empty());
+    }
+}
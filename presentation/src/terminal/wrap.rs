@@ -0,0 +1,38 @@
+/// Wrap each line of `text` at word boundaries so that no line is wider than
+/// `width` columns. Words which don't fit on a line of their own are split.
+pub fn wrap_text(text: &str, width: usize) -> String {
+    let width = width.max(1);
+    let mut result = String::with_capacity(text.len());
+    for (i, line) in text.split('\n').enumerate() {
+        if i > 0 {
+            result.push('\n');
+        }
+        let mut column = 0;
+        for (j, mut word) in line.split(' ').enumerate() {
+            let mut word_len = word.chars().count();
+            if j > 0 {
+                if column > 0 && column + 1 + word_len > width {
+                    result.push('\n');
+                    column = 0;
+                } else {
+                    result.push(' ');
+                    column += 1;
+                }
+            }
+            while column + word_len > width {
+                let split = word
+                    .char_indices()
+                    .nth(width.saturating_sub(column))
+                    .map_or(word.len(), |(idx, _)| idx);
+                result.push_str(&word[..split]);
+                result.push('\n');
+                word = &word[split..];
+                word_len = word.chars().count();
+                column = 0;
+            }
+            result.push_str(word);
+            column += word_len;
+        }
+    }
+    result
+}
@@ -1,72 +1,243 @@
-use std::{env::consts::EXE_SUFFIX, path::Path, process::Stdio, sync::Arc};
+use std::{
+    collections::HashMap,
+    env::consts::EXE_SUFFIX,
+    fmt::{self, Debug},
+    path::Path,
+    process::Stdio,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
 
-use anyhow::{anyhow, Context};
+use anyhow::{anyhow, bail, Context};
 use async_trait::async_trait;
 use ijson::IValue;
 use playferrous_launcher::{GameError, GameInstance, Launcher, LauncherConfig, LauncherError};
 use playferrous_types::{
-    CommandResponse, ConsoleUi, GameRequest, GameResponse, GameSetup, GameState, GameTick,
+    CommandResponse, ConsoleUi, GameDescription, GameRequest, GameResponse, GameSetup, GameState,
+    GameTick, PlayerView, SnapshotEnvelope,
 };
 use serde::{Deserialize, Serialize};
-use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter},
-    process::{Child, ChildStdin, ChildStdout, Command},
-};
+use tokio::process::{Child, Command};
+
+use self::transport::Transport;
+
+mod transport;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProcessLauncherConfig {
     path: String,
+    /// How many processes to keep spawned ahead of time for each game type,
+    /// so that games can be launched without waiting for a process to start.
+    #[serde(default)]
+    pool_size: HashMap<String, usize>,
+    /// Limits on the address space and CPU time of each game process, which
+    /// is killed if it exceeds them. Limits are best-effort, and are ignored
+    /// on platforms other than Linux.
+    #[serde(default)]
+    memory_limit_mb: Option<u64>,
+    #[serde(default)]
+    cpu_time_limit_s: Option<u64>,
 }
 
 #[derive(Debug)]
 pub struct ProcessLauncher {
-    config: ProcessLauncherConfig,
+    config: Arc<ProcessLauncherConfig>,
+    pool: Arc<ProcessPool>,
+}
+
+/// Processes which have been spawned but not yet initialized, by game type.
+/// Any left over are killed when the pool is dropped.
+#[derive(Default)]
+struct ProcessPool {
+    idle: Mutex<HashMap<String, Vec<GameInstanceProcess>>>,
+}
+
+impl Debug for ProcessPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let idle = self.idle.lock().unwrap();
+        f.debug_map()
+            .entries(
+                idle.iter()
+                    .map(|(game_type, processes)| (game_type, processes.len())),
+            )
+            .finish()
+    }
+}
+
+impl ProcessPool {
+    /// Take an idle process, skipping any which have died while waiting.
+    fn take(&self, game_type: &str) -> Option<GameInstanceProcess> {
+        let mut idle = self.idle.lock().unwrap();
+        let processes = idle.get_mut(game_type)?;
+        while let Some(mut process) = processes.pop() {
+            if matches!(process.child.try_wait(), Ok(None)) {
+                return Some(process);
+            }
+        }
+        None
+    }
+    /// Spawn processes until the pool for this game type is full. Failures
+    /// are ignored here, since `launch` will report them when it falls back
+    /// to spawning a process itself.
+    fn refill(&self, config: &ProcessLauncherConfig, game_type: &str) {
+        let Some(&size) = config.pool_size.get(game_type) else {
+            return;
+        };
+        let missing = {
+            let mut idle = self.idle.lock().unwrap();
+            let processes = idle.entry(game_type.into()).or_default();
+            processes.retain_mut(|process| matches!(process.child.try_wait(), Ok(None)));
+            size.saturating_sub(processes.len())
+        };
+        // Spawned without the lock held, so that launches aren't held up
+        let spawned: Vec<_> = (0..missing)
+            .map_while(|_| config.spawn(game_type).ok())
+            .collect();
+        let mut idle = self.idle.lock().unwrap();
+        let processes = idle.entry(game_type.into()).or_default();
+        processes.extend(spawned);
+        // Refills running at the same time may have overfilled the pool, in
+        // which case the extra processes are killed as they're dropped
+        processes.truncate(size);
+    }
 }
 
 #[async_trait]
 impl LauncherConfig for ProcessLauncherConfig {
     async fn start_launcher(&self) -> anyhow::Result<Arc<dyn Launcher>> {
+        let pool = Arc::new(ProcessPool::default());
+        for (game_type, &size) in &self.pool_size {
+            // Spawn the first process directly, so that misconfigured game
+            // types are reported at startup
+            if size > 0 {
+                let process = self.spawn(game_type)?;
+                pool.idle
+                    .lock()
+                    .unwrap()
+                    .insert(game_type.clone(), vec![process]);
+            }
+            pool.refill(self, game_type);
+        }
         Ok(Arc::new(ProcessLauncher {
-            config: self.clone(),
+            config: Arc::new(self.clone()),
+            pool,
         }))
     }
 }
 
-#[async_trait]
-impl Launcher for ProcessLauncher {
-    async fn launch(&self, game_setup: GameSetup) -> Result<Box<dyn GameInstance>, LauncherError> {
-        let binary_name = format!("{}{}", game_setup.game_type, EXE_SUFFIX);
-        let process_path = Path::new(&self.config.path).join(binary_name);
+impl ProcessLauncherConfig {
+    fn spawn(&self, game_type: &str) -> Result<GameInstanceProcess, LauncherError> {
+        let binary_name = format!("{}{}", game_type, EXE_SUFFIX);
+        let process_path = Path::new(&self.path).join(binary_name);
         if !process_path.is_file() {
             return Err(LauncherError::UnknownGameType);
         }
 
-        let mut child = Command::new(process_path)
+        let mut command = Command::new(process_path);
+        command
             .arg("--playferrous")
             .kill_on_drop(true)
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
-            .stderr(Stdio::inherit())
-            .spawn()
-            .context("Failed to spawn game process")?;
-
-        let stdin = BufWriter::new(
-            child
-                .stdin
-                .take()
-                .context("Failed to obtain stdin for child process")?,
-        );
-        let stdout = BufReader::new(
-            child
-                .stdout
-                .take()
-                .context("Failed to obtain stdout for child process")?,
-        );
-        let mut res = Box::new(GameInstanceProcess {
-            _child: child,
-            stdin,
-            stdout,
-        });
+            .stderr(Stdio::inherit());
+        #[cfg(target_os = "linux")]
+        self.apply_limits(&mut command);
+        let mut child = command.spawn().context("Failed to spawn game process")?;
+
+        let stdin = child
+            .stdin
+            .take()
+            .context("Failed to obtain stdin for child process")?;
+        let stdout = child
+            .stdout
+            .take()
+            .context("Failed to obtain stdout for child process")?;
+        Ok(GameInstanceProcess {
+            child,
+            transport: Transport::new(stdin, stdout, game_type.into()),
+        })
+    }
+    #[cfg(target_os = "linux")]
+    fn apply_limits(&self, command: &mut Command) {
+        let limits = [
+            (
+                libc::RLIMIT_AS,
+                self.memory_limit_mb
+                    .map(|mb| mb.saturating_mul(1024 * 1024)),
+            ),
+            (libc::RLIMIT_CPU, self.cpu_time_limit_s),
+        ];
+        if limits.iter().all(|(_, limit)| limit.is_none()) {
+            return;
+        }
+        // Safety: `setrlimit` is async-signal-safe, and nothing is allocated
+        // between forking and exec-ing the game.
+        unsafe {
+            command.pre_exec(move || {
+                for (resource, limit) in limits {
+                    let Some(limit) = limit else { continue };
+                    let rlimit = libc::rlimit {
+                        rlim_cur: limit,
+                        rlim_max: limit,
+                    };
+                    if libc::setrlimit(resource, &rlimit) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+}
+
+#[async_trait]
+impl Launcher for ProcessLauncher {
+    async fn describe(&self, game_type: &str) -> Result<GameDescription, LauncherError> {
+        // The process is killed as soon as we're done with it
+        let mut process = self.config.spawn(game_type)?;
+
+        let req = GameRequest::Describe;
+        let resp = process.request(&req).await?;
+        if let GameResponse::Describe(description) = resp {
+            Ok(description)
+        } else {
+            Err(GameInstanceProcess::response_type_error(&req, &resp).into())
+        }
+    }
+    async fn validate_rules(&self, game_type: &str, rules: IValue) -> Result<(), LauncherError> {
+        let mut process = self.config.spawn(game_type)?;
+
+        let req = GameRequest::ValidateRules(rules);
+        let resp = process.request(&req).await?;
+        if let GameResponse::ValidateRules(res) = resp {
+            res.map_err(LauncherError::InvalidRules)
+        } else {
+            Err(GameInstanceProcess::response_type_error(&req, &resp).into())
+        }
+    }
+    async fn rules_schema(&self, game_type: &str) -> Result<IValue, LauncherError> {
+        let mut process = self.config.spawn(game_type)?;
+
+        let req = GameRequest::RulesSchema;
+        let resp = process.request(&req).await?;
+        if let GameResponse::RulesSchema(schema) = resp {
+            Ok(schema)
+        } else {
+            Err(GameInstanceProcess::response_type_error(&req, &resp).into())
+        }
+    }
+    async fn launch(&self, game_setup: GameSetup) -> Result<Box<dyn GameInstance>, LauncherError> {
+        let game_type = game_setup.game_type.clone();
+        let process = match self.pool.take(&game_type) {
+            Some(process) => {
+                let config = self.config.clone();
+                let pool = self.pool.clone();
+                tokio::task::spawn_blocking(move || pool.refill(&config, &game_type));
+                process
+            }
+            None => self.config.spawn(&game_type)?,
+        };
+        let mut res = Box::new(process);
 
         let req = GameRequest::Initialize(game_setup);
         let resp = res.request(&req).await?;
@@ -76,27 +247,81 @@ impl Launcher for ProcessLauncher {
 
         Ok(res)
     }
+    async fn health_check(&self) -> anyhow::Result<()> {
+        let metadata = tokio::fs::metadata(&self.config.path)
+            .await
+            .with_context(|| format!("Cannot access game directory `{}`", self.config.path))?;
+        if !metadata.is_dir() {
+            bail!("`{}` is not a directory", self.config.path);
+        }
+        Ok(())
+    }
+    async fn supported_game_types(&self) -> anyhow::Result<Vec<String>> {
+        let mut game_types = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.config.path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if !is_executable(&entry.metadata().await?) {
+                continue;
+            }
+            let file_name = entry.file_name();
+            if let Some(game_type) = file_name
+                .to_str()
+                .and_then(|name| name.strip_suffix(EXE_SUFFIX))
+            {
+                game_types.push(game_type.into());
+            }
+        }
+        game_types.sort();
+        Ok(game_types)
+    }
+}
+
+/// On unix, the game directory may hold other files too, such as READMEs,
+/// which don't have a suffix to tell them apart.
+#[cfg(unix)]
+fn is_executable(metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    metadata.is_file() && metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(metadata: &std::fs::Metadata) -> bool {
+    metadata.is_file()
 }
 
 struct GameInstanceProcess {
-    _child: Child,
-    stdin: BufWriter<ChildStdin>,
-    stdout: BufReader<ChildStdout>,
+    child: Child,
+    transport: Transport,
 }
 
 impl GameInstanceProcess {
+    /// Send a request to the game, recording how long it took and whether it
+    /// failed. Metrics are discarded unless a recorder has been installed.
     async fn request(&mut self, request: &GameRequest) -> anyhow::Result<GameResponse> {
-        // Request
-        let mut request_str = serde_json::to_string(&request)?;
-        request_str.push('\n');
-        self.stdin.write_all(request_str.as_bytes()).await?;
-        drop(request_str);
-        self.stdin.flush().await?;
-
-        // Response
-        let mut buf = String::new();
-        self.stdout.read_line(&mut buf).await?;
-        Ok(serde_json::from_str(&buf)?)
+        let kind = request.kind();
+        let start = Instant::now();
+        let res = self.request_inner(request).await;
+        metrics::increment_counter!("playferrous_game_requests_total", "request" => kind);
+        metrics::histogram!(
+            "playferrous_game_request_duration_seconds",
+            start.elapsed(),
+            "request" => kind
+        );
+        if res.is_err() {
+            metrics::increment_counter!("playferrous_game_request_errors_total", "request" => kind);
+        }
+        res
+    }
+    async fn request_inner(&mut self, request: &GameRequest) -> anyhow::Result<GameResponse> {
+        match self.transport.request(request).await? {
+            Some(response) => Ok(response),
+            None => {
+                // Most likely killed for exceeding its resource limits
+                let status = self.child.wait().await?;
+                bail!("Game process exited unexpectedly ({status})");
+            }
+        }
     }
     fn response_type_error(req: &GameRequest, resp: &GameResponse) -> anyhow::Error {
         anyhow!("Invalid response {resp:?} for {req:?}")
@@ -105,7 +330,7 @@ impl GameInstanceProcess {
 
 #[async_trait]
 impl GameInstance for GameInstanceProcess {
-    async fn load_snapshot(&mut self, snapshot: IValue) -> anyhow::Result<()> {
+    async fn load_snapshot(&mut self, snapshot: SnapshotEnvelope) -> anyhow::Result<()> {
         let req = GameRequest::LoadSnapshot(snapshot);
         let resp = self.request(&req).await?;
         if let GameResponse::LoadSnapshot = resp {
@@ -114,7 +339,7 @@ impl GameInstance for GameInstanceProcess {
             Err(Self::response_type_error(&req, &resp))
         }
     }
-    async fn save_snapshot(&mut self) -> anyhow::Result<IValue> {
+    async fn save_snapshot(&mut self) -> anyhow::Result<SnapshotEnvelope> {
         let req = GameRequest::SaveSnapshot;
         let resp = self.request(&req).await?;
         if let GameResponse::SaveSnapshot(snapshot) = resp {
@@ -123,6 +348,24 @@ impl GameInstance for GameInstanceProcess {
             Err(Self::response_type_error(&req, &resp))
         }
     }
+    async fn load_snapshot_binary(&mut self, version: u32, data: Vec<u8>) -> anyhow::Result<()> {
+        let req = GameRequest::LoadSnapshotBinary { version, data };
+        let resp = self.request(&req).await?;
+        if let GameResponse::LoadSnapshotBinary = resp {
+            Ok(())
+        } else {
+            Err(Self::response_type_error(&req, &resp))
+        }
+    }
+    async fn save_snapshot_binary(&mut self) -> anyhow::Result<Vec<u8>> {
+        let req = GameRequest::SaveSnapshotBinary;
+        let resp = self.request(&req).await?;
+        if let GameResponse::SaveSnapshotBinary(data) = resp {
+            Ok(data)
+        } else {
+            Err(Self::response_type_error(&req, &resp))
+        }
+    }
     async fn advance(&mut self, tick: GameTick, action: IValue) -> anyhow::Result<()> {
         let req = GameRequest::Advance { tick, action };
         let resp = self.request(&req).await?;
@@ -141,9 +384,58 @@ impl GameInstance for GameInstanceProcess {
             Err(Self::response_type_error(&req, &resp))
         }
     }
+    async fn undo(&mut self, to_tick: GameTick) -> Result<GameState, GameError> {
+        let req = GameRequest::Undo { to_tick };
+        let resp = self.request(&req).await?;
+        if let GameResponse::Undo(res) = resp {
+            res.map_err(GameError::Refused)
+        } else {
+            Err(Self::response_type_error(&req, &resp).into())
+        }
+    }
+    async fn resign(&mut self, player: i32) -> anyhow::Result<GameState> {
+        let req = GameRequest::Resign { player };
+        let resp = self.request(&req).await?;
+        if let GameResponse::Resign(state) = resp {
+            Ok(state)
+        } else {
+            Err(Self::response_type_error(&req, &resp))
+        }
+    }
+    async fn render_player_state(&mut self, player: PlayerView) -> anyhow::Result<IValue> {
+        let req = GameRequest::RenderPlayerState { player };
+        let resp = self.request(&req).await?;
+        if let GameResponse::RenderPlayerState(state) = resp {
+            Ok(state)
+        } else {
+            Err(Self::response_type_error(&req, &resp))
+        }
+    }
+    async fn render_diff(
+        &mut self,
+        player: PlayerView,
+        since_tick: GameTick,
+    ) -> anyhow::Result<Option<IValue>> {
+        let req = GameRequest::RenderDiff { player, since_tick };
+        let resp = self.request(&req).await?;
+        if let GameResponse::RenderDiff(patch) = resp {
+            Ok(patch)
+        } else {
+            Err(Self::response_type_error(&req, &resp))
+        }
+    }
+    async fn ping(&mut self) -> anyhow::Result<()> {
+        let req = GameRequest::Ping;
+        let resp = self.request(&req).await?;
+        if let GameResponse::Pong = resp {
+            Ok(())
+        } else {
+            Err(Self::response_type_error(&req, &resp))
+        }
+    }
 
     // Presentation-specific functionality
-    async fn render_console_ui(&mut self, player: i32) -> Result<ConsoleUi, GameError> {
+    async fn render_console_ui(&mut self, player: PlayerView) -> Result<ConsoleUi, GameError> {
         let req = GameRequest::RenderConsoleUi { player };
         let resp = self.request(&req).await?;
         if let GameResponse::RenderConsoleUi(ui) = resp {
@@ -169,3 +461,100 @@ impl GameInstance for GameInstanceProcess {
         }
     }
 }
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::{io::BufRead, os::unix::fs::PermissionsExt};
+
+    use super::*;
+
+    /// Set when the test binary is re-run as a game, which allocates this
+    /// many megabytes before answering its first request.
+    const ALLOCATE_VAR: &str = "PLAYFERROUS_TEST_ALLOCATE_MB";
+
+    fn setup(game_type: &str) -> GameSetup {
+        GameSetup {
+            game_type: game_type.into(),
+            num_players: 2,
+            seed: 0,
+            rules: IValue::NULL,
+            snapshot: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn game_over_its_memory_limit_fails() {
+        if let Ok(mb) = std::env::var(ALLOCATE_VAR) {
+            let mut line = String::new();
+            std::io::stdin().lock().read_line(&mut line).unwrap();
+            let request: serde_json::Value = serde_json::from_str(&line).unwrap();
+            let mb: usize = mb.parse().unwrap();
+            let buffer = vec![1u8; mb * 1024 * 1024];
+            assert_eq!(buffer.len(), mb * 1024 * 1024);
+            println!(
+                "{}",
+                serde_json::json!({ "id": request["id"], "message": "Initialize" })
+            );
+            return;
+        }
+
+        // Each game type re-runs this test as the game. The test harness's
+        // own output is skipped as invalid responses.
+        let dir = std::env::temp_dir().join(format!("playferrous-limits-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let exe = std::env::current_exe().unwrap();
+        for (game_type, mb) in [("small", 16), ("large", 1024)] {
+            let script = dir.join(game_type);
+            std::fs::write(
+                &script,
+                format!(
+                    "#!/bin/sh\n{ALLOCATE_VAR}={mb} exec '{}' --exact \
+                     tests::game_over_its_memory_limit_fails --test-threads=1 --nocapture --quiet\n",
+                    exe.display()
+                ),
+            )
+            .unwrap();
+            std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        let launcher = ProcessLauncherConfig {
+            path: dir.to_string_lossy().into_owned(),
+            pool_size: HashMap::new(),
+            memory_limit_mb: Some(256),
+            cpu_time_limit_s: None,
+        }
+        .start_launcher()
+        .await
+        .unwrap();
+
+        if let Err(e) = launcher.launch(setup("small")).await {
+            panic!("a game within its memory limit failed: {e:#}");
+        }
+        let Err(e) = launcher.launch(setup("large")).await else {
+            panic!("expected the game to exceed its memory limit");
+        };
+        assert!(e.to_string().contains("exited unexpectedly"), "{e}");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    #[tokio::test]
+    async fn only_executables_are_game_types() {
+        let dir = std::env::temp_dir().join(format!("playferrous-types-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("subdir")).unwrap();
+        for (name, mode) in [("game", 0o755), ("README.md", 0o644)] {
+            std::fs::write(dir.join(name), "").unwrap();
+            std::fs::set_permissions(dir.join(name), std::fs::Permissions::from_mode(mode))
+                .unwrap();
+        }
+        let launcher = ProcessLauncherConfig {
+            path: dir.to_string_lossy().into_owned(),
+            pool_size: HashMap::new(),
+            memory_limit_mb: None,
+            cpu_time_limit_s: None,
+        }
+        .start_launcher()
+        .await
+        .unwrap();
+
+        assert_eq!(launcher.supported_game_types().await.unwrap(), ["game"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,217 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::bail;
+use playferrous_types::{GameRequest, GameResponse, Tagged};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter},
+    process::{ChildStdin, ChildStdout},
+    sync::oneshot,
+};
+
+/// How many lines which aren't valid responses a game may print in a row
+/// before it's considered broken.
+const MAX_SKIPPED_LINES: usize = 16;
+
+/// Sends requests to a game process, tagging each with an ID so that several
+/// may be in flight at once. Responses are read by a background task, which
+/// matches them up with their requests, and stops once the game closes its
+/// stdout.
+pub struct Transport {
+    stdin: tokio::sync::Mutex<BufWriter<ChildStdin>>,
+    pending: Arc<Mutex<Pending>>,
+    next_id: AtomicU64,
+}
+
+#[derive(Default)]
+struct Pending {
+    senders: HashMap<u64, oneshot::Sender<GameResponse>>,
+    closed: Option<Closed>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Closed {
+    Exited,
+    InvalidResponses,
+}
+
+impl Transport {
+    pub fn new(stdin: ChildStdin, stdout: ChildStdout, game_type: String) -> Self {
+        let pending = Arc::new(Mutex::new(Pending::default()));
+        tokio::spawn(read_responses(
+            BufReader::new(stdout),
+            pending.clone(),
+            game_type,
+        ));
+        Self {
+            stdin: tokio::sync::Mutex::new(BufWriter::new(stdin)),
+            pending,
+            next_id: AtomicU64::new(0),
+        }
+    }
+    /// Returns `None` if the game closed its stdout, most likely because it
+    /// exited, before responding.
+    pub async fn request(&self, request: &GameRequest) -> anyhow::Result<Option<GameResponse>> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (s, r) = oneshot::channel();
+        {
+            let mut pending = self.pending.lock().unwrap();
+            if let Some(closed) = pending.closed {
+                return closed.into_result();
+            }
+            pending.senders.insert(id, s);
+        }
+        let mut line = serde_json::to_string(&Tagged {
+            id: Some(id),
+            message: request,
+        })?;
+        line.push('\n');
+        let res = async {
+            let mut stdin = self.stdin.lock().await;
+            stdin.write_all(line.as_bytes()).await?;
+            stdin.flush().await
+        }
+        .await;
+        if let Err(e) = res {
+            self.pending.lock().unwrap().senders.remove(&id);
+            return Err(e.into());
+        }
+        match r.await {
+            Ok(response) => Ok(Some(response)),
+            // The reader only drops senders once it has stopped
+            Err(_) => match self.pending.lock().unwrap().closed {
+                Some(closed) => closed.into_result(),
+                None => Ok(None),
+            },
+        }
+    }
+}
+
+impl Closed {
+    fn into_result<T>(self) -> anyhow::Result<Option<T>> {
+        match self {
+            Self::Exited => Ok(None),
+            Self::InvalidResponses => {
+                bail!("Game printed more than {MAX_SKIPPED_LINES} invalid responses in a row")
+            }
+        }
+    }
+}
+
+/// Games should log to stderr, which is passed through, but a stray line on
+/// stdout shouldn't bring the game down.
+async fn read_responses(
+    mut stdout: BufReader<ChildStdout>,
+    pending: Arc<Mutex<Pending>>,
+    game_type: String,
+) {
+    let mut buf = String::new();
+    let mut skipped = 0;
+    let closed = loop {
+        buf.clear();
+        match stdout.read_line(&mut buf).await {
+            Ok(0) | Err(_) => break Closed::Exited,
+            Ok(_) => {}
+        }
+        match serde_json::from_str::<Tagged<GameResponse>>(&buf) {
+            Ok(Tagged {
+                id: Some(id),
+                message,
+            }) => {
+                skipped = 0;
+                match pending.lock().unwrap().senders.remove(&id) {
+                    Some(s) => {
+                        let _ = s.send(message);
+                    }
+                    None => tracing::warn!(
+                        game_type = %game_type,
+                        "Ignoring response to unknown request {id}"
+                    ),
+                }
+            }
+            res => {
+                let reason = match res {
+                    Err(e) => e.to_string(),
+                    Ok(_) => "missing request ID".into(),
+                };
+                tracing::warn!(
+                    game_type = %game_type,
+                    "Skipping invalid response from game ({reason}): {}",
+                    buf.trim_end()
+                );
+                skipped += 1;
+                if skipped > MAX_SKIPPED_LINES {
+                    break Closed::InvalidResponses;
+                }
+            }
+        }
+    };
+    let mut pending = pending.lock().unwrap();
+    pending.closed = Some(closed);
+    pending.senders.clear();
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::process::Stdio;
+
+    use ijson::IValue;
+    use playferrous_types::GameTick;
+    use tokio::process::{Child, Command};
+
+    use super::*;
+
+    fn spawn_script(script: &str) -> (Child, Transport) {
+        let mut child = Command::new("sh")
+            .args(["-c", script])
+            .kill_on_drop(true)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        (child, Transport::new(stdin, stdout, "test".into()))
+    }
+    /// A game which answers the first request with `Pong`, after printing
+    /// some invalid lines.
+    fn spawn_game(invalid_lines: usize) -> (Child, Transport) {
+        spawn_script(&format!(
+            r#"read request; for i in $(seq {invalid_lines}); do echo not json; done; echo '{{"id":0,"message":"Pong"}}'; sleep 10"#
+        ))
+    }
+
+    #[tokio::test]
+    async fn invalid_lines_are_skipped() {
+        let (_child, transport) = spawn_game(MAX_SKIPPED_LINES);
+        let response = transport.request(&GameRequest::Ping).await.unwrap();
+        assert!(matches!(response, Some(GameResponse::Pong)));
+    }
+    #[tokio::test]
+    async fn too_many_invalid_lines_are_an_error() {
+        let (_child, transport) = spawn_game(MAX_SKIPPED_LINES + 1);
+        assert!(transport.request(&GameRequest::Ping).await.is_err());
+    }
+    #[tokio::test]
+    async fn responses_are_matched_to_requests_in_flight() {
+        // Only responds once both requests have arrived, and in reverse order
+        let (_child, transport) = spawn_script(
+            r#"read first; read second; echo '{"id":1,"message":"Advance"}'; echo '{"id":0,"message":"Pong"}'; sleep 10"#,
+        );
+        let advance = GameRequest::Advance {
+            tick: GameTick(1),
+            action: IValue::NULL,
+        };
+        let (ping, advance) = tokio::join!(
+            transport.request(&GameRequest::Ping),
+            transport.request(&advance),
+        );
+        assert!(matches!(ping.unwrap(), Some(GameResponse::Pong)));
+        assert!(matches!(advance.unwrap(), Some(GameResponse::Advance)));
+    }
+}
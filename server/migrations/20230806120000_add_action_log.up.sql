@@ -0,0 +1,10 @@
+CREATE TABLE action_log (
+    id BIGSERIAL PRIMARY KEY,
+    game_id BIGINT NOT NULL REFERENCES game ON DELETE CASCADE,
+    tick BIGINT NOT NULL,
+    -- NULL for actions not taken by a player, such as timeouts
+    player_index INT,
+    action JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX ON action_log(game_id, id);
@@ -0,0 +1 @@
+DROP INDEX message_search_idx;
@@ -0,0 +1 @@
+CREATE INDEX message_search_idx ON message USING GIN (to_tsvector('english', subject || ' ' || body));
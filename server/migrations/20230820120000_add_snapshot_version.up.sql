@@ -0,0 +1 @@
+ALTER TABLE game ADD COLUMN snapshot_version INT NOT NULL DEFAULT 0;
@@ -0,0 +1 @@
+ALTER TABLE game_proposal DROP COLUMN creator_id;
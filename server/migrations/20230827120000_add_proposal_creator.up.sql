@@ -0,0 +1 @@
+ALTER TABLE game_proposal ADD COLUMN creator_id BIGINT REFERENCES "user" ON DELETE SET NULL;
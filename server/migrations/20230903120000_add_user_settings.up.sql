@@ -0,0 +1,4 @@
+CREATE TABLE user_settings (
+    user_id BIGINT PRIMARY KEY REFERENCES "user" ON DELETE CASCADE,
+    turn_bell BOOLEAN NOT NULL DEFAULT TRUE
+);
@@ -0,0 +1 @@
+ALTER TABLE user_settings ADD COLUMN timezone TEXT;
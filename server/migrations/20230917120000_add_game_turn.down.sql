@@ -0,0 +1 @@
+ALTER TABLE game DROP COLUMN player_turn, DROP COLUMN deadline;
@@ -0,0 +1 @@
+ALTER TABLE game ADD COLUMN player_turn INT, ADD COLUMN deadline TIMESTAMPTZ;
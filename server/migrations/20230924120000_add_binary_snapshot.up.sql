@@ -0,0 +1 @@
+ALTER TABLE game ADD COLUMN snapshot_binary BYTEA;
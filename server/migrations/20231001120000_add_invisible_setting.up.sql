@@ -0,0 +1 @@
+ALTER TABLE user_settings ADD COLUMN invisible BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1 @@
+ALTER TABLE game_player DROP COLUMN resigned_at;
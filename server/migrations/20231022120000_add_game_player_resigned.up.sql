@@ -0,0 +1 @@
+ALTER TABLE game_player ADD COLUMN resigned_at TIMESTAMPTZ;
@@ -0,0 +1,10 @@
+CREATE TABLE proposal_template (
+    user_id BIGINT NOT NULL REFERENCES "user" ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    game_type TEXT NOT NULL,
+    min_players INT NOT NULL,
+    max_players INT NOT NULL,
+    rules JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, name)
+);
@@ -0,0 +1,10 @@
+CREATE TABLE user_rating (
+    user_id BIGINT NOT NULL REFERENCES "user" ON DELETE CASCADE,
+    game_type TEXT NOT NULL,
+    rating INT NOT NULL,
+    games_played INT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, game_type)
+);
+
+CREATE INDEX ON user_rating(game_type, rating DESC);
@@ -0,0 +1,2 @@
+DELETE FROM action_log WHERE resign;
+ALTER TABLE action_log DROP COLUMN resign;
@@ -0,0 +1,2 @@
+-- Resignations are logged alongside actions so that games can be replayed
+ALTER TABLE action_log ADD COLUMN resign BOOLEAN NOT NULL DEFAULT FALSE;
@@ -1,44 +1,107 @@
-use std::{convert::Infallible, sync::Arc, time::Duration};
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use aerosol::{Aero, Constructible};
 use async_trait::async_trait;
 use dashmap::{mapref::entry::Entry, DashMap};
 use futures::{stream::FuturesUnordered, StreamExt};
+use ijson::{IObject, IValue};
+use playferrous_launcher::LauncherError;
 use playferrous_presentation::{
     actor::Actor,
     bichannel::{bichannel, Bichannel},
-    ConnectionToPresentationMsg, CreateGameProposal, PresentationKind, PresentationToConnectionMsg,
-    SessionCommand, SessionEvent, SessionId, SessionInfo, SessionKind, TerminalSessionEvent,
-    UserId,
+    ConnectionToPresentationMsg, CreateGameProposal, GameId, GameProposalId, ListKind,
+    PresentationError, PresentationKind, PresentationToConnectionMsg, RequestId, SessionCommand,
+    SessionEvent, SessionId, SessionInfo, SessionKind, TerminalSessionEvent, UserId,
+    UserManagementError,
 };
+use playferrous_types::GameDescription;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
 
 use crate::{
     database::{
         self,
+        proposal::JoinOutcome,
+        request::AcceptOutcome,
         session::{Session, SessionType},
         TransactError,
     },
     game_manager::GameManager,
+    launchers::Launchers,
     proposal_manager::ProposalManager,
     utils::FutureIteratorExt,
+    Config, Shutdown,
 };
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConnectionConfig {
+    /// Further attempts to connect as the same user are refused.
+    pub max_connections_per_user: usize,
+    /// Connections which receive nothing for this long are closed.
+    pub idle_timeout_secs: u64,
+    /// A user's last-seen time is saved at most this often per connection.
+    pub last_seen_interval_secs: u64,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            max_connections_per_user: 8,
+            idle_timeout_secs: 30 * 60,
+            last_seen_interval_secs: 60,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum SystemToConnectionMsg {
     NewMessage,
+    YourTurn { game_id: GameId, bell: bool },
+    Announcement(String),
 }
 
+/// Distinguishes the connections of a user who is connected more than once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ConnectionId(pub(crate) u64);
+
+/// The longest announcement an administrator can send, in characters.
+const MAX_ANNOUNCEMENT_LENGTH: usize = 500;
+
+/// How long a broadcast waits for a connection which is keeping up.
+const BROADCAST_TIMEOUT: Duration = Duration::from_millis(500);
+/// Connections which miss this many broadcasts in a row are disconnected.
+const MAX_BROADCAST_FAILURES: u32 = 3;
+
 #[derive(Debug)]
 struct Connection {
     s: mpsc::Sender<SystemToConnectionMsg>,
+    /// Broadcasts missed in a row. Connections which have missed one aren't
+    /// waited for, so that a stuck client doesn't slow down every broadcast.
+    failures: Arc<AtomicU32>,
+}
+
+impl Connection {
+    fn is_healthy(&self) -> bool {
+        !self.s.is_closed() && self.failures.load(Ordering::Relaxed) < MAX_BROADCAST_FAILURES
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct ConnectionManager {
     aero: Aero,
     connections: Arc<DashMap<UserId, Vec<Connection>>>,
+    next_connection_id: Arc<AtomicU64>,
 }
 
 impl Constructible for ConnectionManager {
@@ -47,6 +110,7 @@ impl Constructible for ConnectionManager {
         Ok(Self {
             aero: aero.clone(),
             connections: Default::default(),
+            next_connection_id: Default::default(),
         })
     }
 }
@@ -54,7 +118,7 @@ impl Constructible for ConnectionManager {
 #[derive(Debug, Error)]
 enum ConnectionError {
     #[error("Present: {0}")]
-    Present(String),
+    Present(PresentationError),
     #[error(transparent)]
     Internal(#[from] anyhow::Error),
 }
@@ -71,21 +135,37 @@ impl ConnectionManager {
         user_id: UserId,
         kind: PresentationKind,
     ) -> anyhow::Result<Bichannel<PresentationToConnectionMsg, ConnectionToPresentationMsg>> {
+        let config: Arc<Config> = self.aero.obtain_async().await;
+        // Closed connections shouldn't count towards the limit
+        self.gc(user_id);
         let (presentation_bichannel, connection_bichannel) = bichannel(4);
         let (system_s, system_r) = mpsc::channel(4);
-        self.connections
-            .entry(user_id)
-            .or_default()
-            .push(Connection { s: system_s });
+        {
+            let mut connections = self.connections.entry(user_id).or_default();
+            if connections.len() >= config.connection.max_connections_per_user {
+                tracing::warn!("User {} has too many connections", user_id);
+                return Err(UserManagementError::TooManyConnections.into());
+            }
+            connections.push(Connection {
+                s: system_s,
+                failures: Default::default(),
+            });
+        }
+        let connection_id = ConnectionId(self.next_connection_id.fetch_add(1, Ordering::Relaxed));
         ConnectionActor {
             aero: self.aero.clone(),
             kind,
             user_id,
+            connection_id,
             presentation_bichannel,
             system_r,
             active_session: None,
+            auth: AuthContext::default(),
+            idle_timeout: Duration::from_secs(config.connection.idle_timeout_secs),
+            last_seen_interval: Duration::from_secs(config.connection.last_seen_interval_secs),
+            last_seen_saved_at: None,
         }
-        .spawn();
+        .spawn_child(&self.aero.obtain::<Shutdown>().0);
         Ok(connection_bichannel)
     }
 
@@ -103,31 +183,83 @@ impl ConnectionManager {
                     .flat_map(move |connections| {
                         connections
                             .iter()
-                            .map(move |connection| (user_id, connection.s.clone()))
+                            .map(move |connection| {
+                                (user_id, connection.s.clone(), connection.failures.clone())
+                            })
                             .collect::<Vec<_>>()
                     })
             })
             .collect();
         senders
             .iter()
-            .map(|(user_id, s)| s.send_timeout(msg_fn(*user_id), Duration::from_millis(500)))
-            .collect::<FuturesUnordered<_>>()
-            .for_each(|res| async move {
-                if let Err(e) = res {
-                    tracing::error!("broadcast: {}", e);
+            .map(|(user_id, s, failures)| {
+                let msg = msg_fn(*user_id);
+                async move {
+                    let res = if failures.load(Ordering::Relaxed) == 0 {
+                        s.send_timeout(msg, BROADCAST_TIMEOUT)
+                            .await
+                            .map_err(|e| e.to_string())
+                    } else {
+                        s.try_send(msg).map_err(|e| e.to_string())
+                    };
+                    match res {
+                        Ok(()) => failures.store(0, Ordering::Relaxed),
+                        Err(e) => {
+                            let count = failures.fetch_add(1, Ordering::Relaxed) + 1;
+                            tracing::warn!("broadcast to {user_id} failed ({count} in a row): {e}");
+                            if count == MAX_BROADCAST_FAILURES {
+                                tracing::warn!("Disconnecting user {user_id}, who can't keep up");
+                            }
+                        }
+                    }
                 }
             })
+            .collect::<FuturesUnordered<_>>()
+            .collect::<()>()
             .await;
+        // Dropping the sender of an unhealthy connection closes it
+        let mut user_ids: Vec<_> = senders.into_iter().map(|(user_id, ..)| user_id).collect();
+        user_ids.dedup();
+        for user_id in user_ids {
+            self.gc(user_id);
+        }
     }
 
     pub async fn send(&self, user_id: UserId, msg: SystemToConnectionMsg) {
         self.broadcast([user_id], |_| msg.clone()).await;
     }
 
+    /// Show a line to every user who is online.
+    pub async fn announce(&self, text: String) {
+        self.broadcast(self.online_user_ids(), |_| {
+            SystemToConnectionMsg::Announcement(text.clone())
+        })
+        .await;
+    }
+
+    /// The number of open connections for this user.
+    pub fn connection_count(&self, user_id: UserId) -> usize {
+        self.connections.get(&user_id).map_or(0, |connections| {
+            connections
+                .iter()
+                .filter(|conn| !conn.s.is_closed())
+                .count()
+        })
+    }
+
+    /// Users with at least one open connection.
+    pub fn online_user_ids(&self) -> Vec<UserId> {
+        self.connections
+            .iter()
+            .filter(|entry| entry.value().iter().any(|conn| !conn.s.is_closed()))
+            .map(|entry| *entry.key())
+            .collect()
+    }
+
     fn gc(&self, user_id: UserId) {
         if let Entry::Occupied(mut occ) = self.connections.entry(user_id) {
             let vec = occ.get_mut();
-            vec.retain(|conn| !conn.s.is_closed());
+            vec.retain(Connection::is_healthy);
             if vec.is_empty() {
                 occ.remove();
             }
@@ -151,21 +283,90 @@ pub enum SessionToConnectionMsg {
     UserEntered(SessionMember),
     UserExited(SessionMember),
     Event(SessionEvent),
+    History(Vec<SessionEvent>),
+    /// The session has ended, and the connection should leave it.
+    Closed {
+        reason: String,
+    },
+}
+
+/// The most recent lines shown in a session, so that those entering it can
+/// catch up on what they missed.
+#[derive(Debug, Default)]
+pub struct SessionHistory(VecDeque<SessionEvent>);
+
+impl SessionHistory {
+    /// Older events are forgotten.
+    const CAPACITY: usize = 100;
+    /// How many events are replayed on entering a session, and by default
+    /// when asked for.
+    pub const REPLAY: usize = 20;
+
+    pub fn push(&mut self, ev: &SessionEvent) {
+        // How input is read would be out of date by the time it's replayed
+        if let SessionEvent::Terminal(TerminalSessionEvent::Line(_)) = ev {
+            if self.0.len() == Self::CAPACITY {
+                self.0.pop_front();
+            }
+            self.0.push_back(ev.clone());
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    /// Up to `count` of the latest events, oldest first.
+    pub fn recent(&self, count: usize) -> Vec<SessionEvent> {
+        let skip = self.0.len().saturating_sub(count);
+        self.0.iter().skip(skip).cloned().collect()
+    }
 }
 
 #[derive(Debug)]
 struct ActiveSession {
-    session: Session,
+    /// `None` when spectating.
+    session: Option<Session>,
     bichannel: Bichannel<ConnectionToSessionMsg, SessionToConnectionMsg>,
 }
 
+const MAX_SEARCH_RESULTS: i64 = 50;
+const MAX_RECENT_OPPONENTS: i64 = 20;
+/// Lists are sent to the presentation this many items at a time.
+const LIST_CHUNK_SIZE: usize = 20;
+/// How many players are shown when asking for the ratings of a game type.
+const LEADERBOARD_SIZE: i64 = 20;
+
 struct ConnectionActor {
     aero: Aero,
     kind: PresentationKind,
     user_id: UserId,
+    connection_id: ConnectionId,
     presentation_bichannel: Bichannel<ConnectionToPresentationMsg, PresentationToConnectionMsg>,
     system_r: mpsc::Receiver<SystemToConnectionMsg>,
     active_session: Option<ActiveSession>,
+    auth: AuthContext,
+    idle_timeout: Duration,
+    last_seen_interval: Duration,
+    last_seen_saved_at: Option<tokio::time::Instant>,
+}
+
+/// What the user behind a connection is allowed to do. This is loaded when
+/// the connection starts, so changes only take effect once the user
+/// reconnects.
+#[derive(Debug, Clone, Copy, Default)]
+struct AuthContext {
+    is_admin: bool,
+}
+
+impl AuthContext {
+    fn require_admin(&self) -> Result<(), ConnectionError> {
+        if self.is_admin {
+            Ok(())
+        } else {
+            Err(ConnectionError::Present(
+                PresentationError::PermissionDenied,
+            ))
+        }
+    }
 }
 
 impl ConnectionActor {
@@ -173,8 +374,120 @@ impl ConnectionActor {
         let _ = self.presentation_bichannel.s.send(msg).await;
     }
     async fn propose(&mut self, proposal: CreateGameProposal) -> Result<(), ConnectionError> {
+        let launchers: Arc<Launchers> = self.aero.obtain_async().await;
+        let description = describe_game(&launchers, &proposal.game_type).await?;
+        let (min_players, max_players) = match proposal.num_players {
+            Some(n) if n < description.min_players || n > description.max_players => {
+                return Err(ConnectionError::Present(
+                    PresentationError::InvalidPlayerCount {
+                        game_type: proposal.game_type,
+                        min_players: description.min_players,
+                        max_players: description.max_players,
+                    },
+                ))
+            }
+            Some(n) => (n, n),
+            None => (description.min_players, description.max_players),
+        };
+        let proposal_id = transact!(ConnectionError, self.aero, |tx| {
+            Ok(database::proposal::create(
+                tx,
+                &proposal.game_type,
+                self.user_id,
+                min_players,
+                max_players,
+                proposal.is_public,
+            )
+            .await?
+            .id)
+        })?;
+        self.invite_all(proposal_id, proposal.invite).await
+    }
+    /// Invite each user to a newly created proposal.
+    async fn invite_all(
+        &mut self,
+        proposal_id: GameProposalId,
+        user_ids: Vec<UserId>,
+    ) -> Result<(), ConnectionError> {
+        for user_id in user_ids {
+            self.invite_to_proposal(proposal_id, user_id).await?;
+        }
+        Ok(())
+    }
+    async fn propose_from_template(
+        &mut self,
+        name: String,
+        is_public: bool,
+        invite: Vec<UserId>,
+    ) -> Result<(), ConnectionError> {
+        let launchers: Arc<Launchers> = self.aero.obtain_async().await;
+        let template = transact!(ConnectionError, self.aero, |tx| {
+            let template = database::proposal_template::get(tx, self.user_id, &name)
+                .await?
+                .ok_or_else(|| {
+                    ConnectionError::Present(PresentationError::UnknownTemplate(name.clone()))
+                })?;
+            Ok(template)
+        })?;
+        // The game may have changed since the template was saved. Checking
+        // may launch the game, so no transaction is held open.
+        let description = describe_game(&launchers, &template.game_type).await?;
+        if template.min_players < description.min_players
+            || template.max_players > description.max_players
+        {
+            return Err(ConnectionError::Present(
+                PresentationError::InvalidPlayerCount {
+                    game_type: template.game_type,
+                    min_players: description.min_players,
+                    max_players: description.max_players,
+                },
+            ));
+        }
+        validate_rules(&launchers, &template.game_type, template.rules.0.clone()).await?;
+        let proposal_id = transact!(ConnectionError, self.aero, |tx| {
+            let proposal = database::proposal::create(
+                tx,
+                &template.game_type,
+                self.user_id,
+                template.min_players,
+                template.max_players,
+                is_public,
+            )
+            .await?;
+            database::proposal::set_rules(tx, proposal.id, &template.rules.0).await?;
+            Ok(proposal.id)
+        })?;
+        self.invite_all(proposal_id, invite).await
+    }
+    async fn proposal_templates(&mut self) -> Result<(), ConnectionError> {
+        let templates = transact!(ConnectionError, self.aero, |tx| {
+            Ok(database::proposal_template::list_for_user(tx, self.user_id).await?)
+        })?;
+        self.send_to_presentation(ConnectionToPresentationMsg::ProposalTemplateList(templates))
+            .await;
+        Ok(())
+    }
+    async fn save_proposal_template(
+        &mut self,
+        proposal_id: GameProposalId,
+        name: String,
+    ) -> Result<(), ConnectionError> {
         transact!(ConnectionError, self.aero, |tx| {
-            database::proposal::create(tx, &proposal.game_type, self.user_id).await?;
+            let proposal = database::proposal::get_joined(tx, proposal_id, self.user_id)
+                .await?
+                .ok_or(ConnectionError::Present(PresentationError::NotJoined))?;
+            database::proposal_template::save(tx, self.user_id, &name, &proposal).await?;
+            Ok(())
+        })
+    }
+    async fn delete_proposal_template(&mut self, name: String) -> Result<(), ConnectionError> {
+        transact!(ConnectionError, self.aero, |tx| {
+            if !database::proposal_template::remove(tx, self.user_id, &name).await? {
+                return Err(ConnectionError::Present(PresentationError::UnknownTemplate(
+                    name.clone(),
+                ))
+                .into());
+            }
             Ok(())
         })
     }
@@ -187,30 +500,44 @@ impl ConnectionActor {
         Ok(())
     }
     async fn enter(&mut self, session_id: SessionId) -> Result<(), ConnectionError> {
+        if let Some(ActiveSession {
+            session: Some(session),
+            ..
+        }) = &self.active_session
+        {
+            if session.id == session_id {
+                return Err(ConnectionError::Present(
+                    PresentationError::AlreadyInSession,
+                ));
+            }
+        }
         let session = transact!(ConnectionError, self.aero, |tx| {
             Ok(
                 database::session::get_by_id_and_user(tx, session_id, self.user_id)
                     .await?
-                    .ok_or_else(|| ConnectionError::Present(format!("Invalid session ID\n")))?,
+                    .ok_or_else(|| {
+                        ConnectionError::Present(PresentationError::InvalidId("session".into()))
+                    })?,
             )
         })?;
 
-        let (kind, bichannel) = match session.type_ {
+        let (kind, bichannel, capabilities) = match session.type_ {
             SessionType::Game => {
                 let game_id = session.game_id.expect("Game ID must be present");
-                (
-                    SessionKind::Game(game_id),
-                    self.aero
-                        .obtain::<Arc<GameManager>>()
-                        .enter_session(
-                            game_id,
-                            session
-                                .game_player_index
-                                .expect("Player index must be present"),
-                            self.kind,
-                        )
-                        .await?,
-                )
+                let (bichannel, capabilities) = self
+                    .aero
+                    .obtain::<Arc<GameManager>>()
+                    .enter_session(
+                        game_id,
+                        self.user_id,
+                        self.connection_id,
+                        session
+                            .game_player_index
+                            .expect("Player index must be present"),
+                        self.kind,
+                    )
+                    .await?;
+                (SessionKind::Game(game_id), bichannel, Some(capabilities))
             }
             SessionType::GameProposal => {
                 let proposal_id = session
@@ -220,16 +547,113 @@ impl ConnectionActor {
                     SessionKind::GameProposal(proposal_id),
                     self.aero
                         .obtain::<ProposalManager>()
-                        .enter_session(proposal_id, self.user_id, self.kind)
+                        .enter_session(proposal_id, self.user_id, self.connection_id, self.kind)
                         .await?,
+                    None,
                 )
             }
         };
 
-        self.active_session = Some(ActiveSession { session, bichannel });
+        self.active_session = Some(ActiveSession {
+            session: Some(session),
+            bichannel,
+        });
         self.send_to_presentation(ConnectionToPresentationMsg::EnteredSession(SessionInfo {
-            id: session_id,
+            id: Some(session_id),
             kind,
+            capabilities,
+        }))
+        .await;
+
+        Ok(())
+    }
+    async fn watch(&mut self, game_id: GameId) -> Result<(), ConnectionError> {
+        transact!(ConnectionError, self.aero, |tx| {
+            if database::game::is_watchable(tx, game_id, self.user_id).await? {
+                Ok(())
+            } else {
+                Err(ConnectionError::Present(PresentationError::InvalidId("game".into())).into())
+            }
+        })?;
+        let (bichannel, capabilities) = self
+            .aero
+            .obtain::<Arc<GameManager>>()
+            .enter_session_spectator(game_id, self.user_id, self.connection_id, self.kind)
+            .await?;
+
+        self.active_session = Some(ActiveSession {
+            session: None,
+            bichannel,
+        });
+        self.send_to_presentation(ConnectionToPresentationMsg::EnteredSession(SessionInfo {
+            id: None,
+            kind: SessionKind::GameSpectator(game_id),
+            capabilities: Some(capabilities),
+        }))
+        .await;
+
+        Ok(())
+    }
+    async fn game_result(&mut self, game_id: GameId) -> Result<(), ConnectionError> {
+        let outcome = transact!(ConnectionError, self.aero, |tx| {
+            Ok(database::game::get_result(tx, game_id, self.user_id).await?)
+        })?
+        .ok_or(ConnectionError::Present(PresentationError::InvalidId(
+            "game".into(),
+        )))?;
+        if outcome.completed_at.is_none() {
+            return Err(ConnectionError::Present(PresentationError::GameNotComplete));
+        }
+        self.send_to_presentation(ConnectionToPresentationMsg::GameResult {
+            game_id,
+            players: outcome.players,
+        })
+        .await;
+        Ok(())
+    }
+    async fn recent_opponents(&mut self) -> Result<(), ConnectionError> {
+        let opponents = transact!(ConnectionError, self.aero, |tx| {
+            Ok(database::game::recent_opponents(tx, self.user_id, MAX_RECENT_OPPONENTS).await?)
+        })?;
+        self.send_to_presentation(ConnectionToPresentationMsg::RecentOpponents(opponents))
+            .await;
+        Ok(())
+    }
+    async fn ratings(&mut self, game_type: Option<String>) -> Result<(), ConnectionError> {
+        let ratings = transact!(ConnectionError, self.aero, |tx| {
+            Ok(match &game_type {
+                Some(game_type) => {
+                    database::rating::leaderboard(tx, game_type, LEADERBOARD_SIZE).await?
+                }
+                None => database::rating::list_for_user(tx, self.user_id).await?,
+            })
+        })?;
+        self.send_to_presentation(ConnectionToPresentationMsg::Ratings { game_type, ratings })
+            .await;
+        Ok(())
+    }
+    async fn observe(&mut self, game_id: GameId) -> Result<(), ConnectionError> {
+        transact!(ConnectionError, self.aero, |tx| {
+            if database::game::get(tx, game_id).await?.is_some() {
+                Ok(())
+            } else {
+                Err(ConnectionError::Present(PresentationError::InvalidId("game".into())).into())
+            }
+        })?;
+        let (bichannel, capabilities) = self
+            .aero
+            .obtain::<Arc<GameManager>>()
+            .observe(game_id, self.user_id, self.kind)
+            .await?;
+
+        self.active_session = Some(ActiveSession {
+            session: None,
+            bichannel,
+        });
+        self.send_to_presentation(ConnectionToPresentationMsg::EnteredSession(SessionInfo {
+            id: None,
+            kind: SessionKind::GameObserver(game_id),
+            capabilities: Some(capabilities),
         }))
         .await;
 
@@ -241,13 +665,242 @@ impl ConnectionActor {
                 .await;
             Ok(())
         } else {
-            Err(ConnectionError::Present("No active session".into()))
+            Err(ConnectionError::Present(PresentationError::NotInSession))
+        }
+    }
+    async fn join(&mut self, proposal_id: GameProposalId) -> Result<(), ConnectionError> {
+        let proposal_manager = self.aero.obtain::<ProposalManager>();
+        if proposal_manager.is_kicked(proposal_id, self.user_id) {
+            return Err(ConnectionError::Present(PresentationError::RecentlyKicked));
+        }
+        let outcome = transact!(ConnectionError, self.aero, |tx| {
+            Ok(database::proposal::join(tx, proposal_id, self.user_id).await?)
+        })?;
+        match outcome {
+            JoinOutcome::Joined => {}
+            JoinOutcome::AlreadyJoined => {
+                return Err(ConnectionError::Present(PresentationError::AlreadyJoined))
+            }
+            JoinOutcome::Full => {
+                return Err(ConnectionError::Present(PresentationError::ProposalFull))
+            }
+            JoinOutcome::NotFound => {
+                return Err(ConnectionError::Present(PresentationError::InvalidId(
+                    "proposal".into(),
+                )))
+            }
+        }
+        proposal_manager
+            .membership_changed(proposal_id, self.user_id, true)
+            .await;
+        Ok(())
+    }
+    async fn leave(&mut self, proposal_id: GameProposalId) -> Result<(), ConnectionError> {
+        let left = transact!(ConnectionError, self.aero, |tx| {
+            Ok(database::proposal::leave(tx, proposal_id, self.user_id).await?)
+        })?;
+        if !left {
+            return Err(ConnectionError::Present(PresentationError::NotJoined));
         }
+        self.aero
+            .obtain::<ProposalManager>()
+            .membership_changed(proposal_id, self.user_id, false)
+            .await;
+        Ok(())
+    }
+    /// Cancel a proposal. Only its creator may do this.
+    async fn withdraw(&mut self, proposal_id: GameProposalId) -> Result<(), ConnectionError> {
+        transact!(ConnectionError, self.aero, |tx| {
+            let proposal = database::proposal::get_joined(tx, proposal_id, self.user_id)
+                .await?
+                .ok_or(ConnectionError::Present(PresentationError::NotJoined))?;
+            if proposal.creator_id != Some(self.user_id) {
+                return Err(ConnectionError::Present(PresentationError::NotProposalCreator).into());
+            }
+            database::proposal::delete(tx, proposal_id).await?;
+            Ok(())
+        })?;
+        self.aero
+            .obtain::<ProposalManager>()
+            .close(
+                proposal_id,
+                "This proposal has been withdrawn by its creator.".into(),
+            )
+            .await;
+        Ok(())
+    }
+    async fn kick_from_proposal(
+        &mut self,
+        proposal_id: GameProposalId,
+        user_id: UserId,
+    ) -> Result<(), ConnectionError> {
+        if user_id == self.user_id {
+            return Err(ConnectionError::Present(PresentationError::CannotKickSelf));
+        }
+        transact!(ConnectionError, self.aero, |tx| {
+            let proposal = database::proposal::get_joined(tx, proposal_id, self.user_id)
+                .await?
+                .ok_or(ConnectionError::Present(PresentationError::NotJoined))?;
+            if proposal.creator_id != Some(self.user_id) {
+                return Err(ConnectionError::Present(PresentationError::NotProposalCreator).into());
+            }
+            if !database::proposal::leave(tx, proposal_id, user_id).await? {
+                return Err(ConnectionError::Present(PresentationError::InvalidId(
+                    "member".into(),
+                ))
+                .into());
+            }
+            Ok(())
+        })?;
+        self.aero
+            .obtain::<ProposalManager>()
+            .kick(proposal_id, user_id)
+            .await;
+        Ok(())
+    }
+    async fn invite_to_proposal(
+        &mut self,
+        proposal_id: GameProposalId,
+        user_id: UserId,
+    ) -> Result<(), ConnectionError> {
+        transact!(ConnectionError, self.aero, |tx| {
+            let proposal = database::proposal::get_joined(tx, proposal_id, self.user_id)
+                .await?
+                .ok_or(ConnectionError::Present(PresentationError::NotJoined))?;
+            let users = database::user::get_by_ids(tx, [self.user_id, user_id]).await?;
+            let (Some(from), Some(_)) = (users.get(&self.user_id), users.get(&user_id)) else {
+                return Err(
+                    ConnectionError::Present(PresentationError::InvalidId("user".into())).into(),
+                );
+            };
+            let request_id =
+                database::request::invite_to_proposal(tx, self.user_id, user_id, proposal_id)
+                    .await?;
+            database::message::send_to_user(
+                tx,
+                user_id,
+                Some(self.user_id),
+                format!("Invitation to play {}", proposal.game_type),
+                format!(
+                    "{} invited you to join proposal {proposal_id} for a game of {}. Use \
+                     `accept {request_id}` to join.",
+                    from.username, proposal.game_type
+                ),
+                Some(request_id),
+            )
+            .await?;
+            Ok(())
+        })
+    }
+    async fn proposal_rules(&mut self, proposal_id: GameProposalId) -> Result<(), ConnectionError> {
+        let proposal = transact!(ConnectionError, self.aero, |tx| {
+            Ok(
+                database::proposal::get_joined(tx, proposal_id, self.user_id)
+                    .await?
+                    .ok_or(ConnectionError::Present(PresentationError::NotJoined))?,
+            )
+        })?;
+        let rules = serde_json::to_string_pretty(&proposal.rules.0).map_err(anyhow::Error::from)?;
+        self.send_to_presentation(ConnectionToPresentationMsg::ProposalRules {
+            proposal_id,
+            rules,
+        })
+        .await;
+        Ok(())
+    }
+    async fn set_proposal_rules(
+        &mut self,
+        proposal_id: GameProposalId,
+        rules: String,
+    ) -> Result<(), ConnectionError> {
+        let rules: IValue = serde_json::from_str(&rules).map_err(|e| {
+            ConnectionError::Present(PresentationError::InvalidRules(e.to_string()))
+        })?;
+        self.update_proposal_rules(proposal_id, |_| Ok(rules)).await
+    }
+    async fn edit_proposal_rule(
+        &mut self,
+        proposal_id: GameProposalId,
+        key: String,
+        value: String,
+    ) -> Result<(), ConnectionError> {
+        self.update_proposal_rules(proposal_id, |rules| {
+            edit_rule(rules, key, value)
+                .map_err(|e| ConnectionError::Present(PresentationError::InvalidRules(e)))
+        })
+        .await
+    }
+    /// Change the rules of a proposal, on behalf of its creator, and let
+    /// everyone in its lobby know.
+    async fn update_proposal_rules(
+        &mut self,
+        proposal_id: GameProposalId,
+        update: impl FnOnce(IValue) -> Result<IValue, ConnectionError> + Send,
+    ) -> Result<(), ConnectionError> {
+        let launchers: Arc<Launchers> = self.aero.obtain_async().await;
+        let proposal = transact!(ConnectionError, self.aero, |tx| {
+            let proposal = database::proposal::get_joined(tx, proposal_id, self.user_id)
+                .await?
+                .ok_or(ConnectionError::Present(PresentationError::NotJoined))?;
+            if proposal.creator_id != Some(self.user_id) {
+                return Err(ConnectionError::Present(PresentationError::NotProposalCreator).into());
+            }
+            Ok(proposal)
+        })?;
+        let rules = update(proposal.rules.0)?;
+        // Validating may launch the game, so no transaction is held open
+        validate_rules(&launchers, &proposal.game_type, rules.clone()).await?;
+        transact!(ConnectionError, self.aero, |tx| {
+            Ok(database::proposal::set_rules(tx, proposal_id, &rules).await?)
+        })?;
+        self.aero
+            .obtain::<ProposalManager>()
+            .rules_changed(proposal_id, self.user_id, rules)
+            .await;
+        Ok(())
+    }
+    async fn start_proposal(&mut self, proposal_id: GameProposalId) -> Result<(), ConnectionError> {
+        let launchers: Arc<Launchers> = self.aero.obtain_async().await;
+        let proposal = transact!(ConnectionError, self.aero, |tx| {
+            let proposal = database::proposal::get_joined(tx, proposal_id, self.user_id)
+                .await?
+                .ok_or(ConnectionError::Present(PresentationError::NotJoined))?;
+            Ok(proposal)
+        })?;
+        // The game may have changed since the rules were set. Validating may
+        // launch the game, so no transaction is held open.
+        validate_rules(&launchers, &proposal.game_type, proposal.rules.0.clone()).await?;
+        let game_id = transact!(ConnectionError, self.aero, |tx| {
+            // Read again, in case anyone joined or left in the meantime
+            let proposal = database::proposal::get_joined(tx, proposal_id, self.user_id)
+                .await?
+                .ok_or(ConnectionError::Present(PresentationError::NotJoined))?;
+            let players = database::proposal::members(tx, proposal_id).await?;
+            if players.len() < usize::try_from(proposal.min_players).unwrap_or(0) {
+                return Err(
+                    ConnectionError::Present(PresentationError::NotEnoughPlayers(
+                        proposal.min_players,
+                    ))
+                    .into(),
+                );
+            }
+            let seed = rand::random();
+            Ok(database::game::create_from_proposal(tx, &proposal, &players, seed).await?)
+        })?;
+        self.aero
+            .obtain::<ProposalManager>()
+            .close(proposal_id, format!("The game has started: {game_id}"))
+            .await;
+        Ok(())
     }
     async fn proposals(&mut self) -> Result<(), ConnectionError> {
-        let proposals = transact!(ConnectionError, self.aero, |tx| {
+        let mut proposals = transact!(ConnectionError, self.aero, |tx| {
             Ok(database::proposal::list_for_user(tx, self.user_id).await?)
         })?;
+        let launchers: Arc<Launchers> = self.aero.obtain_async().await;
+        for proposal in &mut proposals {
+            proposal.display_name = launchers.display_name(&proposal.game_type).await;
+        }
         self.send_to_presentation(ConnectionToPresentationMsg::ProposalList(proposals))
             .await;
         Ok(())
@@ -256,24 +909,269 @@ impl ConnectionActor {
         let messages = transact!(ConnectionError, self.aero, |tx| {
             Ok(database::message::list_for_user(tx, self.user_id).await?)
         })?;
+        self.send_to_presentation(ConnectionToPresentationMsg::ListBegin {
+            kind: ListKind::Messages,
+            total: messages.len(),
+        })
+        .await;
+        for chunk in messages.chunks(LIST_CHUNK_SIZE) {
+            self.send_to_presentation(ConnectionToPresentationMsg::MessageChunk(chunk.to_vec()))
+                .await;
+        }
+        self.send_to_presentation(ConnectionToPresentationMsg::ListEnd)
+            .await;
+        Ok(())
+    }
+    async fn search_messages(&mut self, query: String) -> Result<(), ConnectionError> {
+        let messages = transact!(ConnectionError, self.aero, |tx| {
+            Ok(
+                database::message::search_for_user(tx, self.user_id, &query, MAX_SEARCH_RESULTS)
+                    .await?,
+            )
+        })?;
         self.send_to_presentation(ConnectionToPresentationMsg::MessageList(messages))
             .await;
         Ok(())
     }
+    /// Let the user know about anything waiting for them when they connect.
+    async fn greet(&mut self) -> Result<(), ConnectionError> {
+        let is_admin = transact!(ConnectionError, self.aero, |tx| {
+            Ok(database::user::is_admin(tx, self.user_id).await?)
+        })?;
+        self.auth = AuthContext { is_admin };
+        self.touch_last_seen().await?;
+        self.settings().await?;
+        let games = transact!(ConnectionError, self.aero, |tx| {
+            Ok(database::game::list_active_for_user(tx, self.user_id).await?)
+        })?;
+        self.send_to_presentation(ConnectionToPresentationMsg::ActiveGames(games))
+            .await;
+        Ok(())
+    }
+    /// Writes are debounced so that a busy connection doesn't update the
+    /// user on every message.
+    async fn touch_last_seen(&mut self) -> Result<(), ConnectionError> {
+        let now = tokio::time::Instant::now();
+        if self
+            .last_seen_saved_at
+            .is_some_and(|saved_at| now < saved_at + self.last_seen_interval)
+        {
+            return Ok(());
+        }
+        transact!(ConnectionError, self.aero, |tx| {
+            Ok(database::user::touch_last_seen(tx, self.user_id).await?)
+        })?;
+        self.last_seen_saved_at = Some(now);
+        Ok(())
+    }
+    async fn online_users(&mut self) -> Result<(), ConnectionError> {
+        let user_ids = self.aero.obtain::<ConnectionManager>().online_user_ids();
+        let users = transact!(ConnectionError, self.aero, |tx| {
+            Ok(database::user::list_visible(tx, self.user_id, &user_ids).await?)
+        })?;
+        self.send_to_presentation(ConnectionToPresentationMsg::OnlineUsers(users))
+            .await;
+        Ok(())
+    }
+    async fn announce(&mut self, text: String) -> Result<(), ConnectionError> {
+        if text.chars().count() > MAX_ANNOUNCEMENT_LENGTH {
+            return Err(ConnectionError::Present(
+                PresentationError::AnnouncementTooLong(MAX_ANNOUNCEMENT_LENGTH),
+            ));
+        }
+        tracing::info!(user_id = %self.user_id, %text, "Sending announcement");
+        self.aero.obtain::<ConnectionManager>().announce(text).await;
+        Ok(())
+    }
+    async fn game_types(&mut self) -> Result<(), ConnectionError> {
+        let launchers: Arc<Launchers> = self.aero.obtain_async().await;
+        let catalog = launchers.catalog().await;
+        self.send_to_presentation(ConnectionToPresentationMsg::GameTypeList(catalog))
+            .await;
+        Ok(())
+    }
+    async fn launchers(&mut self) -> Result<(), ConnectionError> {
+        let launchers: Arc<Launchers> = self.aero.obtain_async().await;
+        let infos = launchers.list().await;
+        self.send_to_presentation(ConnectionToPresentationMsg::LauncherList(infos))
+            .await;
+        Ok(())
+    }
+    async fn games(&mut self) -> Result<(), ConnectionError> {
+        let mut games = transact!(ConnectionError, self.aero, |tx| {
+            Ok(database::game::list_active_for_user(tx, self.user_id).await?)
+        })?;
+        let launchers: Arc<Launchers> = self.aero.obtain_async().await;
+        for game in &mut games {
+            game.display_name = launchers.display_name(&game.game_type).await;
+        }
+        self.send_to_presentation(ConnectionToPresentationMsg::GameList(games))
+            .await;
+        Ok(())
+    }
+    async fn settings(&mut self) -> Result<(), ConnectionError> {
+        let settings = transact!(ConnectionError, self.aero, |tx| {
+            Ok(database::user::get_settings(tx, self.user_id).await?)
+        })?;
+        self.send_to_presentation(ConnectionToPresentationMsg::Settings(settings))
+            .await;
+        Ok(())
+    }
+    async fn set_setting(&mut self, key: String, value: String) -> Result<(), ConnectionError> {
+        let settings = transact!(ConnectionError, self.aero, |tx| {
+            let mut settings = database::user::get_settings(tx, self.user_id).await?;
+            settings
+                .set(&key, &value)
+                .map_err(ConnectionError::Present)?;
+            database::user::set_settings(tx, self.user_id, &settings).await?;
+            Ok(settings)
+        })?;
+        self.send_to_presentation(ConnectionToPresentationMsg::Settings(settings))
+            .await;
+        Ok(())
+    }
+    async fn accept_request(&mut self, request_id: RequestId) -> Result<(), ConnectionError> {
+        let outcome = transact!(ConnectionError, self.aero, |tx| {
+            let outcome = database::request::accept(tx, request_id, self.user_id)
+                .await?
+                .ok_or_else(|| {
+                    ConnectionError::Present(PresentationError::InvalidId("request".into()))
+                })?;
+            Ok(outcome)
+        })?;
+        match outcome {
+            AcceptOutcome::Accepted(_) => {}
+            AcceptOutcome::JoinedProposal(request) => {
+                let proposal_id = request
+                    .game_proposal_id
+                    .expect("Proposal ID must be present");
+                self.aero
+                    .obtain::<ProposalManager>()
+                    .membership_changed(proposal_id, self.user_id, true)
+                    .await;
+            }
+            AcceptOutcome::ProposalUnavailable(JoinOutcome::Full) => {
+                return Err(ConnectionError::Present(PresentationError::ProposalFull))
+            }
+            AcceptOutcome::ProposalUnavailable(_) => {
+                return Err(ConnectionError::Present(PresentationError::InvalidId(
+                    "proposal".into(),
+                )))
+            }
+        }
+        Ok(())
+    }
+    async fn reject_request(&mut self, request_id: RequestId) -> Result<(), ConnectionError> {
+        transact!(ConnectionError, self.aero, |tx| {
+            database::request::reject(tx, request_id, self.user_id)
+                .await?
+                .ok_or_else(|| {
+                    ConnectionError::Present(PresentationError::InvalidId("request".into()))
+                })?;
+            Ok(())
+        })
+    }
+    async fn cancel_request(&mut self, request_id: RequestId) -> Result<(), ConnectionError> {
+        transact!(ConnectionError, self.aero, |tx| {
+            database::request::cancel(tx, request_id, self.user_id)
+                .await?
+                .ok_or_else(|| {
+                    ConnectionError::Present(PresentationError::InvalidId("request".into()))
+                })?;
+            Ok(())
+        })
+    }
+    async fn requests(&mut self) -> Result<(), ConnectionError> {
+        let requests = transact!(ConnectionError, self.aero, |tx| {
+            Ok(database::request::list_pending(tx, self.user_id).await?)
+        })?;
+        self.send_to_presentation(ConnectionToPresentationMsg::RequestList(requests))
+            .await;
+        Ok(())
+    }
     #[tracing::instrument(skip(self))]
     async fn handle_presentation_msg(
         &mut self,
         msg: PresentationToConnectionMsg,
     ) -> Result<(), ConnectionError> {
+        self.touch_last_seen().await?;
+        if msg.requires_admin() {
+            self.auth.require_admin()?;
+        }
         match msg {
-            PresentationToConnectionMsg::ListGames => todo!(),
+            PresentationToConnectionMsg::ListGames => self.games().await?,
+            PresentationToConnectionMsg::ListOnlineUsers => self.online_users().await?,
+            PresentationToConnectionMsg::ListLaunchers => self.launchers().await?,
+            PresentationToConnectionMsg::ListGameTypes => self.game_types().await?,
             PresentationToConnectionMsg::ListProposals => self.proposals().await?,
             PresentationToConnectionMsg::ListSessions => self.sessions().await?,
             PresentationToConnectionMsg::ListMessages => self.messages().await?,
+            PresentationToConnectionMsg::SearchMessages(query) => {
+                self.search_messages(query).await?
+            }
             PresentationToConnectionMsg::Propose(proposal) => self.propose(proposal).await?,
-            PresentationToConnectionMsg::Withdraw(_) => todo!(),
+            PresentationToConnectionMsg::ProposeFromTemplate {
+                name,
+                is_public,
+                invite,
+            } => self.propose_from_template(name, is_public, invite).await?,
+            PresentationToConnectionMsg::ListProposalTemplates => self.proposal_templates().await?,
+            PresentationToConnectionMsg::SaveProposalTemplate { proposal_id, name } => {
+                self.save_proposal_template(proposal_id, name).await?
+            }
+            PresentationToConnectionMsg::DeleteProposalTemplate(name) => {
+                self.delete_proposal_template(name).await?
+            }
+            PresentationToConnectionMsg::Withdraw(proposal_id) => {
+                self.withdraw(proposal_id).await?
+            }
+            PresentationToConnectionMsg::Join(proposal_id) => self.join(proposal_id).await?,
+            PresentationToConnectionMsg::Leave(proposal_id) => self.leave(proposal_id).await?,
+            PresentationToConnectionMsg::GetProposalRules(proposal_id) => {
+                self.proposal_rules(proposal_id).await?
+            }
+            PresentationToConnectionMsg::SetProposalRules { proposal_id, rules } => {
+                self.set_proposal_rules(proposal_id, rules).await?
+            }
+            PresentationToConnectionMsg::EditProposalRule {
+                proposal_id,
+                key,
+                value,
+            } => self.edit_proposal_rule(proposal_id, key, value).await?,
+            PresentationToConnectionMsg::KickFromProposal {
+                proposal_id,
+                user_id,
+            } => self.kick_from_proposal(proposal_id, user_id).await?,
+            PresentationToConnectionMsg::InviteToProposal {
+                proposal_id,
+                user_id,
+            } => self.invite_to_proposal(proposal_id, user_id).await?,
+            PresentationToConnectionMsg::StartProposal(proposal_id) => {
+                self.start_proposal(proposal_id).await?
+            }
             PresentationToConnectionMsg::Enter(session_id) => self.enter(session_id).await?,
+            PresentationToConnectionMsg::Watch(game_id) => self.watch(game_id).await?,
+            PresentationToConnectionMsg::GetGameResult(game_id) => {
+                self.game_result(game_id).await?
+            }
+            PresentationToConnectionMsg::ListRecentOpponents => self.recent_opponents().await?,
+            PresentationToConnectionMsg::GetRatings(game_type) => self.ratings(game_type).await?,
+            PresentationToConnectionMsg::Observe(game_id) => self.observe(game_id).await?,
+            PresentationToConnectionMsg::Announce(text) => self.announce(text).await?,
             PresentationToConnectionMsg::Exit => self.exit().await?,
+            PresentationToConnectionMsg::ListRequests => self.requests().await?,
+            PresentationToConnectionMsg::CancelRequest(request_id) => {
+                self.cancel_request(request_id).await?
+            }
+            PresentationToConnectionMsg::AcceptRequest(request_id) => {
+                self.accept_request(request_id).await?
+            }
+            PresentationToConnectionMsg::RejectRequest(request_id) => {
+                self.reject_request(request_id).await?
+            }
+            PresentationToConnectionMsg::SetSetting { key, value } => {
+                self.set_setting(key, value).await?
+            }
             PresentationToConnectionMsg::SessionCommand(cmd) => {
                 if let Some(session) = &mut self.active_session {
                     let _ = session
@@ -308,6 +1206,17 @@ impl ConnectionActor {
                 self.send_to_presentation(ConnectionToPresentationMsg::SessionEvent(ev))
                     .await;
             }
+            SessionToConnectionMsg::History(events) => {
+                self.send_to_presentation(ConnectionToPresentationMsg::SessionHistory(events))
+                    .await;
+            }
+            SessionToConnectionMsg::Closed { reason } => {
+                self.send_to_presentation(ConnectionToPresentationMsg::SessionEvent(
+                    SessionEvent::Terminal(TerminalSessionEvent::Line(reason)),
+                ))
+                .await;
+                self.exit().await?;
+            }
         }
         Ok(())
     }
@@ -317,22 +1226,60 @@ impl ConnectionActor {
         msg: SystemToConnectionMsg,
     ) -> Result<(), ConnectionError> {
         match msg {
-            SystemToConnectionMsg::NewMessage => todo!(),
+            SystemToConnectionMsg::NewMessage => {
+                self.send_to_presentation(ConnectionToPresentationMsg::NewMessage)
+                    .await;
+                Ok(())
+            }
+            SystemToConnectionMsg::YourTurn { game_id, bell } => {
+                self.send_to_presentation(ConnectionToPresentationMsg::YourTurn { game_id, bell })
+                    .await;
+                Ok(())
+            }
+            SystemToConnectionMsg::Announcement(text) => {
+                self.send_to_presentation(ConnectionToPresentationMsg::Announcement(text))
+                    .await;
+                Ok(())
+            }
         }
     }
 }
 
 #[async_trait]
 impl Actor for ConnectionActor {
-    async fn run(mut self) -> anyhow::Result<()> {
+    fn span(&self) -> tracing::Span {
+        tracing::info_span!(
+            "connection",
+            user_id = %self.user_id,
+            connection_id = ?self.connection_id
+        )
+    }
+    async fn run(mut self, shutdown: CancellationToken) -> anyhow::Result<()> {
+        if let Err(ConnectionError::Internal(e)) = self.greet().await {
+            return Err(e);
+        }
+        let idle = tokio::time::sleep(self.idle_timeout);
+        tokio::pin!(idle);
+        // Only the presentation and the session count as activity, so that
+        // a connection which just receives announcements still idles out
         loop {
             let res = tokio::select! {
                 biased;
+                _ = shutdown.cancelled() => break,
+                _ = &mut idle => {
+                    tracing::info!("Closing idle connection for user {}", self.user_id);
+                    self.send_to_presentation(ConnectionToPresentationMsg::Error(
+                        PresentationError::IdleTimeout,
+                    ))
+                    .await;
+                    break;
+                },
                 maybe_msg = self.system_r.recv() => {
                     let Some(msg) = maybe_msg else { break };
                     self.handle_system_msg(msg).await
                 },
                 maybe_msg = self.active_session.as_mut().map(|session| session.bichannel.r.recv()).select() => {
+                    idle.as_mut().reset(tokio::time::Instant::now() + self.idle_timeout);
                     if let Some(msg) = maybe_msg {
                         self.handle_session_msg(msg).await
                     } else {
@@ -341,6 +1288,7 @@ impl Actor for ConnectionActor {
                 },
                 maybe_msg = self.presentation_bichannel.r.recv() => {
                     let Some(msg) = maybe_msg else { break };
+                    idle.as_mut().reset(tokio::time::Instant::now() + self.idle_timeout);
                     self.handle_presentation_msg(msg).await
                 },
             };
@@ -363,3 +1311,49 @@ impl Drop for ConnectionActor {
         self.aero.obtain::<ConnectionManager>().gc(self.user_id);
     }
 }
+
+/// Set a top-level rule, treating the rules as an empty object if none have
+/// been set yet.
+fn edit_rule(mut rules: IValue, key: String, value: String) -> Result<IValue, String> {
+    if rules.is_null() {
+        rules = IObject::new().into();
+    }
+    // Values which aren't valid JSON are taken to be strings, so that they
+    // needn't be quoted
+    let value = serde_json::from_str(&value).unwrap_or_else(|_| IValue::from(value));
+    rules
+        .as_object_mut()
+        .ok_or("The rules are not a JSON object")?
+        .insert(key, value);
+    Ok(rules)
+}
+
+async fn describe_game(
+    launchers: &Launchers,
+    game_type: &str,
+) -> Result<GameDescription, ConnectionError> {
+    match launchers.describe(game_type).await {
+        Ok(description) => Ok(description),
+        Err(LauncherError::UnknownGameType) => Err(ConnectionError::Present(
+            PresentationError::UnknownGameType(game_type.into()),
+        )),
+        Err(e) => Err(anyhow::Error::from(e).into()),
+    }
+}
+
+async fn validate_rules(
+    launchers: &Launchers,
+    game_type: &str,
+    rules: IValue,
+) -> Result<(), ConnectionError> {
+    match launchers.validate_rules(game_type, rules).await {
+        Ok(()) => Ok(()),
+        Err(LauncherError::InvalidRules(e)) => {
+            Err(ConnectionError::Present(PresentationError::InvalidRules(e)))
+        }
+        Err(LauncherError::UnknownGameType) => Err(ConnectionError::Present(
+            PresentationError::UnknownGameType(game_type.into()),
+        )),
+        Err(e) => Err(anyhow::Error::from(e).into()),
+    }
+}
@@ -5,11 +5,44 @@ use async_trait::async_trait;
 use sqlx::{postgres::PgPoolOptions, PgPool, Postgres, Transaction};
 use thiserror::Error;
 
+pub mod game;
 pub mod message;
 pub mod proposal;
+pub mod proposal_template;
+pub mod rating;
+pub mod request;
 pub mod session;
 pub mod transaction;
 pub mod user;
+pub mod user_key;
+
+#[cfg(test)]
+pub mod test_util {
+    use aerosol::Aero;
+    use playferrous_presentation::UserId;
+    use sqlx::PgPool;
+
+    use super::{transaction::Transaction, Database};
+
+    /// Run transactions against the pool given to a `sqlx::test`.
+    pub fn aero(pool: PgPool) -> Aero {
+        Aero::new().with(Database { pool }).into()
+    }
+
+    /// A user who can't log in, for tests which need someone to own things.
+    pub async fn create_user(tx: &mut Transaction, username: &str) -> sqlx::Result<UserId> {
+        sqlx::query_scalar!(
+            r#"
+            INSERT INTO "user" (username, password_salt, password_hash)
+            VALUES ($1, '', '')
+            RETURNING id as "id: UserId"
+            "#,
+            username
+        )
+        .fetch_one(tx)
+        .await
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Database {
@@ -29,6 +62,13 @@ impl AsyncConstructible for Database {
     }
 }
 
+impl Database {
+    /// Wait for any transactions in progress, then close every connection.
+    pub async fn close(&self) {
+        self.pool.close().await;
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum TransactError<A = Infallible> {
     #[error(transparent)]
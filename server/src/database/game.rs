@@ -0,0 +1,559 @@
+use chrono::{DateTime, Utc};
+use ijson::IValue;
+use playferrous_presentation::{GameId, GameMin, OpponentMin, PlayerResultMin, UserId};
+use playferrous_types::{GameResult, SnapshotEnvelope};
+use sqlx::types::Json;
+
+use super::{proposal::GameProposal, transaction::Transaction};
+
+#[derive(Debug)]
+pub struct Game {
+    pub id: GameId,
+    pub game_type: String,
+    pub num_players: i32,
+    pub rules: Json<IValue>,
+    pub seed: i64,
+    pub snapshot: Json<IValue>,
+    /// Set instead of `snapshot` for games which save binary snapshots.
+    pub snapshot_binary: Option<Vec<u8>>,
+    pub snapshot_ply: i32,
+    pub snapshot_version: i32,
+    pub started_at: DateTime<Utc>,
+}
+
+/// A snapshot in whichever format the game saves it.
+#[derive(Debug)]
+pub enum SavedSnapshot {
+    Json(SnapshotEnvelope),
+    Binary { version: u32, data: Vec<u8> },
+}
+
+impl SavedSnapshot {
+    fn version(&self) -> u32 {
+        match self {
+            Self::Json(envelope) => envelope.version,
+            Self::Binary { version, .. } => *version,
+        }
+    }
+}
+
+/// How a game turned out, or `None` for `completed_at` if it hasn't yet.
+#[derive(Debug)]
+pub struct GameOutcome {
+    pub completed_at: Option<DateTime<Utc>>,
+    pub players: Vec<PlayerResultMin>,
+}
+
+#[derive(Debug)]
+pub struct LoggedAction {
+    pub tick: i64,
+    pub player_index: Option<i32>,
+    pub action: Json<IValue>,
+    /// Set for a player resigning, which has no action of its own.
+    pub resign: bool,
+}
+
+pub async fn get(tx: &mut Transaction, game_id: GameId) -> sqlx::Result<Option<Game>> {
+    Ok(sqlx::query_as!(
+        Game,
+        r#"
+        SELECT
+            id as "id: _",
+            game_type,
+            num_players,
+            rules as "rules: _",
+            seed,
+            snapshot as "snapshot: _",
+            snapshot_binary,
+            snapshot_ply,
+            snapshot_version,
+            started_at
+        FROM game
+        WHERE id = $1
+        "#,
+        game_id as _
+    )
+    .fetch_optional(tx)
+    .await?)
+}
+
+/// The user currently occupying a player's seat, if anyone.
+pub async fn player_id(
+    tx: &mut Transaction,
+    game_id: GameId,
+    player_index: i32,
+) -> sqlx::Result<Option<UserId>> {
+    Ok(sqlx::query_scalar!(
+        r#"
+        SELECT player_id as "player_id: _" FROM game_player
+        WHERE game_id = $1 AND player_index = $2
+        "#,
+        game_id as _,
+        player_index
+    )
+    .fetch_optional(tx)
+    .await?
+    .flatten())
+}
+
+/// The user in each seat, in player order.
+pub async fn player_ids(
+    tx: &mut Transaction,
+    game_id: GameId,
+) -> sqlx::Result<Vec<Option<UserId>>> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT player_id as "player_id: _" FROM game_player
+        WHERE game_id = $1
+        ORDER BY player_index
+        "#,
+        game_id as _
+    )
+    .fetch_all(tx)
+    .await
+}
+
+/// Whether a user may watch a game: either it's public, or they're playing in it.
+pub async fn is_watchable(
+    tx: &mut Transaction,
+    game_id: GameId,
+    user_id: UserId,
+) -> sqlx::Result<bool> {
+    Ok(sqlx::query_scalar!(
+        r#"
+        SELECT EXISTS (
+            SELECT 1 FROM game
+            WHERE id = $1 AND (
+                is_public OR EXISTS (
+                    SELECT 1 FROM game_player
+                    WHERE game_id = game.id AND player_id = $2
+                )
+            )
+        ) AS "watchable!"
+        "#,
+        game_id as _,
+        user_id as _
+    )
+    .fetch_one(tx)
+    .await?)
+}
+
+/// Record an action applied to a game, along with its new snapshot.
+pub async fn record_action(
+    tx: &mut Transaction,
+    game_id: GameId,
+    tick: i64,
+    player_index: Option<i32>,
+    action: &IValue,
+    snapshot: &SavedSnapshot,
+) -> sqlx::Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO action_log (game_id, tick, player_index, action)
+        VALUES ($1, $2, $3, $4)
+        "#,
+        game_id as _,
+        tick,
+        player_index,
+        Json(action) as _
+    )
+    .execute(&mut *tx)
+    .await?;
+    update_snapshot(tx, game_id, snapshot).await
+}
+
+/// Roll a game back to the given tick, discarding every action applied after
+/// it, so that replays match what the players saw.
+pub async fn undo(
+    tx: &mut Transaction,
+    game_id: GameId,
+    to_tick: i64,
+    snapshot: &SavedSnapshot,
+) -> sqlx::Result<()> {
+    sqlx::query!(
+        // Resignations can't be taken back
+        "DELETE FROM action_log WHERE game_id = $1 AND tick > $2 AND NOT resign",
+        game_id as _,
+        to_tick
+    )
+    .execute(&mut *tx)
+    .await?;
+    update_snapshot(tx, game_id, snapshot).await
+}
+
+/// Record that a player has resigned, placing them last of those still
+/// playing. If only one player is left, they win.
+pub async fn resign(
+    tx: &mut Transaction,
+    game_id: GameId,
+    tick: i64,
+    player_index: i32,
+    snapshot: &SavedSnapshot,
+) -> sqlx::Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO action_log (game_id, tick, player_index, action, resign)
+        VALUES ($1, $2, $3, 'null'::jsonb, TRUE)
+        "#,
+        game_id as _,
+        tick,
+        player_index
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        r#"
+        UPDATE game_player
+        SET
+            resigned_at = NOW(),
+            result_position = (
+                SELECT COUNT(*) FROM game_player WHERE game_id = $1 AND resigned_at IS NULL
+            )::INT
+        WHERE game_id = $1 AND player_index = $2 AND resigned_at IS NULL
+        "#,
+        game_id as _,
+        player_index
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        r#"
+        UPDATE game_player
+        SET result_position = 1
+        WHERE game_id = $1 AND resigned_at IS NULL
+        AND (SELECT COUNT(*) FROM game_player WHERE game_id = $1 AND resigned_at IS NULL) = 1
+        "#,
+        game_id as _
+    )
+    .execute(&mut *tx)
+    .await?;
+    update_snapshot(tx, game_id, snapshot).await
+}
+
+pub async fn resigned_players(tx: &mut Transaction, game_id: GameId) -> sqlx::Result<Vec<i32>> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT player_index
+        FROM game_player
+        WHERE game_id = $1 AND resigned_at IS NOT NULL
+        ORDER BY player_index
+        "#,
+        game_id as _
+    )
+    .fetch_all(tx)
+    .await
+}
+
+/// The snapshot's ply is the number of actions logged, so a game with none is
+/// restored from its setup alone.
+async fn update_snapshot(
+    tx: &mut Transaction,
+    game_id: GameId,
+    snapshot: &SavedSnapshot,
+) -> sqlx::Result<()> {
+    let (json, binary) = match snapshot {
+        SavedSnapshot::Json(envelope) => (Some(Json(&envelope.data)), None),
+        SavedSnapshot::Binary { data, .. } => (None, Some(data.as_slice())),
+    };
+    sqlx::query!(
+        r#"
+        UPDATE game
+        SET
+            snapshot = COALESCE($2, 'null'::jsonb),
+            snapshot_binary = $3,
+            snapshot_ply = (SELECT COUNT(*) FROM action_log WHERE game_id = $1)::INT,
+            snapshot_version = $4,
+            updated_at = NOW()
+        WHERE id = $1
+        "#,
+        game_id as _,
+        json as _,
+        binary,
+        i32::try_from(snapshot.version()).unwrap_or(i32::MAX)
+    )
+    .execute(tx)
+    .await?;
+    Ok(())
+}
+
+/// Record whose turn it is and when they must act by, or `None` once the game
+/// is complete, so that players can see which games are waiting on them
+/// without every game being launched. Returns whether this completed the game.
+pub async fn set_turn(
+    tx: &mut Transaction,
+    game_id: GameId,
+    turn: Option<(i32, DateTime<Utc>)>,
+) -> sqlx::Result<bool> {
+    let (player_turn, deadline) = turn.unzip();
+    Ok(sqlx::query_scalar!(
+        r#"
+        WITH old AS (
+            SELECT completed_at FROM game WHERE id = $1 FOR UPDATE
+        )
+        UPDATE game
+        SET
+            player_turn = $2,
+            deadline = $3,
+            completed_at = CASE
+                WHEN $2::INT IS NULL THEN COALESCE(game.completed_at, NOW())
+            END
+        FROM old
+        WHERE id = $1
+        RETURNING old.completed_at IS NULL AND game.completed_at IS NOT NULL AS "completed!"
+        "#,
+        game_id as _,
+        player_turn,
+        deadline
+    )
+    .fetch_optional(tx)
+    .await?
+    .unwrap_or(false))
+}
+
+/// Record each player's final score, placing them by score, with tied players
+/// sharing a place. Players who resigned keep the place they were given.
+pub async fn set_result(
+    tx: &mut Transaction,
+    game_id: GameId,
+    result: &GameResult,
+) -> sqlx::Result<()> {
+    let scores: Vec<i64> = result.player_results.iter().map(|r| r.score).collect();
+    let positions: Vec<i32> = scores
+        .iter()
+        .map(|score| 1 + scores.iter().filter(|other| *other > score).count() as i32)
+        .collect();
+    let player_indices: Vec<i32> = (0..scores.len() as i32).collect();
+    sqlx::query!(
+        r#"
+        UPDATE game_player gp
+        SET
+            result_score = r.score,
+            result_position = COALESCE(gp.result_position, r.position)
+        FROM UNNEST($2::INT[], $3::BIGINT[], $4::INT[]) AS r(player_index, score, position)
+        WHERE gp.game_id = $1 AND gp.player_index = r.player_index
+        "#,
+        game_id as _,
+        &player_indices,
+        &scores,
+        &positions
+    )
+    .execute(tx)
+    .await?;
+    Ok(())
+}
+
+/// The final standings of a game, if the user played in it. Players are
+/// listed in the order they placed.
+pub async fn get_result(
+    tx: &mut Transaction,
+    game_id: GameId,
+    user_id: UserId,
+) -> sqlx::Result<Option<GameOutcome>> {
+    let Some(completed_at) = sqlx::query_scalar!(
+        r#"
+        SELECT g.completed_at
+        FROM game g
+        WHERE g.id = $1 AND EXISTS (
+            SELECT 1 FROM game_player gp
+            WHERE gp.game_id = g.id AND $2 IN (gp.player_id, gp.initial_player_id)
+        )
+        "#,
+        game_id as _,
+        user_id as _
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        return Ok(None);
+    };
+    let players = sqlx::query_as!(
+        PlayerResultMin,
+        r#"
+        SELECT
+            gp.player_index,
+            u.username as "username?",
+            gp.result_score as score,
+            gp.result_position as position
+        FROM game_player gp
+        LEFT JOIN "user" u ON u.id = gp.player_id
+        WHERE gp.game_id = $1
+        ORDER BY gp.result_position NULLS LAST, gp.player_index
+        "#,
+        game_id as _
+    )
+    .fetch_all(tx)
+    .await?;
+    Ok(Some(GameOutcome {
+        completed_at,
+        players,
+    }))
+}
+
+/// The users who have played in the same games as this user, with the most
+/// recent game each of them played with the user.
+pub async fn recent_opponents(
+    tx: &mut Transaction,
+    user_id: UserId,
+    limit: i64,
+) -> sqlx::Result<Vec<OpponentMin>> {
+    Ok(sqlx::query_as!(
+        OpponentMin,
+        r#"
+        SELECT
+            o.user_id as "user_id!: _",
+            o.username as "username!",
+            o.game_id as "game_id!: _",
+            o.game_type as "game_type!",
+            o.played_at as "played_at!"
+        FROM (
+            SELECT DISTINCT ON (them.player_id)
+                them.player_id as user_id,
+                u.username,
+                g.id as game_id,
+                g.game_type,
+                COALESCE(g.completed_at, g.started_at) as played_at
+            FROM game_player me
+            JOIN game_player them ON them.game_id = me.game_id AND them.player_id <> me.player_id
+            JOIN game g ON g.id = me.game_id
+            JOIN "user" u ON u.id = them.player_id
+            WHERE me.player_id = $1
+            ORDER BY them.player_id, COALESCE(g.completed_at, g.started_at) DESC
+        ) o
+        ORDER BY o.played_at DESC
+        LIMIT $2
+        "#,
+        user_id as _,
+        limit
+    )
+    .fetch_all(tx)
+    .await?)
+}
+
+/// Games the user is playing in which have not yet finished, with those
+/// waiting on the user first.
+pub async fn list_active_for_user(
+    tx: &mut Transaction,
+    user_id: UserId,
+) -> sqlx::Result<Vec<GameMin>> {
+    Ok(sqlx::query_as!(
+        GameMin,
+        r#"
+        SELECT
+            g.id as "id: _",
+            g.game_type,
+            -- Replaced with the game's own name once it's been described
+            g.game_type as "display_name",
+            COALESCE(g.player_turn = gp.player_index, FALSE) as "your_turn!",
+            g.deadline
+        FROM game g
+        JOIN game_player gp ON gp.game_id = g.id
+        WHERE gp.player_id = $1 AND g.completed_at IS NULL
+        ORDER BY 3 DESC, g.deadline NULLS LAST, g.id
+        "#,
+        user_id as _
+    )
+    .fetch_all(tx)
+    .await?)
+}
+
+/// Every action applied to a game, and every resignation, in the order they
+/// were applied.
+pub async fn load_actions(
+    tx: &mut Transaction,
+    game_id: GameId,
+) -> sqlx::Result<Vec<LoggedAction>> {
+    Ok(sqlx::query_as!(
+        LoggedAction,
+        r#"
+        SELECT
+            tick,
+            player_index,
+            action as "action: _",
+            resign
+        FROM action_log
+        WHERE game_id = $1
+        ORDER BY id
+        "#,
+        game_id as _
+    )
+    .fetch_all(tx)
+    .await?)
+}
+
+/// Turn a proposal into a game, seating the players in the order given. The
+/// proposal is removed, along with its sessions, and each player is given a
+/// session for the new game instead.
+pub async fn create_from_proposal(
+    tx: &mut Transaction,
+    proposal: &GameProposal,
+    players: &[UserId],
+    seed: i64,
+) -> sqlx::Result<GameId> {
+    let game_id = sqlx::query_scalar!(
+        r#"
+        INSERT INTO game (
+            game_type,
+            is_public,
+            num_players,
+            rules,
+            seed,
+            snapshot,
+            snapshot_ply
+        ) VALUES (
+            $1,
+            $2,
+            $3,
+            $4,
+            $5,
+            'null'::jsonb,
+            0
+        )
+        RETURNING id as "id: GameId"
+        "#,
+        proposal.game_type,
+        proposal.is_public,
+        i32::try_from(players.len()).unwrap_or(i32::MAX),
+        Json(&proposal.rules.0) as _,
+        seed
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    for (player_index, &user_id) in players.iter().enumerate() {
+        let player_index = i32::try_from(player_index).unwrap_or(i32::MAX);
+        sqlx::query!(
+            r#"
+            INSERT INTO game_player (game_id, player_index, initial_player_id, player_id)
+            VALUES ($1, $2, $3, $3)
+            "#,
+            game_id as _,
+            player_index,
+            user_id as _
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            r#"
+            INSERT INTO session (
+                type,
+                user_id,
+                game_id,
+                game_player_index
+            ) VALUES (
+                'Game',
+                $1,
+                $2,
+                $3
+            )
+            "#,
+            user_id as _,
+            game_id as _,
+            player_index
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    sqlx::query!("DELETE FROM game_proposal WHERE id = $1", proposal.id as _)
+        .execute(&mut *tx)
+        .await?;
+    Ok(game_id)
+}
@@ -176,3 +176,41 @@ pub async fn list_for_user(tx: &mut Transaction, user_id: UserId) -> sqlx::Resul
 
     Ok(records.into_iter().map(|r| r.reify(&users)).collect())
 }
+
+/// Search the same messages as `list_for_user`, most relevant first.
+pub async fn search_for_user(
+    tx: &mut Transaction,
+    user_id: UserId,
+    query: &str,
+    limit: i64,
+) -> sqlx::Result<Vec<MessageMin>> {
+    // The expression must match `message_search_idx` for the index to be used
+    let records = sqlx::query_as!(
+        MessageMinRecord,
+        r#"
+        SELECT
+            id as "id: _",
+            sent_at,
+            subject,
+            from_id as "from_id: _",
+            request_id as "request_id: _"
+        FROM message, websearch_to_tsquery('english', $2) query
+        WHERE to_id = $1 AND NOT was_read
+            AND to_tsvector('english', subject || ' ' || body) @@ query
+        ORDER BY
+            ts_rank(to_tsvector('english', subject || ' ' || body), query) DESC,
+            sent_at DESC
+        LIMIT $3
+        "#,
+        user_id as _,
+        query,
+        limit
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let user_ids = records.iter().flat_map(|r| r.from_id);
+    let users = super::user::get_by_ids(tx, user_ids).await?;
+
+    Ok(records.into_iter().map(|r| r.reify(&users)).collect())
+}
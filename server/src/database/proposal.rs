@@ -5,6 +5,15 @@ use sqlx::types::Json;
 
 use super::transaction::Transaction;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinOutcome {
+    Joined,
+    AlreadyJoined,
+    Full,
+    /// The proposal doesn't exist, has expired, or isn't visible to the user.
+    NotFound,
+}
+
 #[derive(Debug)]
 pub struct GameProposal {
     pub id: GameProposalId,
@@ -13,6 +22,9 @@ pub struct GameProposal {
     pub min_players: i32,
     pub max_players: i32,
     pub mod_players: i32,
+    /// `None` for proposals created before creators were recorded, or whose
+    /// creator has since been deleted.
+    pub creator_id: Option<UserId>,
     pub rules: Json<IValue>,
     pub created_at: DateTime<Utc>,
     pub deadline: DateTime<Utc>,
@@ -22,6 +34,9 @@ pub async fn create(
     tx: &mut Transaction,
     game_type: &str,
     user_id: UserId,
+    min_players: i32,
+    max_players: i32,
+    is_public: bool,
 ) -> sqlx::Result<GameProposal> {
     let proposal = sqlx::query_as!(
         GameProposal,
@@ -32,14 +47,16 @@ pub async fn create(
             min_players,
             max_players,
             mod_players,
+            creator_id,
             rules,
             deadline
         ) VALUES (
             $1,
-            FALSE,
-            2,
-            8,
+            $5,
+            $2,
+            $3,
             1,
+            $4,
             'null'::jsonb,
             NOW() + INTERVAL '5 minutes'
         )
@@ -50,11 +67,16 @@ pub async fn create(
             min_players,
             max_players,
             mod_players,
+            creator_id as "creator_id: _",
             rules as "rules: _",
             created_at,
             deadline
         "#,
-        game_type
+        game_type,
+        min_players,
+        max_players,
+        user_id as _,
+        is_public
     )
     .fetch_one(&mut *tx)
     .await?;
@@ -88,14 +110,221 @@ pub async fn list_for_user(
         GameProposalMin,
         r#"
         SELECT
-            id as "id!: _",
-            game_type as "game_type!",
-            created_at as "created_at!"
-        FROM visible_game_proposals($1)
-        ORDER BY created_at DESC
+            p.id as "id!: _",
+            p.game_type as "game_type!",
+            -- Replaced with the game's own name once it's been described
+            p.game_type as "display_name!",
+            p.created_at as "created_at!",
+            (
+                SELECT COUNT(*) FROM session
+                WHERE session."type" = 'GameProposal' AND session.game_proposal_id = p.id
+            )::INT as "joined!",
+            p.max_players as "max_players!"
+        FROM visible_game_proposals($1) p
+        WHERE p.deadline > NOW()
+        ORDER BY p.created_at DESC
         "#,
         user_id as _
     )
     .fetch_all(tx)
     .await?)
 }
+
+/// Delete every proposal which has passed its deadline, returning their IDs.
+pub async fn delete_expired(tx: &mut Transaction) -> sqlx::Result<Vec<GameProposalId>> {
+    Ok(sqlx::query_scalar!(
+        r#"
+        DELETE FROM game_proposal
+        WHERE deadline <= NOW()
+        RETURNING id as "id: _"
+        "#
+    )
+    .fetch_all(tx)
+    .await?)
+}
+
+pub async fn join(
+    tx: &mut Transaction,
+    proposal_id: GameProposalId,
+    user_id: UserId,
+) -> sqlx::Result<JoinOutcome> {
+    // Lock the proposal so that concurrent joins can't overfill it
+    let Some(max_players) = sqlx::query_scalar!(
+        r#"
+        SELECT max_players FROM game_proposal
+        WHERE id = $1 AND deadline > NOW()
+            AND id IN (SELECT id FROM visible_game_proposals($2))
+        FOR UPDATE
+        "#,
+        proposal_id as _,
+        user_id as _
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        return Ok(JoinOutcome::NotFound);
+    };
+    let joined = sqlx::query!(
+        r#"
+        SELECT
+            COUNT(*) as "count!",
+            COALESCE(BOOL_OR(user_id = $2), FALSE) as "includes_user!"
+        FROM session
+        WHERE "type" = 'GameProposal' AND game_proposal_id = $1
+        "#,
+        proposal_id as _,
+        user_id as _
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    if joined.includes_user {
+        return Ok(JoinOutcome::AlreadyJoined);
+    }
+    if joined.count >= i64::from(max_players) {
+        return Ok(JoinOutcome::Full);
+    }
+    sqlx::query!(
+        r#"
+            INSERT INTO session (
+                type,
+                user_id,
+                game_proposal_id,
+                is_ready
+            ) VALUES (
+                'GameProposal',
+                $1,
+                $2,
+                FALSE
+            )
+        "#,
+        user_id as _,
+        proposal_id as _
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok(JoinOutcome::Joined)
+}
+
+/// Returns `false` if the user had not joined the proposal.
+pub async fn leave(
+    tx: &mut Transaction,
+    proposal_id: GameProposalId,
+    user_id: UserId,
+) -> sqlx::Result<bool> {
+    Ok(sqlx::query!(
+        r#"
+        DELETE FROM session
+        WHERE "type" = 'GameProposal' AND game_proposal_id = $1 AND user_id = $2
+        "#,
+        proposal_id as _,
+        user_id as _
+    )
+    .execute(tx)
+    .await?
+    .rows_affected()
+        > 0)
+}
+
+/// Delete a proposal, along with its sessions.
+pub async fn delete(tx: &mut Transaction, proposal_id: GameProposalId) -> sqlx::Result<()> {
+    sqlx::query!("DELETE FROM game_proposal WHERE id = $1", proposal_id as _)
+        .execute(tx)
+        .await?;
+    Ok(())
+}
+
+/// A proposal which the user has joined, locked until the end of the transaction.
+pub async fn get_joined(
+    tx: &mut Transaction,
+    proposal_id: GameProposalId,
+    user_id: UserId,
+) -> sqlx::Result<Option<GameProposal>> {
+    Ok(sqlx::query_as!(
+        GameProposal,
+        r#"
+        SELECT
+            id as "id: _",
+            game_type,
+            is_public,
+            min_players,
+            max_players,
+            mod_players,
+            creator_id as "creator_id: _",
+            rules as "rules: _",
+            created_at,
+            deadline
+        FROM game_proposal
+        WHERE id = $1 AND deadline > NOW() AND EXISTS (
+            SELECT 1 FROM session
+            WHERE "type" = 'GameProposal' AND game_proposal_id = $1 AND user_id = $2
+        )
+        FOR UPDATE
+        "#,
+        proposal_id as _,
+        user_id as _
+    )
+    .fetch_optional(tx)
+    .await?)
+}
+
+pub async fn set_rules(
+    tx: &mut Transaction,
+    proposal_id: GameProposalId,
+    rules: &IValue,
+) -> sqlx::Result<()> {
+    sqlx::query!(
+        "UPDATE game_proposal SET rules = $2 WHERE id = $1",
+        proposal_id as _,
+        Json(rules) as _
+    )
+    .execute(tx)
+    .await?;
+    Ok(())
+}
+
+/// The users who have joined a proposal, in the order they joined.
+pub async fn members(
+    tx: &mut Transaction,
+    proposal_id: GameProposalId,
+) -> sqlx::Result<Vec<UserId>> {
+    Ok(sqlx::query_scalar!(
+        r#"
+        SELECT user_id as "user_id: _" FROM session
+        WHERE "type" = 'GameProposal' AND game_proposal_id = $1
+        ORDER BY created_at, id
+        "#,
+        proposal_id as _
+    )
+    .fetch_all(tx)
+    .await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::database::test_util;
+
+    #[sqlx::test]
+    async fn proposals_past_their_deadline_are_not_listed(pool: PgPool) -> sqlx::Result<()> {
+        let aero = test_util::aero(pool);
+        let mut tx = Transaction::begin(&aero).await?;
+        let user_id = test_util::create_user(&mut tx, "alice").await?;
+        let current = create(&mut tx, "rock-paper-scissors", user_id, 2, 2, true).await?;
+        let expired = create(&mut tx, "rock-paper-scissors", user_id, 2, 2, true).await?;
+        sqlx::query!(
+            "UPDATE game_proposal SET deadline = NOW() - INTERVAL '1 second' WHERE id = $1",
+            expired.id as _
+        )
+        .execute(&mut tx)
+        .await?;
+        let listed: Vec<_> = list_for_user(&mut tx, user_id)
+            .await?
+            .into_iter()
+            .map(|proposal| proposal.id)
+            .collect();
+        assert_eq!(listed, [current.id]);
+        Ok(())
+    }
+}
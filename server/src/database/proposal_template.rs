@@ -0,0 +1,113 @@
+use ijson::IValue;
+use playferrous_presentation::{ProposalTemplateMin, UserId};
+use sqlx::types::Json;
+
+use super::{proposal::GameProposal, transaction::Transaction};
+
+#[derive(Debug)]
+pub struct ProposalTemplate {
+    pub name: String,
+    pub game_type: String,
+    pub min_players: i32,
+    pub max_players: i32,
+    pub rules: Json<IValue>,
+}
+
+/// Save the settings of a proposal under a name, replacing any template the
+/// user already has with that name.
+pub async fn save(
+    tx: &mut Transaction,
+    user_id: UserId,
+    name: &str,
+    proposal: &GameProposal,
+) -> sqlx::Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO proposal_template (
+            user_id,
+            name,
+            game_type,
+            min_players,
+            max_players,
+            rules
+        ) VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (user_id, name) DO UPDATE SET
+            game_type = EXCLUDED.game_type,
+            min_players = EXCLUDED.min_players,
+            max_players = EXCLUDED.max_players,
+            rules = EXCLUDED.rules,
+            created_at = NOW()
+        "#,
+        user_id as _,
+        name,
+        proposal.game_type,
+        proposal.min_players,
+        proposal.max_players,
+        &proposal.rules as _
+    )
+    .execute(tx)
+    .await?;
+    Ok(())
+}
+
+pub async fn get(
+    tx: &mut Transaction,
+    user_id: UserId,
+    name: &str,
+) -> sqlx::Result<Option<ProposalTemplate>> {
+    Ok(sqlx::query_as!(
+        ProposalTemplate,
+        r#"
+        SELECT
+            name,
+            game_type,
+            min_players,
+            max_players,
+            rules as "rules: _"
+        FROM proposal_template
+        WHERE user_id = $1 AND name = $2
+        "#,
+        user_id as _,
+        name
+    )
+    .fetch_optional(tx)
+    .await?)
+}
+
+pub async fn list_for_user(
+    tx: &mut Transaction,
+    user_id: UserId,
+) -> sqlx::Result<Vec<ProposalTemplateMin>> {
+    Ok(sqlx::query_as!(
+        ProposalTemplateMin,
+        r#"
+        SELECT
+            name,
+            game_type,
+            min_players,
+            max_players,
+            created_at
+        FROM proposal_template
+        WHERE user_id = $1
+        ORDER BY name
+        "#,
+        user_id as _
+    )
+    .fetch_all(tx)
+    .await?)
+}
+
+pub async fn remove(tx: &mut Transaction, user_id: UserId, name: &str) -> sqlx::Result<bool> {
+    Ok(sqlx::query!(
+        r#"
+        DELETE FROM proposal_template
+        WHERE user_id = $1 AND name = $2
+        "#,
+        user_id as _,
+        name
+    )
+    .execute(tx)
+    .await?
+    .rows_affected()
+        == 1)
+}
@@ -0,0 +1,177 @@
+use std::{cmp::Ordering, collections::HashMap};
+
+use playferrous_presentation::{RatingMin, UserId};
+use playferrous_types::GameResult;
+
+use super::transaction::Transaction;
+
+/// The rating given to a user the first time they finish a game of some type.
+pub const INITIAL_RATING: i32 = 1500;
+/// The most a user's rating can change by in one game.
+const K_FACTOR: f64 = 32.0;
+
+/// Update the ratings of everyone who played in a game, given the user in each
+/// seat. Games with fewer than two players, or where a user played more than
+/// one seat, or where a seat is empty, aren't rated.
+pub async fn apply_result(
+    tx: &mut Transaction,
+    game_type: &str,
+    result: &GameResult,
+    players: &[Option<UserId>],
+) -> sqlx::Result<()> {
+    let Some(user_ids) = players
+        .iter()
+        .map(|player| player.map(|user_id| user_id.0))
+        .collect::<Option<Vec<i64>>>()
+    else {
+        return Ok(());
+    };
+    let mut distinct = user_ids.clone();
+    distinct.sort_unstable();
+    distinct.dedup();
+    if distinct.len() < 2
+        || distinct.len() != user_ids.len()
+        || user_ids.len() != result.player_results.len()
+    {
+        return Ok(());
+    }
+    let current: HashMap<i64, i32> = sqlx::query!(
+        r#"
+        SELECT user_id, rating
+        FROM user_rating
+        WHERE game_type = $1 AND user_id = ANY($2)
+        FOR UPDATE
+        "#,
+        game_type,
+        &user_ids
+    )
+    .fetch_all(&mut *tx)
+    .await?
+    .into_iter()
+    .map(|row| (row.user_id, row.rating))
+    .collect();
+    let ratings: Vec<i32> = user_ids
+        .iter()
+        .map(|user_id| current.get(user_id).copied().unwrap_or(INITIAL_RATING))
+        .collect();
+    let scores: Vec<i64> = result.player_results.iter().map(|r| r.score).collect();
+    let new_ratings: Vec<i32> = ratings
+        .iter()
+        .zip(rating_changes(&ratings, &scores))
+        .map(|(rating, change)| rating + change)
+        .collect();
+    sqlx::query!(
+        r#"
+        INSERT INTO user_rating (user_id, game_type, rating, games_played)
+        SELECT r.user_id, $1, r.rating, 1
+        FROM UNNEST($2::BIGINT[], $3::INT[]) AS r(user_id, rating)
+        ON CONFLICT (user_id, game_type) DO UPDATE SET
+            rating = EXCLUDED.rating,
+            games_played = user_rating.games_played + 1,
+            updated_at = NOW()
+        "#,
+        game_type,
+        &user_ids,
+        &new_ratings
+    )
+    .execute(tx)
+    .await?;
+    Ok(())
+}
+
+/// Elo, with a game of more than two players scored as though each pair of
+/// players had played each other, ranked by score. The K-factor is shared
+/// between the pairs so that bigger games don't move ratings further.
+fn rating_changes(ratings: &[i32], scores: &[i64]) -> Vec<i32> {
+    let k = K_FACTOR / (ratings.len() - 1) as f64;
+    (0..ratings.len())
+        .map(|i| {
+            let change: f64 = (0..ratings.len())
+                .filter(|&j| j != i)
+                .map(|j| {
+                    let expected =
+                        1.0 / (1.0 + 10f64.powf(f64::from(ratings[j] - ratings[i]) / 400.0));
+                    let actual = match scores[i].cmp(&scores[j]) {
+                        Ordering::Greater => 1.0,
+                        Ordering::Equal => 0.5,
+                        Ordering::Less => 0.0,
+                    };
+                    actual - expected
+                })
+                .sum();
+            (k * change).round() as i32
+        })
+        .collect()
+}
+
+/// The user's rating in each game type they've finished a game of.
+pub async fn list_for_user(tx: &mut Transaction, user_id: UserId) -> sqlx::Result<Vec<RatingMin>> {
+    Ok(sqlx::query_as!(
+        RatingMin,
+        r#"
+        SELECT
+            r.user_id as "user_id: _",
+            u.username,
+            r.game_type,
+            r.rating,
+            r.games_played
+        FROM user_rating r
+        JOIN "user" u ON u.id = r.user_id
+        WHERE r.user_id = $1
+        ORDER BY r.game_type
+        "#,
+        user_id as _
+    )
+    .fetch_all(tx)
+    .await?)
+}
+
+/// The highest rated users for a game type.
+pub async fn leaderboard(
+    tx: &mut Transaction,
+    game_type: &str,
+    limit: i64,
+) -> sqlx::Result<Vec<RatingMin>> {
+    Ok(sqlx::query_as!(
+        RatingMin,
+        r#"
+        SELECT
+            r.user_id as "user_id: _",
+            u.username,
+            r.game_type,
+            r.rating,
+            r.games_played
+        FROM user_rating r
+        JOIN "user" u ON u.id = r.user_id
+        WHERE r.game_type = $1
+        ORDER BY r.rating DESC, r.games_played DESC, r.user_id
+        LIMIT $2
+        "#,
+        game_type,
+        limit
+    )
+    .fetch_all(tx)
+    .await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn winner_takes_from_loser() {
+        assert_eq!(rating_changes(&[1500, 1500], &[1, 0]), [16, -16]);
+        assert_eq!(rating_changes(&[1500, 1500], &[0, 0]), [0, 0]);
+        // Beating a much stronger player is worth more
+        assert_eq!(rating_changes(&[1200, 1600], &[1, 0]), [29, -29]);
+        assert_eq!(rating_changes(&[1200, 1600], &[0, 1]), [-3, 3]);
+    }
+    #[test]
+    fn bigger_games_share_the_k_factor() {
+        assert_eq!(
+            rating_changes(&[1500, 1500, 1500], &[3, 2, 1]),
+            [16, 0, -16]
+        );
+        assert_eq!(rating_changes(&[1500, 1500, 1500], &[1, 1, 0]), [8, 8, -16]);
+    }
+}
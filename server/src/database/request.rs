@@ -1,7 +1,12 @@
 use chrono::{DateTime, Utc};
-use playferrous_presentation::{GameId, GameProposalId, GroupId, RequestId, UserId};
+use playferrous_presentation::{GameId, GameProposalId, GroupId, RequestId, RequestMin, UserId};
 
-#[derive(Debug, sqlx::Type)]
+use super::{
+    proposal::{self, JoinOutcome},
+    transaction::Transaction,
+};
+
+#[derive(Debug, Clone, Copy, sqlx::Type)]
 #[sqlx(type_name = "request_type")]
 pub enum RequestType {
     Friend,
@@ -15,12 +20,396 @@ pub enum RequestType {
 pub struct Request {
     pub id: RequestId,
     pub type_: RequestType,
-    pub from_user_id: Option<UserId>,
+    pub from_user_id: UserId,
     pub from_group_id: Option<GroupId>,
     pub to_user_id: Option<UserId>,
     pub to_group_id: Option<GroupId>,
     pub game_proposal_id: Option<GameProposalId>,
     pub game_id: Option<GameId>,
-    pub player_index: i32,
+    pub player_index: Option<i32>,
     pub sent_at: DateTime<Utc>,
 }
+
+#[derive(Debug)]
+pub enum AcceptOutcome {
+    Accepted(Request),
+    /// The user was invited to a proposal, and has now joined it.
+    JoinedProposal(Request),
+    /// The proposal the user was invited to can't be joined, so the
+    /// invitation is still pending.
+    ProposalUnavailable(JoinOutcome),
+}
+
+impl RequestType {
+    fn describe(self) -> &'static str {
+        match self {
+            RequestType::Friend => "friend request",
+            RequestType::JoinGroup => "request to join the group",
+            RequestType::GroupInvite => "group invitation",
+            RequestType::GameProposal => "game proposal",
+            RequestType::GameInvite => "game invitation",
+        }
+    }
+}
+
+/// Invite a user to join a proposal, which makes it visible to them until they
+/// respond.
+pub async fn invite_to_proposal(
+    tx: &mut Transaction,
+    from_user_id: UserId,
+    to_user_id: UserId,
+    proposal_id: GameProposalId,
+) -> sqlx::Result<RequestId> {
+    sqlx::query_scalar!(
+        r#"
+        INSERT INTO request (type_, from_user_id, to_user_id, game_proposal_id)
+        VALUES ('GameProposal', $1, $2, $3)
+        RETURNING id as "id: RequestId"
+        "#,
+        from_user_id as _,
+        to_user_id as _,
+        proposal_id as _
+    )
+    .fetch_one(tx)
+    .await
+}
+
+/// Find a request which the user is entitled to respond to, locking it until
+/// the end of the transaction.
+async fn get_for_responder(
+    tx: &mut Transaction,
+    request_id: RequestId,
+    user_id: UserId,
+) -> sqlx::Result<Option<Request>> {
+    Ok(sqlx::query_as!(
+        Request,
+        r#"
+        SELECT
+            id as "id: _",
+            type_ as "type_: _",
+            from_user_id as "from_user_id: _",
+            from_group_id as "from_group_id: _",
+            to_user_id as "to_user_id: _",
+            to_group_id as "to_group_id: _",
+            game_proposal_id as "game_proposal_id: _",
+            game_id as "game_id: _",
+            player_index,
+            sent_at
+        FROM request
+        WHERE id = $1 AND (
+            to_user_id = $2
+            -- Requests to join a group are answered by its admins
+            OR (type_ = 'JoinGroup' AND to_group_id IN (
+                SELECT group_id FROM group_member
+                WHERE member_id = $2 AND membership_type = 'Admin'
+            ))
+            -- Proposals shared with a group may be joined by any member
+            OR (type_ = 'GameProposal' AND to_group_id IN (
+                SELECT group_id FROM group_member
+                WHERE member_id = $2
+            ))
+        )
+        FOR UPDATE
+        "#,
+        request_id as _,
+        user_id as _
+    )
+    .fetch_optional(tx)
+    .await?)
+}
+
+/// Accept a request on behalf of one of its recipients. Returns `None` if the
+/// request does not exist or the user may not respond to it.
+pub async fn accept(
+    tx: &mut Transaction,
+    request_id: RequestId,
+    user_id: UserId,
+) -> sqlx::Result<Option<AcceptOutcome>> {
+    let Some(request) = get_for_responder(tx, request_id, user_id).await? else {
+        return Ok(None);
+    };
+    let mut joined = JoinOutcome::AlreadyJoined;
+    match request.type_ {
+        RequestType::Friend => {
+            sqlx::query!(
+                r#"
+                INSERT INTO user_friend (user_id, friend_id)
+                VALUES ($1, $2), ($2, $1)
+                ON CONFLICT DO NOTHING
+                "#,
+                request.from_user_id as _,
+                user_id as _
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        RequestType::JoinGroup | RequestType::GroupInvite => {
+            let (group_id, member_id) = match request.type_ {
+                RequestType::JoinGroup => (request.to_group_id, request.from_user_id),
+                _ => (request.from_group_id, user_id),
+            };
+            sqlx::query!(
+                r#"
+                INSERT INTO group_member (group_id, member_id, membership_type)
+                VALUES ($1, $2, 'Regular')
+                ON CONFLICT DO NOTHING
+                "#,
+                group_id.expect("Group ID must be present") as _,
+                member_id as _
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        RequestType::GameProposal => {
+            let proposal_id = request
+                .game_proposal_id
+                .expect("Proposal ID must be present");
+            joined = proposal::join(tx, proposal_id, user_id).await?;
+            if matches!(joined, JoinOutcome::Full | JoinOutcome::NotFound) {
+                // Leave the invitation pending, in case a place opens up
+                return Ok(Some(AcceptOutcome::ProposalUnavailable(joined)));
+            }
+        }
+        RequestType::GameInvite => {
+            let game_id = request.game_id.expect("Game ID must be present");
+            let player_index = request.player_index.expect("Player index must be present");
+            sqlx::query!(
+                r#"
+                UPDATE game_player SET player_id = $3
+                WHERE game_id = $1 AND player_index = $2
+                "#,
+                game_id as _,
+                player_index,
+                user_id as _
+            )
+            .execute(&mut *tx)
+            .await?;
+            // The seat may previously have belonged to someone else
+            sqlx::query!(
+                r#"
+                DELETE FROM session
+                WHERE game_id = $1 AND game_player_index = $2
+                "#,
+                game_id as _,
+                player_index
+            )
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query!(
+                r#"
+                INSERT INTO session (
+                    type,
+                    user_id,
+                    game_id,
+                    game_player_index
+                ) VALUES (
+                    'Game',
+                    $1,
+                    $2,
+                    $3
+                )
+                "#,
+                user_id as _,
+                game_id as _,
+                player_index
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+    respond(tx, &request, user_id, "accepted").await?;
+    Ok(Some(match joined {
+        JoinOutcome::Joined => AcceptOutcome::JoinedProposal(request),
+        _ => AcceptOutcome::Accepted(request),
+    }))
+}
+
+/// Reject a request on behalf of one of its recipients. Returns `None` if the
+/// request does not exist or the user may not respond to it.
+pub async fn reject(
+    tx: &mut Transaction,
+    request_id: RequestId,
+    user_id: UserId,
+) -> sqlx::Result<Option<Request>> {
+    let Some(request) = get_for_responder(tx, request_id, user_id).await? else {
+        return Ok(None);
+    };
+    respond(tx, &request, user_id, "declined").await?;
+    Ok(Some(request))
+}
+
+/// Withdraw a request on behalf of its sender, and let those who could have
+/// responded know. Returns `None` if the request does not exist or the user
+/// didn't send it.
+pub async fn cancel(
+    tx: &mut Transaction,
+    request_id: RequestId,
+    from_user_id: UserId,
+) -> sqlx::Result<Option<Request>> {
+    // Messages about the request would otherwise still ask for a response
+    sqlx::query!(
+        r#"
+        UPDATE message SET was_read = TRUE
+        WHERE request_id = $1 AND EXISTS (
+            SELECT 1 FROM request WHERE id = $1 AND from_user_id = $2
+        )
+        "#,
+        request_id as _,
+        from_user_id as _
+    )
+    .execute(&mut *tx)
+    .await?;
+    let Some(request) = sqlx::query_as!(
+        Request,
+        r#"
+        DELETE FROM request
+        WHERE id = $1 AND from_user_id = $2
+        RETURNING
+            id as "id: _",
+            type_ as "type_: _",
+            from_user_id as "from_user_id: _",
+            from_group_id as "from_group_id: _",
+            to_user_id as "to_user_id: _",
+            to_group_id as "to_group_id: _",
+            game_proposal_id as "game_proposal_id: _",
+            game_id as "game_id: _",
+            player_index,
+            sent_at
+        "#,
+        request_id as _,
+        from_user_id as _
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        return Ok(None);
+    };
+    // Requests to join a group are answered by its admins, whereas proposals
+    // shared with a group may be joined by any member
+    let recipients: Vec<UserId> = match (request.to_user_id, request.to_group_id) {
+        (Some(to_user_id), _) => vec![to_user_id],
+        (None, Some(to_group_id)) => {
+            sqlx::query_scalar!(
+                r#"
+                SELECT member_id as "member_id: _" FROM group_member
+                WHERE group_id = $1 AND (membership_type = 'Admin' OR $2)
+                "#,
+                to_group_id as _,
+                matches!(request.type_, RequestType::GameProposal)
+            )
+            .fetch_all(&mut *tx)
+            .await?
+        }
+        (None, None) => Vec::new(),
+    };
+    let description = request.type_.describe();
+    for to_user_id in recipients {
+        super::message::send_to_user(
+            tx,
+            to_user_id,
+            Some(from_user_id),
+            format!("A {description} was withdrawn"),
+            format!(
+                "The {description} ({}) was withdrawn by its sender.",
+                request.id
+            ),
+            None,
+        )
+        .await?;
+    }
+    Ok(Some(request))
+}
+
+struct PendingRequest {
+    id: RequestId,
+    type_: RequestType,
+    outgoing: bool,
+    other: Option<String>,
+    sent_at: DateTime<Utc>,
+}
+
+/// Requests the user has sent, and those they may respond to, which are still
+/// awaiting a response. Incoming requests are listed first.
+pub async fn list_pending(tx: &mut Transaction, user_id: UserId) -> sqlx::Result<Vec<RequestMin>> {
+    let requests = sqlx::query_as!(
+        PendingRequest,
+        r#"
+        SELECT
+            r.id as "id: _",
+            r.type_ as "type_: _",
+            r.from_user_id = $1 as "outgoing!",
+            CASE
+                WHEN r.from_user_id = $1 THEN COALESCE(tu.username, tg.name)
+                ELSE COALESCE(fg.name, fu.username)
+            END as other,
+            r.sent_at
+        FROM request r
+        JOIN "user" fu ON fu.id = r.from_user_id
+        LEFT JOIN "group" fg ON fg.id = r.from_group_id
+        LEFT JOIN "user" tu ON tu.id = r.to_user_id
+        LEFT JOIN "group" tg ON tg.id = r.to_group_id
+        WHERE r.from_user_id = $1
+            OR r.to_user_id = $1
+            OR (r.type_ = 'JoinGroup' AND r.to_group_id IN (
+                SELECT group_id FROM group_member
+                WHERE member_id = $1 AND membership_type = 'Admin'
+            ))
+            OR (r.type_ = 'GameProposal' AND r.to_group_id IN (
+                SELECT group_id FROM group_member
+                WHERE member_id = $1
+            ))
+        ORDER BY r.from_user_id = $1, r.sent_at DESC
+        "#,
+        user_id as _
+    )
+    .fetch_all(tx)
+    .await?;
+    Ok(requests
+        .into_iter()
+        .map(|request| RequestMin {
+            id: request.id,
+            outgoing: request.outgoing,
+            description: request.type_.describe().into(),
+            other: request.other,
+            sent_at: request.sent_at,
+        })
+        .collect())
+}
+
+/// Clear up after a response, and let the sender know the outcome.
+async fn respond(
+    tx: &mut Transaction,
+    request: &Request,
+    user_id: UserId,
+    outcome: &str,
+) -> sqlx::Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE message SET was_read = TRUE
+        WHERE to_id = $1 AND request_id = $2
+        "#,
+        user_id as _,
+        request.id as _
+    )
+    .execute(&mut *tx)
+    .await?;
+    // A proposal shared with a group stays open to the other members
+    let is_shared =
+        matches!(request.type_, RequestType::GameProposal) && request.to_group_id.is_some();
+    if !is_shared {
+        sqlx::query!("DELETE FROM request WHERE id = $1", request.id as _)
+            .execute(&mut *tx)
+            .await?;
+    }
+    let description = request.type_.describe();
+    super::message::send_to_user(
+        tx,
+        request.from_user_id,
+        Some(user_id),
+        format!("Your {description} was {outcome}"),
+        format!("Your {description} ({}) was {outcome}.", request.id),
+        None,
+    )
+    .await?;
+    Ok(())
+}
@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 
-use playferrous_presentation::{UserId, UserMin};
+use playferrous_presentation::{OnlineUserMin, UserId, UserMin, UserSettings};
 
 use super::transaction::Transaction;
 
@@ -26,3 +26,102 @@ pub async fn get_by_ids(
     .map(|u| (u.id, u))
     .collect())
 }
+
+pub async fn is_admin(tx: &mut Transaction, user_id: UserId) -> sqlx::Result<bool> {
+    Ok(
+        sqlx::query_scalar!(r#"SELECT is_admin FROM "user" WHERE id = $1"#, user_id as _)
+            .fetch_optional(tx)
+            .await?
+            .unwrap_or(false),
+    )
+}
+
+pub async fn grant_admin(tx: &mut Transaction, usernames: &[String]) -> sqlx::Result<()> {
+    sqlx::query!(
+        r#"UPDATE "user" SET is_admin = TRUE WHERE username = ANY($1)"#,
+        usernames
+    )
+    .execute(tx)
+    .await?;
+    Ok(())
+}
+
+pub async fn touch_last_seen(tx: &mut Transaction, user_id: UserId) -> sqlx::Result<()> {
+    sqlx::query!(
+        r#"UPDATE "user" SET last_seen_at = NOW() WHERE id = $1"#,
+        user_id as _
+    )
+    .execute(tx)
+    .await?;
+    Ok(())
+}
+
+/// Those of the given users who `viewer` can see: users who are invisible are
+/// only visible to themselves and their friends.
+pub async fn list_visible(
+    tx: &mut Transaction,
+    viewer: UserId,
+    user_ids: &[UserId],
+) -> sqlx::Result<Vec<OnlineUserMin>> {
+    sqlx::query_as!(
+        OnlineUserMin,
+        r#"
+        SELECT
+            u.id as "id: _",
+            u.username,
+            u.last_seen_at
+        FROM "user" u
+        LEFT JOIN user_settings s ON s.user_id = u.id
+        WHERE u.id = ANY($2)
+        AND (
+            NOT COALESCE(s.invisible, FALSE)
+            OR u.id = $1
+            OR EXISTS (
+                SELECT 1 FROM user_friend f WHERE f.user_id = u.id AND f.friend_id = $1
+            )
+        )
+        ORDER BY u.username
+        "#,
+        viewer as _,
+        user_ids as &[UserId]
+    )
+    .fetch_all(tx)
+    .await
+}
+
+/// Users without any settings saved get the defaults.
+pub async fn get_settings(tx: &mut Transaction, user_id: UserId) -> sqlx::Result<UserSettings> {
+    Ok(sqlx::query_as!(
+        UserSettings,
+        "SELECT turn_bell, timezone, invisible FROM user_settings WHERE user_id = $1",
+        user_id as _
+    )
+    .fetch_optional(tx)
+    .await?
+    .unwrap_or_default())
+}
+
+pub async fn set_settings(
+    tx: &mut Transaction,
+    user_id: UserId,
+    settings: &UserSettings,
+) -> sqlx::Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO user_settings (user_id, turn_bell, timezone, invisible)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (user_id) DO UPDATE
+        SET
+            turn_bell = EXCLUDED.turn_bell,
+            timezone = EXCLUDED.timezone,
+            invisible = EXCLUDED.invisible
+        "#,
+        user_id as _,
+        settings.turn_bell,
+        settings.timezone,
+        settings.invisible,
+    )
+    .execute(tx)
+    .await?;
+    Ok(())
+}
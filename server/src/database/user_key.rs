@@ -0,0 +1,39 @@
+use playferrous_presentation::{UserId, UserKeyMin};
+
+use super::transaction::Transaction;
+
+pub async fn list_for_user(tx: &mut Transaction, user_id: UserId) -> sqlx::Result<Vec<UserKeyMin>> {
+    Ok(sqlx::query_as!(
+        UserKeyMin,
+        r#"
+        SELECT
+            fingerprint,
+            created_at
+        FROM user_key
+        WHERE user_id = $1
+        ORDER BY created_at
+        "#,
+        user_id as _
+    )
+    .fetch_all(tx)
+    .await?)
+}
+
+pub async fn remove(
+    tx: &mut Transaction,
+    user_id: UserId,
+    fingerprint: &str,
+) -> sqlx::Result<bool> {
+    Ok(sqlx::query!(
+        r#"
+        DELETE FROM user_key
+        WHERE user_id = $1 AND fingerprint = $2
+        "#,
+        user_id as _,
+        fingerprint
+    )
+    .execute(tx)
+    .await?
+    .rows_affected()
+        == 1)
+}
@@ -1,27 +1,83 @@
-use std::{convert::Infallible, sync::Arc};
+use std::{collections::HashMap, convert::Infallible, sync::Arc, time::Duration};
 
 use aerosol::{Aero, Constructible};
+use anyhow::{anyhow, bail};
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use dashmap::DashMap;
+use futures::{future::BoxFuture, FutureExt};
+use ijson::IValue;
+use playferrous_launcher::{GameError, GameInstance};
 use playferrous_presentation::{
     actor::Actor,
     bichannel::{bichannel, Bichannel},
-    GameId, PresentationKind,
+    GameCapabilities, GameId, GraphicalSessionEvent, PresentationKind, SessionCommand,
+    SessionEvent, TerminalInputMode, TerminalSessionCommand, TerminalSessionEvent, UserId,
 };
-use tokio::sync::mpsc;
+use playferrous_types::{
+    GameDescription, GameResult, GameSetup, GameState, GameTick, InputMode, PlayerResult,
+    PlayerView, SnapshotEnvelope,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    connection_manager::{
+        ConnectionId, ConnectionManager, ConnectionToSessionMsg, SessionHistory, SessionMember,
+        SessionToConnectionMsg, SystemToConnectionMsg,
+    },
+    database::{self, game::SavedSnapshot, transaction::Transaction},
+    launchers::Launchers,
+    replay::replay,
+    utils::{FutureExt2, FutureIteratorExt, Rotation},
+    Config, Shutdown,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GameConfig {
+    /// How long a game may go without any requests before its process is
+    /// pinged to check that it is still responsive, in seconds.
+    pub ping_interval_secs: u64,
+    /// How long to wait for a reply to a ping before restarting the
+    /// process, in seconds.
+    pub ping_timeout_secs: u64,
+}
 
-use crate::connection_manager::{ConnectionToSessionMsg, SessionToConnectionMsg};
+impl Default for GameConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval_secs: 30,
+            ping_timeout_secs: 5,
+        }
+    }
+}
 
 #[derive(Debug)]
 struct EnterGameSession {
-    player_index: i32,
+    user_id: UserId,
+    connection_id: ConnectionId,
+    /// `None` for spectators.
+    player_index: Option<i32>,
     bichannel: Bichannel<SessionToConnectionMsg, ConnectionToSessionMsg>,
     kind: PresentationKind,
+    /// Told what the game supports once it has been launched.
+    capabilities: oneshot::Sender<GameCapabilities>,
+}
+
+#[derive(Debug)]
+struct ObserveGame {
+    user_id: UserId,
+    bichannel: Bichannel<SessionToConnectionMsg, ConnectionToSessionMsg>,
+    kind: PresentationKind,
+    capabilities: oneshot::Sender<GameCapabilities>,
 }
 
 #[derive(Debug)]
 enum SystemToGameMsg {
     Enter(EnterGameSession),
+    Observe(ObserveGame),
 }
 
 #[derive(Debug)]
@@ -49,51 +105,666 @@ impl GameManager {
     pub async fn enter_session(
         &self,
         game_id: GameId,
+        user_id: UserId,
+        connection_id: ConnectionId,
         player_index: i32,
         kind: PresentationKind,
-    ) -> anyhow::Result<Bichannel<ConnectionToSessionMsg, SessionToConnectionMsg>> {
-        let s = {
-            self.games
-                .entry(game_id)
-                .or_insert_with(|| self.start_game(game_id))
-                .s
-                .clone()
-        };
+    ) -> anyhow::Result<(
+        Bichannel<ConnectionToSessionMsg, SessionToConnectionMsg>,
+        GameCapabilities,
+    )> {
+        self.enter_session_inner(game_id, user_id, connection_id, Some(player_index), kind)
+            .await
+    }
+
+    /// Watch a game without taking part. Anything sent by a spectator is ignored.
+    pub async fn enter_session_spectator(
+        &self,
+        game_id: GameId,
+        user_id: UserId,
+        connection_id: ConnectionId,
+        kind: PresentationKind,
+    ) -> anyhow::Result<(
+        Bichannel<ConnectionToSessionMsg, SessionToConnectionMsg>,
+        GameCapabilities,
+    )> {
+        self.enter_session_inner(game_id, user_id, connection_id, None, kind)
+            .await
+    }
+
+    /// Watch a game as each of its players sees it. Unlike spectators,
+    /// observers aren't announced to the players.
+    pub async fn observe(
+        &self,
+        game_id: GameId,
+        user_id: UserId,
+        kind: PresentationKind,
+    ) -> anyhow::Result<(
+        Bichannel<ConnectionToSessionMsg, SessionToConnectionMsg>,
+        GameCapabilities,
+    )> {
+        let (session_bichannel, connection_bichannel) = bichannel(4);
+        let (capabilities_s, capabilities_r) = oneshot::channel();
+        self.game_sender(game_id)
+            .send(SystemToGameMsg::Observe(ObserveGame {
+                user_id,
+                bichannel: connection_bichannel,
+                kind,
+                capabilities: capabilities_s,
+            }))
+            .await?;
+        Ok((session_bichannel, capabilities_r.await?))
+    }
+
+    async fn enter_session_inner(
+        &self,
+        game_id: GameId,
+        user_id: UserId,
+        connection_id: ConnectionId,
+        player_index: Option<i32>,
+        kind: PresentationKind,
+    ) -> anyhow::Result<(
+        Bichannel<ConnectionToSessionMsg, SessionToConnectionMsg>,
+        GameCapabilities,
+    )> {
         let (session_bichannel, connection_bichannel) = bichannel(4);
+        let (capabilities_s, capabilities_r) = oneshot::channel();
+        let s = self.game_sender(game_id);
         s.send(SystemToGameMsg::Enter(EnterGameSession {
+            user_id,
+            connection_id,
             player_index,
             bichannel: connection_bichannel,
             kind,
+            capabilities: capabilities_s,
         }))
         .await?;
-        Ok(session_bichannel)
+        Ok((session_bichannel, capabilities_r.await?))
+    }
+
+    fn game_sender(&self, game_id: GameId) -> mpsc::Sender<SystemToGameMsg> {
+        self.games
+            .entry(game_id)
+            .or_insert_with(|| self.start_game(game_id))
+            .s
+            .clone()
     }
 
     fn start_game(&self, game_id: GameId) -> Game {
-        let (system_s, system_r) = mpsc::channel(4);
-        GameActor {
-            aero: self.aero.clone(),
-            game_id,
-            system_r,
+        let shutdown = self.aero.obtain::<Shutdown>();
+        let (game_actor, instance_actor, game) = GameActor::new(&self.aero, game_id);
+        instance_actor.spawn_child(&shutdown.0);
+        game_actor.spawn_child(&shutdown.0);
+        game
+    }
+}
+
+#[derive(Debug)]
+struct Connection {
+    user_id: UserId,
+    /// Graphical presentations are also sent the state of the game.
+    kind: PresentationKind,
+    bichannel: Bichannel<SessionToConnectionMsg, ConnectionToSessionMsg>,
+}
+
+/// A connection which is waiting for its first view of the game before it
+/// joins the others.
+#[derive(Debug)]
+enum Entering {
+    Player((i32, ConnectionId), Connection),
+    Spectator(Connection),
+    Observer(Connection),
+}
+
+/// Who is watching the game, so that the game instance only renders views
+/// which someone will see.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Audience {
+    players: Vec<i32>,
+    spectated: bool,
+    observed: bool,
+    /// Views which a graphical presentation is shown.
+    graphical: Vec<PlayerView>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum View {
+    Player(i32),
+    Spectator,
+    /// Every player's view, labelled by player.
+    Observer,
+}
+
+impl View {
+    /// Observers only ever see the game as text.
+    fn player_view(self) -> Option<PlayerView> {
+        match self {
+            Self::Player(player_index) => Some(PlayerView::Player(player_index)),
+            Self::Spectator => Some(PlayerView::Spectator),
+            Self::Observer => None,
         }
-        .spawn();
-        Game { s: system_s }
     }
 }
 
+#[derive(Debug)]
+enum GameCommand {
+    Audience(Audience),
+    Line {
+        player_index: i32,
+        line: String,
+    },
+    /// Render a view for a connection which has just entered.
+    Render {
+        token: u64,
+        view: View,
+        graphical: bool,
+    },
+}
+
+#[derive(Debug)]
+enum InstanceToGameMsg {
+    Launched(GameCapabilities),
+    /// Sent to every connection of the player, and remembered for them.
+    Player(i32, TerminalSessionEvent),
+    /// What an absent player would have been shown.
+    Observed(i32, TerminalSessionEvent),
+    Spectators(TerminalSessionEvent),
+    /// Sent to the graphical presentations shown the view.
+    Graphical(PlayerView, GraphicalSessionEvent),
+    Rendered {
+        token: u64,
+        events: Vec<TerminalSessionEvent>,
+        state: Option<GraphicalSessionEvent>,
+    },
+}
+
+/// Keeps track of who is in a game and relays what they send. Everything
+/// which involves the game itself is queued for the `GameInstanceActor`, so
+/// that a slow game never holds up players entering or leaving.
 struct GameActor {
     aero: Aero,
     game_id: GameId,
     system_r: mpsc::Receiver<SystemToGameMsg>,
+    commands: mpsc::Sender<GameCommand>,
+    instance_r: mpsc::UnboundedReceiver<InstanceToGameMsg>,
+    /// A player may be connected more than once, eg. from two terminals.
+    players: HashMap<(i32, ConnectionId), Connection>,
+    /// Spectators are sent everything the players are, but nothing they send
+    /// is read, and they play no part in deciding whose turn it is.
+    spectators: Vec<Connection>,
+    /// Administrators who see what every player sees, labelled by player.
+    /// Like spectators, they're never read from, but the players aren't told
+    /// about them.
+    observers: Vec<Connection>,
+    rotation: Rotation,
+    /// Set once the game has been launched.
+    capabilities: Option<GameCapabilities>,
+    /// Connections which entered before the game was launched.
+    waiting: Vec<oneshot::Sender<GameCapabilities>>,
+    /// Connections whose first view is still being rendered, by token.
+    entering: HashMap<u64, Entering>,
+    next_token: u64,
+    /// The audience last sent to the game instance.
+    audience: Audience,
+    /// What each player has recently been shown.
+    history: HashMap<i32, SessionHistory>,
 }
 
+const USER_TIMEOUT: Duration = Duration::from_millis(200);
+/// How long a game with nobody in it keeps running.
+const EMPTY_TIMEOUT: Duration = Duration::from_secs(1);
+/// How many commands may be waiting for the game instance before players'
+/// input is left unread.
+const COMMAND_QUEUE_SIZE: usize = 16;
+
 #[async_trait]
 impl Actor for GameActor {
-    async fn run(self) -> anyhow::Result<()> {
+    fn span(&self) -> tracing::Span {
+        tracing::info_span!("game", game_id = %self.game_id)
+    }
+    async fn run(mut self, shutdown: CancellationToken) -> anyhow::Result<()> {
+        tracing::info!("Running game {}", self.game_id);
+        // Created once rather than in the `select!`, since otherwise the
+        // game's own messages would keep restarting it
+        let empty_timeout = tokio::time::sleep(EMPTY_TIMEOUT);
+        tokio::pin!(empty_timeout);
+        let mut was_empty = true;
+        loop {
+            tokio::select! {
+                biased;
+                _ = shutdown.cancelled() => break,
+                maybe_msg = self.instance_r.recv() => if let Some(msg) = maybe_msg { self.handle_instance_msg(msg).await } else { break },
+                maybe_msg = self.system_r.recv() => if let Some(msg) = maybe_msg { self.handle_system_msg(msg).await? } else { break },
+                (key, maybe_msg) = self.players.iter_mut().map(|(key, conn)| conn.bichannel.r.recv().with_key(*key)).select_fair(&mut self.rotation), if self.commands.capacity() > 0 => {
+                    if let Some(msg) = maybe_msg {
+                        self.handle_player_msg(key.0, msg).await?;
+                    } else {
+                        self.disconnect_player(key).await;
+                    }
+                },
+                _ = &mut empty_timeout, if self.is_empty() => {
+                    break;
+                }
+            }
+            self.sync_audience().await?;
+            let is_empty = self.is_empty();
+            if is_empty && !was_empty {
+                empty_timeout
+                    .as_mut()
+                    .reset(tokio::time::Instant::now() + EMPTY_TIMEOUT);
+            }
+            was_empty = is_empty;
+        }
+        tracing::info!("Stopping game {}", self.game_id);
         Ok(())
     }
 }
 
+impl GameActor {
+    /// The actor for a game, and the actor for its instance, which is
+    /// launched once it starts running.
+    fn new(aero: &Aero, game_id: GameId) -> (Self, GameInstanceActor, Game) {
+        let (system_s, system_r) = mpsc::channel(4);
+        let (commands_s, commands_r) = mpsc::channel(COMMAND_QUEUE_SIZE);
+        let (instance_s, instance_r) = mpsc::unbounded_channel();
+        let instance_actor = GameInstanceActor {
+            aero: aero.clone(),
+            game_id,
+            commands: commands_r,
+            events: instance_s,
+            instance: None,
+            game_type: String::new(),
+            description: None,
+            started_at: Utc::now(),
+            num_players: 0,
+            resigned: Vec::new(),
+            player_turn: None,
+            audience: Audience::default(),
+            refresh: false,
+            renders: Vec::new(),
+            tick: None,
+            graphical_ticks: HashMap::new(),
+        };
+        let game_actor = GameActor {
+            aero: aero.clone(),
+            game_id,
+            system_r,
+            commands: commands_s,
+            instance_r,
+            players: Default::default(),
+            spectators: Default::default(),
+            observers: Default::default(),
+            rotation: Default::default(),
+            capabilities: None,
+            waiting: Vec::new(),
+            entering: HashMap::new(),
+            next_token: 0,
+            audience: Audience::default(),
+            history: HashMap::new(),
+        };
+        (game_actor, instance_actor, Game { s: system_s })
+    }
+    fn is_empty(&self) -> bool {
+        self.players.is_empty()
+            && self.spectators.is_empty()
+            && self.observers.is_empty()
+            && self.entering.is_empty()
+    }
+    async fn queue(&mut self, cmd: GameCommand) -> anyhow::Result<()> {
+        self.commands
+            .send(cmd)
+            .await
+            .map_err(|_| anyhow!("Game {} has stopped", self.game_id))
+    }
+    /// Let the game instance know if anyone has come or gone.
+    async fn sync_audience(&mut self) -> anyhow::Result<()> {
+        self.spectators.retain(|conn| !conn.bichannel.is_closed());
+        self.observers.retain(|conn| !conn.bichannel.is_closed());
+        let mut graphical: Vec<_> = self
+            .players
+            .iter()
+            .filter(|(_, conn)| conn.kind == PresentationKind::Graphical)
+            .map(|(&(player_index, _), _)| PlayerView::Player(player_index))
+            .collect();
+        graphical.sort_unstable_by_key(|view| match view {
+            PlayerView::Player(player_index) => *player_index,
+            PlayerView::Spectator => -1,
+        });
+        graphical.dedup();
+        if self
+            .spectators
+            .iter()
+            .any(|conn| conn.kind == PresentationKind::Graphical)
+        {
+            graphical.push(PlayerView::Spectator);
+        }
+        let audience = Audience {
+            players: self.player_indices(),
+            spectated: !self.spectators.is_empty(),
+            observed: !self.observers.is_empty(),
+            graphical,
+        };
+        if audience != self.audience {
+            self.audience = audience.clone();
+            self.queue(GameCommand::Audience(audience)).await?;
+        }
+        Ok(())
+    }
+    fn send_capabilities(&mut self, s: oneshot::Sender<GameCapabilities>) {
+        match &self.capabilities {
+            Some(capabilities) => {
+                let _ = s.send(capabilities.clone());
+            }
+            None => self.waiting.push(s),
+        }
+    }
+    /// Queue the first view for a connection, which joins once it has been
+    /// rendered.
+    async fn render_for(&mut self, view: View, entering: Entering) -> anyhow::Result<()> {
+        let token = self.next_token;
+        self.next_token += 1;
+        let graphical = match &entering {
+            Entering::Player(_, conn) | Entering::Spectator(conn) | Entering::Observer(conn) => {
+                conn.kind == PresentationKind::Graphical
+            }
+        };
+        self.entering.insert(token, entering);
+        self.queue(GameCommand::Render {
+            token,
+            view,
+            graphical,
+        })
+        .await
+    }
+    async fn finish_entering(
+        &mut self,
+        token: u64,
+        events: Vec<TerminalSessionEvent>,
+        state: Option<GraphicalSessionEvent>,
+    ) {
+        let Some(entering) = self.entering.remove(&token) else {
+            return;
+        };
+        let conn = match &entering {
+            Entering::Player(_, conn) | Entering::Spectator(conn) | Entering::Observer(conn) => {
+                conn
+            }
+        };
+        let events = events
+            .into_iter()
+            .map(terminal_event)
+            .chain(state.map(graphical_event));
+        for msg in events {
+            let _ = conn.bichannel.s.send_timeout(msg, USER_TIMEOUT).await;
+        }
+        match entering {
+            Entering::Player(key, conn) => {
+                self.players.insert(key, conn);
+            }
+            Entering::Spectator(conn) => self.spectators.push(conn),
+            Entering::Observer(conn) => self.observers.push(conn),
+        }
+    }
+    fn player_indices(&self) -> Vec<i32> {
+        let mut player_indices: Vec<_> = self.players.keys().map(|&(index, _)| index).collect();
+        player_indices.sort_unstable();
+        player_indices.dedup();
+        player_indices
+    }
+    fn is_player_present(&self, player_index: i32) -> bool {
+        self.players.keys().any(|&(index, _)| index == player_index)
+    }
+    /// Send an event to every connection of the given player.
+    async fn send_event_to_player(&mut self, player_index: i32, ev: TerminalSessionEvent) {
+        self.send_to_observers(player_index, &ev);
+        let msg = terminal_event(ev);
+        if let SessionToConnectionMsg::Event(ev) = &msg {
+            self.history.entry(player_index).or_default().push(ev);
+        }
+        self.send_to_player(player_index, msg).await
+    }
+    async fn send_history_to_player(&mut self, player_index: i32, count: usize) {
+        let events = self
+            .history
+            .get(&player_index)
+            .map(|history| history.recent(count))
+            .unwrap_or_default();
+        self.send_to_player(player_index, SessionToConnectionMsg::History(events))
+            .await
+    }
+    async fn send_to_player(&mut self, player_index: i32, msg: SessionToConnectionMsg) {
+        let mut timed_out = Vec::new();
+        for (&key, conn) in &self.players {
+            if key.0 == player_index
+                && conn
+                    .bichannel
+                    .s
+                    .send_timeout(msg.clone(), USER_TIMEOUT)
+                    .await
+                    .is_err()
+            {
+                timed_out.push(key);
+            }
+        }
+        for key in timed_out {
+            self.timeout_player(key).await;
+        }
+    }
+    /// Observers must never hold up the game, so an observer which can't keep
+    /// up just misses events.
+    fn send_to_observers(&mut self, player_index: i32, ev: &TerminalSessionEvent) {
+        let Some(ev) = observed_event(player_index, ev) else {
+            return;
+        };
+        self.observers.retain(|conn| !conn.bichannel.is_closed());
+        for conn in &self.observers {
+            let _ = conn.bichannel.try_send(terminal_event(ev.clone()));
+        }
+    }
+    /// Changes only make sense to a presentation which saw every one before,
+    /// so a graphical spectator which can't keep up is dropped rather than
+    /// left to miss one.
+    async fn send_graphical(&mut self, view: PlayerView, ev: GraphicalSessionEvent) {
+        let msg = graphical_event(ev);
+        match view {
+            PlayerView::Player(player_index) => {
+                let mut timed_out = Vec::new();
+                for (&key, conn) in &self.players {
+                    if key.0 == player_index
+                        && conn.kind == PresentationKind::Graphical
+                        && conn
+                            .bichannel
+                            .s
+                            .send_timeout(msg.clone(), USER_TIMEOUT)
+                            .await
+                            .is_err()
+                    {
+                        timed_out.push(key);
+                    }
+                }
+                for key in timed_out {
+                    self.timeout_player(key).await;
+                }
+            }
+            PlayerView::Spectator => self.spectators.retain(|conn| {
+                conn.kind != PresentationKind::Graphical
+                    || conn.bichannel.try_send(msg.clone()).is_ok()
+            }),
+        }
+    }
+    async fn handle_instance_msg(&mut self, msg: InstanceToGameMsg) {
+        match msg {
+            InstanceToGameMsg::Launched(capabilities) => {
+                for s in self.waiting.drain(..) {
+                    let _ = s.send(capabilities.clone());
+                }
+                self.capabilities = Some(capabilities);
+            }
+            InstanceToGameMsg::Player(player_index, ev) => {
+                self.send_event_to_player(player_index, ev).await
+            }
+            InstanceToGameMsg::Observed(player_index, ev) => {
+                self.send_to_observers(player_index, &ev)
+            }
+            InstanceToGameMsg::Spectators(ev) => {
+                self.spectators.retain(|conn| !conn.bichannel.is_closed());
+                for conn in &self.spectators {
+                    let _ = conn.bichannel.try_send(terminal_event(ev.clone()));
+                }
+            }
+            InstanceToGameMsg::Graphical(view, ev) => self.send_graphical(view, ev).await,
+            InstanceToGameMsg::Rendered {
+                token,
+                events,
+                state,
+            } => self.finish_entering(token, events, state).await,
+        }
+    }
+    #[tracing::instrument(skip(self))]
+    async fn handle_system_msg(&mut self, msg: SystemToGameMsg) -> anyhow::Result<()> {
+        match msg {
+            SystemToGameMsg::Enter(enter) => {
+                let conn = Connection {
+                    user_id: enter.user_id,
+                    kind: enter.kind,
+                    bichannel: enter.bichannel,
+                };
+                self.send_capabilities(enter.capabilities);
+                // A player who is already connected elsewhere, or whose
+                // previous connection hasn't been noticed as dropped yet, is
+                // re-entering, and the others already know they're here.
+                let is_reentry = enter
+                    .player_index
+                    .is_some_and(|player_index| self.is_player_present(player_index));
+                if !is_reentry {
+                    self.broadcast(SessionToConnectionMsg::UserEntered(SessionMember {
+                        user_id: enter.user_id,
+                        player_index: enter.player_index.map(Into::into),
+                    }))
+                    .await;
+                }
+                // Bring the new connection up to date, since otherwise it
+                // would see nothing until the game next changes.
+                if let Some(history) = enter
+                    .player_index
+                    .and_then(|player_index| self.history.get(&player_index))
+                    .filter(|history| !history.is_empty())
+                {
+                    let events = history.recent(SessionHistory::REPLAY);
+                    let _ = conn
+                        .bichannel
+                        .s
+                        .send_timeout(SessionToConnectionMsg::History(events), USER_TIMEOUT)
+                        .await;
+                }
+                if let Some(player_index) = enter.player_index {
+                    if is_reentry {
+                        tracing::info!("Player {} re-entered.", player_index);
+                    } else {
+                        tracing::info!("Player {} entered.", player_index);
+                    }
+                    let key = (player_index, enter.connection_id);
+                    self.render_for(View::Player(player_index), Entering::Player(key, conn))
+                        .await?;
+                } else {
+                    tracing::info!("Spectator {} entered.", enter.user_id);
+                    self.render_for(View::Spectator, Entering::Spectator(conn))
+                        .await?;
+                }
+            }
+            SystemToGameMsg::Observe(observe) => {
+                let conn = Connection {
+                    user_id: observe.user_id,
+                    kind: observe.kind,
+                    bichannel: observe.bichannel,
+                };
+                self.send_capabilities(observe.capabilities);
+                tracing::info!("Observer {} entered.", observe.user_id);
+                self.render_for(View::Observer, Entering::Observer(conn))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+    #[tracing::instrument(skip(self))]
+    async fn handle_player_msg(
+        &mut self,
+        player_index: i32,
+        msg: ConnectionToSessionMsg,
+    ) -> anyhow::Result<()> {
+        match msg {
+            ConnectionToSessionMsg::Command(SessionCommand::Terminal(
+                TerminalSessionCommand::Line(line),
+            )) => self.queue(GameCommand::Line { player_index, line }).await?,
+            ConnectionToSessionMsg::Command(SessionCommand::Terminal(
+                TerminalSessionCommand::Scrollback(count),
+            )) => {
+                self.send_history_to_player(player_index, count.unwrap_or(SessionHistory::REPLAY))
+                    .await
+            }
+        }
+        Ok(())
+    }
+    #[tracing::instrument(skip(self))]
+    async fn disconnect_player(&mut self, key: (i32, ConnectionId)) {
+        let (player_index, _) = key;
+        if let Some(conn) = self.players.remove(&key) {
+            // The player may still be connected elsewhere
+            if !self.is_player_present(player_index) {
+                tracing::info!("Player {} left.", player_index);
+                self.broadcast(SessionToConnectionMsg::UserExited(SessionMember {
+                    user_id: conn.user_id,
+                    player_index: Some(player_index.into()),
+                }))
+                .await;
+            }
+        }
+    }
+    fn timeout_player(&mut self, key: (i32, ConnectionId)) -> BoxFuture<()> {
+        async move {
+            let (player_index, _) = key;
+            if let Some(conn) = self.players.remove(&key) {
+                if !self.is_player_present(player_index) {
+                    tracing::info!("Player {} left due to a timeout.", player_index);
+                    self.broadcast(SessionToConnectionMsg::UserExited(SessionMember {
+                        user_id: conn.user_id,
+                        player_index: Some(player_index.into()),
+                    }))
+                    .await;
+                }
+            }
+        }
+        .boxed()
+    }
+    async fn broadcast(&mut self, cmd: SessionToConnectionMsg) {
+        let mut timed_out = Vec::new();
+        for (&key, conn) in &self.players {
+            if conn
+                .bichannel
+                .s
+                .send_timeout(cmd.clone(), USER_TIMEOUT)
+                .await
+                .is_err()
+            {
+                timed_out.push(key);
+            }
+        }
+        for key in timed_out {
+            self.timeout_player(key).await;
+        }
+        // Spectators must never hold up the game, so a spectator which can't
+        // keep up just misses messages.
+        self.spectators.retain(|conn| !conn.bichannel.is_closed());
+        for conn in &self.spectators {
+            let _ = conn.bichannel.try_send(cmd.clone());
+        }
+        self.observers.retain(|conn| !conn.bichannel.is_closed());
+        for conn in &self.observers {
+            let _ = conn.bichannel.try_send(cmd.clone());
+        }
+    }
+}
+
 impl Drop for GameActor {
     fn drop(&mut self) {
         self.aero
@@ -102,3 +773,749 @@ impl Drop for GameActor {
             .remove(&self.game_id);
     }
 }
+
+/// Owns the running game, and works through the commands queued by the
+/// `GameActor` one at a time. Commands which arrive while the game is busy
+/// are handled together, and then everyone is shown the result once.
+struct GameInstanceActor {
+    aero: Aero,
+    game_id: GameId,
+    commands: mpsc::Receiver<GameCommand>,
+    events: mpsc::UnboundedSender<InstanceToGameMsg>,
+    instance: Option<Box<dyn GameInstance>>,
+    /// Set once the game has been launched.
+    game_type: String,
+    description: Option<GameDescription>,
+    started_at: DateTime<Utc>,
+    num_players: i32,
+    /// Players who have given up, in the order they resigned.
+    resigned: Vec<i32>,
+    /// Whose turn it was when last checked, so that players are only
+    /// notified when their turn starts.
+    player_turn: Option<i32>,
+    audience: Audience,
+    /// Whether the game has changed since everyone was last shown it.
+    refresh: bool,
+    /// Views requested since the queue was last drained, and whether each
+    /// is for a graphical presentation.
+    renders: Vec<(u64, View, bool)>,
+    /// The tick of the last action, by which the game identifies its state,
+    /// or `None` if it has changed in some other way since.
+    tick: Option<GameTick>,
+    /// The tick of the state each view's graphical presentations were last
+    /// sent, so that they can be sent only what has changed since.
+    graphical_ticks: HashMap<PlayerView, Option<GameTick>>,
+}
+
+#[async_trait]
+impl Actor for GameInstanceActor {
+    fn span(&self) -> tracing::Span {
+        tracing::info_span!("game_instance", game_id = %self.game_id)
+    }
+    async fn run(mut self, shutdown: CancellationToken) -> anyhow::Result<()> {
+        let config: Arc<Config> = self.aero.obtain_async().await;
+        let ping_interval = Duration::from_secs(config.game.ping_interval_secs);
+        let ping_timeout = Duration::from_secs(config.game.ping_timeout_secs);
+        self.launch().await?;
+        self.serve(ping_interval, ping_timeout, shutdown).await
+    }
+}
+
+impl GameInstanceActor {
+    /// Work through the queued commands until the `GameActor` stops.
+    async fn serve(
+        mut self,
+        ping_interval: Duration,
+        ping_timeout: Duration,
+        shutdown: CancellationToken,
+    ) -> anyhow::Result<()> {
+        let ping = tokio::time::sleep(ping_interval);
+        tokio::pin!(ping);
+        loop {
+            tokio::select! {
+                biased;
+                _ = shutdown.cancelled() => break,
+                maybe_cmd = self.commands.recv() => if let Some(cmd) = maybe_cmd { self.handle_command(cmd).await? } else { break },
+                _ = &mut ping => self.ping(ping_timeout).await?,
+            }
+            // Anything which queued up in the meantime is handled before
+            // rendering, so that a burst of commands only renders once.
+            while let Ok(cmd) = self.commands.try_recv() {
+                self.handle_command(cmd).await?;
+            }
+            self.render().await?;
+            // Only ping games which have been idle for a while
+            ping.as_mut()
+                .reset(tokio::time::Instant::now() + ping_interval);
+        }
+        Ok(())
+    }
+    async fn launch(&mut self) -> anyhow::Result<()> {
+        let (game, resigned) = transact!(anyhow::Error, self.aero, |tx| {
+            Ok((
+                database::game::get(tx, self.game_id).await?,
+                database::game::resigned_players(tx, self.game_id).await?,
+            ))
+        })?;
+        let game = game.ok_or_else(|| anyhow!("Game {} does not exist", self.game_id))?;
+        let launchers: Arc<Launchers> = self.aero.obtain_async().await;
+        // Older snapshots can be migrated by the game, but there's no going back
+        let description = launchers.describe(&game.game_type).await?;
+        let snapshot_version = u32::try_from(game.snapshot_version)?;
+        if snapshot_version > description.snapshot_version {
+            bail!(
+                "Game {} was saved by a newer version of `{}` (snapshot version {} > {})",
+                self.game_id,
+                game.game_type,
+                snapshot_version,
+                description.snapshot_version
+            );
+        }
+        // A game which hasn't had any actions applied yet has no snapshot, and
+        // is fully described by its setup. Binary snapshots can't be sent as
+        // part of the setup, so are loaded once the game is running.
+        let (snapshot, snapshot_binary) = if game.snapshot_ply == 0 {
+            (None, None)
+        } else if let Some(data) = game.snapshot_binary {
+            (None, Some(data))
+        } else {
+            let snapshot = SnapshotEnvelope {
+                version: snapshot_version,
+                data: game.snapshot.0,
+            };
+            (Some(snapshot), None)
+        };
+        let setup = |snapshot| GameSetup {
+            game_type: game.game_type.clone(),
+            num_players: game.num_players,
+            seed: game.seed,
+            rules: game.rules.0.clone(),
+            snapshot,
+        };
+        let restored = async {
+            let mut instance = launchers.launch(setup(snapshot)).await?;
+            if let Some(data) = snapshot_binary {
+                instance
+                    .load_snapshot_binary(snapshot_version, data)
+                    .await?;
+            }
+            anyhow::Ok(instance)
+        }
+        .await;
+        let instance = match restored {
+            Ok(instance) => instance,
+            // The action log can still bring a fresh game up to date
+            Err(e) if game.snapshot_ply > 0 => {
+                tracing::warn!(
+                    "Game {} couldn't be restored from its snapshot, replaying it instead: {e:#}",
+                    self.game_id
+                );
+                let actions = transact!(anyhow::Error, self.aero, |tx| {
+                    Ok(database::game::load_actions(tx, self.game_id).await?)
+                })?;
+                let mut instance = launchers.launch(setup(None)).await?;
+                replay(&mut *instance, actions).await?;
+                instance
+            }
+            Err(e) => return Err(e),
+        };
+        self.instance = Some(instance);
+        self.game_type = game.game_type;
+        // A new instance remembers nothing it has rendered
+        self.tick = None;
+        self.emit(InstanceToGameMsg::Launched(capabilities(
+            &description.capabilities,
+        )));
+        self.description = Some(description);
+        self.started_at = game.started_at;
+        self.num_players = game.num_players;
+        self.resigned = resigned;
+        // Games which have never been launched have no turn recorded yet
+        let turn = self.current_turn().await?;
+        self.player_turn = turn.map(|(player_turn, _)| player_turn);
+        transact!(anyhow::Error, self.aero, |tx| {
+            database::game::set_turn(tx, self.game_id, turn).await?;
+            Ok(())
+        })?;
+        Ok(())
+    }
+    /// Restart the game process if it doesn't reply to a ping in time.
+    async fn ping(&mut self, timeout: Duration) -> anyhow::Result<()> {
+        let res = match self.instance()?.ping().with_timeout(timeout).await {
+            Ok(res) => res,
+            Err(elapsed) => Err(anyhow!("{elapsed} after {timeout:?}")),
+        };
+        if let Err(e) = res {
+            tracing::warn!("Game {} is unresponsive, restarting: {e:#}", self.game_id);
+            self.instance = None;
+            self.launch().await?;
+        }
+        Ok(())
+    }
+    /// The `GameActor` only stops once everyone has left, so there's nobody
+    /// to tell if it has gone.
+    fn emit(&self, msg: InstanceToGameMsg) {
+        let _ = self.events.send(msg);
+    }
+    async fn handle_command(&mut self, cmd: GameCommand) -> anyhow::Result<()> {
+        match cmd {
+            GameCommand::Audience(audience) => self.audience = audience,
+            GameCommand::Line { player_index, line } => {
+                self.handle_line(player_index, line).await?
+            }
+            GameCommand::Render {
+                token,
+                view,
+                graphical,
+            } => self.renders.push((token, view, graphical)),
+        }
+        Ok(())
+    }
+    /// Show everyone the game if it has changed, and anyone who has just
+    /// entered what they asked to see. Connections which asked for the same
+    /// view share a render.
+    async fn render(&mut self) -> anyhow::Result<()> {
+        if std::mem::take(&mut self.refresh) {
+            self.refresh_all().await?;
+            self.notify_player_turn().await?;
+        }
+        let mut rendered = HashMap::new();
+        for (token, view, graphical) in std::mem::take(&mut self.renders) {
+            let events = match rendered.get(&view) {
+                Some(events) => events.clone(),
+                None => {
+                    let events = self.describe_view(view).await?;
+                    rendered.insert(view, events.clone());
+                    events
+                }
+            };
+            let state = match view.player_view().filter(|_| graphical) {
+                Some(view) => Some(self.graphical_state(view).await?),
+                None => None,
+            };
+            self.emit(InstanceToGameMsg::Rendered {
+                token,
+                events,
+                state,
+            });
+        }
+        Ok(())
+    }
+    /// Everything the view may see, which later changes are relative to.
+    async fn graphical_state(&mut self, view: PlayerView) -> anyhow::Result<GraphicalSessionEvent> {
+        let state = self.instance()?.render_player_state(view).await?;
+        self.graphical_ticks.insert(view, self.tick);
+        Ok(GraphicalSessionEvent::State {
+            tick: self.tick.map(|tick| tick.0),
+            state,
+        })
+    }
+    /// What has changed for the view since its graphical presentations were
+    /// last sent it, or the whole state if the game can't say.
+    async fn graphical_update(
+        &mut self,
+        view: PlayerView,
+    ) -> anyhow::Result<GraphicalSessionEvent> {
+        let since_tick = self
+            .graphical_ticks
+            .get(&view)
+            .copied()
+            .flatten()
+            .filter(|_| self.supports_render_diff());
+        if let Some(since_tick) = since_tick {
+            if let Some(patch) = self.instance()?.render_diff(view, since_tick).await? {
+                self.graphical_ticks.insert(view, self.tick);
+                return Ok(GraphicalSessionEvent::Diff {
+                    since_tick: since_tick.0,
+                    tick: self.tick.map(|tick| tick.0),
+                    patch,
+                });
+            }
+        }
+        self.graphical_state(view).await
+    }
+    /// Whose turn it is and when they must act by, as stored in the database.
+    async fn current_turn(&mut self) -> anyhow::Result<Option<(i32, DateTime<Utc>)>> {
+        let state = self.state().await?;
+        Ok(self.turn(state))
+    }
+    /// The state of the game, taking resignations into account.
+    async fn state(&mut self) -> anyhow::Result<GameState> {
+        let state = self.instance()?.state().await?;
+        Ok(self.apply_resignations(state))
+    }
+    /// Games which don't end themselves when players resign are over once
+    /// only one player is left, who wins.
+    fn apply_resignations(&self, state: GameState) -> GameState {
+        match state {
+            GameState::InProgress(_) if self.is_won_by_resignation() => {
+                GameState::Complete(GameResult {
+                    player_results: (0..self.num_players)
+                        .map(|player_index| PlayerResult {
+                            score: (!self.resigned.contains(&player_index)).into(),
+                        })
+                        .collect(),
+                })
+            }
+            state => state,
+        }
+    }
+    fn is_won_by_resignation(&self) -> bool {
+        !self.resigned.is_empty() && self.num_players - self.resigned.len() as i32 <= 1
+    }
+    fn turn(&self, state: GameState) -> Option<(i32, DateTime<Utc>)> {
+        match state {
+            GameState::InProgress(state) => {
+                Some((state.player_turn, self.tick_time(state.deadline)))
+            }
+            GameState::Complete(_) => None,
+        }
+    }
+    async fn current_player_turn(&mut self) -> anyhow::Result<Option<i32>> {
+        Ok(match self.state().await? {
+            GameState::InProgress(state) => Some(state.player_turn),
+            GameState::Complete(_) => None,
+        })
+    }
+    /// Let the player whose turn it is know, wherever they are, if their turn
+    /// has just started.
+    async fn notify_player_turn(&mut self) -> anyhow::Result<()> {
+        let player_turn = self.current_player_turn().await?;
+        if player_turn == self.player_turn {
+            return Ok(());
+        }
+        self.player_turn = player_turn;
+        let Some(player_index) = player_turn else {
+            return Ok(());
+        };
+        let notify = transact!(anyhow::Error, self.aero, |tx| {
+            Ok(
+                match database::game::player_id(tx, self.game_id, player_index).await? {
+                    Some(user_id) => {
+                        let settings = database::user::get_settings(tx, user_id).await?;
+                        Some((user_id, settings.turn_bell))
+                    }
+                    None => None,
+                },
+            )
+        })?;
+        if let Some((user_id, bell)) = notify {
+            self.aero
+                .obtain::<ConnectionManager>()
+                .send(
+                    user_id,
+                    SystemToConnectionMsg::YourTurn {
+                        game_id: self.game_id,
+                        bell,
+                    },
+                )
+                .await;
+        }
+        Ok(())
+    }
+    /// Games which support it save their snapshots as opaque bytes.
+    async fn save_snapshot(&mut self) -> anyhow::Result<SavedSnapshot> {
+        let (binary, version) = self
+            .description
+            .as_ref()
+            .map(|d| (d.capabilities.binary_snapshots, d.snapshot_version))
+            .ok_or_else(|| anyhow!("Game {} has not been launched", self.game_id))?;
+        let instance = self.instance()?;
+        Ok(if binary {
+            SavedSnapshot::Binary {
+                version,
+                data: instance.save_snapshot_binary().await?,
+            }
+        } else {
+            SavedSnapshot::Json(instance.save_snapshot().await?)
+        })
+    }
+    /// Game time is measured in milliseconds since the game started.
+    fn current_tick(&self) -> GameTick {
+        GameTick((Utc::now() - self.started_at).num_milliseconds())
+    }
+    fn tick_time(&self, tick: GameTick) -> DateTime<Utc> {
+        self.started_at + chrono::Duration::milliseconds(tick.0)
+    }
+    /// Advance the game, recording the action so that the game can be
+    /// replayed later. Everyone is shown the result once the queue is empty.
+    async fn apply_action(
+        &mut self,
+        player_index: Option<i32>,
+        action: IValue,
+    ) -> anyhow::Result<()> {
+        let tick = self.current_tick();
+        self.instance()?.advance(tick, action.clone()).await?;
+        let snapshot = self.save_snapshot().await?;
+        let state = self.state().await?;
+        let turn = self.turn(state.clone());
+        transact!(anyhow::Error, self.aero, |tx| {
+            database::game::record_action(
+                tx,
+                self.game_id,
+                tick.0,
+                player_index,
+                &action,
+                &snapshot,
+            )
+            .await?;
+            let completed = database::game::set_turn(tx, self.game_id, turn).await?;
+            if let (true, GameState::Complete(result)) = (completed, &state) {
+                record_result(tx, self.game_id, &self.game_type, result).await?;
+            }
+            Ok(())
+        })?;
+        self.tick = Some(tick);
+        self.refresh = true;
+        Ok(())
+    }
+    /// Take back every action after the given tick. Only the player whose turn
+    /// it is may ask, and the game decides whether its rules allow it.
+    async fn undo(&mut self, player_index: i32, to_tick: GameTick) -> anyhow::Result<()> {
+        if self.current_player_turn().await? != Some(player_index) {
+            self.send_line_to_player(player_index, "You can only undo on your turn.".into());
+            return Ok(());
+        }
+        let state = match self.instance()?.undo(to_tick).await {
+            Ok(state) => state,
+            Err(GameError::Refused(reason)) => {
+                self.send_line_to_player(player_index, format!("Can't undo: {reason}"));
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let snapshot = self.save_snapshot().await?;
+        let turn = self.turn(state);
+        transact!(anyhow::Error, self.aero, |tx| {
+            database::game::undo(tx, self.game_id, to_tick.0, &snapshot).await?;
+            database::game::set_turn(tx, self.game_id, turn).await?;
+            Ok(())
+        })?;
+        self.tick = Some(to_tick);
+        for &other_index in &self.audience.players {
+            self.send_line_to_player(
+                other_index,
+                format!(
+                    "Player {player_index} undid the game back to tick {}.",
+                    to_tick.0
+                ),
+            );
+        }
+        self.refresh = true;
+        Ok(())
+    }
+    /// The player gives up. The game decides what becomes of their turns, but
+    /// the resignation is recorded so that the game can be ended for them.
+    async fn resign(&mut self, player_index: i32) -> anyhow::Result<()> {
+        if let GameState::Complete(_) = self.state().await? {
+            self.send_line_to_player(player_index, "The game is already over.".into());
+            return Ok(());
+        }
+        let state = self.instance()?.resign(player_index).await?;
+        self.resigned.push(player_index);
+        // Resigning changes the game without a tick of its own, but is
+        // logged at the current tick so that it's replayed in order
+        self.tick = None;
+        let tick = self.current_tick();
+        let snapshot = self.save_snapshot().await?;
+        let state = self.apply_resignations(state);
+        let turn = self.turn(state.clone());
+        transact!(anyhow::Error, self.aero, |tx| {
+            database::game::resign(tx, self.game_id, tick.0, player_index, &snapshot).await?;
+            let completed = database::game::set_turn(tx, self.game_id, turn).await?;
+            if let (true, GameState::Complete(result)) = (completed, &state) {
+                record_result(tx, self.game_id, &self.game_type, result).await?;
+            }
+            Ok(())
+        })?;
+        let line = TerminalSessionEvent::Line(format!("Player {player_index} resigned."));
+        for &other_index in &self.audience.players {
+            self.emit(InstanceToGameMsg::Player(other_index, line.clone()));
+        }
+        self.emit(InstanceToGameMsg::Spectators(line));
+        self.refresh = true;
+        Ok(())
+    }
+    fn supports_undo(&self) -> bool {
+        self.description
+            .as_ref()
+            .is_some_and(|d| d.capabilities.undo)
+    }
+    fn supports_render_diff(&self) -> bool {
+        self.description
+            .as_ref()
+            .is_some_and(|d| d.capabilities.render_diff)
+    }
+    async fn refresh_all(&mut self) -> anyhow::Result<()> {
+        for player_index in 0..self.num_players {
+            // Players who aren't here are only shown to observers
+            let is_present = self.audience.players.contains(&player_index);
+            if !is_present && !self.audience.observed {
+                continue;
+            }
+            for ev in self.describe_current_state(Some(player_index)).await? {
+                self.emit(if is_present {
+                    InstanceToGameMsg::Player(player_index, ev)
+                } else {
+                    InstanceToGameMsg::Observed(player_index, ev)
+                });
+            }
+        }
+        if self.audience.spectated {
+            for ev in self.describe_current_state(None).await? {
+                self.emit(InstanceToGameMsg::Spectators(ev));
+            }
+        }
+        for view in self.audience.graphical.clone() {
+            let ev = self.graphical_update(view).await?;
+            self.emit(InstanceToGameMsg::Graphical(view, ev));
+        }
+        Ok(())
+    }
+    fn send_line_to_player(&self, player_index: i32, line: String) {
+        self.emit(InstanceToGameMsg::Player(
+            player_index,
+            TerminalSessionEvent::Line(line),
+        ));
+    }
+    fn instance(&mut self) -> anyhow::Result<&mut dyn GameInstance> {
+        Ok(self
+            .instance
+            .as_deref_mut()
+            .ok_or_else(|| anyhow!("Game {} has not been launched", self.game_id))?)
+    }
+    async fn describe_view(&mut self, view: View) -> anyhow::Result<Vec<TerminalSessionEvent>> {
+        Ok(match view {
+            View::Player(player_index) => self.describe_current_state(Some(player_index)).await?,
+            View::Spectator => self.describe_current_state(None).await?,
+            View::Observer => {
+                let mut events = Vec::new();
+                for player_index in 0..self.num_players {
+                    for ev in self.describe_current_state(Some(player_index)).await? {
+                        events.extend(observed_event(player_index, &ev));
+                    }
+                }
+                events
+            }
+        })
+    }
+    /// Events describing the current state of the game, as seen by the given
+    /// player, or by a spectator if `player_index` is `None`.
+    async fn describe_current_state(
+        &mut self,
+        player_index: Option<i32>,
+    ) -> anyhow::Result<Vec<TerminalSessionEvent>> {
+        let instance = self.instance()?;
+        let mut events = Vec::new();
+        let mut input = None;
+        let view = player_index.map_or(PlayerView::Spectator, PlayerView::Player);
+        match instance.render_console_ui(view).await {
+            Ok(ui) => {
+                events.push(TerminalSessionEvent::Line(ui.prompt));
+                // Spectators can't send commands, so only players are asked for input
+                if player_index.is_some() {
+                    input = Some(input_event(ui.input));
+                }
+            }
+            Err(GameError::UnsupportedPresentationMode) => {}
+            Err(GameError::Other(e)) => return Err(e),
+        }
+        events.push(TerminalSessionEvent::Line(match self.state().await? {
+            GameState::InProgress(state) => {
+                format!("Waiting for player {}.", state.player_turn)
+            }
+            GameState::Complete(result) => format!(
+                "Game over. Scores: {}",
+                result
+                    .player_results
+                    .iter()
+                    .map(|r| r.score.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }));
+        events.extend(input);
+        Ok(events)
+    }
+    #[tracing::instrument(skip(self))]
+    async fn handle_line(&mut self, player_index: i32, line: String) -> anyhow::Result<()> {
+        if self.resigned.contains(&player_index) {
+            self.send_line_to_player(player_index, "You have resigned.".into());
+            return Ok(());
+        }
+        if line.trim() == "resign" {
+            return self.resign(player_index).await;
+        }
+        if let GameState::Complete(_) = self.state().await? {
+            self.send_line_to_player(player_index, "The game is over.".into());
+            return Ok(());
+        }
+        // Games which support undo leave the command to us, since it
+        // has to be persisted
+        if let Some(args) = line.strip_prefix("undo ").filter(|_| self.supports_undo()) {
+            match args.trim().parse() {
+                Ok(tick) => self.undo(player_index, GameTick(tick)).await?,
+                Err(_) => self.send_line_to_player(player_index, "Usage: undo <tick>".into()),
+            }
+            return Ok(());
+        }
+        let response = match self
+            .instance()?
+            .interpret_console_command(player_index, &line)
+            .await
+        {
+            Ok(response) => response,
+            Err(GameError::UnsupportedPresentationMode) => {
+                self.send_line_to_player(
+                    player_index,
+                    "This game can't be played from a terminal.".into(),
+                );
+                return Ok(());
+            }
+            Err(GameError::Other(e)) => return Err(e),
+        };
+        if let Some(ui) = response.update_ui {
+            self.send_line_to_player(player_index, ui.prompt);
+            self.emit(InstanceToGameMsg::Player(
+                player_index,
+                input_event(ui.input),
+            ));
+        }
+        for (other_index, ui) in response.update_others {
+            self.send_line_to_player(other_index, ui.prompt);
+            self.emit(InstanceToGameMsg::Player(
+                other_index,
+                input_event(ui.input),
+            ));
+        }
+        if let Some(action) = response.advance {
+            self.apply_action(Some(player_index), action).await?;
+        }
+        Ok(())
+    }
+}
+
+fn terminal_event(ev: TerminalSessionEvent) -> SessionToConnectionMsg {
+    SessionToConnectionMsg::Event(SessionEvent::Terminal(ev))
+}
+
+fn graphical_event(ev: GraphicalSessionEvent) -> SessionToConnectionMsg {
+    SessionToConnectionMsg::Event(SessionEvent::Graphical(ev))
+}
+
+/// Observers are shown what each player sees, labelled by player. Only the
+/// player needs to know how to prompt for input.
+fn observed_event(player_index: i32, ev: &TerminalSessionEvent) -> Option<TerminalSessionEvent> {
+    match ev {
+        TerminalSessionEvent::Line(line) => Some(TerminalSessionEvent::Line(format!(
+            "[Player {player_index}] {line}"
+        ))),
+        TerminalSessionEvent::Input(_) => None,
+    }
+}
+
+fn capabilities(capabilities: &playferrous_types::GameCapabilities) -> GameCapabilities {
+    GameCapabilities {
+        console_ui: capabilities.console_ui,
+        masked_input: capabilities.masked_input,
+        spectators: capabilities.spectators,
+    }
+}
+
+fn input_event(input: InputMode) -> TerminalSessionEvent {
+    TerminalSessionEvent::Input(match input {
+        InputMode::Line => TerminalInputMode::Line,
+        InputMode::Masked => TerminalInputMode::Masked,
+        InputMode::Choice(choices) => TerminalInputMode::Choice(choices),
+    })
+}
+
+/// Record the final scores of a game, and update its players' ratings. Must
+/// only be called once per game, when it's completed.
+async fn record_result(
+    tx: &mut Transaction,
+    game_id: GameId,
+    game_type: &str,
+    result: &GameResult,
+) -> sqlx::Result<()> {
+    database::game::set_result(tx, game_id, result).await?;
+    let players = database::game::player_ids(tx, game_id).await?;
+    database::rating::apply_result(tx, game_type, result, &players).await
+}
+
+#[cfg(test)]
+mod tests {
+    use playferrous_launcher::mock::{MockGameInstance, TakeTurns};
+
+    use super::*;
+
+    async fn enter(
+        game: &Game,
+        user_id: i64,
+        player_index: Option<i32>,
+    ) -> Bichannel<ConnectionToSessionMsg, SessionToConnectionMsg> {
+        let (session_bichannel, connection_bichannel) = bichannel(4);
+        let (capabilities_s, capabilities_r) = oneshot::channel();
+        game.s
+            .send(SystemToGameMsg::Enter(EnterGameSession {
+                user_id: UserId(user_id),
+                connection_id: ConnectionId(user_id as u64),
+                player_index,
+                bichannel: connection_bichannel,
+                kind: PresentationKind::Terminal,
+                capabilities: capabilities_s,
+            }))
+            .await
+            .unwrap();
+        capabilities_r.await.unwrap();
+        session_bichannel
+    }
+
+    #[tokio::test]
+    async fn slow_render_does_not_delay_a_second_player_joining() {
+        let instance = MockGameInstance::new(TakeTurns::setup(2)).unwrap();
+        let render_delay = instance.render_delay();
+        let (game_actor, mut instance_actor, game) = GameActor::new(&Aero::new(), GameId(1));
+        instance_actor.instance = Some(Box::new(instance));
+        instance_actor.num_players = 2;
+        instance_actor.emit(InstanceToGameMsg::Launched(GameCapabilities::default()));
+        let shutdown = CancellationToken::new();
+        let _stop = shutdown.clone().drop_guard();
+        tokio::spawn(game_actor.run(shutdown.clone()));
+        tokio::spawn(instance_actor.serve(
+            Duration::from_secs(60),
+            Duration::from_secs(5),
+            shutdown.clone(),
+        ));
+
+        // Player 0 joins once their first view has been rendered
+        let mut player0 = enter(&game, 1, Some(0)).await;
+        assert!(matches!(
+            player0.r.recv().await,
+            Some(SessionToConnectionMsg::Event(_))
+        ));
+
+        // A spectator's first view keeps the game busy, but player 1 still
+        // gets in, and player 0 hears about it
+        *render_delay.lock().unwrap() = Duration::from_secs(10);
+        let _spectator = enter(&game, 3, None).await;
+        let joined = async {
+            let _player1 = enter(&game, 2, Some(1)).await;
+            loop {
+                match player0.r.recv().await {
+                    Some(SessionToConnectionMsg::UserEntered(member))
+                        if member.user_id == UserId(2) =>
+                    {
+                        break member
+                    }
+                    Some(_) => {}
+                    None => panic!("player 0 was disconnected"),
+                }
+            }
+        };
+        let member = tokio::time::timeout(Duration::from_secs(1), joined)
+            .await
+            .expect("player 1 was held up by the render");
+        assert_eq!(member.player_index, Some(1));
+    }
+}
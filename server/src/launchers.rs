@@ -1,18 +1,31 @@
-use std::sync::Arc;
+use std::{
+    convert::Infallible,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 
 use aerosol::{Aero, AsyncConstructible};
+use anyhow::anyhow;
 use async_trait::async_trait;
+use dashmap::DashMap;
+use ijson::IValue;
+use jsonschema::JSONSchema;
+use playferrous_presentation::{actor::Actor, GameTypeInfo, LauncherInfo};
 use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
 
-use playferrous_launcher::{Launcher, LauncherConfig};
+use playferrous_launcher::{GameInstance, Launcher, LauncherConfig, LauncherError};
 use playferrous_process_launcher::ProcessLauncherConfig;
+use playferrous_types::{GameDescription, GameSetup};
+use playferrous_wasm_launcher::WasmLauncherConfig;
 
-use crate::Config;
+use crate::{Config, Shutdown};
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum AnyLauncherConfig {
     Process(ProcessLauncherConfig),
+    Wasm(WasmLauncherConfig),
 }
 
 #[async_trait]
@@ -20,13 +33,35 @@ impl LauncherConfig for AnyLauncherConfig {
     async fn start_launcher(&self) -> anyhow::Result<Arc<dyn Launcher>> {
         match self {
             AnyLauncherConfig::Process(c) => c.start_launcher().await,
+            AnyLauncherConfig::Wasm(c) => c.start_launcher().await,
         }
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HealthCheckConfig {
+    /// How often to check that each launcher is healthy, in seconds.
+    pub interval_secs: u64,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self { interval_secs: 60 }
+    }
+}
+
 #[derive(Debug)]
 pub struct Launchers {
     launchers: Vec<Arc<dyn Launcher>>,
+    descriptions: DashMap<String, GameDescription>,
+    rules_schemas: DashMap<String, Arc<JSONSchema>>,
+    /// Every game type which can be launched, or `None` until it's first
+    /// needed.
+    catalog: RwLock<Option<Vec<GameTypeInfo>>>,
+    /// The error from the most recent health check of each unhealthy
+    /// launcher, keyed by its index.
+    failures: DashMap<usize, String>,
 }
 
 #[async_trait]
@@ -38,6 +73,270 @@ impl AsyncConstructible for Launchers {
         for item in &config.launcher {
             launchers.push(item.start_launcher().await?);
         }
-        Ok(Self { launchers })
+        let res = Self {
+            launchers,
+            descriptions: DashMap::new(),
+            rules_schemas: DashMap::new(),
+            catalog: RwLock::new(None),
+            failures: DashMap::new(),
+        };
+        res.check_health().await;
+        Ok(res)
+    }
+}
+
+impl Launchers {
+    /// Describe a game type, using the first launcher which supports it.
+    /// Descriptions are cached, since they can be expensive to obtain.
+    pub async fn describe(&self, game_type: &str) -> Result<GameDescription, LauncherError> {
+        if let Some(description) = self.descriptions.get(game_type) {
+            return Ok(description.clone());
+        }
+        for launcher in &self.launchers {
+            match launcher.describe(game_type).await {
+                Err(LauncherError::UnknownGameType) => continue,
+                Ok(description) => {
+                    self.descriptions
+                        .insert(game_type.into(), description.clone());
+                    return Ok(description);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(LauncherError::UnknownGameType)
+    }
+    /// What to call a game type when showing it to users. Falls back to the
+    /// game type itself if the game can't be described, or has no name.
+    pub async fn display_name(&self, game_type: &str) -> String {
+        match self.describe(game_type).await {
+            Ok(GameDescription {
+                display_name: Some(display_name),
+                ..
+            }) => display_name,
+            _ => game_type.into(),
+        }
+    }
+    /// The schema the rules of a game type must match, compiled. Schemas are
+    /// cached like descriptions.
+    async fn rules_schema(&self, game_type: &str) -> Result<Arc<JSONSchema>, LauncherError> {
+        if let Some(schema) = self.rules_schemas.get(game_type) {
+            return Ok(schema.clone());
+        }
+        for launcher in &self.launchers {
+            match launcher.rules_schema(game_type).await {
+                Err(LauncherError::UnknownGameType) => continue,
+                Ok(schema) => {
+                    let schema = serde_json::to_value(schema).map_err(anyhow::Error::from)?;
+                    let schema = JSONSchema::compile(&schema)
+                        .map_err(|e| anyhow!("`{game_type}` has an invalid rules schema: {e}"))?;
+                    let schema = Arc::new(schema);
+                    self.rules_schemas.insert(game_type.into(), schema.clone());
+                    return Ok(schema);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(LauncherError::UnknownGameType)
+    }
+    /// Check rules against the game type's schema, which explains exactly
+    /// which fields are wrong, and then using the first launcher which
+    /// supports the game type.
+    pub async fn validate_rules(
+        &self,
+        game_type: &str,
+        rules: IValue,
+    ) -> Result<(), LauncherError> {
+        let schema = self.rules_schema(game_type).await?;
+        let instance = serde_json::to_value(&rules).map_err(anyhow::Error::from)?;
+        if let Err(errors) = schema.validate(&instance) {
+            let errors: Vec<_> = errors
+                .map(|e| {
+                    let path = e.instance_path.to_string();
+                    if path.is_empty() {
+                        e.to_string()
+                    } else {
+                        format!("`{path}`: {e}")
+                    }
+                })
+                .collect();
+            return Err(LauncherError::InvalidRules(errors.join("; ")));
+        }
+        for launcher in &self.launchers {
+            match launcher.validate_rules(game_type, rules.clone()).await {
+                Err(LauncherError::UnknownGameType) => continue,
+                res => return res,
+            }
+        }
+        Err(LauncherError::UnknownGameType)
+    }
+    /// Launch a game using the first launcher which supports its game type.
+    pub async fn launch(
+        &self,
+        game_setup: GameSetup,
+    ) -> Result<Box<dyn GameInstance>, LauncherError> {
+        for launcher in &self.launchers {
+            match launcher.launch(game_setup.clone()).await {
+                Err(LauncherError::UnknownGameType) => continue,
+                res => return res,
+            }
+        }
+        Err(LauncherError::UnknownGameType)
+    }
+    /// Run a health check on every launcher, logging any which fail.
+    pub async fn check_health(&self) {
+        for (index, launcher) in self.launchers.iter().enumerate() {
+            match launcher.health_check().await {
+                Ok(()) => {
+                    if self.failures.remove(&index).is_some() {
+                        tracing::info!("Launcher {:?} has recovered", launcher);
+                    }
+                }
+                Err(e) => {
+                    tracing::error!("Launcher {:?} is unhealthy: {:#}", launcher, e);
+                    self.failures.insert(index, format!("{e:#}"));
+                }
+            }
+        }
+    }
+    /// Every configured launcher, with the game types it supports, for
+    /// operators to check what's available.
+    pub async fn list(&self) -> Vec<LauncherInfo> {
+        let mut infos = Vec::with_capacity(self.launchers.len());
+        for (index, launcher) in self.launchers.iter().enumerate() {
+            let (game_types, error) = match launcher.supported_game_types().await {
+                Ok(game_types) => (game_types, None),
+                Err(e) => (Vec::new(), Some(format!("{e:#}"))),
+            };
+            infos.push(LauncherInfo {
+                launcher: format!("{launcher:?}"),
+                game_types,
+                // A failure to list game types is more relevant than an
+                // earlier health check
+                error: error.or_else(|| self.failures.get(&index).map(|e| e.value().clone())),
+            });
+        }
+        infos
+    }
+    /// Every game type which can be launched, as each game describes itself,
+    /// sorted by name. The catalog is cached, since describing a game can
+    /// mean spawning a process, and is refreshed along with the health checks.
+    pub async fn catalog(&self) -> Vec<GameTypeInfo> {
+        if let Some(catalog) = &*self.catalog.read().unwrap() {
+            return catalog.clone();
+        }
+        self.build_catalog().await
+    }
+    /// Describe every game type again, in case the games have changed. Does
+    /// nothing if the catalog hasn't been needed yet.
+    pub async fn refresh_catalog(&self) {
+        if self.catalog.read().unwrap().is_some() {
+            self.build_catalog().await;
+        }
+    }
+    async fn build_catalog(&self) -> Vec<GameTypeInfo> {
+        let mut catalog: Vec<GameTypeInfo> = Vec::new();
+        for launcher in &self.launchers {
+            // A launcher which can't list its game types fails its health
+            // check too, so there's no need to log it here
+            let Ok(game_types) = launcher.supported_game_types().await else {
+                continue;
+            };
+            for game_type in game_types {
+                // Earlier launchers take precedence, as they do when launching
+                if catalog.iter().any(|info| info.game_type == game_type) {
+                    continue;
+                }
+                let description = match launcher.describe(&game_type).await {
+                    Ok(description) => description,
+                    Err(e) => {
+                        tracing::warn!("Failed to describe `{game_type}`: {e}");
+                        continue;
+                    }
+                };
+                catalog.push(GameTypeInfo {
+                    display_name: description
+                        .display_name
+                        .clone()
+                        .unwrap_or_else(|| game_type.clone()),
+                    description: description.description.clone(),
+                    min_players: description.min_players,
+                    max_players: description.max_players,
+                    game_type: game_type.clone(),
+                });
+                self.descriptions.insert(game_type, description);
+            }
+        }
+        catalog.sort_by(|a, b| a.display_name.cmp(&b.display_name));
+        *self.catalog.write().unwrap() = Some(catalog.clone());
+        catalog
+    }
+    /// Describe each launcher which failed its most recent health check.
+    pub fn unhealthy(&self) -> Vec<String> {
+        let mut failures: Vec<_> = self
+            .failures
+            .iter()
+            .map(|entry| (*entry.key(), entry.value().clone()))
+            .collect();
+        failures.sort();
+        failures
+            .into_iter()
+            .map(|(index, error)| format!("{:?}: {}", self.launchers[index], error))
+            .collect()
+    }
+}
+
+/// Periodically re-runs the launcher health checks, and refreshes the catalog
+/// of game types.
+#[derive(Debug)]
+struct LauncherHealthActor {
+    aero: Aero,
+    interval: Duration,
+}
+
+#[async_trait]
+impl Actor for LauncherHealthActor {
+    async fn run(self, shutdown: CancellationToken) -> anyhow::Result<()> {
+        let launchers: Arc<Launchers> = self.aero.obtain_async().await;
+        let mut interval = tokio::time::interval(self.interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        // The first tick completes immediately, and the launchers were already
+        // checked when they were constructed.
+        interval.tick().await;
+        loop {
+            tokio::select! {
+                biased;
+                _ = shutdown.cancelled() => break,
+                _ = interval.tick() => {
+                    launchers.check_health().await;
+                    launchers.refresh_catalog().await;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct LauncherHealthMonitor {
+    shutdown: CancellationToken,
+}
+
+#[async_trait]
+impl AsyncConstructible for LauncherHealthMonitor {
+    type Error = Infallible;
+    async fn construct_async(aero: &Aero) -> Result<Self, Self::Error> {
+        let config: Arc<Config> = aero.obtain_async().await;
+        let (_, shutdown) = LauncherHealthActor {
+            aero: aero.clone(),
+            interval: Duration::from_secs(config.health_check.interval_secs.max(1)),
+        }
+        .spawn_child(&aero.obtain::<Shutdown>().0);
+        Ok(Self { shutdown })
+    }
+}
+
+impl Drop for LauncherHealthMonitor {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
     }
 }
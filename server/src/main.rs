@@ -1,13 +1,29 @@
-use std::sync::Arc;
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use aerosol::{Aero, AsyncConstructible};
+use aerosol::{Aero, AsyncConstructible, Constructible};
+use anyhow::bail;
 use async_trait::async_trait;
-use launchers::AnyLauncherConfig;
+use connection_manager::ConnectionConfig;
+use game_manager::GameConfig;
+use launchers::{AnyLauncherConfig, HealthCheckConfig, LauncherHealthMonitor, Launchers};
+use metrics_exporter_prometheus::PrometheusBuilder;
+use playferrous_presentation::{UserManagement, UserManagementError};
 use presentations::{AnyPresentationConfig, Presentations};
+use proposal_manager::{ProposalConfig, ProposalSweeper};
 use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
 use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
 
-use crate::{connection_manager::ConnectionManager, user_management::UserManagementImpl};
+use crate::{
+    connection_manager::ConnectionManager,
+    database::Database,
+    user_management::{PasswordConfig, UserManagementImpl},
+};
 
 #[macro_use]
 mod database;
@@ -16,13 +32,53 @@ mod game_manager;
 mod launchers;
 mod presentations;
 mod proposal_manager;
+mod replay;
 mod user_management;
 mod utils;
 
+/// How long to wait for connections to close when shutting down.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Debug, Serialize, Deserialize)]
 struct Config {
     launcher: Vec<AnyLauncherConfig>,
     presentation: Vec<AnyPresentationConfig>,
+    #[serde(default)]
+    connection: ConnectionConfig,
+    #[serde(default)]
+    proposal: ProposalConfig,
+    #[serde(default)]
+    health_check: HealthCheckConfig,
+    #[serde(default)]
+    metrics: MetricsConfig,
+    #[serde(default)]
+    password: PasswordConfig,
+    #[serde(default)]
+    game: GameConfig,
+    /// Usernames of the users who administer the server. They're made
+    /// administrators when they register, or at startup if they already have.
+    #[serde(default)]
+    admins: Vec<String>,
+}
+
+/// Cancelled when the server shuts down, which stops every actor spawned as
+/// its child.
+#[derive(Debug, Clone, Default)]
+struct Shutdown(CancellationToken);
+
+impl Constructible for Shutdown {
+    type Error = Infallible;
+    fn construct(_aero: &Aero) -> Result<Self, Self::Error> {
+        Ok(Self::default())
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct MetricsConfig {
+    /// Where to serve metrics in the Prometheus format. Metrics are not
+    /// collected at all if this is not set.
+    prometheus_listen: Option<SocketAddr>,
 }
 
 #[async_trait]
@@ -42,16 +98,102 @@ async fn main() -> anyhow::Result<()> {
         .with_env_filter(EnvFilter::from_default_env())
         .init();
 
-    let aero = Aero::new()
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        None => {}
+        Some("create-user") => {
+            let (Some(username), None) = (args.next(), args.next()) else {
+                bail!("Usage: playferrous-server create-user <username>");
+            };
+            return create_user(&username).await;
+        }
+        Some(other) => bail!("Unknown command `{other}`"),
+    }
+
+    let aero = Aero::new().with_constructed_async::<Arc<Config>>().await;
+    // Installed before anything else is constructed, so no metrics are missed
+    if let Some(addr) = aero.get::<Arc<Config>, _>().metrics.prometheus_listen {
+        PrometheusBuilder::new()
+            .with_http_listener(addr)
+            .install()?;
+    }
+
+    let aero = aero
+        .with_constructed::<Shutdown>()
         .with_constructed::<Arc<UserManagementImpl>>()
         .with_constructed::<ConnectionManager>()
         .with_constructed_async::<Arc<Presentations>>()
+        .await
+        .with_constructed_async::<Arc<ProposalSweeper>>()
+        .await
+        .with_constructed_async::<Arc<LauncherHealthMonitor>>()
         .await;
+    aero.get::<Arc<UserManagementImpl>, _>()
+        .grant_admins()
+        .await?;
 
-    aero.get::<ConnectionManager, _>()
-        .broadcast([], |_| panic!())
-        .await;
-
+    let unhealthy = aero.obtain_async::<Arc<Launchers>>().await.unhealthy();
+    if !unhealthy.is_empty() {
+        tracing::warn!("Started with unhealthy launchers: {}", unhealthy.join(", "));
+    }
     println!("Started...");
+
+    // Presentations run in background tasks, which would die with the
+    // runtime if `main` returned
+    wait_for_shutdown_signal().await?;
+    tracing::info!("Shutting down...");
+    aero.get::<Arc<Presentations>, _>().shutdown().await;
+    // Give connections a chance to leave their sessions cleanly
+    let connection_manager = aero.get::<ConnectionManager, _>();
+    let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+    while !connection_manager.online_user_ids().is_empty() && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    // Stop every actor which is still running, such as games with nobody
+    // connected, so that they don't outlive the database
+    aero.get::<Shutdown, _>().0.cancel();
+    if let Some(database) = aero.try_get::<Database>() {
+        database.close().await;
+    }
+    // Any game processes still running are killed as the runtime shuts down
     Ok(())
 }
+
+/// Resolves on Ctrl-C, or on SIGTERM, which is how service managers ask the
+/// server to stop.
+async fn wait_for_shutdown_signal() -> anyhow::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut sigterm = signal(SignalKind::terminate())?;
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => res?,
+            _ = sigterm.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
+/// Create a user without going through a presentation, reading the password
+/// from stdin so that it doesn't end up in the shell history.
+async fn create_user(username: &str) -> anyhow::Result<()> {
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']);
+
+    let aero = Aero::new().with_constructed::<Arc<UserManagementImpl>>();
+    let user_management = aero.get::<Arc<UserManagementImpl>, _>();
+    match user_management.create_user(username, password).await {
+        Ok(user_id) => {
+            println!("Created user {username} ({user_id})");
+            Ok(())
+        }
+        Err(UserManagementError::UserAlreadyExists) => {
+            bail!("A user named `{username}` already exists")
+        }
+        Err(e) => Err(e.into()),
+    }
+}
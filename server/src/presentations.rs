@@ -1,10 +1,11 @@
-use std::{any::Any, sync::Arc};
+use std::{fmt, sync::Arc};
 
 use aerosol::{Aero, AsyncConstructible};
 use anyhow::Context;
 use async_trait::async_trait;
 use playferrous_presentation::{Presentation, UserManagement};
 use playferrous_presentation_ssh::PresentationSsh;
+use playferrous_presentation_ws::PresentationWs;
 use serde::{Deserialize, Serialize};
 
 use crate::Config;
@@ -13,22 +14,63 @@ use crate::Config;
 #[serde(tag = "type")]
 pub enum AnyPresentationConfig {
     Ssh(<PresentationSsh as Presentation>::Config),
+    Ws(<PresentationWs as Presentation>::Config),
+}
+
+/// Object-safe view of a running presentation.
+#[async_trait]
+trait AnyPresentation: Send + Sync {
+    async fn shutdown(&self);
+}
+
+#[async_trait]
+impl<P: Presentation + Send + Sync> AnyPresentation for P {
+    async fn shutdown(&self) {
+        Presentation::shutdown(self).await
+    }
 }
 
 impl AnyPresentationConfig {
     async fn start_presentation(
         &self,
         user_management: Arc<dyn UserManagement>,
-    ) -> anyhow::Result<Arc<dyn Any + Send + Sync>> {
+    ) -> anyhow::Result<Arc<dyn AnyPresentation>> {
         Ok(match self {
             Self::Ssh(config) => Arc::new(PresentationSsh::new(config, user_management).await?),
+            Self::Ws(config) => Arc::new(PresentationWs::new(config, user_management).await?),
         })
     }
 }
 
-#[derive(Debug, Clone)]
 pub struct Presentations {
-    _presentations: Vec<Arc<dyn Any + Send + Sync>>,
+    presentations: Vec<Arc<dyn AnyPresentation>>,
+}
+
+impl fmt::Debug for Presentations {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Presentations")
+            .field("count", &self.presentations.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Presentations {
+    pub async fn shutdown(&self) {
+        for presentation in &self.presentations {
+            presentation.shutdown().await;
+        }
+    }
+}
+
+impl Drop for Presentations {
+    fn drop(&mut self) {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        for presentation in self.presentations.drain(..) {
+            runtime.spawn(async move { presentation.shutdown().await });
+        }
+    }
 }
 
 #[async_trait]
@@ -43,8 +85,6 @@ impl AsyncConstructible for Presentations {
                     .await?,
             );
         }
-        Ok(Self {
-            _presentations: presentations,
-        })
+        Ok(Self { presentations })
     }
 }
@@ -1,25 +1,61 @@
-use std::{collections::HashMap, convert::Infallible, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    ops::ControlFlow,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use aerosol::{Aero, Constructible};
+use aerosol::{Aero, AsyncConstructible, Constructible};
 use async_trait::async_trait;
 use dashmap::DashMap;
 use futures::{future::BoxFuture, FutureExt};
+use ijson::IValue;
 use playferrous_presentation::{
     actor::Actor,
     bichannel::{bichannel, Bichannel},
     GameProposalId, PresentationKind, SessionCommand, SessionEvent, TerminalSessionCommand,
     TerminalSessionEvent, UserId,
 };
+use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
 
 use crate::{
-    connection_manager::{ConnectionToSessionMsg, SessionMember, SessionToConnectionMsg},
-    utils::{FutureExt2, FutureIteratorExt},
+    connection_manager::{
+        ConnectionId, ConnectionToSessionMsg, SessionHistory, SessionMember, SessionToConnectionMsg,
+    },
+    database,
+    utils::{FutureExt2, FutureIteratorExt, Rotation},
+    Config, Shutdown,
 };
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProposalConfig {
+    /// How often to look for proposals which have passed their deadline, in seconds.
+    pub sweep_interval_secs: u64,
+    /// Longer chat messages are refused, in characters.
+    pub max_line_length: usize,
+    /// How long a proposal's session stays open once everyone has left, so
+    /// that someone reconnecting finds it as they left it, in seconds.
+    pub empty_grace_secs: u64,
+}
+
+impl Default for ProposalConfig {
+    fn default() -> Self {
+        Self {
+            sweep_interval_secs: 30,
+            max_line_length: 500,
+            empty_grace_secs: 30,
+        }
+    }
+}
+
 #[derive(Debug)]
 struct EnterProposalSession {
     user_id: UserId,
+    connection_id: ConnectionId,
     bichannel: Bichannel<SessionToConnectionMsg, ConnectionToSessionMsg>,
     kind: PresentationKind,
 }
@@ -27,6 +63,10 @@ struct EnterProposalSession {
 #[derive(Debug)]
 enum SystemToProposalMsg {
     Enter(EnterProposalSession),
+    MembershipChanged { user_id: UserId, joined: bool },
+    Kick { user_id: UserId },
+    RulesChanged { user_id: UserId, rules: IValue },
+    Close { reason: String },
 }
 
 #[derive(Debug)]
@@ -37,14 +77,20 @@ struct Proposal {
 #[derive(Debug, Clone)]
 pub struct ProposalManager {
     proposals: Arc<DashMap<GameProposalId, Proposal>>,
+    /// When each user was last kicked from each proposal.
+    kicked: Arc<DashMap<(GameProposalId, UserId), Instant>>,
     aero: Aero,
 }
 
+/// How long a kicked user must wait before joining the proposal again.
+const KICK_COOLDOWN: Duration = Duration::from_secs(300);
+
 impl Constructible for ProposalManager {
     type Error = Infallible;
     fn construct(aero: &Aero) -> Result<Self, Self::Error> {
         Ok(Self {
             proposals: Default::default(),
+            kicked: Default::default(),
             aero: aero.clone(),
         })
     }
@@ -55,6 +101,7 @@ impl ProposalManager {
         &self,
         proposal_id: GameProposalId,
         user_id: UserId,
+        connection_id: ConnectionId,
         kind: PresentationKind,
     ) -> anyhow::Result<Bichannel<ConnectionToSessionMsg, SessionToConnectionMsg>> {
         let s = {
@@ -67,6 +114,7 @@ impl ProposalManager {
         let (session_bichannel, connection_bichannel) = bichannel(4);
         s.send(SystemToProposalMsg::Enter(EnterProposalSession {
             user_id,
+            connection_id,
             bichannel: connection_bichannel,
             kind,
         }))
@@ -74,16 +122,90 @@ impl ProposalManager {
         Ok(session_bichannel)
     }
 
-    fn start_proposal(&self, proposal_id: GameProposalId) -> Proposal {
+    /// Delete any proposals which have passed their deadline, and stop their actors.
+    pub async fn sweep_expired(&self) -> anyhow::Result<()> {
+        let expired = transact!(anyhow::Error, self.aero, |tx| {
+            Ok(database::proposal::delete_expired(tx).await?)
+        })?;
+        for proposal_id in expired {
+            tracing::info!("Proposal {} expired.", proposal_id);
+            self.close(proposal_id, "This proposal has expired.".into())
+                .await;
+        }
+        Ok(())
+    }
+
+    /// Stop the proposal's lobby, if it's running, telling anyone in it why.
+    pub async fn close(&self, proposal_id: GameProposalId, reason: String) {
+        let s = self.proposals.get(&proposal_id).map(|p| p.s.clone());
+        if let Some(s) = s {
+            let _ = s.send(SystemToProposalMsg::Close { reason }).await;
+        }
+    }
+
+    /// Let anyone in the proposal's lobby know that a user joined or left it.
+    pub async fn membership_changed(
+        &self,
+        proposal_id: GameProposalId,
+        user_id: UserId,
+        joined: bool,
+    ) {
+        // Nobody needs telling if the lobby isn't running
+        let s = self.proposals.get(&proposal_id).map(|p| p.s.clone());
+        if let Some(s) = s {
+            let _ = s
+                .send(SystemToProposalMsg::MembershipChanged { user_id, joined })
+                .await;
+        }
+    }
+
+    /// Show everyone in the proposal's lobby the new rules.
+    pub async fn rules_changed(&self, proposal_id: GameProposalId, user_id: UserId, rules: IValue) {
+        let s = self.proposals.get(&proposal_id).map(|p| p.s.clone());
+        if let Some(s) = s {
+            let _ = s
+                .send(SystemToProposalMsg::RulesChanged { user_id, rules })
+                .await;
+        }
+    }
+
+    /// Throw a user out of the proposal's lobby, and stop them joining again
+    /// for a while. The caller is responsible for removing their membership.
+    pub async fn kick(&self, proposal_id: GameProposalId, user_id: UserId) {
+        self.kicked
+            .retain(|_, kicked_at| kicked_at.elapsed() < KICK_COOLDOWN);
+        self.kicked.insert((proposal_id, user_id), Instant::now());
+        let s = self.proposals.get(&proposal_id).map(|p| p.s.clone());
+        if let Some(s) = s {
+            let _ = s.send(SystemToProposalMsg::Kick { user_id }).await;
+        }
+    }
+
+    pub fn is_kicked(&self, proposal_id: GameProposalId, user_id: UserId) -> bool {
+        self.kicked
+            .get(&(proposal_id, user_id))
+            .is_some_and(|kicked_at| kicked_at.elapsed() < KICK_COOLDOWN)
+    }
+
+    fn new_proposal(&self, proposal_id: GameProposalId) -> (ProposalActor, Proposal) {
         let (system_s, system_r) = mpsc::channel(4);
-        ProposalActor {
+        let actor = ProposalActor {
             aero: self.aero.clone(),
             proposal_id,
             system_r,
             connections: Default::default(),
-        }
-        .spawn();
-        Proposal { s: system_s }
+            rotation: Default::default(),
+            history: Default::default(),
+        };
+        (actor, Proposal { s: system_s })
+    }
+
+    fn start_proposal(&self, proposal_id: GameProposalId) -> Proposal {
+        let (actor, proposal) = self.new_proposal(proposal_id);
+        // Not restarted if it panics: its members are only known to the actor
+        // itself, so a replacement would be an empty session nobody is in.
+        actor.spawn_child(&self.aero.obtain::<Shutdown>().0);
+        proposal
     }
 }
 
@@ -99,30 +221,58 @@ struct ProposalActor {
     aero: Aero,
     proposal_id: GameProposalId,
     system_r: mpsc::Receiver<SystemToProposalMsg>,
-    connections: HashMap<UserId, Connection>,
+    /// A user may be present through more than one connection at once.
+    connections: HashMap<(UserId, ConnectionId), Connection>,
+    rotation: Rotation,
+    history: SessionHistory,
 }
 
 const USER_TIMEOUT: Duration = Duration::from_millis(200);
+/// How long a session which nobody has entered yet stays open, since the
+/// connection which started it may have failed to enter.
+const UNENTERED_TIMEOUT: Duration = Duration::from_secs(1);
 
 #[async_trait]
 impl Actor for ProposalActor {
-    async fn run(mut self) -> anyhow::Result<()> {
+    fn span(&self) -> tracing::Span {
+        tracing::info_span!("proposal", proposal_id = %self.proposal_id)
+    }
+    async fn run(mut self, shutdown: CancellationToken) -> anyhow::Result<()> {
         tracing::info!("Running proposal {}", self.proposal_id);
+        let config: Arc<Config> = self.aero.obtain_async().await;
+        let empty_grace = Duration::from_secs(config.proposal.empty_grace_secs);
+        let empty_timeout = tokio::time::sleep(UNENTERED_TIMEOUT);
+        tokio::pin!(empty_timeout);
+        let mut was_empty = true;
         loop {
             tokio::select! {
                 biased;
-                maybe_msg = self.system_r.recv() => if let Some(msg) = maybe_msg { self.handle_system_msg(msg).await? } else {break},
-                (user_id, maybe_msg) = self.connections.iter_mut().map(|(user_id, conn)| conn.bichannel.r.recv().with_key(*user_id)).select() => {
+                _ = shutdown.cancelled() => break,
+                maybe_msg = self.system_r.recv() => if let Some(msg) = maybe_msg {
+                    if self.handle_system_msg(msg).await?.is_break() {
+                        break;
+                    }
+                } else {break},
+                (key, maybe_msg) = self.connections.iter_mut().map(|(key, conn)| conn.bichannel.r.recv().with_key(*key)).select_fair(&mut self.rotation) => {
                     if let Some(msg) = maybe_msg {
-                        self.handle_connection_msg(user_id, msg).await?;
+                        self.handle_connection_msg(key, msg).await?;
                     } else {
-                        self.disconnect_user(user_id).await;
+                        self.disconnect_user(key).await;
                     }
                 },
-                _ = tokio::time::sleep(Duration::from_secs(1)), if self.connections.is_empty() => {
+                _ = &mut empty_timeout, if self.connections.is_empty() => {
                     break;
                 }
             }
+            // The grace period starts afresh each time the last connection
+            // leaves, however long ago the session was last empty
+            let is_empty = self.connections.is_empty();
+            if is_empty && !was_empty {
+                empty_timeout
+                    .as_mut()
+                    .reset(tokio::time::Instant::now() + empty_grace);
+            }
+            was_empty = is_empty;
         }
         tracing::info!("Stopping proposal {}", self.proposal_id);
         Ok(())
@@ -131,77 +281,169 @@ impl Actor for ProposalActor {
 
 impl ProposalActor {
     #[tracing::instrument(skip(self))]
-    async fn handle_system_msg(&mut self, msg: SystemToProposalMsg) -> anyhow::Result<()> {
+    async fn handle_system_msg(
+        &mut self,
+        msg: SystemToProposalMsg,
+    ) -> anyhow::Result<ControlFlow<()>> {
         match msg {
             SystemToProposalMsg::Enter(conn) => {
-                tracing::info!("User {} entered.", conn.user_id);
-                self.broadcast(SessionToConnectionMsg::UserEntered(SessionMember {
-                    user_id: conn.user_id,
-                    player_index: None,
-                }))
-                .await;
+                // Only announce the user the first time they enter, rather
+                // than once for each of their connections.
+                if !self.is_present(conn.user_id) {
+                    tracing::info!("User {} entered.", conn.user_id);
+                    self.broadcast(SessionToConnectionMsg::UserEntered(SessionMember {
+                        user_id: conn.user_id,
+                        player_index: None,
+                    }))
+                    .await;
+                }
+                let key = (conn.user_id, conn.connection_id);
                 self.connections.insert(
-                    conn.user_id,
+                    key,
                     Connection {
                         kind: conn.kind,
                         bichannel: conn.bichannel,
                     },
                 );
+                if !self.history.is_empty() {
+                    let events = self.history.recent(SessionHistory::REPLAY);
+                    self.send_to_connection(key, SessionToConnectionMsg::History(events))
+                        .await;
+                }
+            }
+            SystemToProposalMsg::MembershipChanged { user_id, joined } => {
+                let line = if joined {
+                    format!("{user_id} joined the proposal.")
+                } else {
+                    format!("{user_id} left the proposal.")
+                };
+                self.broadcast(SessionToConnectionMsg::Event(SessionEvent::Terminal(
+                    TerminalSessionEvent::Line(line),
+                )))
+                .await;
+            }
+            SystemToProposalMsg::Kick { user_id } => {
+                let keys: Vec<_> = self
+                    .connections
+                    .keys()
+                    .copied()
+                    .filter(|&(id, _)| id == user_id)
+                    .collect();
+                for key in keys {
+                    if let Some(conn) = self.connections.remove(&key) {
+                        let _ = conn.bichannel.try_send(SessionToConnectionMsg::Closed {
+                            reason: "You were kicked from this proposal.".into(),
+                        });
+                    }
+                }
+                tracing::info!("User {} was kicked.", user_id);
+                self.broadcast(SessionToConnectionMsg::Event(SessionEvent::Terminal(
+                    TerminalSessionEvent::Line(format!("{user_id} was kicked from the proposal.")),
+                )))
+                .await;
+            }
+            SystemToProposalMsg::RulesChanged { user_id, rules } => {
+                let rules = serde_json::to_string(&rules)?;
+                self.broadcast(SessionToConnectionMsg::Event(SessionEvent::Terminal(
+                    TerminalSessionEvent::Line(format!("{user_id} changed the rules to {rules}")),
+                )))
+                .await;
+            }
+            SystemToProposalMsg::Close { reason } => {
+                self.broadcast(SessionToConnectionMsg::Closed { reason })
+                    .await;
+                self.connections.clear();
+                return Ok(ControlFlow::Break(()));
             }
         }
-        Ok(())
+        Ok(ControlFlow::Continue(()))
+    }
+    fn is_present(&self, user_id: UserId) -> bool {
+        self.connections.keys().any(|&(id, _)| id == user_id)
     }
     async fn handle_terminal_cmd(
         &mut self,
-        user_id: UserId,
+        key: (UserId, ConnectionId),
         msg: TerminalSessionCommand,
     ) -> anyhow::Result<()> {
+        let (user_id, _) = key;
         match msg {
             TerminalSessionCommand::Line(line) => {
+                let config: Arc<Config> = self.aero.obtain_async().await;
+                let max_line_length = config.proposal.max_line_length;
+                if line.chars().count() > max_line_length {
+                    self.send_to_connection(
+                        key,
+                        SessionToConnectionMsg::Event(SessionEvent::Terminal(
+                            TerminalSessionEvent::Line(format!(
+                                "Line too long: messages may be at most {max_line_length} \
+                                 characters."
+                            )),
+                        )),
+                    )
+                    .await;
+                    return Ok(());
+                }
                 self.broadcast(SessionToConnectionMsg::Event(SessionEvent::Terminal(
                     TerminalSessionEvent::Line(format!("{user_id}: {line}")),
                 )))
                 .await;
             }
+            TerminalSessionCommand::Scrollback(count) => {
+                let events = self.history.recent(count.unwrap_or(SessionHistory::REPLAY));
+                self.send_to_connection(key, SessionToConnectionMsg::History(events))
+                    .await;
+            }
         }
         Ok(())
     }
     #[tracing::instrument(skip(self))]
     async fn handle_connection_msg(
         &mut self,
-        user_id: UserId,
+        key: (UserId, ConnectionId),
         msg: ConnectionToSessionMsg,
     ) -> anyhow::Result<()> {
         match msg {
             ConnectionToSessionMsg::Command(SessionCommand::Terminal(cmd)) => {
-                self.handle_terminal_cmd(user_id, cmd).await
+                self.handle_terminal_cmd(key, cmd).await
             }
         }
     }
     #[tracing::instrument(skip(self))]
-    async fn disconnect_user(&mut self, user_id: UserId) {
-        self.connections.remove(&user_id);
-        tracing::info!("User {} left.", user_id);
-        self.broadcast(SessionToConnectionMsg::UserExited(SessionMember {
-            user_id,
-            player_index: None,
-        }))
-        .await;
-    }
-    fn timeout_user(&mut self, user_id: UserId) -> BoxFuture<()> {
-        async move {
-            self.connections.remove(&user_id);
-            tracing::info!("User {} left due to a timeout.", user_id);
+    async fn disconnect_user(&mut self, key: (UserId, ConnectionId)) {
+        self.connections.remove(&key);
+        let (user_id, _) = key;
+        // The user may still be here through another connection
+        if !self.is_present(user_id) {
+            tracing::info!("User {} left.", user_id);
             self.broadcast(SessionToConnectionMsg::UserExited(SessionMember {
                 user_id,
                 player_index: None,
             }))
             .await;
         }
+    }
+    fn timeout_user(&mut self, key: (UserId, ConnectionId)) -> BoxFuture<()> {
+        async move {
+            self.connections.remove(&key);
+            let (user_id, _) = key;
+            if !self.is_present(user_id) {
+                tracing::info!("User {} left due to a timeout.", user_id);
+                self.broadcast(SessionToConnectionMsg::UserExited(SessionMember {
+                    user_id,
+                    player_index: None,
+                }))
+                .await;
+            }
+        }
         .boxed()
     }
-    async fn send_to_user(&mut self, user_id: UserId, cmd: SessionToConnectionMsg) {
-        if let Some(conn) = self.connections.get_mut(&user_id) {
+    async fn send_to_connection(
+        &mut self,
+        key: (UserId, ConnectionId),
+        cmd: SessionToConnectionMsg,
+    ) {
+        if let Some(conn) = self.connections.get_mut(&key) {
             if conn
                 .bichannel
                 .s
@@ -209,23 +451,161 @@ impl ProposalActor {
                 .await
                 .is_err()
             {
-                self.timeout_user(user_id).await;
+                self.timeout_user(key).await;
             }
         }
     }
     async fn broadcast(&mut self, cmd: SessionToConnectionMsg) {
-        let user_ids: Vec<_> = self.connections.keys().copied().collect();
-        for user_id in user_ids {
-            self.send_to_user(user_id, cmd.clone()).await;
+        if let SessionToConnectionMsg::Event(ev) = &cmd {
+            self.history.push(ev);
+        }
+        let keys: Vec<_> = self.connections.keys().copied().collect();
+        for key in keys {
+            self.send_to_connection(key, cmd.clone()).await;
         }
     }
 }
 
 impl Drop for ProposalActor {
     fn drop(&mut self) {
+        // Anyone still here would otherwise only see the session vanish.
+        for conn in self.connections.values() {
+            let _ = conn.bichannel.try_send(SessionToConnectionMsg::Closed {
+                reason: "This proposal has ended.".into(),
+            });
+        }
         self.aero
             .obtain::<ProposalManager>()
             .proposals
             .remove(&self.proposal_id);
     }
 }
+
+/// Periodically removes proposals which have passed their deadline.
+#[derive(Debug)]
+struct ProposalSweeperActor {
+    aero: Aero,
+    interval: Duration,
+}
+
+#[async_trait]
+impl Actor for ProposalSweeperActor {
+    async fn run(self, shutdown: CancellationToken) -> anyhow::Result<()> {
+        let mut interval = tokio::time::interval(self.interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                biased;
+                _ = shutdown.cancelled() => break,
+                _ = interval.tick() => {
+                    // A failed sweep will be retried on the next tick.
+                    if let Err(e) = self.aero.obtain::<ProposalManager>().sweep_expired().await {
+                        tracing::error!("Failed to sweep expired proposals: {}", e);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct ProposalSweeper {
+    shutdown: CancellationToken,
+}
+
+#[async_trait]
+impl AsyncConstructible for ProposalSweeper {
+    type Error = Infallible;
+    async fn construct_async(aero: &Aero) -> Result<Self, Self::Error> {
+        let config: Arc<Config> = aero.obtain_async().await;
+        let (_, shutdown) = ProposalSweeperActor {
+            aero: aero.clone(),
+            interval: Duration::from_secs(config.proposal.sweep_interval_secs.max(1)),
+        }
+        .spawn_child(&aero.obtain::<Shutdown>().0);
+        Ok(Self { shutdown })
+    }
+}
+
+impl Drop for ProposalSweeper {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::task::JoinHandle;
+
+    use super::*;
+
+    /// A proposal actor whose session stays open for `empty_grace` once
+    /// everyone has left.
+    fn start(empty_grace: Duration) -> (JoinHandle<anyhow::Result<()>>, Proposal) {
+        let mut config: Config = toml::from_str("launcher = []\npresentation = []").unwrap();
+        config.proposal.empty_grace_secs = empty_grace.as_secs();
+        let aero: Aero = Aero::new().with(Arc::new(config)).into();
+        let (actor, proposal) = aero
+            .obtain::<ProposalManager>()
+            .new_proposal(GameProposalId(1));
+        (tokio::spawn(actor.run(CancellationToken::new())), proposal)
+    }
+
+    /// The session is left by dropping the returned end of it.
+    async fn enter(
+        proposal: &Proposal,
+        user_id: i64,
+    ) -> Bichannel<ConnectionToSessionMsg, SessionToConnectionMsg> {
+        let (session_bichannel, connection_bichannel) = bichannel(4);
+        proposal
+            .s
+            .send(SystemToProposalMsg::Enter(EnterProposalSession {
+                user_id: UserId(user_id),
+                connection_id: ConnectionId(user_id as u64),
+                bichannel: connection_bichannel,
+                kind: PresentationKind::Terminal,
+            }))
+            .await
+            .unwrap();
+        session_bichannel
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn brief_gaps_in_membership_keep_the_session_open() {
+        let empty_grace = Duration::from_secs(30);
+        let (handle, proposal) = start(empty_grace);
+        for _ in 0..3 {
+            drop(enter(&proposal, 1).await);
+            tokio::time::sleep(empty_grace - Duration::from_secs(1)).await;
+            assert!(!handle.is_finished());
+        }
+        // The grace period runs from when the last member left
+        drop(enter(&proposal, 1).await);
+        let left_at = tokio::time::Instant::now();
+        handle.await.unwrap().unwrap();
+        assert_eq!(left_at.elapsed(), empty_grace);
+    }
+    #[tokio::test(start_paused = true)]
+    async fn empty_session_closes_after_exactly_the_grace_period() {
+        let empty_grace = Duration::from_secs(30);
+        let (handle, proposal) = start(empty_grace);
+        drop(enter(&proposal, 1).await);
+        let left_at = tokio::time::Instant::now();
+        // Messages which arrive while the session is empty don't restart
+        // the grace period
+        for _ in 0..3 {
+            tokio::time::sleep(Duration::from_secs(10) - Duration::from_millis(1)).await;
+            proposal
+                .s
+                .send(SystemToProposalMsg::MembershipChanged {
+                    user_id: UserId(2),
+                    joined: true,
+                })
+                .await
+                .unwrap();
+        }
+        handle.await.unwrap().unwrap();
+        assert_eq!(left_at.elapsed(), empty_grace);
+    }
+}
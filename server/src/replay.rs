@@ -0,0 +1,75 @@
+use playferrous_launcher::GameInstance;
+use playferrous_types::{GameState, GameTick};
+
+use crate::database::game::LoggedAction;
+
+/// Run a freshly launched game through a log of actions, collecting its state
+/// before the first action and after each one, so that a reviewer can step
+/// backwards and forwards through the game. Games whose snapshot can't be
+/// restored are brought back up to date the same way.
+pub async fn replay(
+    instance: &mut dyn GameInstance,
+    actions: Vec<LoggedAction>,
+) -> anyhow::Result<Vec<GameState>> {
+    let mut states = Vec::with_capacity(actions.len() + 1);
+    states.push(instance.state().await?);
+    for action in actions {
+        let state = match action.player_index.filter(|_| action.resign) {
+            Some(player_index) => instance.resign(player_index).await?,
+            None => {
+                instance
+                    .advance(GameTick(action.tick), action.action.0)
+                    .await?;
+                instance.state().await?
+            }
+        };
+        states.push(state);
+    }
+    Ok(states)
+}
+
+#[cfg(test)]
+mod tests {
+    use ijson::IValue;
+    use playferrous_launcher::mock::{MockGameInstance, TakeTurns};
+    use playferrous_types::PlayerResult;
+    use sqlx::types::Json;
+
+    use super::*;
+
+    fn logged(tick: i64, player_index: i32, resign: bool) -> LoggedAction {
+        LoggedAction {
+            tick,
+            player_index: Some(player_index),
+            action: Json(IValue::NULL),
+            resign,
+        }
+    }
+
+    #[tokio::test]
+    async fn replays_actions_and_resignations() {
+        let mut instance = MockGameInstance::new(TakeTurns::setup(2)).unwrap();
+        let actions = vec![
+            logged(5, 0, false),
+            logged(9, 1, false),
+            logged(12, 0, true),
+        ];
+        let states = replay(&mut instance, actions).await.unwrap();
+        assert_eq!(instance.game().ticks, [GameTick(5), GameTick(9)]);
+        let turns: Vec<_> = states
+            .iter()
+            .map(|state| match state {
+                GameState::InProgress(state) => Some(state.player_turn),
+                GameState::Complete(_) => None,
+            })
+            .collect();
+        assert_eq!(turns, [Some(0), Some(1), Some(0), None]);
+        let GameState::Complete(result) = &states[3] else {
+            unreachable!()
+        };
+        assert_eq!(
+            result.player_results,
+            [PlayerResult { score: 0 }, PlayerResult { score: 1 }]
+        );
+    }
+}
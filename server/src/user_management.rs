@@ -4,14 +4,63 @@ use aerosol::{Aero, Constructible};
 use async_trait::async_trait;
 use playferrous_presentation::{
     bichannel::Bichannel, ConnectionToPresentationMsg, PresentationKind,
-    PresentationToConnectionMsg, UserId, UserManagement, UserManagementError,
+    PresentationToConnectionMsg, UserId, UserKeyMin, UserManagement, UserManagementError,
 };
+use serde::{Deserialize, Serialize};
 
 use crate::{
     connection_manager::ConnectionManager,
-    database::{transaction::Transaction, TransactError},
+    database::{self, transaction::Transaction, TransactError},
+    Config,
 };
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PasswordConfig {
+    /// Minimum length in characters.
+    pub min_length: usize,
+    pub require_mixed_case: bool,
+    pub require_digit: bool,
+    /// Require a character which is neither a letter nor a digit.
+    pub require_symbol: bool,
+}
+
+impl Default for PasswordConfig {
+    fn default() -> Self {
+        Self {
+            min_length: 8,
+            require_mixed_case: false,
+            require_digit: false,
+            require_symbol: false,
+        }
+    }
+}
+
+impl PasswordConfig {
+    fn validate(&self, password: &str) -> Result<(), UserManagementError> {
+        let weak = |msg: String| Err(UserManagementError::WeakPassword(msg));
+        if password.chars().count() < self.min_length {
+            return weak(format!(
+                "Password must be at least {} characters long.",
+                self.min_length
+            ));
+        }
+        if self.require_mixed_case
+            && !(password.chars().any(char::is_uppercase)
+                && password.chars().any(char::is_lowercase))
+        {
+            return weak("Password must contain both upper and lower case letters.".into());
+        }
+        if self.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+            return weak("Password must contain a digit.".into());
+        }
+        if self.require_symbol && password.chars().all(char::is_alphanumeric) {
+            return weak("Password must contain a symbol.".into());
+        }
+        Ok(())
+    }
+}
+
 pub struct UserManagementImpl {
     aero: Aero,
 }
@@ -52,6 +101,14 @@ impl UserManagementImpl {
         .await?
         .ok_or(UserManagementError::UserDoesNotExist)?)
     }
+    /// Users registered before being configured as administrators are only
+    /// made administrators here.
+    pub async fn grant_admins(&self) -> Result<(), UserManagementError> {
+        let config: Arc<Config> = self.aero.obtain_async().await;
+        transact!(UserManagementError, self.aero, |tx| {
+            Ok(database::user::grant_admin(tx, &config.admins).await?)
+        })
+    }
 }
 
 #[async_trait]
@@ -111,11 +168,18 @@ impl UserManagement for UserManagementImpl {
             }
         })
     }
+    async fn validate_password(&self, password: &str) -> Result<(), UserManagementError> {
+        let config: Arc<Config> = self.aero.obtain_async().await;
+        config.password.validate(password)
+    }
     async fn create_user(
         &self,
         username: &str,
         password: &str,
     ) -> Result<UserId, UserManagementError> {
+        self.validate_password(password).await?;
+        let config: Arc<Config> = self.aero.obtain_async().await;
+        let is_admin = config.admins.iter().any(|admin| admin == username);
         transact!(UserManagementError, self.aero, |tx| {
             Ok(sqlx::query_scalar!(
                 r#"
@@ -125,18 +189,21 @@ impl UserManagement for UserManagementImpl {
                 INSERT INTO "user" (
                     username,
                     password_salt,
-                    password_hash
+                    password_hash,
+                    is_admin
                 )
                 SELECT
                     $1,
                     password_salt,
-                    crypt($2, password_salt)
+                    crypt($2, password_salt),
+                    $3
                 FROM params
                 ON CONFLICT DO NOTHING
                 RETURNING id AS "id: _"
                 "#,
                 username,
-                password
+                password,
+                is_admin
             )
             .fetch_optional(tx)
             .await?
@@ -164,6 +231,77 @@ impl UserManagement for UserManagementImpl {
             Ok(())
         })
     }
+    async fn list_user_public_keys(
+        &self,
+        user_id: UserId,
+    ) -> Result<Vec<UserKeyMin>, UserManagementError> {
+        transact!(UserManagementError, self.aero, |tx| {
+            Ok(database::user_key::list_for_user(tx, user_id).await?)
+        })
+    }
+    async fn remove_user_public_key(
+        &self,
+        user_id: UserId,
+        fingerprint: &str,
+    ) -> Result<(), UserManagementError> {
+        transact!(UserManagementError, self.aero, |tx| {
+            let has_password = sqlx::query_scalar!(
+                r#"
+                SELECT password_hash <> '' AS "has_password!"
+                FROM "user"
+                WHERE id = $1
+                "#,
+                user_id as _
+            )
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or(UserManagementError::UserDoesNotExist)?;
+            let keys = database::user_key::list_for_user(tx, user_id).await?;
+            if !keys.iter().any(|key| key.fingerprint == fingerprint) {
+                return Err(UserManagementError::KeyDoesNotExist.into());
+            }
+            if !has_password && keys.len() == 1 {
+                return Err(UserManagementError::LastAuthMethod.into());
+            }
+            database::user_key::remove(tx, user_id, fingerprint).await?;
+            Ok(())
+        })
+    }
+    async fn change_password(
+        &self,
+        user_id: UserId,
+        old_password: &str,
+        new_password: &str,
+    ) -> Result<(), UserManagementError> {
+        self.validate_password(new_password).await?;
+        transact!(UserManagementError, self.aero, |tx| {
+            if sqlx::query!(
+                r#"
+                WITH params AS (
+                    SELECT gen_salt('bf') AS password_salt
+                )
+                UPDATE "user"
+                SET
+                    password_salt = params.password_salt,
+                    password_hash = crypt($3, params.password_salt)
+                FROM params
+                WHERE id = $1 AND password_hash = crypt($2, "user".password_salt)
+                "#,
+                user_id as _,
+                old_password,
+                new_password
+            )
+            .execute(tx)
+            .await?
+            .rows_affected()
+                == 1
+            {
+                Ok(())
+            } else {
+                Err(UserManagementError::InvalidAuth.into())
+            }
+        })
+    }
     async fn connect(
         &self,
         user_id: UserId,
@@ -1,12 +1,19 @@
 use std::{
     pin::Pin,
-    task::{Context, Poll},
+    task::{ready, Context, Poll},
+    time::Duration,
 };
 
 use futures::{future::FusedFuture, Future};
 use pin_project_lite::pin_project;
 use rand::{seq::SliceRandom, thread_rng};
+use thiserror::Error;
+use tokio::time::{sleep, Sleep};
 
+/// Polls the futures in a random order, chosen once at construction, and
+/// resolves to the first one which is ready. Only a select which is rebuilt
+/// each time is fair, and then only by chance, so loops which must serve every
+/// branch should use `select_fair` instead.
 #[derive(Debug)]
 pub struct Select<T> {
     futures: Pin<Box<[T]>>,
@@ -46,8 +53,66 @@ impl<T: Future> FusedFuture for Select<T> {
     }
 }
 
+/// Remembers which branch a `select_fair` should start polling from, so that
+/// it can be kept across the repeated selects of an actor's main loop.
+#[derive(Debug, Default)]
+pub struct Rotation(usize);
+
+/// Like `Select`, but polls the futures in round-robin order.
+///
+/// Each poll starts one branch further along than the last, and after a
+/// branch completes, the next select using the same `Rotation` starts from
+/// the branch after it. A branch which is always ready therefore cannot
+/// prevent the others from completing.
+#[derive(Debug)]
+pub struct SelectFair<'a, T> {
+    futures: Pin<Box<[T]>>,
+    rotation: &'a mut Rotation,
+}
+
+impl<'a, T: Future> Future for SelectFair<'a, T> {
+    type Output = T::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let len = this.futures.len();
+        if len == 0 {
+            return Poll::Pending;
+        }
+        let start = this.rotation.0 % len;
+        let mut poll_range = |skip: usize, take: usize| {
+            iter_pin_mut(this.futures.as_mut())
+                .enumerate()
+                .skip(skip)
+                .take(take)
+                .find_map(|(i, fut)| match fut.poll(cx) {
+                    Poll::Ready(x) => Some((i, x)),
+                    Poll::Pending => None,
+                })
+        };
+        match poll_range(start, len - start).or_else(|| poll_range(0, start)) {
+            Some((i, x)) => {
+                this.rotation.0 = i + 1;
+                this.futures = Box::pin([]);
+                Poll::Ready(x)
+            }
+            None => {
+                this.rotation.0 = start + 1;
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<'a, T: Future> FusedFuture for SelectFair<'a, T> {
+    fn is_terminated(&self) -> bool {
+        self.futures.is_empty()
+    }
+}
+
 pub trait FutureIteratorExt: IntoIterator {
     fn select(self) -> Select<Self::Item>;
+    fn select_fair(self, rotation: &mut Rotation) -> SelectFair<'_, Self::Item>;
 }
 
 impl<I: IntoIterator> FutureIteratorExt for I
@@ -64,12 +129,21 @@ where
             futures: Box::into_pin(futures),
         }
     }
+    fn select_fair(self, rotation: &mut Rotation) -> SelectFair<'_, Self::Item> {
+        SelectFair {
+            futures: Box::into_pin(self.into_iter().collect()),
+            rotation,
+        }
+    }
 }
 
 pub trait FutureExt2: Future {
     fn with_key<K>(self, key: K) -> WithKey<Self, K>
     where
         Self: Sized;
+    fn with_timeout(self, duration: Duration) -> WithTimeout<Self>
+    where
+        Self: Sized;
 }
 
 pin_project! {
@@ -100,6 +174,47 @@ impl<T: Future, K> FusedFuture for WithKey<T, K> {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error("Timed out")]
+pub struct Elapsed;
+
+pin_project! {
+    #[derive(Debug)]
+    pub struct WithTimeout<T> {
+        done: bool,
+        #[pin]
+        fut: T,
+        #[pin]
+        sleep: Sleep,
+    }
+}
+
+impl<T: Future> Future for WithTimeout<T> {
+    type Output = Result<T::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        if *this.done {
+            return Poll::Pending;
+        }
+        let res = match this.fut.poll(cx) {
+            Poll::Ready(x) => Ok(x),
+            Poll::Pending => {
+                ready!(this.sleep.poll(cx));
+                Err(Elapsed)
+            }
+        };
+        *this.done = true;
+        Poll::Ready(res)
+    }
+}
+
+impl<T: Future> FusedFuture for WithTimeout<T> {
+    fn is_terminated(&self) -> bool {
+        self.done
+    }
+}
+
 impl<T: Future> FutureExt2 for T {
     fn with_key<K>(self, key: K) -> WithKey<Self, K>
     where
@@ -110,4 +225,42 @@ impl<T: Future> FutureExt2 for T {
             fut: self,
         }
     }
+    fn with_timeout(self, duration: Duration) -> WithTimeout<Self>
+    where
+        Self: Sized,
+    {
+        WithTimeout {
+            done: false,
+            fut: self,
+            sleep: sleep(duration),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{
+        executor::block_on,
+        future::{pending, ready},
+        poll,
+    };
+
+    use super::*;
+
+    #[test]
+    fn select_fair_serves_every_ready_branch_in_turn() {
+        let mut rotation = Rotation::default();
+        let served: Vec<_> = (0..8)
+            .map(|_| block_on((0..4).map(ready).select_fair(&mut rotation)))
+            .collect();
+        assert_eq!(served, [0, 1, 2, 3, 0, 1, 2, 3]);
+    }
+    #[tokio::test(start_paused = true)]
+    async fn timed_out_branch_yields_err_once() {
+        let fut = pending::<()>().with_timeout(Duration::from_secs(1));
+        tokio::pin!(fut);
+        assert_eq!([fut.as_mut()].into_iter().select().await, Err(Elapsed));
+        assert!(fut.is_terminated());
+        assert_eq!(poll!(fut.as_mut()), Poll::Pending);
+    }
 }
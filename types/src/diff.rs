@@ -0,0 +1,100 @@
+//! Describing how the state shown to a player has changed, so that graphical
+//! clients needn't be sent all of it every time.
+
+use ijson::{IObject, IValue};
+
+use crate::{GameTick, PlayerView};
+
+/// A JSON merge patch (RFC 7386) which turns `from` into `to`.
+///
+/// Merge patches use `null` to remove members, so they can't set a member to
+/// `null`. Returns `None` if `to` has any such member which the patch would
+/// need to include.
+pub fn merge_patch(from: &IValue, to: &IValue) -> Option<IValue> {
+    match (from.as_object(), to.as_object()) {
+        (Some(from), Some(to)) => {
+            let mut patch = IObject::new();
+            for (key, _) in from {
+                if !to.contains_key(key) {
+                    patch.insert(key.clone(), IValue::NULL);
+                }
+            }
+            for (key, value) in to {
+                match from.get(key) {
+                    Some(old) if old == value => {}
+                    _ if value.is_null() => return None,
+                    Some(old) => {
+                        patch.insert(key.clone(), merge_patch(old, value)?);
+                    }
+                    None if has_null_member(value) => return None,
+                    None => {
+                        patch.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+            Some(patch.into())
+        }
+        // Anything other than an object replaces the target outright, but an
+        // object is merged into it, which would drop its `null` members.
+        _ if has_null_member(to) => None,
+        _ => Some(to.clone()),
+    }
+}
+
+fn has_null_member(value: &IValue) -> bool {
+    value.as_object().is_some_and(|object| {
+        object
+            .values()
+            .any(|value| value.is_null() || has_null_member(value))
+    })
+}
+
+/// What each view of the game looked like at recent ticks, so that a game can
+/// describe what has changed since one of them.
+#[derive(Debug, Default)]
+pub struct RenderCache {
+    /// The tick of the last action applied, or `None` if the game has changed
+    /// in some other way since, in which case nothing is cached until the
+    /// next action.
+    tick: Option<GameTick>,
+    renders: Vec<(PlayerView, GameTick, IValue)>,
+}
+
+impl RenderCache {
+    /// How many renders are kept, across all views.
+    pub const CAPACITY: usize = 32;
+
+    pub const fn new() -> Self {
+        Self {
+            tick: None,
+            renders: Vec::new(),
+        }
+    }
+    /// The game reached this tick by applying an action or undoing.
+    pub fn set_tick(&mut self, tick: GameTick) {
+        self.tick = Some(tick);
+    }
+    /// The game changed without a tick to identify its new state by, so
+    /// nothing rendered from now on can be compared against.
+    pub fn invalidate(&mut self) {
+        self.tick = None;
+        self.renders.clear();
+    }
+    /// Remember how the view looks now.
+    pub fn insert(&mut self, view: PlayerView, render: &IValue) {
+        let Some(tick) = self.tick else {
+            return;
+        };
+        self.renders.retain(|(v, t, _)| (*v, *t) != (view, tick));
+        if self.renders.len() == Self::CAPACITY {
+            self.renders.remove(0);
+        }
+        self.renders.push((view, tick, render.clone()));
+    }
+    pub fn get(&self, view: PlayerView, tick: GameTick) -> Option<&IValue> {
+        self.renders
+            .iter()
+            .find(|(v, t, _)| (*v, *t) == (view, tick))
+            .map(|(_, _, render)| render)
+    }
+}
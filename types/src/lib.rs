@@ -6,8 +6,11 @@ use std::{
 use ijson::IValue;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
+pub mod diff;
 #[cfg(feature = "process")]
 pub mod process;
+#[cfg(feature = "process")]
+pub mod wasm;
 
 pub trait Game {
     type Snapshot: Serialize + DeserializeOwned + Debug + Clone;
@@ -28,6 +31,19 @@ pub trait GameUi: Serialize + DeserializeOwned + Debug + Clone {}
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ConsoleUi {
     pub prompt: String,
+    #[serde(default)]
+    pub input: InputMode,
+}
+
+/// How the player's next command should be entered.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum InputMode {
+    #[default]
+    Line,
+    /// Free text which is not echoed back, for anything secret.
+    Masked,
+    /// One of a fixed set of commands, which may also be picked by number.
+    Choice(Vec<String>),
 }
 
 impl GameUi for ConsoleUi {}
@@ -36,12 +52,16 @@ impl GameUi for ConsoleUi {}
 #[serde(bound = "T: GameUi, G: Game")]
 pub struct CommandResponse<T: GameUi, G: Game = GenericGame> {
     pub update_ui: Option<T>,
+    /// UI updates for other players, by player index.
+    #[serde(default)]
+    pub update_others: Vec<(i32, T)>,
     pub advance: Option<G::Action>,
 }
 
 impl<T: GameUi, G: Game> CommandResponse<T, G> {
     pub const IGNORE: Self = Self {
         update_ui: None,
+        update_others: Vec::new(),
         advance: None,
     };
 }
@@ -50,6 +70,7 @@ impl<T: GameUi, G: Game> Debug for CommandResponse<T, G> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("CommandResponse")
             .field("update_ui", &self.update_ui)
+            .field("update_others", &self.update_others)
             .field("advance", &self.advance)
             .finish()
     }
@@ -59,6 +80,7 @@ impl<T: GameUi, G: Game> Clone for CommandResponse<T, G> {
     fn clone(&self) -> Self {
         Self {
             update_ui: self.update_ui.clone(),
+            update_others: self.update_others.clone(),
             advance: self.advance.clone(),
         }
     }
@@ -147,6 +169,77 @@ pub enum GameState {
     Complete(GameResult),
 }
 
+/// Static information about a game type, which can be requested before a game
+/// is initialized.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct GameDescription {
+    /// A friendly name to show to users, since the game type is an
+    /// identifier. Defaults to the game type.
+    #[serde(default)]
+    pub display_name: Option<String>,
+    /// A sentence or two explaining what the game is.
+    #[serde(default)]
+    pub description: Option<String>,
+    pub min_players: i32,
+    pub max_players: i32,
+    /// Incremented whenever the snapshot format changes, so that snapshots
+    /// saved by older versions of the game can be migrated.
+    #[serde(default)]
+    pub snapshot_version: u32,
+    #[serde(default)]
+    pub capabilities: GameCapabilities,
+}
+
+/// Games which don't describe themselves are assumed to support the player
+/// counts proposals have always allowed, and nothing else.
+impl Default for GameDescription {
+    fn default() -> Self {
+        Self {
+            display_name: None,
+            description: None,
+            min_players: 2,
+            max_players: 8,
+            snapshot_version: 0,
+            capabilities: GameCapabilities::default(),
+        }
+    }
+}
+
+/// Optional features a game supports, so that presentations can adapt to it.
+/// Games which don't describe their capabilities are assumed to have none.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct GameCapabilities {
+    /// Implements `render_console_ui` and `interpret_console_command`.
+    pub console_ui: bool,
+    /// May ask for input to be hidden as it is typed.
+    pub masked_input: bool,
+    /// Can be rendered for spectators without revealing hidden information.
+    pub spectators: bool,
+    /// Implements `save_snapshot_binary` and `load_snapshot_binary`, which
+    /// are used instead of the JSON snapshot methods.
+    pub binary_snapshots: bool,
+    /// Implements `undo`, so that players may take back moves.
+    pub undo: bool,
+    /// Answers `RenderDiff`, so that graphical clients can be sent only what
+    /// has changed.
+    pub render_diff: bool,
+}
+
+/// Whose point of view a game should be rendered from.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum PlayerView {
+    Player(i32),
+    /// Someone watching the game, who must not see any player's hidden information.
+    Spectator,
+}
+
+/// A snapshot tagged with the version of the game which saved it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotEnvelope {
+    pub version: u32,
+    pub data: IValue,
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(bound = "G: Game")]
 pub struct GameSetup<G: Game = GenericGame> {
@@ -154,15 +247,20 @@ pub struct GameSetup<G: Game = GenericGame> {
     pub num_players: i32,
     pub seed: i64,
     pub rules: G::Rules,
+    /// State to resume from, so that a game can be restored without a
+    /// separate `LoadSnapshot` request.
+    #[serde(default)]
+    pub snapshot: Option<SnapshotEnvelope>,
 }
 
 impl<G: Game> Clone for GameSetup<G> {
     fn clone(&self) -> Self {
         Self {
             game_type: self.game_type.clone(),
-            num_players: self.num_players.clone(),
-            seed: self.seed.clone(),
+            num_players: self.num_players,
+            seed: self.seed,
             rules: self.rules.clone(),
+            snapshot: self.snapshot.clone(),
         }
     }
 }
@@ -174,34 +272,134 @@ impl<G: Game> Debug for GameSetup<G> {
             .field("num_players", &self.num_players)
             .field("seed", &self.seed)
             .field("rules", &self.rules)
+            .field("snapshot", &self.snapshot)
             .finish()
     }
 }
 
+/// A request or response, tagged with an ID so that a response can be matched
+/// to its request. Responses must carry the ID of their request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tagged<T> {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    pub message: T,
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(bound = "G: Game")]
 pub enum GameRequest<G: Game = GenericGame> {
+    Describe,
+    /// Rules are sent untyped, so that invalid rules can be reported rather
+    /// than failing to deserialize the request.
+    ValidateRules(IValue),
+    /// A JSON Schema the rules must match, so that they can be checked, and
+    /// explained, without starting the game.
+    RulesSchema,
     Initialize(GameSetup<G>),
-    LoadSnapshot(G::Snapshot),
+    LoadSnapshot(SnapshotEnvelope),
     SaveSnapshot,
-    Advance { tick: GameTick, action: G::Action },
+    /// The version is that of the game which saved the snapshot, so that the
+    /// game can migrate it if necessary.
+    LoadSnapshotBinary {
+        version: u32,
+        data: Vec<u8>,
+    },
+    SaveSnapshotBinary,
+    Advance {
+        tick: GameTick,
+        action: G::Action,
+    },
     State,
-    RenderConsoleUi { player: i32 },
-    InterpretConsoleCommand { player: i32, command: String },
+    /// Roll the game back to how it was at the given tick, as though no
+    /// action after it had been applied.
+    Undo {
+        to_tick: GameTick,
+    },
+    /// The given player has given up, and will take no further part.
+    Resign {
+        player: i32,
+    },
+    /// Only what the given player may see, unlike `SaveSnapshot`, which
+    /// includes hidden information and must never be sent to clients.
+    RenderPlayerState {
+        player: PlayerView,
+    },
+    /// A JSON merge patch from what the player was shown at `since_tick` to
+    /// what they would be shown now, if the game remembers it.
+    RenderDiff {
+        player: PlayerView,
+        since_tick: GameTick,
+    },
+    RenderConsoleUi {
+        player: PlayerView,
+    },
+    InterpretConsoleCommand {
+        player: i32,
+        command: String,
+    },
+    /// Answered without touching the game, to check that the process is
+    /// still responsive.
+    Ping,
+}
+
+impl<G: Game> GameRequest<G> {
+    /// The name of the request variant, for use in logs and metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Describe => "Describe",
+            Self::ValidateRules(_) => "ValidateRules",
+            Self::RulesSchema => "RulesSchema",
+            Self::Initialize(_) => "Initialize",
+            Self::LoadSnapshot(_) => "LoadSnapshot",
+            Self::SaveSnapshot => "SaveSnapshot",
+            Self::LoadSnapshotBinary { .. } => "LoadSnapshotBinary",
+            Self::SaveSnapshotBinary => "SaveSnapshotBinary",
+            Self::Advance { .. } => "Advance",
+            Self::State => "State",
+            Self::Undo { .. } => "Undo",
+            Self::Resign { .. } => "Resign",
+            Self::RenderPlayerState { .. } => "RenderPlayerState",
+            Self::RenderDiff { .. } => "RenderDiff",
+            Self::RenderConsoleUi { .. } => "RenderConsoleUi",
+            Self::InterpretConsoleCommand { .. } => "InterpretConsoleCommand",
+            Self::Ping => "Ping",
+        }
+    }
 }
 
 impl<G: Game> Debug for GameRequest<G> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            Self::Describe => write!(f, "Describe"),
+            Self::ValidateRules(rules) => f.debug_tuple("ValidateRules").field(rules).finish(),
+            Self::RulesSchema => write!(f, "RulesSchema"),
             Self::Initialize(arg0) => f.debug_tuple("Initialize").field(arg0).finish(),
             Self::LoadSnapshot(snapshot) => f.debug_tuple("LoadSnapshot").field(snapshot).finish(),
             Self::SaveSnapshot => write!(f, "SaveSnapshot"),
+            Self::LoadSnapshotBinary { version, data } => f
+                .debug_struct("LoadSnapshotBinary")
+                .field("version", version)
+                .field("len", &data.len())
+                .finish(),
+            Self::SaveSnapshotBinary => write!(f, "SaveSnapshotBinary"),
             Self::Advance { tick, action } => f
                 .debug_struct("Advance")
                 .field("tick", tick)
                 .field("action", action)
                 .finish(),
             Self::State => write!(f, "State"),
+            Self::Undo { to_tick } => f.debug_struct("Undo").field("to_tick", to_tick).finish(),
+            Self::Resign { player } => f.debug_struct("Resign").field("player", player).finish(),
+            Self::RenderPlayerState { player } => f
+                .debug_struct("RenderPlayerState")
+                .field("player", player)
+                .finish(),
+            Self::RenderDiff { player, since_tick } => f
+                .debug_struct("RenderDiff")
+                .field("player", player)
+                .field("since_tick", since_tick)
+                .finish(),
             Self::RenderConsoleUi { player } => f
                 .debug_struct("RenderConsoleUi")
                 .field("player", player)
@@ -211,6 +409,7 @@ impl<G: Game> Debug for GameRequest<G> {
                 .field("player", player)
                 .field("command", command)
                 .finish(),
+            Self::Ping => write!(f, "Ping"),
         }
     }
 }
@@ -218,21 +417,39 @@ impl<G: Game> Debug for GameRequest<G> {
 impl<G: Game> Clone for GameRequest<G> {
     fn clone(&self) -> Self {
         match self {
+            Self::Describe => Self::Describe,
+            Self::ValidateRules(rules) => Self::ValidateRules(rules.clone()),
+            Self::RulesSchema => Self::RulesSchema,
             Self::Initialize(arg0) => Self::Initialize(arg0.clone()),
             Self::LoadSnapshot(snapshot) => Self::LoadSnapshot(snapshot.clone()),
             Self::SaveSnapshot => Self::SaveSnapshot,
+            Self::LoadSnapshotBinary { version, data } => Self::LoadSnapshotBinary {
+                version: *version,
+                data: data.clone(),
+            },
+            Self::SaveSnapshotBinary => Self::SaveSnapshotBinary,
             Self::Advance { tick, action } => Self::Advance {
-                tick: tick.clone(),
+                tick: *tick,
                 action: action.clone(),
             },
             Self::State => Self::State,
+            Self::Undo { to_tick } => Self::Undo { to_tick: *to_tick },
+            Self::Resign { player } => Self::Resign { player: *player },
+            Self::RenderPlayerState { player } => Self::RenderPlayerState {
+                player: *player,
+            },
+            Self::RenderDiff { player, since_tick } => Self::RenderDiff {
+                player: *player,
+                since_tick: *since_tick,
+            },
             Self::RenderConsoleUi { player } => Self::RenderConsoleUi {
-                player: player.clone(),
+                player: *player,
             },
             Self::InterpretConsoleCommand { player, command } => Self::InterpretConsoleCommand {
-                player: player.clone(),
+                player: *player,
                 command: command.clone(),
             },
+            Self::Ping => Self::Ping,
         }
     }
 }
@@ -240,41 +457,80 @@ impl<G: Game> Clone for GameRequest<G> {
 #[derive(Serialize, Deserialize)]
 #[serde(bound = "G: Game")]
 pub enum GameResponse<G: Game = GenericGame> {
+    Describe(GameDescription),
+    ValidateRules(Result<(), String>),
+    RulesSchema(IValue),
     Initialize,
     LoadSnapshot,
-    SaveSnapshot(G::Snapshot),
+    SaveSnapshot(SnapshotEnvelope),
+    LoadSnapshotBinary,
+    SaveSnapshotBinary(Vec<u8>),
     Advance,
     State(GameState),
+    /// The state after undoing, or why the game refused to undo, which is not
+    /// a failure of the game itself.
+    Undo(Result<GameState, String>),
+    Resign(GameState),
+    RenderPlayerState(IValue),
+    /// `None` if the game doesn't remember what the player was shown then, in
+    /// which case the whole state must be rendered instead.
+    RenderDiff(Option<IValue>),
     RenderConsoleUi(Option<ConsoleUi>),
     InterpretConsoleCommand(Option<CommandResponse<ConsoleUi, G>>),
+    Pong,
 }
 
 impl<G: Game> Debug for GameResponse<G> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            Self::Describe(arg0) => f.debug_tuple("Describe").field(arg0).finish(),
+            Self::ValidateRules(arg0) => f.debug_tuple("ValidateRules").field(arg0).finish(),
+            Self::RulesSchema(arg0) => f.debug_tuple("RulesSchema").field(arg0).finish(),
             Self::Initialize => write!(f, "Initialize"),
             Self::LoadSnapshot => write!(f, "LoadSnapshot"),
             Self::SaveSnapshot(arg0) => f.debug_tuple("SaveSnapshot").field(arg0).finish(),
+            Self::LoadSnapshotBinary => write!(f, "LoadSnapshotBinary"),
+            Self::SaveSnapshotBinary(arg0) => f
+                .debug_struct("SaveSnapshotBinary")
+                .field("len", &arg0.len())
+                .finish(),
             Self::Advance => write!(f, "Advance"),
             Self::State(arg0) => f.debug_tuple("State").field(arg0).finish(),
+            Self::Undo(arg0) => f.debug_tuple("Undo").field(arg0).finish(),
+            Self::Resign(arg0) => f.debug_tuple("Resign").field(arg0).finish(),
+            Self::RenderPlayerState(arg0) => {
+                f.debug_tuple("RenderPlayerState").field(arg0).finish()
+            }
+            Self::RenderDiff(arg0) => f.debug_tuple("RenderDiff").field(arg0).finish(),
             Self::RenderConsoleUi(arg0) => f.debug_tuple("RenderConsoleUi").field(arg0).finish(),
             Self::InterpretConsoleCommand(arg0) => f
                 .debug_tuple("InterpretConsoleCommand")
                 .field(arg0)
                 .finish(),
+            Self::Pong => write!(f, "Pong"),
         }
     }
 }
 impl<G: Game> Clone for GameResponse<G> {
     fn clone(&self) -> Self {
         match self {
+            Self::Describe(arg0) => Self::Describe(arg0.clone()),
+            Self::ValidateRules(arg0) => Self::ValidateRules(arg0.clone()),
+            Self::RulesSchema(arg0) => Self::RulesSchema(arg0.clone()),
             Self::Initialize => Self::Initialize,
             Self::LoadSnapshot => Self::LoadSnapshot,
             Self::SaveSnapshot(arg0) => Self::SaveSnapshot(arg0.clone()),
+            Self::LoadSnapshotBinary => Self::LoadSnapshotBinary,
+            Self::SaveSnapshotBinary(arg0) => Self::SaveSnapshotBinary(arg0.clone()),
             Self::Advance => Self::Advance,
             Self::State(arg0) => Self::State(arg0.clone()),
+            Self::Undo(arg0) => Self::Undo(arg0.clone()),
+            Self::Resign(arg0) => Self::Resign(arg0.clone()),
+            Self::RenderPlayerState(arg0) => Self::RenderPlayerState(arg0.clone()),
+            Self::RenderDiff(arg0) => Self::RenderDiff(arg0.clone()),
             Self::RenderConsoleUi(arg0) => Self::RenderConsoleUi(arg0.clone()),
             Self::InterpretConsoleCommand(arg0) => Self::InterpretConsoleCommand(arg0.clone()),
+            Self::Pong => Self::Pong,
         }
     }
 }
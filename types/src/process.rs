@@ -1,20 +1,72 @@
 use std::io::{stdin, stdout, Write};
 
 use anyhow::bail;
+use ijson::IValue;
 
 use crate::{
-    CommandResponse, ConsoleUi, Game, GameRequest, GameResponse, GameSetup, GameState, GameTick,
+    diff::{merge_patch, RenderCache},
+    CommandResponse, ConsoleUi, Game, GameDescription, GameRequest, GameResponse, GameSetup,
+    GameState, GameTick, PlayerView, SnapshotEnvelope, Tagged,
 };
 
 pub trait GameProcess: Game + Sized {
+    /// By default the game has no display name or description, and is
+    /// assumed to support between two and eight players.
+    fn describe() -> GameDescription {
+        GameDescription::default()
+    }
+    /// Check rules beyond what is enforced by deserializing them.
+    fn validate_rules(_rules: &Self::Rules) -> anyhow::Result<()> {
+        Ok(())
+    }
+    /// A JSON Schema describing the rules, which the server checks before
+    /// asking the game to validate them. By default anything is allowed.
+    fn rules_schema() -> IValue {
+        IValue::TRUE
+    }
     fn new(setup: GameSetup<Self>) -> anyhow::Result<Self>;
     fn load_snapshot(&mut self, snapshot: Self::Snapshot) -> anyhow::Result<()>;
     fn save_snapshot(&mut self) -> anyhow::Result<Self::Snapshot>;
+    /// Games with large state may save it as opaque bytes instead, if they
+    /// advertise the `binary_snapshots` capability.
+    fn save_snapshot_binary(&mut self) -> anyhow::Result<Vec<u8>> {
+        bail!("Binary snapshots are not supported")
+    }
+    fn load_snapshot_binary(&mut self, _version: u32, _data: Vec<u8>) -> anyhow::Result<()> {
+        bail!("Binary snapshots are not supported")
+    }
     fn advance(&mut self, tick: GameTick, action: Self::Action) -> anyhow::Result<()>;
     fn state(&mut self) -> anyhow::Result<GameState>;
+    /// Roll the game back to how it was at `to_tick`, for games with the
+    /// `undo` capability. Games should refuse if their rules don't allow
+    /// takebacks, and may rebuild their state by replaying their own log of
+    /// actions. Undoing must be deterministic: the state must be exactly as
+    /// it was at that tick, so anything random must be derived from the
+    /// seed, never drawn afresh.
+    fn undo(&mut self, _to_tick: GameTick) -> anyhow::Result<GameState> {
+        bail!("Undo is not supported")
+    }
+    /// Forfeit the game for `player`. Games with more than two players should
+    /// skip the player's turns from now on. By default the game is left as
+    /// it is, and the server ends it once only one player hasn't resigned.
+    fn resign(&mut self, _player: i32) -> anyhow::Result<GameState> {
+        self.state()
+    }
+    /// Convert a snapshot saved by a different version of the game. By default
+    /// snapshots from other versions can't be loaded.
+    fn migrate_snapshot(from_version: u32, _data: IValue) -> anyhow::Result<Self::Snapshot> {
+        bail!("Cannot load a snapshot from version {from_version}")
+    }
+    /// The part of the game state which the given player is allowed to see.
+    /// Graphical clients should be sent this, never the raw snapshot. Games
+    /// with hidden information must override this, since by default the whole
+    /// snapshot is visible.
+    fn render_player_state(&mut self, _player: PlayerView) -> anyhow::Result<IValue> {
+        Ok(ijson::to_value(self.save_snapshot()?)?)
+    }
 
     // Presentation-specific functionality
-    fn render_console_ui(&mut self, _player: i32) -> anyhow::Result<Option<ConsoleUi>> {
+    fn render_console_ui(&mut self, _player: PlayerView) -> anyhow::Result<Option<ConsoleUi>> {
         Ok(None)
     }
     fn interpret_console_command(
@@ -25,42 +77,125 @@ pub trait GameProcess: Game + Sized {
         Ok(None)
     }
 
+    /// Extract a snapshot from its envelope, migrating it if it was saved by
+    /// a different version of the game.
+    fn unwrap_snapshot(envelope: SnapshotEnvelope) -> anyhow::Result<Self::Snapshot> {
+        if envelope.version == Self::describe().snapshot_version {
+            Ok(ijson::from_value(&envelope.data)?)
+        } else {
+            log::info!("Migrating snapshot from version {}", envelope.version);
+            Self::migrate_snapshot(envelope.version, envelope.data)
+        }
+    }
+
+    /// Answer a single request. `game` is `None` until the game has been
+    /// initialized. What has been rendered is remembered in `renders`, so that
+    /// later renders can be sent as changes to it.
+    fn handle_request(
+        game: &mut Option<Self>,
+        renders: &mut RenderCache,
+        request: GameRequest<Self>,
+    ) -> anyhow::Result<GameResponse<Self>> {
+        let version = Self::describe().snapshot_version;
+        Ok(match (&mut *game, request) {
+            (_, GameRequest::Describe) => GameResponse::Describe(Self::describe()),
+            (_, GameRequest::Ping) => GameResponse::Pong,
+            (_, GameRequest::RulesSchema) => GameResponse::RulesSchema(Self::rules_schema()),
+            (_, GameRequest::ValidateRules(rules)) => GameResponse::ValidateRules(
+                ijson::from_value::<Self::Rules>(&rules)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|rules| Self::validate_rules(&rules))
+                    .map_err(|e| e.to_string()),
+            ),
+            (None, GameRequest::Initialize(mut setup)) => {
+                let envelope = setup.snapshot.take();
+                let mut new_game = Self::new(setup)?;
+                if let Some(envelope) = envelope {
+                    new_game.load_snapshot(Self::unwrap_snapshot(envelope)?)?;
+                }
+                *game = Some(new_game);
+                renders.invalidate();
+                GameResponse::Initialize
+            }
+            (Some(game), GameRequest::LoadSnapshot(envelope)) => {
+                game.load_snapshot(Self::unwrap_snapshot(envelope)?)?;
+                renders.invalidate();
+                GameResponse::LoadSnapshot
+            }
+            (Some(game), GameRequest::SaveSnapshot) => {
+                GameResponse::SaveSnapshot(SnapshotEnvelope {
+                    version,
+                    data: ijson::to_value(game.save_snapshot()?)?,
+                })
+            }
+            (Some(game), GameRequest::LoadSnapshotBinary { version, data }) => {
+                game.load_snapshot_binary(version, data)?;
+                renders.invalidate();
+                GameResponse::LoadSnapshotBinary
+            }
+            (Some(game), GameRequest::SaveSnapshotBinary) => {
+                GameResponse::SaveSnapshotBinary(game.save_snapshot_binary()?)
+            }
+            (Some(game), GameRequest::Advance { tick, action }) => {
+                game.advance(tick, action)?;
+                renders.set_tick(tick);
+                GameResponse::Advance
+            }
+            (Some(game), GameRequest::State) => GameResponse::State(game.state()?),
+            (Some(game), GameRequest::Undo { to_tick }) => {
+                let res = game.undo(to_tick).map_err(|e| format!("{e:#}"));
+                // Undoing is deterministic, so the game is exactly as it
+                // was when last at this tick
+                if res.is_ok() {
+                    renders.set_tick(to_tick);
+                }
+                GameResponse::Undo(res)
+            }
+            (Some(game), GameRequest::Resign { player }) => {
+                let state = game.resign(player)?;
+                renders.invalidate();
+                GameResponse::Resign(state)
+            }
+            (Some(game), GameRequest::RenderPlayerState { player }) => {
+                let render = game.render_player_state(player)?;
+                renders.insert(player, &render);
+                GameResponse::RenderPlayerState(render)
+            }
+            (Some(game), GameRequest::RenderDiff { player, since_tick }) => {
+                let render = game.render_player_state(player)?;
+                let patch = renders
+                    .get(player, since_tick)
+                    .and_then(|previous| merge_patch(previous, &render));
+                renders.insert(player, &render);
+                GameResponse::RenderDiff(patch)
+            }
+            (Some(game), GameRequest::RenderConsoleUi { player }) => {
+                GameResponse::RenderConsoleUi(game.render_console_ui(player)?)
+            }
+            (Some(game), GameRequest::InterpretConsoleCommand { player, command }) => {
+                GameResponse::InterpretConsoleCommand(
+                    game.interpret_console_command(player, &command)?,
+                )
+            }
+            (_, request) => bail!("Unexpected gmae request: {request:?}"),
+        })
+    }
+
     fn main() -> anyhow::Result<()> {
         let mut game: Option<Self> = None;
+        let mut renders = RenderCache::new();
         for line in stdin().lines() {
             let line = line?;
             log::debug!("Request: {line}");
-            let request: GameRequest<Self> = serde_json::from_str(&line)?;
-            let response: GameResponse<Self> = match (&mut game, request) {
-                (None, GameRequest::Initialize(setup)) => {
-                    game = Some(Self::new(setup)?);
-                    GameResponse::Initialize
-                }
-                (Some(game), GameRequest::LoadSnapshot(snapshot)) => {
-                    game.load_snapshot(snapshot)?;
-                    GameResponse::LoadSnapshot
-                }
-                (Some(game), GameRequest::SaveSnapshot) => {
-                    GameResponse::SaveSnapshot(game.save_snapshot()?)
-                }
-                (Some(game), GameRequest::Advance { tick, action }) => {
-                    game.advance(tick, action)?;
-                    GameResponse::Advance
-                }
-                (Some(game), GameRequest::State) => GameResponse::State(game.state()?),
-                (Some(game), GameRequest::RenderConsoleUi { player }) => {
-                    GameResponse::RenderConsoleUi(game.render_console_ui(player)?)
-                }
-                (Some(game), GameRequest::InterpretConsoleCommand { player, command }) => {
-                    GameResponse::InterpretConsoleCommand(
-                        game.interpret_console_command(player, &command)?,
-                    )
-                }
-                (_, request) => bail!("Unexpected gmae request: {request:?}"),
-            };
+            let Tagged { id, message } = serde_json::from_str::<Tagged<GameRequest<Self>>>(&line)?;
+            let response = Self::handle_request(&mut game, &mut renders, message)?;
 
             {
-                let response_line = serde_json::to_string(&response)?;
+                // The response must carry the ID of the request
+                let response_line = serde_json::to_string(&Tagged {
+                    id,
+                    message: response,
+                })?;
                 log::debug!("Response: {response_line}");
                 let mut o = stdout().lock();
                 writeln!(o, "{}", response_line)?;
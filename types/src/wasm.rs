@@ -0,0 +1,100 @@
+//! Support for compiling games to WebAssembly, to be run by the WASM launcher.
+//!
+//! A game module must export:
+//!
+//! - `memory`: its linear memory.
+//! - `playferrous_alloc(len: i32) -> i32`: allocates `len` bytes, into which
+//!   the host writes a request.
+//! - `playferrous_request(ptr: i32, len: i32) -> i64`: takes ownership of a
+//!   JSON-encoded `GameRequest`, and returns a JSON-encoded
+//!   `Result<GameResponse, String>`, with its address in the upper 32 bits
+//!   and its length in the lower 32 bits.
+//! - `playferrous_free(ptr: i32, len: i32)`: frees a response once the host
+//!   has read it.
+//!
+//! Modules may not import anything, so games have no access to the outside
+//! world. Use `export_wasm_game!` rather than implementing this by hand.
+
+use std::{ptr, sync::Mutex};
+
+use crate::{diff::RenderCache, process::GameProcess, GameRequest};
+
+/// Export the functions required by the WASM launcher for the given game. Has
+/// no effect unless compiling for `wasm32`.
+#[macro_export]
+macro_rules! export_wasm_game {
+    ($game:ty) => {
+        #[cfg(target_arch = "wasm32")]
+        static PLAYFERROUS_GAME: ::std::sync::Mutex<Option<$game>> = ::std::sync::Mutex::new(None);
+        #[cfg(target_arch = "wasm32")]
+        static PLAYFERROUS_RENDERS: ::std::sync::Mutex<$crate::diff::RenderCache> =
+            ::std::sync::Mutex::new($crate::diff::RenderCache::new());
+
+        #[cfg(target_arch = "wasm32")]
+        #[no_mangle]
+        pub extern "C" fn playferrous_alloc(len: i32) -> i32 {
+            $crate::wasm::alloc(len)
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        #[no_mangle]
+        pub unsafe extern "C" fn playferrous_free(ptr: i32, len: i32) {
+            $crate::wasm::free(ptr, len)
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        #[no_mangle]
+        pub unsafe extern "C" fn playferrous_request(ptr: i32, len: i32) -> i64 {
+            $crate::wasm::request(&PLAYFERROUS_GAME, &PLAYFERROUS_RENDERS, ptr, len)
+        }
+    };
+}
+
+#[doc(hidden)]
+pub fn alloc(len: i32) -> i32 {
+    let buf = vec![0u8; len as usize].into_boxed_slice();
+    Box::into_raw(buf) as *mut u8 as usize as i32
+}
+
+/// # Safety
+///
+/// Must only be called with a buffer returned by `alloc` or `request`.
+#[doc(hidden)]
+pub unsafe fn free(ptr: i32, len: i32) {
+    drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+        ptr as usize as *mut u8,
+        len as usize,
+    )));
+}
+
+/// # Safety
+///
+/// Must only be called with a buffer returned by `alloc`, which is freed.
+#[doc(hidden)]
+pub unsafe fn request<G: GameProcess>(
+    game: &Mutex<Option<G>>,
+    renders: &Mutex<RenderCache>,
+    ptr: i32,
+    len: i32,
+) -> i64 {
+    let input = Box::from_raw(ptr::slice_from_raw_parts_mut(
+        ptr as usize as *mut u8,
+        len as usize,
+    ));
+    let result = serde_json::from_slice::<GameRequest<G>>(&input)
+        .map_err(anyhow::Error::from)
+        .and_then(|request| {
+            G::handle_request(
+                &mut game.lock().unwrap(),
+                &mut renders.lock().unwrap(),
+                request,
+            )
+        })
+        .map_err(|e| format!("{e:#}"));
+    let output = serde_json::to_vec(&result)
+        .unwrap_or_else(|e| serde_json::to_vec(&Err::<(), _>(e.to_string())).unwrap())
+        .into_boxed_slice();
+    let len = output.len() as i64;
+    let ptr = Box::into_raw(output) as *mut u8 as usize as i64;
+    (ptr << 32) | len
+}
@@ -0,0 +1,435 @@
+//! Runs games compiled to WebAssembly, which unlike game processes are
+//! sandboxed, and can be built once for every platform. See
+//! `playferrous_types::wasm` for the interface a game module must implement.
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context};
+use async_trait::async_trait;
+use ijson::IValue;
+use playferrous_launcher::{GameError, GameInstance, Launcher, LauncherConfig, LauncherError};
+use playferrous_types::{
+    CommandResponse, ConsoleUi, GameDescription, GameRequest, GameResponse, GameSetup, GameState,
+    GameTick, PlayerView, SnapshotEnvelope,
+};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use wasmtime::{
+    Config, Engine, Instance, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc,
+};
+
+/// How often the engine's epoch is advanced, which is how precisely a game's
+/// time limit is enforced.
+const EPOCH_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WasmLauncherConfig {
+    /// The directory containing a `<game-type>.wasm` module for each game.
+    path: String,
+    /// How long a game may spend on a single request before it's stopped,
+    /// in milliseconds.
+    #[serde(default = "default_call_timeout_ms")]
+    call_timeout_ms: u64,
+    /// How large a game's memory may grow, in megabytes. Growing beyond this
+    /// fails, as if the memory were exhausted.
+    #[serde(default = "default_memory_limit_mb")]
+    memory_limit_mb: u64,
+}
+
+fn default_call_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_memory_limit_mb() -> u64 {
+    64
+}
+
+pub struct WasmLauncher {
+    config: WasmLauncherConfig,
+    engine: Engine,
+    /// Modules are compiled the first time each game type is used.
+    modules: Mutex<HashMap<String, Module>>,
+    /// Advances the engine's epoch, so that games which run for too long are
+    /// interrupted.
+    ticker: JoinHandle<()>,
+}
+
+impl Drop for WasmLauncher {
+    fn drop(&mut self) {
+        self.ticker.abort();
+    }
+}
+
+impl Debug for WasmLauncher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WasmLauncher")
+            .field("config", &self.config)
+            .field("modules", &self.modules.lock().unwrap().keys())
+            .finish()
+    }
+}
+
+#[async_trait]
+impl LauncherConfig for WasmLauncherConfig {
+    async fn start_launcher(&self) -> anyhow::Result<Arc<dyn Launcher>> {
+        // Guest code can't otherwise be stopped once it's running, since it
+        // runs on a blocking thread
+        let engine = Engine::new(Config::new().epoch_interruption(true))?;
+        let ticker = tokio::spawn({
+            let engine = engine.clone();
+            async move {
+                let mut interval = tokio::time::interval(EPOCH_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    engine.increment_epoch();
+                }
+            }
+        });
+        Ok(Arc::new(WasmLauncher {
+            config: self.clone(),
+            engine,
+            modules: Default::default(),
+            ticker,
+        }))
+    }
+}
+
+impl WasmLauncher {
+    async fn module(&self, game_type: &str) -> Result<Module, LauncherError> {
+        if let Some(module) = self.modules.lock().unwrap().get(game_type) {
+            return Ok(module.clone());
+        }
+        let module_path = Path::new(&self.config.path).join(format!("{game_type}.wasm"));
+        if !module_path.is_file() {
+            return Err(LauncherError::UnknownGameType);
+        }
+        // Compilation is slow, so keep it off the async threads
+        let engine = self.engine.clone();
+        let module = tokio::task::spawn_blocking(move || Module::from_file(&engine, module_path))
+            .await
+            .map_err(anyhow::Error::from)?
+            .context("Failed to compile game module")?;
+        self.modules
+            .lock()
+            .unwrap()
+            .insert(game_type.into(), module.clone());
+        Ok(module)
+    }
+    async fn instantiate(&self, game_type: &str) -> Result<GameInstanceWasm, LauncherError> {
+        let module = self.module(game_type).await?;
+        let deadline = Duration::from_millis(self.config.call_timeout_ms).as_millis()
+            / EPOCH_INTERVAL.as_millis();
+        let deadline = u64::try_from(deadline).unwrap_or(u64::MAX).max(1);
+        let memory_size = self.config.memory_limit_mb.saturating_mul(1024 * 1024);
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(usize::try_from(memory_size).unwrap_or(usize::MAX))
+            .build();
+        // Instantiating runs the module's start function, which may take as
+        // long as any request, so it's kept off the async threads too
+        let engine = self.engine.clone();
+        let game =
+            tokio::task::spawn_blocking(move || WasmGame::new(&engine, &module, deadline, limits))
+                .await
+                .map_err(anyhow::Error::from)?
+                .context("Failed to instantiate game module")?;
+        Ok(GameInstanceWasm { game: Some(game) })
+    }
+}
+
+#[async_trait]
+impl Launcher for WasmLauncher {
+    async fn describe(&self, game_type: &str) -> Result<GameDescription, LauncherError> {
+        let mut instance = self.instantiate(game_type).await?;
+
+        let req = GameRequest::Describe;
+        let resp = instance.request(&req).await?;
+        if let GameResponse::Describe(description) = resp {
+            Ok(description)
+        } else {
+            Err(GameInstanceWasm::response_type_error(&req, &resp).into())
+        }
+    }
+    async fn validate_rules(&self, game_type: &str, rules: IValue) -> Result<(), LauncherError> {
+        let mut instance = self.instantiate(game_type).await?;
+
+        let req = GameRequest::ValidateRules(rules);
+        let resp = instance.request(&req).await?;
+        if let GameResponse::ValidateRules(res) = resp {
+            res.map_err(LauncherError::InvalidRules)
+        } else {
+            Err(GameInstanceWasm::response_type_error(&req, &resp).into())
+        }
+    }
+    async fn rules_schema(&self, game_type: &str) -> Result<IValue, LauncherError> {
+        let mut instance = self.instantiate(game_type).await?;
+
+        let req = GameRequest::RulesSchema;
+        let resp = instance.request(&req).await?;
+        if let GameResponse::RulesSchema(schema) = resp {
+            Ok(schema)
+        } else {
+            Err(GameInstanceWasm::response_type_error(&req, &resp).into())
+        }
+    }
+    async fn launch(&self, game_setup: GameSetup) -> Result<Box<dyn GameInstance>, LauncherError> {
+        let mut res = Box::new(self.instantiate(&game_setup.game_type).await?);
+
+        let req = GameRequest::Initialize(game_setup);
+        let resp = res.request(&req).await?;
+        if !matches!(resp, GameResponse::Initialize) {
+            return Err(GameInstanceWasm::response_type_error(&req, &resp).into());
+        }
+
+        Ok(res)
+    }
+    async fn health_check(&self) -> anyhow::Result<()> {
+        let metadata = tokio::fs::metadata(&self.config.path)
+            .await
+            .with_context(|| format!("Cannot access game directory `{}`", self.config.path))?;
+        if !metadata.is_dir() {
+            bail!("`{}` is not a directory", self.config.path);
+        }
+        Ok(())
+    }
+    async fn supported_game_types(&self) -> anyhow::Result<Vec<String>> {
+        let mut game_types = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.config.path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "wasm") {
+                if let Some(game_type) = path.file_stem().and_then(|stem| stem.to_str()) {
+                    game_types.push(game_type.into());
+                }
+            }
+        }
+        game_types.sort();
+        Ok(game_types)
+    }
+}
+
+/// An instantiated game module, along with the exports used to talk to it.
+struct WasmGame {
+    store: Store<StoreLimits>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    free: TypedFunc<(i32, i32), ()>,
+    request: TypedFunc<(i32, i32), i64>,
+    /// How many epochs each request may take.
+    deadline: u64,
+}
+
+impl WasmGame {
+    fn new(
+        engine: &Engine,
+        module: &Module,
+        deadline: u64,
+        limits: StoreLimits,
+    ) -> anyhow::Result<Self> {
+        let mut store = Store::new(engine, limits);
+        store.limiter(|limits| limits);
+        // Instantiating runs the module's start function, if it has one
+        store.set_epoch_deadline(deadline);
+        // Nothing is provided to the module, so it has no way to reach the
+        // outside world
+        let instance = Instance::new(&mut store, module, &[])?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .context("Game module does not export `memory`")?;
+        Ok(Self {
+            alloc: instance.get_typed_func(&mut store, "playferrous_alloc")?,
+            free: instance.get_typed_func(&mut store, "playferrous_free")?,
+            request: instance.get_typed_func(&mut store, "playferrous_request")?,
+            store,
+            memory,
+            deadline,
+        })
+    }
+    fn call(&mut self, request: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.store.set_epoch_deadline(self.deadline);
+        let len = i32::try_from(request.len())?;
+        let ptr = self.alloc.call(&mut self.store, len)?;
+        self.memory
+            .write(&mut self.store, ptr as u32 as usize, request)?;
+        // The address of the response is in the upper half, and its length
+        // in the lower half
+        let packed = self.request.call(&mut self.store, (ptr, len))?;
+        let (resp_ptr, resp_len) = ((packed >> 32) as u32, packed as u32);
+        let mut response = vec![0; resp_len as usize];
+        self.memory
+            .read(&self.store, resp_ptr as usize, &mut response)?;
+        self.free
+            .call(&mut self.store, (resp_ptr as i32, resp_len as i32))?;
+        Ok(response)
+    }
+}
+
+struct GameInstanceWasm {
+    /// Only `None` while a request is in progress.
+    game: Option<WasmGame>,
+}
+
+impl GameInstanceWasm {
+    async fn request(&mut self, request: &GameRequest) -> anyhow::Result<GameResponse> {
+        let request = serde_json::to_vec(request)?;
+        let mut game = self
+            .game
+            .take()
+            .context("Game was lost by an earlier request")?;
+        // Games run on a blocking thread so that a slow game can't hold up
+        // anything else
+        let (game, response) = tokio::task::spawn_blocking(move || {
+            let response = game.call(&request);
+            (game, response)
+        })
+        .await?;
+        // A game which was interrupted, or otherwise failed part way through
+        // a request, may have been left in an inconsistent state
+        let response = response.context("Game module failed")?;
+        self.game = Some(game);
+        let response: Result<GameResponse, String> = serde_json::from_slice(&response)?;
+        response.map_err(|e| anyhow!(e))
+    }
+    fn response_type_error(req: &GameRequest, resp: &GameResponse) -> anyhow::Error {
+        anyhow!("Invalid response {resp:?} for {req:?}")
+    }
+}
+
+#[async_trait]
+impl GameInstance for GameInstanceWasm {
+    async fn load_snapshot(&mut self, snapshot: SnapshotEnvelope) -> anyhow::Result<()> {
+        let req = GameRequest::LoadSnapshot(snapshot);
+        let resp = self.request(&req).await?;
+        if let GameResponse::LoadSnapshot = resp {
+            Ok(())
+        } else {
+            Err(Self::response_type_error(&req, &resp))
+        }
+    }
+    async fn save_snapshot(&mut self) -> anyhow::Result<SnapshotEnvelope> {
+        let req = GameRequest::SaveSnapshot;
+        let resp = self.request(&req).await?;
+        if let GameResponse::SaveSnapshot(snapshot) = resp {
+            Ok(snapshot)
+        } else {
+            Err(Self::response_type_error(&req, &resp))
+        }
+    }
+    async fn load_snapshot_binary(&mut self, version: u32, data: Vec<u8>) -> anyhow::Result<()> {
+        let req = GameRequest::LoadSnapshotBinary { version, data };
+        let resp = self.request(&req).await?;
+        if let GameResponse::LoadSnapshotBinary = resp {
+            Ok(())
+        } else {
+            Err(Self::response_type_error(&req, &resp))
+        }
+    }
+    async fn save_snapshot_binary(&mut self) -> anyhow::Result<Vec<u8>> {
+        let req = GameRequest::SaveSnapshotBinary;
+        let resp = self.request(&req).await?;
+        if let GameResponse::SaveSnapshotBinary(data) = resp {
+            Ok(data)
+        } else {
+            Err(Self::response_type_error(&req, &resp))
+        }
+    }
+    async fn advance(&mut self, tick: GameTick, action: IValue) -> anyhow::Result<()> {
+        let req = GameRequest::Advance { tick, action };
+        let resp = self.request(&req).await?;
+        if let GameResponse::Advance = resp {
+            Ok(())
+        } else {
+            Err(Self::response_type_error(&req, &resp))
+        }
+    }
+    async fn state(&mut self) -> anyhow::Result<GameState> {
+        let req = GameRequest::State;
+        let resp = self.request(&req).await?;
+        if let GameResponse::State(state) = resp {
+            Ok(state)
+        } else {
+            Err(Self::response_type_error(&req, &resp))
+        }
+    }
+    async fn undo(&mut self, to_tick: GameTick) -> Result<GameState, GameError> {
+        let req = GameRequest::Undo { to_tick };
+        let resp = self.request(&req).await?;
+        if let GameResponse::Undo(res) = resp {
+            res.map_err(GameError::Refused)
+        } else {
+            Err(Self::response_type_error(&req, &resp).into())
+        }
+    }
+    async fn resign(&mut self, player: i32) -> anyhow::Result<GameState> {
+        let req = GameRequest::Resign { player };
+        let resp = self.request(&req).await?;
+        if let GameResponse::Resign(state) = resp {
+            Ok(state)
+        } else {
+            Err(Self::response_type_error(&req, &resp))
+        }
+    }
+    async fn render_player_state(&mut self, player: PlayerView) -> anyhow::Result<IValue> {
+        let req = GameRequest::RenderPlayerState { player };
+        let resp = self.request(&req).await?;
+        if let GameResponse::RenderPlayerState(state) = resp {
+            Ok(state)
+        } else {
+            Err(Self::response_type_error(&req, &resp))
+        }
+    }
+    async fn render_diff(
+        &mut self,
+        player: PlayerView,
+        since_tick: GameTick,
+    ) -> anyhow::Result<Option<IValue>> {
+        let req = GameRequest::RenderDiff { player, since_tick };
+        let resp = self.request(&req).await?;
+        if let GameResponse::RenderDiff(patch) = resp {
+            Ok(patch)
+        } else {
+            Err(Self::response_type_error(&req, &resp))
+        }
+    }
+    async fn ping(&mut self) -> anyhow::Result<()> {
+        let req = GameRequest::Ping;
+        let resp = self.request(&req).await?;
+        if let GameResponse::Pong = resp {
+            Ok(())
+        } else {
+            Err(Self::response_type_error(&req, &resp))
+        }
+    }
+
+    // Presentation-specific functionality
+    async fn render_console_ui(&mut self, player: PlayerView) -> Result<ConsoleUi, GameError> {
+        let req = GameRequest::RenderConsoleUi { player };
+        let resp = self.request(&req).await?;
+        if let GameResponse::RenderConsoleUi(ui) = resp {
+            ui.ok_or(GameError::UnsupportedPresentationMode)
+        } else {
+            Err(Self::response_type_error(&req, &resp).into())
+        }
+    }
+    async fn interpret_console_command(
+        &mut self,
+        player: i32,
+        command: &str,
+    ) -> Result<CommandResponse<ConsoleUi>, GameError> {
+        let req = GameRequest::InterpretConsoleCommand {
+            player,
+            command: command.into(),
+        };
+        let resp = self.request(&req).await?;
+        if let GameResponse::InterpretConsoleCommand(r) = resp {
+            r.ok_or(GameError::UnsupportedPresentationMode)
+        } else {
+            Err(Self::response_type_error(&req, &resp).into())
+        }
+    }
+}